@@ -8,7 +8,7 @@ fn main() {
   let nm = args[1].clone();
 
   let vmi =
-    vboxhelper::get_vm_info(&VmId::Name(nm)).expect("Unable to get vm info");
+    vboxhelper::get_vm_info(VmId::Name(nm)).expect("Unable to get vm info");
 
   for n in &vmi.nics {
     let t = match &n.nictype {
@@ -18,6 +18,12 @@ fn main() {
       NICType::IntNet(i) => {
         format!("intnet:{}", i.name)
       }
+      NICType::Nat(n) => {
+        format!("nat:{} forwards", n.forwards.len())
+      }
+      NICType::Generic(g) => {
+        format!("generic:{}", g.driver)
+      }
     };
     println!(
       "idx:[{}]  mac:[{}]  {}",
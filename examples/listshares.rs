@@ -7,7 +7,7 @@ fn main() {
   let nm = args[1].clone();
 
   let vmi =
-    vboxhelper::get_vm_info(&VmId::Name(nm)).expect("Unable to get vm info");
+    vboxhelper::get_vm_info(VmId::Name(nm)).expect("Unable to get vm info");
 
   for (name, path) in &vmi.shares_list {
     println!("{}  {}", name, path.display());
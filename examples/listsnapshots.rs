@@ -7,7 +7,7 @@ fn main() {
   let nm = args[1].clone();
 
   let map =
-    snapshot::map(&VmId::Name(nm)).expect("Unable to get snapshot map");
+    snapshot::map(VmId::Name(nm)).expect("Unable to get snapshot map");
 
   for (k, v) in map.iter() {
     println!("{}  {}", k, v);
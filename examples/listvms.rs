@@ -1,5 +1,3 @@
-use vboxhelper;
-
 fn main() {
   let lst = vboxhelper::get_vm_list().expect("Unable to get VM list");
 
@@ -6,7 +6,7 @@ fn main() {
   let args: Vec<String> = env::args().collect();
   let nm = args[1].clone();
 
-  let map = vboxhelper::get_vm_info_map(&VmId::Name(nm))
+  let map = vboxhelper::get_vm_info_map(VmId::Name(nm))
     .expect("Unable to get VM list");
 
   let mut keys = Vec::new();
@@ -15,7 +15,7 @@ fn main() {
   // let kline = map.keys().map(String::len).max();
 
   let mut klen = 0;
-  for (k, _) in &map {
+  for k in map.keys() {
     if k.len() > klen {
       klen = k.len();
     }
@@ -10,12 +10,12 @@ fn main() {
   let args: Vec<String> = env::args().collect();
   let nm = args[1].clone();
 
-  let snaps = snapshot::get(&VmId::Name(nm)).expect("Unable to get snapshots");
+  let snaps = snapshot::get(VmId::Name(nm)).expect("Unable to get snapshots");
 
   // This works as well
   /*
   let snaps =
-    vboxhelper::get_vm_info(&VmId::Name(nm)).expect("Unable to get VM list");
+    vboxhelper::get_vm_info(VmId::Name(nm)).expect("Unable to get VM list");
   let snaps = snaps.snapshots;
   */
 
@@ -29,11 +29,8 @@ fn main() {
   q.push_back(root);
   stack.push(q);
 
-  while !stack.is_empty() {
-    let mut q = stack.pop().unwrap();
-
-    while !q.is_empty() {
-      let s = q.pop_front().unwrap();
+  while let Some(mut q) = stack.pop() {
+    while let Some(s) = q.pop_front() {
 
       let level = stack.len();
 
@@ -0,0 +1,35 @@
+//! Export virtual machines as appliances.
+
+use std::borrow::Borrow;
+use std::path::Path;
+use std::process::Command;
+
+use crate::platform;
+use crate::utils;
+use crate::{Error, VmId};
+
+
+/// Export a virtual machine to an appliance file.
+///
+/// The format is selected by `output`'s extension; `.ova` files bundle the
+/// descriptor and disk images in a single archive, `.ovf` files are written
+/// alongside separate disk image files.
+pub fn export<V, P>(vid: V, output: P) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>
+{
+  // VBoxManage export $VM --output <file>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("export");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--output");
+  cmd.arg(output.as_ref());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
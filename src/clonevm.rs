@@ -0,0 +1,245 @@
+//! Clone virtual machines.
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::guestproperty;
+use crate::platform;
+use crate::snapshot::SnapshotId;
+use crate::utils;
+use crate::{list_vms, Error, VmId};
+
+
+/// Which parts of a virtual machine's snapshot tree to clone.
+pub enum CloneMode {
+  /// Clone the current state (or the selected snapshot) only.
+  Machine,
+
+  /// Clone the selected snapshot and all its children.
+  MachineAndChildren,
+
+  /// Clone all snapshots.
+  All
+}
+
+
+/// Options for cloning a virtual machine.
+pub struct CloneOptions {
+  /// Name of the new virtual machine.
+  pub name: String,
+
+  /// Clone from this snapshot rather than from the current state.
+  pub snapshot: Option<SnapshotId>,
+
+  pub mode: Option<CloneMode>,
+
+  /// Create a linked clone, which uses differencing images on top of the
+  /// source's disks instead of copying them.  Requires `snapshot` to be set.
+  pub link: bool,
+
+  /// Keep the MAC addresses of all network adapters rather than generating
+  /// new ones.
+  pub keep_all_macs: bool,
+
+  /// Keep the MAC addresses of NAT network adapters.
+  pub keep_nat_macs: bool,
+
+  pub basefolder: Option<PathBuf>
+}
+
+impl CloneOptions {
+  pub fn new<N>(name: N) -> Self
+  where
+    N: AsRef<str>
+  {
+    CloneOptions {
+      name: name.as_ref().to_string(),
+      snapshot: None,
+      mode: None,
+      link: false,
+      keep_all_macs: false,
+      keep_nat_macs: false,
+      basefolder: None
+    }
+  }
+}
+
+
+/// Clone a virtual machine and register the clone.
+///
+/// Returns the uuid of the new virtual machine.
+pub fn clone<V>(vid: V, opts: &CloneOptions) -> Result<uuid::Uuid, Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage clonevm $VM --name <name> --register [--snapshot <snap>] \
+  //  [--mode machine|machineandchildren|all] [--options link,keepallmacs]
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("clonevm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--name");
+  cmd.arg(&opts.name);
+  cmd.arg("--register");
+  if let Some(ref snap) = opts.snapshot {
+    cmd.arg("--snapshot");
+    cmd.arg(snap.to_string());
+  }
+  if let Some(ref mode) = opts.mode {
+    cmd.arg("--mode");
+    cmd.arg(match mode {
+      CloneMode::Machine => "machine",
+      CloneMode::MachineAndChildren => "machineandchildren",
+      CloneMode::All => "all"
+    });
+  }
+
+  let mut options = Vec::new();
+  if opts.link {
+    options.push("link");
+  }
+  if opts.keep_all_macs {
+    options.push("keepallmacs");
+  }
+  if opts.keep_nat_macs {
+    options.push("keepnatmacs");
+  }
+  if !options.is_empty() {
+    cmd.arg("--options");
+    cmd.arg(options.join(","));
+  }
+
+  if let Some(ref basefolder) = opts.basefolder {
+    cmd.arg("--basefolder");
+    cmd.arg(basefolder);
+  }
+
+  // clonevm doesn't report the new virtual machine's uuid, so look for a
+  // virtual machine with the requested name which wasn't there before.
+  // The list is fetched directly since a cached one may be out of date.
+  let before: HashSet<uuid::Uuid> =
+    list_vms("vms")?.into_iter().map(|(_, u)| u).collect();
+
+  let res = utils::exec(cmd);
+  crate::invalidate_vm_list();
+  res?;
+
+  let after = list_vms("vms")?;
+  match after
+    .into_iter()
+    .find(|(name, u)| *name == opts.name && !before.contains(u))
+  {
+    Some((_, u)) => Ok(u),
+    None => {
+      let s = format!("Unable to find cloned virtual machine '{}'", opts.name);
+      Err(Error::Missing(s))
+    }
+  }
+}
+
+
+/// Guest property [`clone_fleet_member()`] stores the requested hostname
+/// in.  Guest-side provisioning is expected to read it and apply it.
+pub const HOSTNAME_PROPERTY: &str = "/vboxhelper/hostname";
+
+
+/// Create a new member of a fleet of virtual machines from a template.
+///
+/// The template's current state is fully cloned into a new virtual machine
+/// named `new_name`, which is registered.  If `regen_macs` is `true` its
+/// network adapters get new MAC addresses (which is what clones of the same
+/// template running side by side on one network need), otherwise the
+/// template's MAC addresses are kept.
+///
+/// If `hostname` is given it's stored in the clone's [`HOSTNAME_PROPERTY`]
+/// guest property; VirtualBox has no way of setting a guest's hostname by
+/// itself.
+///
+/// This always makes full clones.  Linked clones share the template's disks
+/// and must be made from a snapshot; use [`clone()`] with
+/// [`CloneOptions::link`] and [`CloneOptions::snapshot`] set for that.
+pub fn clone_fleet_member(
+  template: &VmId,
+  new_name: &str,
+  regen_macs: bool,
+  hostname: Option<&str>
+) -> Result<uuid::Uuid, Error> {
+  let mut opts = CloneOptions::new(new_name);
+  opts.keep_all_macs = !regen_macs;
+
+  let u = clone(template, &opts)?;
+
+  if let Some(hostname) = hostname {
+    guestproperty::set(VmId::Uuid(u), HOSTNAME_PROPERTY, hostname)?;
+  }
+
+  Ok(u)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  const TEMPLATE: &str = "11111111-1111-1111-1111-111111111111";
+  const MEMBER: &str = "22222222-2222-2222-2222-222222222222";
+
+  /// Queue the replies for a clone of the template named `web1`.
+  fn queue_clone(runner: &Runner) {
+    let before = format!("\"template\" {{{}}}\n", TEMPLATE);
+    let after = format!("{}\"web1\" {{{}}}\n", before, MEMBER);
+    runner.ok(&before);
+    runner.ok("");
+    runner.ok(&after);
+  }
+
+  #[test]
+  fn fleet_member_clone_options() {
+    let runner = Runner::new();
+    let template = VmId::from("template");
+    queue_clone(&runner);
+    let u = clone_fleet_member(&template, "web1", true, None).unwrap();
+    assert_eq!(u.to_string(), MEMBER);
+    queue_clone(&runner);
+    clone_fleet_member(&template, "web1", false, None).unwrap();
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "list vms",
+        "clonevm template --name web1 --register",
+        "list vms",
+        "list vms",
+        "clonevm template --name web1 --register --options keepallmacs",
+        "list vms"
+      ]
+    );
+  }
+
+  #[test]
+  fn fleet_member_hostname_property() {
+    let runner = Runner::new();
+    queue_clone(&runner);
+    let template = VmId::from("template");
+    clone_fleet_member(&template, "web1", true, Some("web1.lab")).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 4);
+    assert_eq!(
+      calls[3],
+      [
+        "guestproperty",
+        "set",
+        &format!("{{{}}}", MEMBER),
+        HOSTNAME_PROPERTY,
+        "web1.lab"
+      ]
+    );
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
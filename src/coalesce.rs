@@ -0,0 +1,168 @@
+//! Share the result of an expensive query between concurrent callers.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+
+struct State<T> {
+  value: Option<(Instant, T)>,
+  inflight: bool,
+
+  /// Bumped by `invalidate()` so that a query which was in flight at the
+  /// time doesn't store its (possibly stale) result.
+  generation: u64
+}
+
+
+/// A cached value which is refreshed by at most one caller at a time.
+///
+/// Callers which arrive while a refresh is in flight wait for it and use its
+/// result.  Errors aren't cached; if the refresh fails the waiters try again
+/// themselves.
+pub(crate) struct Coalesced<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar
+}
+
+
+/// Clears the in-flight flag even if the query panics, so waiters don't hang.
+struct Inflight<'a, T> {
+  owner: &'a Coalesced<T>
+}
+
+impl<T> Drop for Inflight<'_, T> {
+  fn drop(&mut self) {
+    self.owner.lock().inflight = false;
+    self.owner.cond.notify_all();
+  }
+}
+
+
+impl<T> Coalesced<T> {
+  fn lock(&self) -> MutexGuard<'_, State<T>> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+impl<T: Clone> Coalesced<T> {
+  pub(crate) const fn new() -> Self {
+    Coalesced {
+      state: Mutex::new(State {
+        value: None,
+        inflight: false,
+        generation: 0
+      }),
+      cond: Condvar::new()
+    }
+  }
+
+  /// Return the cached value if it's younger than `ttl`, otherwise run
+  /// `query` (or wait for a concurrent caller's `query`) to refresh it.
+  pub(crate) fn get<F>(&self, ttl: Duration, query: F) -> Result<T, Error>
+  where
+    F: FnOnce() -> Result<T, Error>
+  {
+    let mut st = self.lock();
+    loop {
+      if let Some((at, ref v)) = st.value {
+        if at.elapsed() < ttl {
+          return Ok(v.clone());
+        }
+      }
+      if !st.inflight {
+        break;
+      }
+      st = self.cond.wait(st).unwrap_or_else(|e| e.into_inner());
+    }
+    st.inflight = true;
+    let generation = st.generation;
+    drop(st);
+
+    let _inflight = Inflight { owner: self };
+    let res = query();
+
+    if let Ok(ref v) = res {
+      let mut st = self.lock();
+      if st.generation == generation {
+        st.value = Some((Instant::now(), v.clone()));
+      }
+    }
+
+    res
+  }
+
+  /// Discard the cached value.
+  pub(crate) fn invalidate(&self) {
+    let mut st = self.lock();
+    st.value = None;
+    st.generation += 1;
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Barrier};
+  use std::thread;
+
+  const TTL: Duration = Duration::from_secs(60);
+
+  #[test]
+  fn concurrent_callers_share_one_query() {
+    const CALLERS: usize = 8;
+    let cache = Arc::new(Coalesced::new());
+    let queries = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(CALLERS));
+
+    let handles: Vec<_> = (0..CALLERS)
+      .map(|_| {
+        let (cache, queries, barrier) =
+          (cache.clone(), queries.clone(), barrier.clone());
+        thread::spawn(move || {
+          barrier.wait();
+          cache.get(TTL, || {
+            queries.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            Ok(42)
+          })
+        })
+      })
+      .collect();
+
+    for h in handles {
+      assert_eq!(h.join().unwrap().unwrap(), 42);
+    }
+    assert_eq!(queries.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn errors_are_not_cached() {
+    let cache = Coalesced::new();
+    let res = cache.get(TTL, || Err(Error::MissingData("x".to_string())));
+    assert!(res.is_err());
+    assert_eq!(cache.get(TTL, || Ok(1)).unwrap(), 1);
+    assert_eq!(cache.get(TTL, || Ok(2)).unwrap(), 1);
+  }
+
+  #[test]
+  fn invalidate_discards_value_and_in_flight_result() {
+    let cache = Coalesced::new();
+    assert_eq!(cache.get(TTL, || Ok(1)).unwrap(), 1);
+    cache.invalidate();
+
+    // A query which was running while the cache was invalidated returns its
+    // result to its caller but doesn't store it.
+    let v = cache.get(TTL, || {
+      cache.invalidate();
+      Ok(2)
+    });
+    assert_eq!(v.unwrap(), 2);
+    assert_eq!(cache.get(TTL, || Ok(3)).unwrap(), 3);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
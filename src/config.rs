@@ -0,0 +1,156 @@
+//! Library-wide settings.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+/// Callback invoked after every `VBoxManage` (or other VirtualBox tool)
+/// command the library runs.
+///
+/// It's passed the command line (program followed by its arguments, with
+/// secrets such as passwords replaced by `***`), how long the command took
+/// to run, and whether it succeeded.
+pub type Observer = Arc<dyn Fn(&[String], Duration, bool) + Send + Sync>;
+
+
+/// Settings which affect how the library talks to `VBoxManage`.
+#[derive(Clone, Default)]
+pub struct Config {
+  /// If set, [`get_vm_list()`](crate::get_vm_list) (and functions built on
+  /// it, such as [`have_vm()`](crate::have_vm)) reuse a virtual machine
+  /// list which is younger than this, and concurrent callers share a single
+  /// `VBoxManage list vms` invocation rather than spawning one each.
+  ///
+  /// The tradeoff is consistency: virtual machines which are registered or
+  /// unregistered by other processes (or by calling `VBoxManage` directly)
+  /// may not show up, or may linger, until the cached list expires.
+  /// Registrations made through this crate discard the cached list.
+  pub vm_list_ttl: Option<Duration>,
+
+  /// If set, called after each command; see [`Observer`].  The observer is
+  /// called on the thread which ran the command and should return quickly.
+  pub observer: Option<Observer>,
+
+  /// Global options, such as `-q` or `--nologo`, which are passed to
+  /// `VBoxManage` ahead of the subcommand on every invocation.
+  pub global_flags: Vec<String>,
+
+  /// The `VBoxManage` executable to run.  If `None` it's looked up in the
+  /// `PATH` (or, on Windows, in the VirtualBox installation directory).
+  pub vboxmanage: Option<PathBuf>,
+
+  /// If set, commands which take longer than this are killed and
+  /// `Err(Error::Timeout)` is returned.
+  ///
+  /// This doesn't apply to commands whose output is handed to the caller
+  /// line by line as it's produced, such as
+  /// [`get_vm_list_iter()`](crate::get_vm_list_iter); how long those take
+  /// depends on how quickly the caller consumes the lines.
+  pub timeout: Option<Duration>,
+
+  /// Number of times a command which fails with a transient error (see
+  /// [`Error::is_transient()`](crate::Error::is_transient)) is retried
+  /// before the error is returned.
+  ///
+  /// Commands whose output is handed to the caller line by line aren't
+  /// retried, since the lines have already been consumed by the time the
+  /// failure is known.
+  pub retries: u32,
+
+  /// Environment variables which are set for every command, in addition to
+  /// the ones inherited from this process.
+  pub env: Vec<(String, String)>
+}
+
+impl Config {
+  /// Get a copy of the settings currently in effect; the same as [`get()`].
+  ///
+  /// Useful for checking that settings installed with [`set()`] took
+  /// effect.  The `Debug` output lists every setting, except that an
+  /// installed observer is only shown as `<observer>`.
+  pub fn current() -> Config {
+    get()
+  }
+}
+
+impl fmt::Debug for Config {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Config")
+      .field("vm_list_ttl", &self.vm_list_ttl)
+      .field("observer", &self.observer.as_ref().map(|_| "<observer>"))
+      .field("global_flags", &self.global_flags)
+      .field("vboxmanage", &self.vboxmanage)
+      .field("timeout", &self.timeout)
+      .field("retries", &self.retries)
+      .field("env", &self.env)
+      .finish()
+  }
+}
+
+static CONFIG: Mutex<Config> = Mutex::new(Config {
+  vm_list_ttl: None,
+  observer: None,
+  global_flags: Vec::new(),
+  vboxmanage: None,
+  timeout: None,
+  retries: 0,
+  env: Vec::new()
+});
+
+
+/// Replace the library-wide settings.
+pub fn set(cfg: Config) {
+  *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = cfg;
+}
+
+
+/// Get a copy of the current library-wide settings.
+pub fn get() -> Config {
+  CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn current_reads_back_installed_settings() {
+    let _runner = Runner::new();
+    set(Config {
+      vm_list_ttl: Some(Duration::from_secs(5)),
+      observer: Some(Arc::new(|_: &[String], _, _| {})),
+      global_flags: vec!["-q".to_string()],
+      vboxmanage: Some(PathBuf::from("/opt/vbox/VBoxManage")),
+      timeout: Some(Duration::from_secs(30)),
+      retries: 3,
+      env: vec![("VBOX_USER_HOME".to_string(), "/srv/vbox".to_string())]
+    });
+    let cfg = Config::current();
+    set(Config::default());
+
+    assert_eq!(cfg.vm_list_ttl, Some(Duration::from_secs(5)));
+    assert!(cfg.observer.is_some());
+    assert_eq!(cfg.global_flags, ["-q"]);
+    assert_eq!(cfg.vboxmanage, Some(PathBuf::from("/opt/vbox/VBoxManage")));
+    assert_eq!(cfg.timeout, Some(Duration::from_secs(30)));
+    assert_eq!(cfg.retries, 3);
+    assert_eq!(
+      cfg.env,
+      [("VBOX_USER_HOME".to_string(), "/srv/vbox".to_string())]
+    );
+    assert_eq!(
+      format!("{:?}", cfg),
+      concat!(
+        "Config { vm_list_ttl: Some(5s), observer: Some(\"<observer>\"), ",
+        "global_flags: [\"-q\"], ",
+        "vboxmanage: Some(\"/opt/vbox/VBoxManage\"), timeout: Some(30s), ",
+        "retries: 3, env: [(\"VBOX_USER_HOME\", \"/srv/vbox\")] }"
+      )
+    );
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -1,10 +1,21 @@
 //! Control the run state of a virtual machine.
 
 use std::borrow::Borrow;
+use std::ops::BitOr;
+use std::path::Path;
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::guestproperty;
+use crate::modifyvm::ClipboardMode;
+use crate::nics::{self, NICType, PortForwardRule};
 use crate::platform;
-use crate::{Error, Headless, RunContext, VmId};
+use crate::strutils::onoff;
+use crate::utils;
+use crate::{
+  version, Error, Headless, RunContext, VmId, VmResults, VmState
+};
 
 
 /// Start a virtual machine by UUID or name.
@@ -19,7 +30,7 @@ where
   V: Borrow<VmId>,
   R: Borrow<RunContext>
 {
-  let mut cmd = match ctx.borrow() {
+  let cmd = match ctx.borrow() {
     RunContext::GUI => {
       let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
       cmd.arg("startvm");
@@ -47,19 +58,9 @@ where
     }
   };
 
-  let out = match cmd.output() {
-    Ok(out) => out,
-    Err(_) => {
-      return Err(Error::FailedToExecute(format!("{:?}", cmd)));
-    }
-  };
+  utils::exec(cmd)?;
 
-  if out.status.success() {
-    Ok(())
-  } else {
-    let s = format!("{:?}", cmd);
-    Err(Error::CommandFailed(s, out))
-  }
+  Ok(())
 }
 
 
@@ -79,18 +80,61 @@ where
   cmd.arg(vid.borrow().to_string());
   cmd.arg("poweroff");
 
-  let out = match cmd.output() {
-    Ok(out) => out,
-    Err(_) => {
-      return Err(Error::FailedToExecute(format!("{:?}", cmd)));
-    }
-  };
+  utils::exec(cmd)?;
 
-  if out.status.success() {
-    Ok(())
-  } else {
-    Err(Error::CommandFailed(format!("{:?}", cmd), out))
-  }
+  Ok(())
+}
+
+
+/// Press a virtual machine's ACPI power button.
+///
+/// This asks the guest operating system to shut down; it returns as soon as
+/// the event has been delivered.  Guests which do not handle ACPI events
+/// will ignore it.
+pub fn acpi_power_button<V>(vid: V) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("acpipowerbutton");
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Pause a running virtual machine.
+pub fn pause<V>(vid: V) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("pause");
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Resume a paused virtual machine.
+pub fn resume<V>(vid: V) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("resume");
+
+  utils::exec(cmd)?;
+
+  Ok(())
 }
 
 
@@ -105,18 +149,1050 @@ where
   cmd.arg(vid.borrow().to_string());
   cmd.arg("reset");
 
-  let out = match cmd.output() {
-    Ok(out) => out,
-    Err(_) => {
-      let s = format!("{:?}", cmd);
-      return Err(Error::FailedToExecute(s));
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Get the timestamp of the Guest Additions version guest property, which is
+/// (re)published every time the Guest Additions start.
+fn ga_version_timestamp(vid: &VmId) -> Result<Option<String>, Error> {
+  let props = guestproperty::enumerate(vid)?;
+  Ok(
+    props
+      .into_iter()
+      .find(|p| p.name == "/VirtualBox/GuestAdd/Version")
+      .map(|p| p.timestamp)
+  )
+}
+
+
+/// Reset a virtual machine and wait until the guest has come back up.
+///
+/// A restart is considered to have happened once the virtual machine is
+/// running and the Guest Additions have re-reported their version after the
+/// reset.  This requires the Guest Additions to be running in the guest
+/// before the reset; if they aren't `Err(Error::MissingData)` is returned
+/// without resetting the virtual machine.
+///
+/// The virtual machine is checked every `poll`.  Returns
+/// `Err(Error::Timeout)` if no restart was observed within `timeout`.
+pub fn reset_verified<V>(
+  vid: V,
+  timeout: Duration,
+  poll: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let before = match ga_version_timestamp(vid.borrow())? {
+    Some(ts) => ts,
+    None => {
+      let s = format!(
+        "Guest Additions of '{}' are not running; can't verify reset",
+        vid.borrow()
+      );
+      return Err(Error::MissingData(s));
     }
   };
 
-  if out.status.success() {
-    Ok(())
+  reset(vid.borrow())?;
+
+  let start = Instant::now();
+  loop {
+    thread::sleep(poll);
+
+    if crate::is_vm_state(vid.borrow(), VmState::Running)? {
+      if let Some(ts) = ga_version_timestamp(vid.borrow())? {
+        if ts != before {
+          break;
+        }
+      }
+    }
+
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout);
+    }
+  }
+
+  Ok(())
+}
+
+
+/// Enable or disable one of a running virtual machine's screens.
+///
+/// `screen` is the zero-based index of the virtual monitor, and must be
+/// lower than the virtual machine's configured monitor count.  The screen's
+/// resolution is left unchanged.  Requires the Guest Additions to be
+/// running in the guest.
+pub fn set_screen_enabled<V>(
+  vid: V,
+  screen: u8,
+  enabled: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let vmi = crate::get_vm_info(vid.borrow())?;
+  let monitors = vmi.monitor_count.unwrap_or(1);
+  if screen >= monitors {
+    let s = format!(
+      "Screen {} out of range; '{}' has {} monitor(s)",
+      screen,
+      vid.borrow(),
+      monitors
+    );
+    return Err(Error::InvalidArgument(s));
+  }
+
+  // A resolution of 0x0x0 means "keep the current mode".
+  // VBoxManage controlvm $VM setvideomodehint 0 0 0 <screen> yes|no
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("setvideomodehint");
+  cmd.args(["0", "0", "0"]);
+  cmd.arg(screen.to_string());
+  cmd.arg(if enabled { "yes" } else { "no" });
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Save a screenshot of a running virtual machine's first screen to `output`,
+/// as a PNG image.
+///
+/// Unless `force` is set, the virtual machine's state is checked first and
+/// `Err(Error::MissingData)` is returned if it isn't running (or paused),
+/// rather than `VBoxManage`'s less obvious failure.
+pub fn screenshot<V, P>(vid: V, output: P, force: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>
+{
+  if !force {
+    let vmi = crate::get_vm_info(vid.borrow())?;
+    if !matches!(vmi.state, VmState::Running | VmState::Paused) {
+      let s = format!("VM '{}' is not running", vid.borrow());
+      return Err(Error::MissingData(s));
+    }
+  }
+
+  // VBoxManage controlvm $VM screenshotpng <output>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("screenshotpng");
+  cmd.arg(output.as_ref());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Change the attachment of network adapter `idx` of a running virtual
+/// machine.
+///
+/// This is the runtime counterpart of [`nics::reconfigure()`], with the
+/// limitations VirtualBox imposes on running virtual machines:
+/// - The virtual machine must be running (or paused); otherwise
+///   `Err(Error::InvalidArgument)` is returned.
+/// - The adapter must already be enabled; adapters can't be added or
+///   removed at runtime, so `Err(Error::InvalidArgument)` is returned for
+///   adapters which are disabled.
+/// - The change only lasts for the current session.
+///
+/// Every attachment type [`NICType`] can express may be selected at runtime,
+/// but each needs its argument; `Err(Error::InvalidArgument)` is returned if
+/// the bridged adapter, internal network name, generic driver, a generic
+/// property name or a port forwarding rule name is empty.
+///
+/// NAT port forwarding rules and generic driver properties in `nictype` are
+/// applied after the attachment has been changed, one command each.  A NAT
+/// adapter's existing rules are kept; an existing rule with the same name
+/// as one in `nictype` is deleted before the new one is added.  Existing
+/// rules are only known for adapters which are attached to NAT at the time
+/// of the call, and can't be attributed to an adapter if several are, so
+/// adding a rule may still fail with a name conflict in those cases.
+///
+/// [`nics::reconfigure()`]: crate::nics::reconfigure
+pub fn set_nic_type_runtime<V>(
+  vid: V,
+  idx: u8,
+  nictype: &NICType
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let map = crate::get_vm_info_map(vid.borrow())?;
+
+  let state = match map.get("VMState") {
+    Some(s) => VmState::from(s),
+    None => VmState::Unknown
+  };
+  if !matches!(state, VmState::Running | VmState::Paused) {
+    let s = format!(
+      "VM '{}' is not running; use nics::reconfigure() instead",
+      vid.borrow()
+    );
+    return Err(Error::InvalidArgument(s));
+  }
+
+  let attached = map.get(&format!("nic{}", idx)).map(|v| v.as_str());
+  match attached {
+    Some("none") | None => {
+      let s = format!(
+        "Network adapter {} of '{}' is disabled and can't be enabled while \
+         the VM is running",
+        idx,
+        vid.borrow()
+      );
+      return Err(Error::InvalidArgument(s));
+    }
+    Some(_) => {}
+  }
+
+  let current = if attached == Some("nat") {
+    nics::get_port_forwards(&map)?
+  } else {
+    Vec::new()
+  };
+
+  for args in nic_runtime_args(idx, nictype, &current)? {
+    let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+    cmd.arg("controlvm");
+    cmd.arg(vid.borrow().to_string());
+    cmd.args(args);
+
+    utils::exec(cmd)?;
+  }
+
+  Ok(())
+}
+
+
+/// Get the `controlvm` arguments for each of the commands needed to change
+/// the attachment of a running virtual machine's network adapter.
+///
+/// `current` are the adapter's existing NAT port forwarding rules.
+fn nic_runtime_args(
+  idx: u8,
+  nictype: &NICType,
+  current: &[PortForwardRule]
+) -> Result<Vec<Vec<String>>, Error> {
+  // VBoxManage controlvm $VM nic1 bridged|intnet|nat|generic [<arg>]
+  // VBoxManage controlvm $VM natpf1 delete "ssh"
+  // VBoxManage controlvm $VM natpf1 "ssh,tcp,,2222,,22"
+  // VBoxManage controlvm $VM nicproperty1 <key>=<value>
+
+  let required = |what: &str, val: &str| {
+    if val.is_empty() {
+      let s = format!("Network adapter {} needs a {}", idx, what);
+      Err(Error::InvalidArgument(s))
+    } else {
+      Ok(val.to_string())
+    }
+  };
+
+  let nic = format!("nic{}", idx);
+  let mut cmds = Vec::new();
+  match nictype {
+    NICType::Bridged(b) => {
+      let adapter = required("host adapter", &b.adapter)?;
+      cmds.push(vec![nic, "bridged".to_string(), adapter]);
+    }
+    NICType::IntNet(i) => {
+      let name = required("network name", &i.name)?;
+      cmds.push(vec![nic, "intnet".to_string(), name]);
+    }
+    NICType::Nat(n) => {
+      cmds.push(vec![nic, "nat".to_string()]);
+
+      // Adding a rule whose name is taken fails, so replace rules by name.
+      let natpf = format!("natpf{}", idx);
+      for rule in &n.forwards {
+        required("port forwarding rule name", &rule.name)?;
+        if current.contains(rule) {
+          continue;
+        }
+        if current.iter().any(|cur| cur.name == rule.name) {
+          let delete = "delete".to_string();
+          cmds.push(vec![natpf.clone(), delete, rule.name.clone()]);
+        }
+        cmds.push(vec![natpf.clone(), rule.to_string()]);
+      }
+    }
+    NICType::Generic(g) => {
+      let driver = required("generic driver", &g.driver)?;
+      cmds.push(vec![nic, "generic".to_string(), driver]);
+
+      // Sorted to get a predictable order.
+      let mut props: Vec<_> = g.properties.iter().collect();
+      props.sort();
+      for (name, val) in props {
+        required("generic property name", name)?;
+        cmds.push(vec![
+          format!("nicproperty{}", idx),
+          format!("{}={}", name, val)
+        ]);
+      }
+    }
+  }
+  Ok(cmds)
+}
+
+
+/// Extradata key under which the GUI stores its scale factors, as a comma
+/// separated list with one entry per screen.
+const SCALE_FACTOR_KEY: &str = "GUI/ScaleFactor";
+
+/// Valid range for scale factors.
+const SCALE_FACTOR_RANGE: std::ops::RangeInclusive<f32> = 0.25..=4.0;
+
+
+/// Set the factor the VirtualBox GUI scales screen `screen` of a virtual
+/// machine's display by, for example `2.0` for HiDPI hosts.
+///
+/// This only affects the GUI frontend; headless sessions and remote display
+/// clients are unaffected, and so is the resolution the guest sees.  The
+/// setting is stored with the virtual machine and may be changed while it's
+/// running.  Returns `Err(Error::InvalidArgument)` if `factor` is outside
+/// the range `0.25..=4.0`.
+pub fn set_scale_factor<V>(
+  vid: V,
+  screen: u8,
+  factor: f32
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if !SCALE_FACTOR_RANGE.contains(&factor) {
+    let s = format!("Scale factor {} out of range (0.25-4.0)", factor);
+    return Err(Error::InvalidArgument(s));
+  }
+
+  let current = get_extradata(vid.borrow(), SCALE_FACTOR_KEY)?;
+  let mut factors: Vec<String> = match current {
+    Some(ref v) if !v.is_empty() => {
+      v.split(',').map(|f| f.trim().to_string()).collect()
+    }
+    _ => Vec::new()
+  };
+  let screen = usize::from(screen);
+  if factors.len() <= screen {
+    factors.resize(screen + 1, "1".to_string());
+  }
+  factors[screen] = factor.to_string();
+
+  set_extradata(vid.borrow(), SCALE_FACTOR_KEY, &factors.join(","))
+}
+
+
+/// Get the factor the VirtualBox GUI scales screen `screen` of a virtual
+/// machine's display by; see [`set_scale_factor()`].
+///
+/// Returns `Ok(None)` if no scale factor has been set for the screen.
+pub fn get_scale_factor<V>(vid: V, screen: u8) -> Result<Option<f32>, Error>
+where
+  V: Borrow<VmId>
+{
+  let factors = match get_extradata(vid.borrow(), SCALE_FACTOR_KEY)? {
+    Some(v) => v,
+    None => return Ok(None)
+  };
+
+  // A single entry applies to all screens.
+  let entries: Vec<&str> = factors.split(',').map(|f| f.trim()).collect();
+  let entry = match entries.len() {
+    1 => entries[0],
+    _ => match entries.get(usize::from(screen)) {
+      Some(entry) => entry,
+      None => return Ok(None)
+    }
+  };
+
+  match entry.parse::<f32>() {
+    Ok(f) => Ok(Some(f)),
+    Err(_) => {
+      let s = format!("Unable to parse scale factor '{}'", entry);
+      Err(Error::BadFormat(s))
+    }
+  }
+}
+
+
+fn get_extradata(vid: &VmId, key: &str) -> Result<Option<String>, Error> {
+  // VBoxManage getextradata $VM <key>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("getextradata");
+  cmd.arg(vid.to_string());
+  cmd.arg(key);
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  // "Value: <value>" or "No value set!"
+  Ok(
+    String::from_utf8_lossy(&stdout)
+      .lines()
+      .find_map(|line| line.strip_prefix("Value: "))
+      .map(|v| v.trim_end().to_string())
+  )
+}
+
+
+fn set_extradata(vid: &VmId, key: &str, value: &str) -> Result<(), Error> {
+  // VBoxManage setextradata $VM <key> <value>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("setextradata");
+  cmd.arg(vid.to_string());
+  cmd.arg(key);
+  cmd.arg(value);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Write a core dump of a running virtual machine's memory to `output`.
+///
+/// The dump is an ELF core file which can be loaded into a debugger for
+/// kernel debugging.  The virtual machine must be running.  Returns
+/// `Err(Error::InvalidArgument)` if the directory `output` is to be written to
+/// does not exist.
+pub fn dump_core<V, P>(vid: V, output: P) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>
+{
+  let output = output.as_ref();
+  if let Some(dir) = output.parent() {
+    if !dir.as_os_str().is_empty() && !dir.is_dir() {
+      let s = format!("Directory '{}' does not exist", dir.display());
+      return Err(Error::InvalidArgument(s));
+    }
+  }
+
+  // VBoxManage debugvm $VM dumpvmcore --filename <output>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("debugvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("dumpvmcore");
+  cmd.arg("--filename");
+  cmd.arg(output);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Set of mouse buttons held down in a mouse event.
+///
+/// Buttons can be combined using `|`:
+///
+/// ```
+/// use vboxhelper::controlvm::MouseButtons;
+///
+/// let both = MouseButtons::LEFT | MouseButtons::RIGHT;
+/// assert!(both.contains(MouseButtons::LEFT));
+/// assert!(!both.contains(MouseButtons::MIDDLE));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u32);
+
+impl MouseButtons {
+  pub const NONE: MouseButtons = MouseButtons(0x00);
+  pub const LEFT: MouseButtons = MouseButtons(0x01);
+  pub const RIGHT: MouseButtons = MouseButtons(0x02);
+  pub const MIDDLE: MouseButtons = MouseButtons(0x04);
+  pub const X1: MouseButtons = MouseButtons(0x08);
+  pub const X2: MouseButtons = MouseButtons(0x10);
+
+  /// Get the raw button state bits, as expected by VirtualBox.
+  pub fn bits(&self) -> u32 {
+    self.0
+  }
+
+  /// Returns `true` if all the buttons in `other` are also in `self`.
+  pub fn contains(&self, other: MouseButtons) -> bool {
+    self.0 & other.0 == other.0
+  }
+}
+
+impl BitOr for MouseButtons {
+  type Output = Self;
+  fn bitor(self, rhs: Self) -> Self {
+    MouseButtons(self.0 | rhs.0)
+  }
+}
+
+
+/// Send a mouse event to a running virtual machine.
+///
+/// If `relative` is `false`, `x` and `y` are absolute screen coordinates the
+/// pointer is moved to; this requires the guest to support absolute pointing
+/// devices (which is typically the case when mouse integration is active).
+/// If `relative` is `true` the pointer is moved `x` and `y` pixels from its
+/// current position, which works with any guest mouse driver.
+///
+/// `buttons` is the set of buttons which are held down after the event, so a
+/// click is an event with the button held followed by one without it.
+pub fn mouse_event<V>(
+  vid: V,
+  x: i32,
+  y: i32,
+  buttons: MouseButtons,
+  relative: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage controlvm $VM mouseputeventabsolute <x> <y> <dz> <dw> <btns>
+  // VBoxManage controlvm $VM mouseputevent <dx> <dy> <dz> <dw> <btns>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  if relative {
+    cmd.arg("mouseputevent");
   } else {
-    Err(Error::CommandFailed(format!("{:?}", cmd), out))
+    cmd.arg("mouseputeventabsolute");
+  }
+  cmd.arg(x.to_string());
+  cmd.arg(y.to_string());
+  cmd.args(["0", "0"]);
+  cmd.arg(buttons.bits().to_string());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Change the shared clipboard mode of a running virtual machine.
+///
+/// See also [`modifyvm::set_clipboard_mode()`].
+///
+/// [`modifyvm::set_clipboard_mode()`]: crate::modifyvm::set_clipboard_mode
+pub fn set_clipboard_mode<V>(vid: V, mode: ClipboardMode) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VirtualBox 6.1 and later:
+  //   VBoxManage controlvm $VM clipboard mode <mode>
+  // Earlier:
+  //   VBoxManage controlvm $VM clipboard <mode>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("clipboard");
+  if version()?.at_least(6, 1) {
+    cmd.arg("mode");
+  }
+  cmd.arg(mode.as_arg());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Enable or disable mouse integration for a running virtual machine.
+///
+/// With mouse integration disabled the guest sees a relative pointing device
+/// and the host pointer has to be captured by the virtual machine's window.
+/// Requires the Guest Additions to be running in the guest.
+pub fn set_mouse_integration<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage controlvm $VM setmouseintegration on|off
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("controlvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("setmouseintegration");
+  cmd.arg(onoff(enabled));
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Actions which can be applied to many virtual machines using [`batch()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlAction {
+  /// See [`start()`].  Requires a [`RunContext`].
+  Start,
+
+  /// See [`kill()`].
+  Kill,
+
+  /// See [`acpi_power_button()`].
+  AcpiPowerButton,
+
+  /// See [`reset()`].
+  Reset
+}
+
+
+/// Apply `action` to each of the virtual machines in `vids`.
+///
+/// The virtual machines are processed concurrently, a few at a time.  A
+/// failure for one virtual machine doesn't affect the others; the outcome
+/// for each virtual machine is returned, in the order of `vids`.
+/// [`ControlAction::Start`] requires `ctx` to be set, and fails with
+/// `Err(Error::InvalidArgument)` for every virtual machine otherwise.
+pub fn batch(
+  vids: &[VmId],
+  action: ControlAction,
+  ctx: Option<RunContext>
+) -> VmResults {
+  let apply = |vid: &VmId| match action {
+    ControlAction::Start => match ctx {
+      Some(ref ctx) => start(vid, ctx),
+      None => {
+        let s = "Starting virtual machines requires a run context".to_string();
+        Err(Error::InvalidArgument(s))
+      }
+    },
+    ControlAction::Kill => kill(vid),
+    ControlAction::AcpiPowerButton => acpi_power_button(vid),
+    ControlAction::Reset => reset(vid)
+  };
+
+  let results = utils::parallel_map(vids, apply);
+
+  vids.iter().cloned().zip(results).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  use crate::nics::{BridgedNIC, GenericNIC, IntNetNIC, NatNIC, Protocol};
+  use std::collections::HashMap;
+
+  fn rule(name: &str, host_port: u16) -> PortForwardRule {
+    PortForwardRule {
+      name: name.to_string(),
+      proto: Protocol::Tcp,
+      host_ip: None,
+      host_port,
+      guest_ip: None,
+      guest_port: 22
+    }
+  }
+
+  fn joined(cmds: Vec<Vec<String>>) -> Vec<String> {
+    cmds.iter().map(|c| c.join(" ")).collect()
+  }
+
+  #[test]
+  fn nic_runtime_args_per_mode() {
+    let bridged = NICType::Bridged(BridgedNIC {
+      adapter: "eth0".to_string()
+    });
+    let intnet = NICType::IntNet(IntNetNIC {
+      name: "lab".to_string()
+    });
+    let mut properties = HashMap::new();
+    properties.insert("dport".to_string(), "10001".to_string());
+    properties.insert("dest".to_string(), "10.0.0.2".to_string());
+    let generic = NICType::Generic(GenericNIC {
+      driver: "UDPTunnel".to_string(),
+      properties
+    });
+
+    let args = |nictype| joined(nic_runtime_args(2, nictype, &[]).unwrap());
+    assert_eq!(args(&bridged), ["nic2 bridged eth0"]);
+    assert_eq!(args(&intnet), ["nic2 intnet lab"]);
+    assert_eq!(
+      args(&generic),
+      [
+        "nic2 generic UDPTunnel",
+        "nicproperty2 dest=10.0.0.2",
+        "nicproperty2 dport=10001"
+      ]
+    );
+  }
+
+  #[test]
+  fn nic_runtime_args_replace_nat_rules_by_name() {
+    let nat = NICType::Nat(NatNIC {
+      forwards: vec![rule("ssh", 2222), rule("web", 8080), rule("new", 9000)]
+    });
+    let current = [rule("ssh", 2222), rule("web", 8081)];
+
+    assert_eq!(
+      joined(nic_runtime_args(1, &nat, &current).unwrap()),
+      [
+        "nic1 nat",
+        "natpf1 delete web",
+        "natpf1 web,tcp,,8080,,22",
+        "natpf1 new,tcp,,9000,,22"
+      ]
+    );
+  }
+
+  #[test]
+  fn nic_runtime_args_require_arguments() {
+    let bridged = NICType::Bridged(BridgedNIC {
+      adapter: String::new()
+    });
+    let intnet = NICType::IntNet(IntNetNIC {
+      name: String::new()
+    });
+    let generic = NICType::Generic(GenericNIC {
+      driver: String::new(),
+      properties: HashMap::new()
+    });
+    let nat = NICType::Nat(NatNIC {
+      forwards: vec![rule("", 2222)]
+    });
+
+    for nictype in &[bridged, intnet, generic, nat] {
+      assert!(matches!(
+        nic_runtime_args(1, nictype, &[]),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn nic_type_runtime_requires_running_vm_and_enabled_nic() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\nnic1=\"nat\"\n");
+    runner.ok("name=\"vm\"\nVMState=\"running\"\nnic1=\"none\"\n");
+
+    let nat = NICType::Nat(NatNIC {
+      forwards: Vec::new()
+    });
+    for _ in 0..2 {
+      assert!(matches!(
+        set_nic_type_runtime(VmId::from("vm"), 1, &nat),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+    assert_eq!(runner.calls().len(), 2);
+  }
+
+  #[test]
+  fn nic_type_runtime_uses_current_nat_rules() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "VMState=\"running\"\n",
+      "nic1=\"nat\"\n",
+      "Forwarding(0)=\"ssh,tcp,,2200,,22\"\n"
+    ));
+
+    let nat = NICType::Nat(NatNIC {
+      forwards: vec![rule("ssh", 2222)]
+    });
+    set_nic_type_runtime(VmId::from("vm"), 1, &nat).unwrap();
+
+    let calls: Vec<String> =
+      runner.calls()[1..].iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "controlvm vm nic1 nat",
+        "controlvm vm natpf1 delete ssh",
+        "controlvm vm natpf1 ssh,tcp,,2222,,22"
+      ]
+    );
+  }
+
+  fn ga_version(ts: u32) -> String {
+    format!("/VirtualBox/GuestAdd/Version = '7.0.10' @ {}\n", ts)
+  }
+
+  #[test]
+  fn reset_verified_waits_for_new_timestamp() {
+    let runner = Runner::new();
+    runner.ok(&ga_version(100));
+    runner.ok("");
+    runner.ok("name=\"vm\"\nVMState=\"starting\"\n");
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+    runner.ok(&ga_version(100));
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+    runner.ok(&ga_version(200));
+
+    let poll = Duration::from_millis(1);
+    reset_verified(VmId::from("vm"), Duration::from_secs(5), poll).unwrap();
+
+    let cmds: Vec<String> =
+      runner.calls().iter().map(|c| c[0].clone()).collect();
+    assert_eq!(
+      cmds,
+      [
+        "guestproperty",
+        "controlvm",
+        "showvminfo",
+        "showvminfo",
+        "guestproperty",
+        "showvminfo",
+        "guestproperty"
+      ]
+    );
+    assert_eq!(runner.calls()[1], ["controlvm", "vm", "reset"]);
+  }
+
+  #[test]
+  fn reset_verified_times_out() {
+    let runner = Runner::new();
+    runner.ok(&ga_version(100));
+    runner.ok("");
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+    runner.ok(&ga_version(100));
+
+    let poll = Duration::from_millis(1);
+    assert!(matches!(
+      reset_verified(VmId::from("vm"), Duration::ZERO, poll),
+      Err(Error::Timeout)
+    ));
+    assert_eq!(runner.calls().len(), 4);
+  }
+
+  #[test]
+  fn reset_verified_requires_guest_additions() {
+    let runner = Runner::new();
+    runner.ok("/VirtualBox/HostInfo/GUI/LanguageID = 'en_US' @ 100\n");
+
+    let poll = Duration::from_millis(1);
+    assert!(matches!(
+      reset_verified(VmId::from("vm"), Duration::from_secs(1), poll),
+      Err(Error::MissingData(_))
+    ));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn batch_reports_each_vm() {
+    let runner = Runner::new();
+    runner.fail_for("vm2", "VBoxManage: error: Machine in invalid state");
+
+    let vids: Vec<VmId> =
+      ["vm1", "vm2", "vm3"].iter().map(|nm| VmId::from(*nm)).collect();
+    let res = batch(&vids, ControlAction::Kill, None);
+
+    let names: Vec<String> = res.iter().map(|(v, _)| v.to_string()).collect();
+    assert_eq!(names, ["vm1", "vm2", "vm3"]);
+    assert!(res[0].1.is_ok());
+    assert!(matches!(res[1].1, Err(Error::CommandFailed(_, _))));
+    assert!(res[2].1.is_ok());
+
+    let mut calls = runner.calls();
+    calls.sort();
+    assert_eq!(
+      calls,
+      [
+        ["controlvm", "vm1", "poweroff"],
+        ["controlvm", "vm2", "poweroff"],
+        ["controlvm", "vm3", "poweroff"]
+      ]
+    );
+  }
+
+  #[test]
+  fn batch_start_requires_context() {
+    let runner = Runner::new();
+
+    let vids = [VmId::from("vm1"), VmId::from("vm2")];
+    let res = batch(&vids, ControlAction::Start, None);
+    assert!(res
+      .iter()
+      .all(|(_, r)| matches!(r, Err(Error::InvalidArgument(_)))));
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn screen_enabled_args() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nmonitorcount=2\n");
+    runner.ok("name=\"vm\"\nmonitorcount=2\n");
+    set_screen_enabled(VmId::from("vm"), 1, false).unwrap();
+    set_screen_enabled(VmId::from("vm"), 0, true).unwrap();
+
+    assert_eq!(
+      joined(runner.calls()),
+      [
+        "showvminfo vm --machinereadable",
+        "controlvm vm setvideomodehint 0 0 0 1 no",
+        "showvminfo vm --machinereadable",
+        "controlvm vm setvideomodehint 0 0 0 0 yes"
+      ]
+    );
+  }
+
+  #[test]
+  fn screen_out_of_range_is_rejected() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nmonitorcount=2\n");
+    runner.ok("name=\"vm\"\n");
+
+    assert!(matches!(
+      set_screen_enabled(VmId::from("vm"), 2, true),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+      set_screen_enabled(VmId::from("vm"), 1, true),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(runner.calls().len(), 2);
+  }
+
+  #[test]
+  fn mouse_event_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    mouse_event(&vid, 100, 200, MouseButtons::LEFT, false).unwrap();
+    mouse_event(&vid, 100, 200, MouseButtons::NONE, false).unwrap();
+    let both = MouseButtons::LEFT | MouseButtons::RIGHT;
+    mouse_event(&vid, -5, 10, both, true).unwrap();
+
+    assert_eq!(
+      joined(runner.calls()),
+      [
+        "controlvm vm mouseputeventabsolute 100 200 0 0 1",
+        "controlvm vm mouseputeventabsolute 100 200 0 0 0",
+        "controlvm vm mouseputevent -5 10 0 0 3"
+      ]
+    );
+  }
+
+  #[test]
+  fn dump_core_args() {
+    let runner = Runner::new();
+    let output = std::env::temp_dir().join("vm.core");
+    dump_core(VmId::from("vm"), &output).unwrap();
+
+    let output = output.to_string_lossy();
+    assert_eq!(
+      runner.calls(),
+      [["debugvm", "vm", "dumpvmcore", "--filename", output.as_ref()]]
+    );
+  }
+
+  #[test]
+  fn dump_core_requires_existing_directory() {
+    let runner = Runner::new();
+    let output = std::env::temp_dir().join("vboxhelper-missing/vm.core");
+
+    assert!(matches!(
+      dump_core(VmId::from("vm"), &output),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn mouse_integration_args() {
+    let runner = Runner::new();
+    set_mouse_integration(VmId::from("vm"), true).unwrap();
+    set_mouse_integration(VmId::from("vm"), false).unwrap();
+
+    assert_eq!(
+      joined(runner.calls()),
+      [
+        "controlvm vm setmouseintegration on",
+        "controlvm vm setmouseintegration off"
+      ]
+    );
+  }
+
+  #[test]
+  fn screenshot_checks_state_unless_forced() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+    screenshot(VmId::from("vm"), "shot.png", false).unwrap();
+    screenshot(VmId::from("vm"), "shot.png", true).unwrap();
+
+    assert_eq!(
+      joined(runner.calls()),
+      [
+        "showvminfo vm --machinereadable",
+        "controlvm vm screenshotpng shot.png",
+        "controlvm vm screenshotpng shot.png"
+      ]
+    );
+  }
+
+  #[test]
+  fn screenshot_requires_running_vm() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\n");
+
+    assert!(matches!(
+      screenshot(VmId::from("vm"), "shot.png", false),
+      Err(Error::MissingData(_))
+    ));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn scale_factor_extradata_args() {
+    let runner = Runner::new();
+    runner.ok("No value set!\n");
+    runner.ok("");
+    runner.ok("Value: 1,1.5\n");
+    set_scale_factor(VmId::from("vm"), 0, 2.0).unwrap();
+    set_scale_factor(VmId::from("vm"), 2, 1.25).unwrap();
+
+    assert_eq!(
+      joined(runner.calls()),
+      [
+        "getextradata vm GUI/ScaleFactor",
+        "setextradata vm GUI/ScaleFactor 2",
+        "getextradata vm GUI/ScaleFactor",
+        "setextradata vm GUI/ScaleFactor 1,1.5,1.25"
+      ]
+    );
+  }
+
+  #[test]
+  fn scale_factor_out_of_range_is_rejected() {
+    let runner = Runner::new();
+    for factor in [0.2, 4.5].iter() {
+      assert!(matches!(
+        set_scale_factor(VmId::from("vm"), 0, *factor),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+    set_scale_factor(VmId::from("vm"), 0, 0.25).unwrap();
+    set_scale_factor(VmId::from("vm"), 0, 4.0).unwrap();
+    assert_eq!(runner.calls().len(), 4);
+  }
+
+  #[test]
+  fn get_scale_factor_per_screen() {
+    let runner = Runner::new();
+    runner.ok("Value: 1.5\n");
+    runner.ok("Value: 1,2\n");
+    runner.ok("Value: 1,2\n");
+    runner.ok("No value set!\n");
+
+    let vid = VmId::from("vm");
+    assert_eq!(get_scale_factor(&vid, 3).unwrap(), Some(1.5));
+    assert_eq!(get_scale_factor(&vid, 1).unwrap(), Some(2.0));
+    assert_eq!(get_scale_factor(&vid, 2).unwrap(), None);
+    assert_eq!(get_scale_factor(&vid, 0).unwrap(), None);
+    assert_eq!(runner.calls()[0], ["getextradata", "vm", "GUI/ScaleFactor"]);
   }
 }
 
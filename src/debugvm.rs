@@ -0,0 +1,151 @@
+//! Query VirtualBox's internal debugging information for a running virtual
+//! machine.
+//!
+//! This wraps `VBoxManage debugvm`, which is an advanced surface intended
+//! for debugging VirtualBox itself.  The available info items, statistics
+//! names and their output formats are not stable between VirtualBox versions.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::process::Command;
+
+use regex::Regex;
+
+use crate::platform;
+use crate::utils;
+use crate::{Error, VmId};
+
+
+/// Get the output of a debugger info item (for example `cpumguest` or
+/// `mmio`) for a running virtual machine.
+///
+/// The output is returned as-is.
+pub fn info<V, I>(vid: V, item: I) -> Result<String, Error>
+where
+  V: Borrow<VmId>,
+  I: AsRef<str>
+{
+  // VBoxManage debugvm $VM info <item>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("debugvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("info");
+  cmd.arg(item.as_ref());
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+
+/// Get a running virtual machine's internal statistics as a map of
+/// statistics names to values.
+///
+/// `pattern` can be used to select a subset of the statistics (for example
+/// `/Devices/*`).  Counters and plain values map to their value; for other
+/// kinds of samples (such as profiles) the tick count is used.
+pub fn statistics<V>(
+  vid: V,
+  pattern: Option<&str>
+) -> Result<HashMap<String, String>, Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage debugvm $VM statistics [--pattern <pattern>]
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("debugvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("statistics");
+  if let Some(pattern) = pattern {
+    cmd.arg("--pattern");
+    cmd.arg(pattern);
+  }
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  Ok(parse_statistics(&String::from_utf8_lossy(&stdout)))
+}
+
+
+/// Parse the XML-like statistics dump, which consists of one element per
+/// sample, e.g. `<Counter c="42" unit="times" name="/TM/CPU/cTicks"/>`.
+fn parse_statistics(output: &str) -> HashMap<String, String> {
+  let re_elem = Regex::new(r#"<\w+\s+([^>]*?)/?>"#).unwrap();
+  let re_attr = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+
+  let mut map = HashMap::new();
+
+  for elem in re_elem.captures_iter(output) {
+    let attrs: HashMap<&str, &str> = re_attr
+      .captures_iter(elem.get(1).unwrap().as_str())
+      .map(|cap| (cap.get(1).unwrap().as_str(), cap.get(2).unwrap().as_str()))
+      .collect();
+
+    let name = match attrs.get("name") {
+      Some(name) => name,
+      None => continue
+    };
+
+    let val = ["c", "val", "cTicks"]
+      .iter()
+      .find_map(|key| attrs.get(key));
+
+    if let Some(val) = val {
+      map.insert(name.to_string(), val.to_string());
+    }
+  }
+
+  map
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  const DUMP: &str = concat!(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n",
+    "<Statistics>\n",
+    "<Counter c=\"7\" unit=\"times\" name=\"/Devices/8237A/DmaRun\"/>\n",
+    "<U64 val=\"268435456\" unit=\"bytes\" name=\"/PGM/Mem/cbRam\"/>\n",
+    "<Profile cPeriods=\"10\" cTicks=\"5000\" cTicksMax=\"900\" ",
+    "cTicksMin=\"100\" unit=\"ticks-per-call\" name=\"/TM/VirtualGetRaw\"/>\n",
+    "</Statistics>\n"
+  );
+
+  #[test]
+  fn parse_statistics_dump() {
+    let stats = parse_statistics(DUMP);
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats["/Devices/8237A/DmaRun"], "7");
+    assert_eq!(stats["/PGM/Mem/cbRam"], "268435456");
+    assert_eq!(stats["/TM/VirtualGetRaw"], "5000");
+  }
+
+  #[test]
+  fn info_and_statistics_args() {
+    let runner = Runner::new();
+    runner.ok("CPUM: Guest CPU state\n");
+    runner.ok(DUMP);
+
+    let vid = VmId::from("vm");
+    assert_eq!(info(&vid, "cpumguest").unwrap(), "CPUM: Guest CPU state\n");
+    let stats = statistics(&vid, Some("/PGM/*")).unwrap();
+    assert_eq!(stats.len(), 3);
+    statistics(&vid, None).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        vec!["debugvm", "vm", "info", "cpumguest"],
+        vec!["debugvm", "vm", "statistics", "--pattern", "/PGM/*"],
+        vec!["debugvm", "vm", "statistics"]
+      ]
+    );
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,183 @@
+//! Portable descriptions of virtual machine settings.
+//!
+//! A [`VmDescriptor`] captures a virtual machine's salient settings (see
+//! [`VmInfo::to_descriptor()`](crate::VmInfo::to_descriptor)) in a form which
+//! can be stored and later applied to the same, or another, virtual machine.
+
+use std::borrow::Borrow;
+
+use crate::modifyvm::{
+  self, AudioController, AudioDriver, Chipset, ClipboardMode, Firmware
+};
+use crate::nics::{self, HwType, NICType};
+use crate::{Error, VmId};
+
+
+/// A network adapter's settings.
+#[derive(Debug, Clone)]
+pub struct NicDescriptor {
+  pub idx: u8,
+  pub nictype: NICType,
+  pub hw_type: Option<HwType>
+}
+
+
+/// Virtual machine settings which can be applied using
+/// [`apply_descriptor()`].
+///
+/// Settings which are `None` are left unchanged when the descriptor is
+/// applied.
+#[derive(Debug, Clone, Default)]
+pub struct VmDescriptor {
+  /// Memory size, in MiB.
+  pub memory: Option<u32>,
+  pub cpus: Option<u32>,
+  pub firmware: Option<Firmware>,
+  pub chipset: Option<Chipset>,
+  pub nested_hw_virt: Option<bool>,
+  pub hpet: Option<bool>,
+  pub rtc_use_utc: Option<bool>,
+  pub monitor_count: Option<u8>,
+  pub groups: Option<Vec<String>>,
+  pub audio_controller: Option<AudioController>,
+  pub audio_driver: Option<AudioDriver>,
+  pub clipboard_mode: Option<ClipboardMode>,
+
+  /// Network adapters to configure.  Adapters which aren't listed are left
+  /// unchanged.
+  pub nics: Vec<NicDescriptor>
+}
+
+
+/// Apply the settings in `desc` to a virtual machine, which must be powered
+/// off.
+///
+/// The settings are applied one at a time, in the order they appear in
+/// [`VmDescriptor`]; if one fails the remaining ones aren't applied.
+/// Network adapters keep their MAC addresses (see [`nics::reconfigure()`]).
+pub fn apply_descriptor<V>(vid: V, desc: &VmDescriptor) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let vid = vid.borrow();
+
+  if let Some(mb) = desc.memory {
+    modifyvm::set_memory(vid, mb)?;
+  }
+  if let Some(n) = desc.cpus {
+    modifyvm::set_cpus(vid, n)?;
+  }
+  if let Some(firmware) = desc.firmware {
+    modifyvm::set_firmware(vid, firmware)?;
+  }
+  if let Some(chipset) = desc.chipset {
+    modifyvm::set_chipset(vid, chipset)?;
+  }
+  if let Some(enabled) = desc.nested_hw_virt {
+    modifyvm::set_nested_hw_virt(vid, enabled)?;
+  }
+  if let Some(enabled) = desc.hpet {
+    modifyvm::set_hpet(vid, enabled)?;
+  }
+  if let Some(utc) = desc.rtc_use_utc {
+    modifyvm::set_rtc_use_utc(vid, utc)?;
+  }
+  if let Some(n) = desc.monitor_count {
+    modifyvm::set_monitor_count(vid, n)?;
+  }
+  if let Some(ref groups) = desc.groups {
+    let groups: Vec<&str> = groups.iter().map(|g| g.as_str()).collect();
+    modifyvm::set_groups(vid, &groups)?;
+  }
+  if let Some(ctrl) = desc.audio_controller {
+    modifyvm::set_audio_controller(vid, ctrl)?;
+  }
+  if let Some(ref driver) = desc.audio_driver {
+    modifyvm::set_audio_driver(vid, driver)?;
+  }
+  if let Some(mode) = desc.clipboard_mode {
+    modifyvm::set_clipboard_mode(vid, mode)?;
+  }
+
+  for nic in &desc.nics {
+    nics::reconfigure(vid, nic.idx, &nic.nictype)?;
+    if let Some(ref hw) = nic.hw_type {
+      nics::set_hw_type(vid, nic.idx, hw)?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  const INFO: &str = concat!(
+    "name=\"vm\"\n",
+    "VMState=\"poweroff\"\n",
+    "memory=2048\n",
+    "cpus=2\n",
+    "firmware=\"EFI\"\n",
+    "chipset=\"ich9\"\n",
+    "nested-hw-virt=\"on\"\n",
+    "hpet=\"off\"\n",
+    "rtcuseutc=\"on\"\n",
+    "monitorcount=2\n",
+    "groups=\"/lab\"\n",
+    "audio_controller=\"hda\"\n",
+    "audio=\"null\"\n",
+    "clipboard=\"bidirectional\"\n",
+    "nic1=\"intnet\"\n",
+    "intnet1=\"lab\"\n",
+    "nictype1=\"virtio\"\n",
+    "macaddress1=\"080027000001\"\n"
+  );
+
+  #[test]
+  fn apply_descriptor_and_read_back() {
+    let runner = Runner::new();
+    runner.ok(INFO);
+    let desc = crate::get_vm_info(VmId::from("vm")).unwrap().to_descriptor();
+
+    // One reply per modifyvm call, then the adapter lookup which picks up
+    // the target's MAC address.
+    for _ in 0..12 {
+      runner.ok("");
+    }
+    runner.ok("name=\"dst\"\nnic1=\"nat\"\nmacaddress1=\"080027000002\"\n");
+    apply_descriptor(VmId::from("dst"), &desc).unwrap();
+
+    let calls: Vec<String> =
+      runner.calls()[1..].iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "modifyvm dst --memory 2048",
+        "modifyvm dst --cpus 2",
+        "modifyvm dst --firmware efi",
+        "modifyvm dst --chipset ich9",
+        "modifyvm dst --nested-hw-virt on",
+        "modifyvm dst --hpet off",
+        "modifyvm dst --rtc-use-utc on",
+        "modifyvm dst --monitor-count 2",
+        "modifyvm dst --groups /lab",
+        "modifyvm dst --audio-controller hda",
+        "modifyvm dst --audio-driver null",
+        "modifyvm dst --clipboard-mode bidirectional",
+        "showvminfo dst --machinereadable",
+        "modifyvm dst --nic1 intnet --intnet1 lab --macaddress1 080027000002",
+        "modifyvm dst --nictype1 virtio"
+      ]
+    );
+
+    // Reading back what VBoxManage reports afterwards gives the same
+    // descriptor.
+    runner.ok(&INFO.replace("080027000001", "080027000002"));
+    let back = crate::get_vm_info(VmId::from("dst")).unwrap().to_descriptor();
+    assert_eq!(format!("{:?}", back), format!("{:?}", desc));
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
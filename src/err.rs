@@ -10,11 +10,68 @@ pub enum Error {
   MissingData(String),
   Ambiguous(String),
   Missing(String),
-  Timeout
+  InvalidArgument(String),
+  Timeout,
+
+  /// The VirtualBox kernel driver (`vboxdrv` on Linux) isn't installed or
+  /// loaded on the host, so virtual machines can't be started.
+  HostDriver(String),
+
+  /// An extension pack is required but missing, or is installed but not
+  /// usable (for instance because its version doesn't match VirtualBox's).
+  ExtPack(String),
+
+  /// The virtual machine is locked by another session or busy with another
+  /// operation (for instance a snapshot being taken or restored).  The
+  /// operation may succeed if retried a little later.
+  Busy(String),
+
+  /// The host or the installed VirtualBox version doesn't support the
+  /// requested feature.
+  Unsupported(String),
+
+  /// Something with the same uuid (or name) is already registered with
+  /// VirtualBox.
+  Conflict(String)
+}
+
+impl Error {
+  /// Returns `true` for errors which are caused by a transient condition,
+  /// and hence may go away if the operation is retried.
+  pub fn is_transient(&self) -> bool {
+    matches!(self, Error::Busy(_))
+  }
 }
 
 impl std::error::Error for Error {}
 
+
+/// Convenience methods for results of operations which may fail with
+/// [`Error`].
+pub trait ResultExt {
+  /// Treat `Err(Error::Missing)` as success.
+  ///
+  /// Useful for idempotent teardown, where it doesn't matter whether the
+  /// thing being removed was there to begin with.
+  ///
+  /// ```no_run
+  /// use vboxhelper::{ResultExt, VmId};
+  ///
+  /// let vm = VmId::Name("scratch".to_string());
+  /// vboxhelper::unregister_vm(&vm, true).ok_if_missing().unwrap();
+  /// ```
+  fn ok_if_missing(self) -> Result<(), Error>;
+}
+
+impl ResultExt for Result<(), Error> {
+  fn ok_if_missing(self) -> Result<(), Error> {
+    match self {
+      Err(Error::Missing(_)) => Ok(()),
+      res => res
+    }
+  }
+}
+
 impl From<io::Error> for Error {
   fn from(err: io::Error) -> Self {
     Error::IO(err.to_string())
@@ -29,7 +86,7 @@ impl From<eui48::ParseError> for Error {
 
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    match &*self {
+    match self {
       Error::IO(s) => write!(f, "I/O error; {}", s),
       Error::BadFormat(s) => write!(f, "Bad format error; {}", s),
       Error::FailedToExecute(s) => {
@@ -44,9 +101,36 @@ impl fmt::Display for Error {
       Error::MissingData(s) => write!(f, "Missing expected data error; {}", s),
       Error::Missing(s) => write!(f, "Unexpectedly missing; {}", s),
       Error::Ambiguous(s) => write!(f, "Ambiguity error; {}", s),
-      Error::Timeout => write!(f, "Timeout")
+      Error::InvalidArgument(s) => write!(f, "Invalid argument; {}", s),
+      Error::Timeout => write!(f, "Timeout"),
+      Error::HostDriver(s) => {
+        write!(f, "VirtualBox kernel driver unavailable; {}", s)
+      }
+      Error::ExtPack(s) => write!(f, "Extension pack problem; {}", s),
+      Error::Busy(s) => write!(f, "Virtual machine busy; {}", s),
+      Error::Unsupported(s) => write!(f, "Unsupported; {}", s),
+      Error::Conflict(s) => write!(f, "Conflict; {}", s)
     }
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ok_if_missing_only_forgives_missing() {
+    let missing: Result<(), Error> = Err(Error::Missing("vm".to_string()));
+    assert!(missing.ok_if_missing().is_ok());
+
+    assert!(Ok(()).ok_if_missing().is_ok());
+
+    let busy: Result<(), Error> = Err(Error::Busy("vm".to_string()));
+    assert!(matches!(busy.ok_if_missing(), Err(Error::Busy(_))));
+
+    let gone: Result<(), Error> = Err(Error::MissingData("x".to_string()));
+    assert!(matches!(gone.ok_if_missing(), Err(Error::MissingData(_))));
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,579 @@
+//! Run commands and manage files inside a virtual machine's guest operating
+//! system.
+//!
+//! All guest control operations require the virtual machine to be running
+//! and the guest to have the VirtualBox Guest Additions installed.
+
+use std::borrow::Borrow;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+use crate::platform;
+use crate::utils;
+use crate::{is_vm_state, Error, GuestRunLevel, VmId, VmState};
+
+
+/// Credentials used to authenticate against the guest operating system.
+#[derive(Clone)]
+pub struct Credentials {
+  pub username: String,
+  pub password: Option<String>,
+  pub domain: Option<String>
+}
+
+impl Credentials {
+  pub fn new<U, P>(username: U, password: P) -> Self
+  where
+    U: AsRef<str>,
+    P: AsRef<str>
+  {
+    Credentials {
+      username: username.as_ref().to_string(),
+      password: Some(password.as_ref().to_string()),
+      domain: None
+    }
+  }
+
+  fn add_args(&self, cmd: &mut Command) {
+    cmd.arg("--username");
+    cmd.arg(&self.username);
+    if let Some(ref password) = self.password {
+      cmd.arg("--password");
+      cmd.arg(password);
+    }
+    if let Some(ref domain) = self.domain {
+      cmd.arg("--domain");
+      cmd.arg(domain);
+    }
+  }
+
+  fn secrets(&self) -> Vec<&str> {
+    match self.password {
+      Some(ref password) => vec![password.as_str()],
+      None => Vec::new()
+    }
+  }
+}
+
+
+/// Create a `VBoxManage guestcontrol <vid> <subcmd>` command with the
+/// credentials attached.
+fn guestcontrol_cmd(vid: &VmId, creds: &Credentials, subcmd: &str) -> Command {
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("guestcontrol");
+  cmd.arg(vid.to_string());
+  cmd.arg(subcmd);
+  creds.add_args(&mut cmd);
+  cmd
+}
+
+
+/// Options for running a program in the guest.
+#[derive(Clone, Default)]
+pub struct RunOptions {
+  /// Environment variables to set for the guest process.
+  pub env: Vec<(String, String)>,
+
+  /// Working directory of the guest process.  Requires VirtualBox 7.0 or
+  /// later.
+  pub cwd: Option<String>
+}
+
+
+/// Run a program in the guest and wait for it to terminate.
+///
+/// `exe` is the absolute path to the program within the guest.  On success
+/// the guest process' stdout and stderr buffers are returned.
+pub fn run<V, E, A>(
+  vid: V,
+  creds: &Credentials,
+  exe: E,
+  args: &[A],
+  opts: &RunOptions
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  V: Borrow<VmId>,
+  E: AsRef<str>,
+  A: AsRef<str>
+{
+  // VBoxManage guestcontrol $VM run --username <user> --password <pass> \
+  //  [--putenv <key>=<val>] [--cwd <dir>] \
+  //  --exe <exe> --wait-stdout --wait-stderr -- <exe> [args]
+
+  let mut cmd = guestcontrol_cmd(vid.borrow(), creds, "run");
+  for (key, val) in &opts.env {
+    // Passed as a single argument, so spaces and '=' in the value are
+    // preserved.
+    cmd.arg("--putenv");
+    cmd.arg(format!("{}={}", key, val));
+  }
+  if let Some(ref cwd) = opts.cwd {
+    cmd.arg("--cwd");
+    cmd.arg(cwd);
+  }
+  cmd.arg("--exe");
+  cmd.arg(exe.as_ref());
+  cmd.arg("--wait-stdout");
+  cmd.arg("--wait-stderr");
+  cmd.arg("--");
+  cmd.arg(exe.as_ref());
+  for arg in args {
+    cmd.arg(arg.as_ref());
+  }
+
+  utils::exec_redacted(cmd, &creds.secrets())
+}
+
+
+/// Copy a file from the host to the guest.
+pub fn copy_to<V, P, G>(
+  vid: V,
+  creds: &Credentials,
+  src: P,
+  dest: G
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>,
+  G: AsRef<str>
+{
+  // VBoxManage guestcontrol $VM copyto --username <user> \
+  //  --password <pass> <src> <dest>
+
+  let mut cmd = guestcontrol_cmd(vid.borrow(), creds, "copyto");
+  cmd.arg(src.as_ref());
+  cmd.arg(dest.as_ref());
+
+  utils::exec_redacted(cmd, &creds.secrets())?;
+
+  Ok(())
+}
+
+
+/// Create a directory in the guest.
+///
+/// If `parents` is `true` any missing parent directories are created as well.
+pub fn mkdir<V, G>(
+  vid: V,
+  creds: &Credentials,
+  path: G,
+  parents: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  G: AsRef<str>
+{
+  // VBoxManage guestcontrol $VM mkdir --username <user> --password <pass> \
+  //  [--parents] <path>
+
+  let mut cmd = guestcontrol_cmd(vid.borrow(), creds, "mkdir");
+  if parents {
+    cmd.arg("--parents");
+  }
+  cmd.arg(path.as_ref());
+
+  utils::exec_redacted(cmd, &creds.secrets())?;
+
+  Ok(())
+}
+
+
+/// Wait for the guest's Guest Additions to reach (at least) the run level
+/// `level`.
+///
+/// Guest control commands fail if they are issued before the Guest Additions
+/// have started, so provisioning code should wait for
+/// [`GuestRunLevel::System`] (or [`GuestRunLevel::Userland`] if a user
+/// session is required) before running commands in a freshly started guest.
+///
+/// The run level is checked every `poll`.  Returns `Err(Error::Timeout)` if
+/// the run level hasn't been reached within `timeout`.
+pub fn wait_runlevel<V>(
+  vid: V,
+  level: GuestRunLevel,
+  timeout: Duration,
+  poll: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let start = Instant::now();
+  loop {
+    let vmi = crate::get_vm_info(vid.borrow())?;
+    if let Some(ga) = vmi.guest_additions {
+      if ga.run_level >= level {
+        break;
+      }
+    }
+
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout);
+    }
+
+    thread::sleep(poll);
+  }
+
+  Ok(())
+}
+
+
+/// A virtual machine paired with the credentials used to access its guest.
+///
+/// This is not a persistent guest session.  `VBoxManage guestcontrol` has no
+/// way for a command to join a session opened by an earlier invocation (only
+/// `closesession` accepts a session name), so every operation still opens
+/// and closes a guest session of its own, and there is nothing to tear down
+/// when a `GuestSession` is dropped.  It captures the virtual machine
+/// identifier and the credentials once so that multi-step provisioning
+/// doesn't need to thread them through every call.
+pub struct GuestSession {
+  vid: VmId,
+  creds: Credentials
+}
+
+impl GuestSession {
+  /// Get the identifier of the virtual machine this session targets.
+  pub fn vmid(&self) -> &VmId {
+    &self.vid
+  }
+
+  /// See [`run()`].
+  pub fn run<E, A>(
+    &self,
+    exe: E,
+    args: &[A],
+    opts: &RunOptions
+  ) -> Result<(Vec<u8>, Vec<u8>), Error>
+  where
+    E: AsRef<str>,
+    A: AsRef<str>
+  {
+    run(&self.vid, &self.creds, exe, args, opts)
+  }
+
+  /// See [`copy_to()`].
+  pub fn copy_to<P, G>(&self, src: P, dest: G) -> Result<(), Error>
+  where
+    P: AsRef<Path>,
+    G: AsRef<str>
+  {
+    copy_to(&self.vid, &self.creds, src, dest)
+  }
+
+  /// See [`mkdir()`].
+  pub fn mkdir<G>(&self, path: G, parents: bool) -> Result<(), Error>
+  where
+    G: AsRef<str>
+  {
+    mkdir(&self.vid, &self.creds, path, parents)
+  }
+}
+
+
+/// Start a guest session for running commands in a virtual machine.
+///
+/// Returns `Err(Error::MissingData)` if the virtual machine isn't running.
+pub fn start_session<V>(
+  vid: V,
+  creds: Credentials
+) -> Result<GuestSession, Error>
+where
+  V: Borrow<VmId>
+{
+  if !is_vm_state(vid.borrow(), VmState::Running)? {
+    let s = format!("Virtual machine '{}' is not running", vid.borrow());
+    return Err(Error::MissingData(s));
+  }
+
+  Ok(GuestSession {
+    vid: vid.borrow().clone(),
+    creds
+  })
+}
+
+
+/// A guest session as reported by `VBoxManage guestcontrol list`.
+#[derive(Debug, Clone)]
+pub struct GuestSessionInfo {
+  pub id: u32,
+  pub user: String,
+  pub domain: Option<String>,
+  pub name: String
+}
+
+
+/// A guest process as reported by `VBoxManage guestcontrol list`.
+#[derive(Debug, Clone)]
+pub struct GuestProcess {
+  /// Id of the guest session the process belongs to.
+  pub session_id: Option<u32>,
+  pub pid: u32,
+
+  /// Process status, for example `started` or `terminated normally`.
+  pub status: String,
+  pub command: String
+}
+
+
+/// Run `VBoxManage guestcontrol list <what>` and return its output.
+fn list(vid: &VmId, creds: &Credentials, what: &str) -> Result<String, Error> {
+  // VBoxManage guestcontrol $VM list --username <user> --password <pass> \
+  //  sessions|processes
+
+  let mut cmd = guestcontrol_cmd(vid, creds, "list");
+  cmd.arg(what);
+
+  let (stdout, _) = utils::exec_redacted(cmd, &creds.secrets())?;
+
+  Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+
+/// Parse the session and process lines of `guestcontrol list` output, such
+/// as:
+///
+/// ```text
+/// Session #0   | ID=1   | User=vbox | Domain= | Name=provision
+///   Process #0   | PID=1234   | Status=started | Command=/bin/sleep
+/// ```
+///
+/// Processes are attributed to the session listed before them.
+fn parse_list(output: &str) -> (Vec<GuestSessionInfo>, Vec<GuestProcess>) {
+  let re_session = Regex::new(concat!(
+    r"^\s*Session #\d+\s*\|\s*ID=(?P<id>\d+)\s*\|\s*User=(?P<user>.*?)\s*",
+    r"\|\s*Domain=(?P<domain>.*?)\s*\|\s*Name=(?P<name>.*?)\s*$"
+  ))
+  .unwrap();
+  let re_process = Regex::new(concat!(
+    r"^\s*Process #\d+\s*\|\s*PID=(?P<pid>\d+)\s*",
+    r"\|\s*Status=(?P<status>.*?)\s*\|\s*Command=(?P<cmd>.*?)\s*$"
+  ))
+  .unwrap();
+
+  let mut sessions: Vec<GuestSessionInfo> = Vec::new();
+  let mut procs = Vec::new();
+
+  for line in output.lines() {
+    if let Some(cap) = re_session.captures(line) {
+      let id = match cap["id"].parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => continue
+      };
+      let domain = match &cap["domain"] {
+        "" => None,
+        d => Some(d.to_string())
+      };
+      sessions.push(GuestSessionInfo {
+        id,
+        user: cap["user"].to_string(),
+        domain,
+        name: cap["name"].to_string()
+      });
+    } else if let Some(cap) = re_process.captures(line) {
+      let pid = match cap["pid"].parse::<u32>() {
+        Ok(pid) => pid,
+        Err(_) => continue
+      };
+      procs.push(GuestProcess {
+        session_id: sessions.last().map(|s| s.id),
+        pid,
+        status: cap["status"].to_string(),
+        command: cap["cmd"].to_string()
+      });
+    }
+  }
+
+  (sessions, procs)
+}
+
+
+/// List the guest sessions of a virtual machine.
+pub fn list_sessions<V>(
+  vid: V,
+  creds: &Credentials
+) -> Result<Vec<GuestSessionInfo>, Error>
+where
+  V: Borrow<VmId>
+{
+  let output = list(vid.borrow(), creds, "sessions")?;
+  Ok(parse_list(&output).0)
+}
+
+
+/// List the processes which were started through guest control in a virtual
+/// machine.
+///
+/// Only processes started through guest sessions are listed, not every
+/// process running in the guest.
+pub fn list_processes<V>(
+  vid: V,
+  creds: &Credentials
+) -> Result<Vec<GuestProcess>, Error>
+where
+  V: Borrow<VmId>
+{
+  let output = list(vid.borrow(), creds, "processes")?;
+  Ok(parse_list(&output).1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn session_passes_vm_and_credentials_to_each_subcommand() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+
+    let creds = Credentials::new("vbox", "secret");
+    let sess = start_session(VmId::from("vm"), creds).unwrap();
+    sess.mkdir("/tmp/work", true).unwrap();
+    sess.copy_to("setup.sh", "/tmp/work/setup.sh").unwrap();
+    sess
+      .run("/bin/sh", &["/tmp/work/setup.sh"], &RunOptions::default())
+      .unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 4);
+    for (call, subcmd) in calls[1..].iter().zip(["mkdir", "copyto", "run"]) {
+      assert_eq!(
+        call[..6],
+        ["guestcontrol", "vm", subcmd, "--username", "vbox", "--password"]
+      );
+      assert_eq!(call[6], "secret");
+    }
+    assert_eq!(calls[1][7..], ["--parents", "/tmp/work"]);
+  }
+
+  #[test]
+  fn run_passes_env_and_cwd_as_single_args() {
+    let runner = Runner::new();
+    let opts = RunOptions {
+      env: vec![
+        ("A".to_string(), "b c".to_string()),
+        ("K".to_string(), "v=w".to_string())
+      ],
+      cwd: Some("/srv/app".to_string())
+    };
+    let creds = Credentials::new("vbox", "secret");
+    run(VmId::from("vm"), &creds, "/bin/env", &["-0"], &opts).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(
+      calls[0][7..],
+      [
+        "--putenv",
+        "A=b c",
+        "--putenv",
+        "K=v=w",
+        "--cwd",
+        "/srv/app",
+        "--exe",
+        "/bin/env",
+        "--wait-stdout",
+        "--wait-stderr",
+        "--",
+        "/bin/env",
+        "-0"
+      ]
+    );
+  }
+
+  #[test]
+  fn wait_runlevel_polls_until_level_is_reached() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+    runner.ok("name=\"vm\"\nGuestAdditionsRunLevel=0\n");
+    runner.ok("name=\"vm\"\nGuestAdditionsRunLevel=1\n");
+    runner.ok("name=\"vm\"\nGuestAdditionsRunLevel=2\n");
+
+    let timeout = Duration::from_secs(5);
+    let poll = Duration::from_millis(1);
+    wait_runlevel(VmId::from("vm"), GuestRunLevel::Userland, timeout, poll)
+      .unwrap();
+
+    assert_eq!(runner.calls().len(), 4);
+  }
+
+  #[test]
+  fn wait_runlevel_times_out() {
+    let _runner = Runner::new();
+
+    let timeout = Duration::from_millis(20);
+    let poll = Duration::from_millis(1);
+    assert!(matches!(
+      wait_runlevel(VmId::from("vm"), GuestRunLevel::System, timeout, poll),
+      Err(Error::Timeout)
+    ));
+  }
+
+  #[test]
+  fn session_requires_running_vm() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\n");
+
+    let creds = Credentials::new("vbox", "secret");
+    assert!(matches!(
+      start_session(VmId::from("vm"), creds),
+      Err(Error::MissingData(_))
+    ));
+  }
+
+  #[test]
+  fn password_is_redacted_from_errors() {
+    let runner = Runner::new();
+    runner.fail("VBoxManage: error: The guest execution service is not ready");
+
+    let creds = Credentials::new("vbox", "secret");
+    match mkdir(VmId::from("vm"), &creds, "/tmp/x", false) {
+      Err(Error::CommandFailed(cmdstr, _)) => {
+        assert!(!cmdstr.contains("secret"));
+        assert!(cmdstr.contains("***"));
+      }
+      res => panic!("unexpected result {:?}", res)
+    }
+  }
+
+  #[test]
+  fn parse_list_output() {
+    let output = concat!(
+      "Session #0   | ID=1   | User=vbox | Domain= | Name=provision\n",
+      "  Process #0   | PID=1234   | Status=started | Command=/bin/sleep\n",
+      "  Process #1   | PID=99   | Status=terminated normally | ",
+      "Command=/usr/bin/env\n",
+      "Session #1   | ID=2   | User=admin | Domain=CORP | Name=\n",
+      "  Process #0   | PID=4321   | Status=started | Command=cmd.exe\n",
+      "Total guest sessions: 2\n"
+    );
+    let (sessions, procs) = parse_list(output);
+
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].id, 1);
+    assert_eq!(sessions[0].user, "vbox");
+    assert_eq!(sessions[0].domain, None);
+    assert_eq!(sessions[0].name, "provision");
+    assert_eq!(sessions[1].domain.as_deref(), Some("CORP"));
+    assert_eq!(sessions[1].name, "");
+
+    let summary: Vec<_> = procs
+      .iter()
+      .map(|p| (p.session_id, p.pid, p.status.as_str(), p.command.as_str()))
+      .collect();
+    assert_eq!(
+      summary,
+      [
+        (Some(1), 1234, "started", "/bin/sleep"),
+        (Some(1), 99, "terminated normally", "/usr/bin/env"),
+        (Some(2), 4321, "started", "cmd.exe")
+      ]
+    );
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
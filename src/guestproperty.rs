@@ -0,0 +1,364 @@
+//! Read and write guest properties.
+//!
+//! Guest properties are key/value pairs shared between the host and a
+//! running guest.  The Guest Additions publish information about the guest,
+//! such as its network configuration, under the `/VirtualBox/` namespace.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+use crate::platform;
+use crate::strutils::{buf_to_strlines, EmptyLine};
+use crate::utils;
+use crate::{Error, VmId};
+
+
+/// A guest property as reported by `VBoxManage guestproperty enumerate`.
+#[derive(Debug, Clone)]
+pub struct GuestProperty {
+  pub name: String,
+  pub value: String,
+
+  /// The time the property was last changed, in the format used by
+  /// `VBoxManage` (nanoseconds since the epoch in older versions, an RFC 3339
+  /// timestamp in newer ones).
+  pub timestamp: String,
+
+  pub flags: Vec<String>
+}
+
+
+/// A guest network interface as reported by the Guest Additions.
+#[derive(Debug, Clone)]
+pub struct GuestNetIf {
+  /// Index of the interface in the Guest Additions' enumeration.  This does
+  /// not necessarily match the virtual machine's NIC index.
+  pub idx: usize,
+  pub ip: Option<String>,
+  pub broadcast: Option<String>,
+  pub netmask: Option<String>,
+  pub mac: Option<String>,
+
+  /// `true` if the Guest Additions report the interface as up.
+  pub up: bool
+}
+
+
+/// Get the value of a guest property.
+///
+/// Returns `Ok(None)` if the property is not set.
+pub fn get<V, K>(vid: V, key: K) -> Result<Option<String>, Error>
+where
+  V: Borrow<VmId>,
+  K: AsRef<str>
+{
+  // VBoxManage guestproperty get $VM <key>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("guestproperty");
+  cmd.arg("get");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(key.as_ref());
+
+  // Some versions exit with a failure status if the property isn't set.
+  let (stdout, _) = utils::exec_accepting(cmd, |out| {
+    [&out.stdout, &out.stderr]
+      .iter()
+      .any(|buf| String::from_utf8_lossy(buf).contains("No value set!"))
+  })?;
+
+  Ok(parse_get(&buf_to_strlines(&stdout, EmptyLine::Ignore)))
+}
+
+
+fn parse_get(lines: &[String]) -> Option<String> {
+  for line in lines {
+    if let Some(val) = line.strip_prefix("Value: ") {
+      return Some(val.trim_end().to_string());
+    }
+  }
+
+  // "No value set!"
+  None
+}
+
+
+/// Set a guest property.
+///
+/// Properties set while the virtual machine is powered off are stored in its
+/// configuration and become visible to the guest once it's started.
+pub fn set<V, K>(vid: V, key: K, value: &str) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  K: AsRef<str>
+{
+  // VBoxManage guestproperty set $VM <key> <value>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("guestproperty");
+  cmd.arg("set");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(key.as_ref());
+  cmd.arg(value);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Wait for a guest property to be set to `expected`.
+///
+/// The property is checked every `poll` until it has the expected value.
+/// Returns `Err(Error::Timeout)` if that doesn't happen within `timeout`.
+pub fn wait_for_value<V, K>(
+  vid: V,
+  key: K,
+  expected: &str,
+  timeout: Duration,
+  poll: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  K: AsRef<str>
+{
+  let start = Instant::now();
+  loop {
+    if get(vid.borrow(), key.as_ref())?.as_deref() == Some(expected) {
+      break;
+    }
+
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout);
+    }
+
+    thread::sleep(poll);
+  }
+
+  Ok(())
+}
+
+
+/// Get all guest properties of a virtual machine.
+pub fn enumerate<V>(vid: V) -> Result<Vec<GuestProperty>, Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage guestproperty enumerate $VM
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("guestproperty");
+  cmd.arg("enumerate");
+  cmd.arg(vid.borrow().to_string());
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  let lines = buf_to_strlines(&stdout, EmptyLine::Ignore);
+
+  // VirtualBox 6.x:
+  // Name: <name>, value: <value>, timestamp: <ns>, flags: <flags>
+  let re_old = Regex::new(concat!(
+    r#"^Name: (?P<name>[^,]+), value: (?P<val>.*), "#,
+    r#"timestamp: (?P<ts>\d+), flags: ?(?P<flags>.*)$"#
+  ))
+  .unwrap();
+
+  // VirtualBox 7.x:
+  // <name> = '<value>' @ <timestamp> [(<flags>)]
+  let re_new = Regex::new(concat!(
+    r#"^(?P<name>\S+)\s+= '(?P<val>.*)' @ (?P<ts>\S+)"#,
+    r#"(?: \((?P<flags>[^)]*)\))?$"#
+  ))
+  .unwrap();
+
+  let mut props = Vec::new();
+
+  for line in lines {
+    let line = line.trim_end();
+    let cap = match re_old.captures(line).or_else(|| re_new.captures(line)) {
+      Some(cap) => cap,
+      None => continue
+    };
+
+    let flags = match cap.name("flags") {
+      Some(f) => f
+        .as_str()
+        .split(',')
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect(),
+      None => Vec::new()
+    };
+
+    props.push(GuestProperty {
+      name: cap["name"].to_string(),
+      value: cap["val"].to_string(),
+      timestamp: cap["ts"].to_string(),
+      flags
+    });
+  }
+
+  Ok(props)
+}
+
+
+/// Get the network interfaces reported by the guest.
+///
+/// The information is published by the Guest Additions under
+/// `/VirtualBox/GuestInfo/Net/`.  If the Guest Additions aren't running (or
+/// have not reported any interfaces) an empty list is returned.
+pub fn guest_network<V>(vid: V) -> Result<Vec<GuestNetIf>, Error>
+where
+  V: Borrow<VmId>
+{
+  let props: HashMap<String, String> = enumerate(vid)?
+    .into_iter()
+    .filter(|p| p.name.starts_with("/VirtualBox/GuestInfo/Net/"))
+    .map(|p| (p.name, p.value))
+    .collect();
+
+  let count = match props.get("/VirtualBox/GuestInfo/Net/Count") {
+    Some(n) => n.parse::<usize>().unwrap_or(0),
+    None => 0
+  };
+
+  let mut ifs = Vec::new();
+  for idx in 0..count {
+    let get = |key: &str| {
+      let name = format!("/VirtualBox/GuestInfo/Net/{}/{}", idx, key);
+      match props.get(&name) {
+        Some(v) if !v.is_empty() => Some(v.clone()),
+        _ => None
+      }
+    };
+
+    ifs.push(GuestNetIf {
+      idx,
+      ip: get("V4/IP"),
+      broadcast: get("V4/Broadcast"),
+      netmask: get("V4/Netmask"),
+      mac: get("MAC"),
+      up: get("Status").as_deref() == Some("Up")
+    });
+  }
+
+  Ok(ifs)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn get_value() {
+    let runner = Runner::new();
+    runner.ok("Value: 7.0.10\n");
+
+    let val = get(VmId::from("vm"), "/VirtualBox/GuestAdd/Version").unwrap();
+    assert_eq!(val.as_deref(), Some("7.0.10"));
+    assert_eq!(
+      runner.calls(),
+      [["guestproperty", "get", "vm", "/VirtualBox/GuestAdd/Version"]]
+    );
+  }
+
+  #[test]
+  fn get_unset_value_with_failure_status() {
+    let runner = Runner::new();
+    runner.fail("No value set!\n");
+
+    assert_eq!(get(VmId::from("vm"), "/Foo").unwrap(), None);
+  }
+
+  #[test]
+  fn get_reports_other_failures() {
+    let runner = Runner::new();
+    runner.fail(
+      "VBoxManage: error: Could not find a registered machine named 'vm'\n"
+    );
+
+    assert!(matches!(
+      get(VmId::from("vm"), "/Foo"),
+      Err(Error::Missing(_))
+    ));
+  }
+
+  #[test]
+  fn guest_network_from_enumerate() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "/VirtualBox/GuestAdd/Version = '7.0.10' @ 2023-05-01T10:20:30Z\n",
+      "/VirtualBox/GuestInfo/Net/Count = '2' @ 2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/0/V4/IP = '10.0.2.15' @ ",
+      "2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/0/V4/Broadcast = '10.0.2.255' @ ",
+      "2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/0/V4/Netmask = '255.255.255.0' @ ",
+      "2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/0/MAC = '080027AABBCC' @ ",
+      "2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/0/Status = 'Up' @ 2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/1/V4/IP = '' @ 2023-05-01T10:20:31Z\n",
+      "/VirtualBox/GuestInfo/Net/1/Status = 'Down' @ 2023-05-01T10:20:31Z\n"
+    ));
+
+    let ifs = guest_network(VmId::from("vm")).unwrap();
+    assert_eq!(ifs.len(), 2);
+    assert_eq!(ifs[0].idx, 0);
+    assert_eq!(ifs[0].ip.as_deref(), Some("10.0.2.15"));
+    assert_eq!(ifs[0].broadcast.as_deref(), Some("10.0.2.255"));
+    assert_eq!(ifs[0].netmask.as_deref(), Some("255.255.255.0"));
+    assert_eq!(ifs[0].mac.as_deref(), Some("080027AABBCC"));
+    assert!(ifs[0].up);
+    assert_eq!(ifs[1].idx, 1);
+    assert_eq!(ifs[1].ip, None);
+    assert_eq!(ifs[1].netmask, None);
+    assert!(!ifs[1].up);
+    assert_eq!(runner.calls(), [["guestproperty", "enumerate", "vm"]]);
+  }
+
+  #[test]
+  fn guest_network_without_guest_additions() {
+    let _runner = Runner::new();
+
+    assert!(guest_network(VmId::from("vm")).unwrap().is_empty());
+  }
+
+  #[test]
+  fn wait_for_value_polls_until_expected() {
+    let runner = Runner::new();
+    runner.ok("No value set!\n");
+    runner.ok("Value: running\n");
+    runner.ok("Value: done\n");
+
+    let key = "/Provision/Status";
+    let timeout = Duration::from_secs(5);
+    let poll = Duration::from_millis(1);
+    wait_for_value(VmId::from("vm"), key, "done", timeout, poll).unwrap();
+
+    assert_eq!(runner.calls().len(), 3);
+  }
+
+  #[test]
+  fn wait_for_value_times_out() {
+    let runner = Runner::new();
+    runner.ok("Value: done-ish\n");
+
+    let key = "/Provision/Status";
+    let timeout = Duration::from_millis(20);
+    let poll = Duration::from_millis(1);
+    assert!(matches!(
+      wait_for_value(VmId::from("vm"), key, "done", timeout, poll),
+      Err(Error::Timeout)
+    ));
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,32 @@
+//! Minimal helpers for writing JSON without pulling in a serializer.
+
+
+/// Quote and escape a string for use as a JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c)
+    }
+  }
+  out.push('"');
+  out
+}
+
+
+/// Format an optional value, using `null` for `None`.
+pub(crate) fn opt<T: ToString>(v: Option<T>) -> String {
+  match v {
+    Some(v) => v.to_string(),
+    None => "null".to_string()
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
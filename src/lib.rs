@@ -29,30 +29,49 @@
 //! This crate will generally attempt to track the latest version of
 //! VirtualBox.
 
+mod coalesce;
+mod json;
+mod parse;
 mod platform;
 mod strutils;
 mod utils;
+mod version;
 
+pub mod appliance;
+pub mod clonevm;
+pub mod config;
 pub mod controlvm;
+pub mod debugvm;
+pub mod descriptor;
 pub mod err;
+pub mod guestcontrol;
+pub mod guestproperty;
+pub mod list;
+pub mod medium;
+pub mod modifyvm;
 pub mod nics;
+pub mod nvram;
+pub mod shares;
 pub mod snapshot;
 pub mod storage;
+pub mod unattended;
 pub mod vmid;
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use regex::Regex;
 
-pub use err::Error;
+pub use descriptor::{apply_descriptor, VmDescriptor};
+pub use err::{Error, ResultExt};
 
 use strutils::{buf_to_strlines, EmptyLine};
 
+pub use version::{version, Version};
 pub use vmid::VmId;
 
 
@@ -88,297 +107,1658 @@ pub enum TimeoutAction {
 }
 
 
+/// Check whether the host is able to run virtual machines.
+///
+/// Returns `Err(Error::HostDriver)` if the VirtualBox kernel driver isn't
+/// loaded, and `Err(Error::ExtPack)` if an installed extension pack isn't
+/// usable.  This is cheap enough to call before starting virtual machines
+/// in order to fail early with a clear cause.
+pub fn check_host_ready() -> Result<(), Error> {
+  let (extpacks, driver_problem) = host_extpacks()?;
+
+  if let Some(line) = driver_problem {
+    return Err(Error::HostDriver(line));
+  }
+
+  for pack in extpacks {
+    if !pack.usable {
+      let s = format!(
+        "'{}' is not usable; {}",
+        pack.name,
+        pack.why_unusable.unwrap_or_default()
+      );
+      return Err(Error::ExtPack(s));
+    }
+  }
+
+  Ok(())
+}
+
+
+/// Get the installed extension packs, along with any warning about the
+/// VirtualBox kernel driver.
+fn host_extpacks() -> Result<(Vec<list::ExtPack>, Option<String>), Error> {
+  // VBoxManage list extpacks
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.args(["list", "extpacks"]);
+
+  let (stdout, stderr) = utils::exec(cmd)?;
+
+  // Missing drivers only cause a warning here.
+  let stdout = String::from_utf8_lossy(&stdout);
+  let stderr = String::from_utf8_lossy(&stderr);
+  let driver_problem =
+    utils::driver_problem(&stderr).or_else(|| utils::driver_problem(&stdout));
+
+  let recs = parse::parse_records(&stdout);
+
+  Ok((list::extpacks_from_records(&recs), driver_problem))
+}
+
+
+/// Feature families which are available on the host, see [`capabilities()`].
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+  pub version: Version,
+  pub extpacks: Vec<list::ExtPack>,
+
+  /// `false` if the VirtualBox kernel driver isn't loaded, in which case no
+  /// virtual machines can be started.
+  pub host_driver: bool,
+
+  /// USB 2.0 (EHCI) controllers.
+  pub usb2: bool,
+
+  /// USB 3.0 (xHCI) controllers.
+  pub usb3: bool,
+
+  /// Remote display (VRDE) servers.
+  pub vrde: bool,
+
+  /// Disk image encryption.
+  pub disk_encryption: bool
+}
+
+impl Capabilities {
+  fn new(
+    version: Version,
+    extpacks: Vec<list::ExtPack>,
+    host_driver: bool
+  ) -> Self {
+    // The USB 2.0/3.0 controllers and disk encryption are provided by
+    // Oracle's extension pack ("Oracle VM VirtualBox Extension Pack", or
+    // "Oracle VirtualBox Extension Pack" in later versions).  Remote display
+    // servers are provided by any pack which has a VRDE module.
+    let oracle = extpacks.iter().any(|pack| {
+      pack.usable
+        && pack.name.starts_with("Oracle")
+        && pack.name.ends_with("Extension Pack")
+    });
+    let vrde = extpacks
+      .iter()
+      .any(|pack| pack.usable && pack.vrde_module.is_some());
+
+    Capabilities {
+      version,
+      extpacks,
+      host_driver,
+      usb2: oracle,
+      usb3: oracle,
+      vrde,
+      disk_encryption: oracle
+    }
+  }
+}
+
+
+/// Find out which feature families the installed VirtualBox supports.
+///
+/// Several features depend on which extension packs are installed (and
+/// usable); this allows callers to check for them up front rather than
+/// interpreting failures.
+pub fn capabilities() -> Result<Capabilities, Error> {
+  let version = version()?;
+  let (extpacks, driver_problem) = host_extpacks()?;
+
+  Ok(Capabilities::new(version, extpacks, driver_problem.is_none()))
+}
+
+
+/// Per-virtual machine outcomes of an operation applied to multiple virtual
+/// machines.
+pub type VmResults<T = ()> = Vec<(VmId, Result<T, Error>)>;
+
+
+/// Virtual machines for which part of an operation applied to multiple
+/// virtual machines failed, with the error for each.
+pub type VmErrors = Vec<(VmId, Error)>;
+
+
 pub fn have_vm<V>(vid: V) -> Result<bool, Error>
 where
   V: Borrow<VmId>
 {
   let lst = get_vm_list()?;
 
+  Ok(in_vm_list(&lst, vid.borrow()))
+}
+
+
+fn in_vm_list(lst: &[(String, uuid::Uuid)], vid: &VmId) -> bool {
   for (name, uuid) in lst {
-    match vid.borrow() {
+    match vid {
       VmId::Name(nm) => {
-        if name == *nm {
-          return Ok(true);
+        if name == nm {
+          return true;
         }
       }
       VmId::Uuid(u) => {
-        if uuid == *u {
-          return Ok(true);
+        if uuid == u {
+          return true;
         }
       }
     }
   }
-  Ok(false)
+  false
 }
 
 
-pub fn get_vm_list() -> Result<Vec<(String, uuid::Uuid)>, Error> {
-  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
-  cmd.args(&["list", "vms"]);
+/// Wait for a virtual machine to show up in the list of registered virtual
+/// machines.
+///
+/// Newly registered or imported virtual machines may not be listed right
+/// away.  The list is checked every `poll` (bypassing any cached list, see
+/// [`config::Config::vm_list_ttl`]); returns `Err(Error::Timeout)` if the
+/// virtual machine hasn't appeared within `timeout`.
+pub fn wait_for_vm<V>(
+  vid: V,
+  timeout: Duration,
+  poll: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let start = Instant::now();
+  loop {
+    if in_vm_list(&list_vms("vms")?, vid.borrow()) {
+      break;
+    }
 
-  let output = match cmd.output() {
-    Ok(out) => out,
-    Err(_) => {
-      return Err(Error::FailedToExecute(format!("{:?}", cmd)));
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout);
     }
-  };
 
-  if !output.status.success() {
-    return Err(Error::CommandFailed(format!("{:?}", cmd), output));
+    thread::sleep(poll);
   }
 
-  let lines = buf_to_strlines(&output.stdout, EmptyLine::Ignore);
+  invalidate_vm_list();
 
-  let mut out = Vec::new();
+  Ok(())
+}
 
-  for line in lines {
-    // Make sure first character is '"'
-    match line.find('"') {
-      Some(idx) => {
-        if idx != 0 {
-          continue;
-        }
-      }
-      None => continue
-    }
 
-    // Find last '"'
-    let idx = match line.rfind('"') {
-      Some(idx) => {
-        if idx == 0 {
-          continue;
-        }
-        idx
-      }
-      None => continue
-    };
+/// Run `VBoxManage list <what>` and parse the `"name" {uuid}` lines it
+/// outputs.
+pub(crate) fn list_vms(
+  what: &str
+) -> Result<Vec<(String, uuid::Uuid)>, Error> {
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.args(["list", what]);
 
+  let (stdout, _) = utils::exec(cmd)?;
 
-    let idx_ub = match line.rfind('{') {
-      Some(idx) => {
-        if idx == 0 {
-          continue;
-        }
-        idx
-      }
-      None => continue
-    };
+  let lines = buf_to_strlines(&stdout, EmptyLine::Ignore);
 
-    let idx_ue = match line.rfind('}') {
-      Some(idx) => {
-        if idx == 0 {
-          continue;
-        }
-        idx
-      }
-      None => continue
-    };
+  Ok(lines.iter().filter_map(|line| parse_vm_line(line)).collect())
+}
 
-    let name = &line[1..idx];
-    let uuidstr = &line[(idx_ub + 1)..idx_ue];
-    let u = match uuid::Uuid::parse_str(uuidstr) {
-      Ok(u) => u,
-      Err(_) => continue
-    };
-    out.push((name.to_string(), u));
+
+/// Parse a `"name" {uuid}` line.
+fn parse_vm_line(line: &str) -> Option<(String, uuid::Uuid)> {
+  // Make sure first character is '"'
+  if line.find('"')? != 0 {
+    return None;
   }
 
-  Ok(out)
+  // Find last '"'
+  let idx = match line.rfind('"')? {
+    0 => return None,
+    idx => idx
+  };
+
+  let idx_ub = match line.rfind('{')? {
+    0 => return None,
+    idx => idx
+  };
+
+  let idx_ue = match line.rfind('}')? {
+    0 => return None,
+    idx => idx
+  };
+
+  let name = &line[1..idx];
+  let uuidstr = line.get((idx_ub + 1)..idx_ue)?;
+  let u = uuid::Uuid::parse_str(uuidstr).ok()?;
+
+  Some((name.to_string(), u))
 }
 
 
-/// Get information about a virtual machine as a map.
-pub fn get_vm_info_map<V>(vid: V) -> Result<HashMap<String, String>, Error>
-where
-  V: Borrow<VmId>
+/// The name `VBoxManage list vms` reports for virtual machines whose
+/// configuration can't be accessed (for instance because its files have been
+/// moved or deleted).
+pub const INACCESSIBLE_NAME: &str = "<inaccessible>";
+
+
+static VM_LIST: coalesce::Coalesced<Vec<(String, uuid::Uuid)>> =
+  coalesce::Coalesced::new();
+
+
+/// Get a list of all registered virtual machines' names and uuids.
+///
+/// Inaccessible virtual machines are included with the name
+/// [`INACCESSIBLE_NAME`]; see [`get_inaccessible_vm_list()`].
+///
+/// The list may be shared between concurrent callers and reused for a while;
+/// see [`config::Config::vm_list_ttl`].
+pub fn get_vm_list() -> Result<Vec<(String, uuid::Uuid)>, Error> {
+  match config::get().vm_list_ttl {
+    Some(ttl) => VM_LIST.get(ttl, || list_vms("vms")),
+    None => list_vms("vms")
+  }
+}
+
+
+/// Get the names and uuids of all registered virtual machines, parsing the
+/// list as `VBoxManage` outputs it rather than collecting it first.
+///
+/// This is meant for hosts with very large numbers of virtual machines.  It
+/// always runs `VBoxManage` (see [`config::Config::vm_list_ttl`]).  Lines
+/// which can't be parsed are reported as `Err(Error::BadFormat)` items
+/// without ending the iteration.  If `VBoxManage` fails the error is
+/// reported as the last item.
+pub fn get_vm_list_iter(
+) -> Result<impl Iterator<Item = Result<(String, uuid::Uuid), Error>>, Error>
 {
   let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
-  cmd.arg("showvminfo");
-  cmd.arg(vid.borrow().to_string());
-  cmd.arg("--machinereadable");
+  cmd.args(["list", "vms"]);
+
+  let lines = utils::exec_lines(cmd)?;
+
+  Ok(lines.filter_map(|line| match line {
+    Ok(line) if line.trim().is_empty() => None,
+    Ok(line) => Some(parse_vm_line(&line).ok_or_else(|| {
+      let s = format!("Unable to parse virtual machine entry '{}'", line);
+      Error::BadFormat(s)
+    })),
+    Err(e) => Some(Err(e))
+  }))
+}
 
-  let output = cmd.output().expect("Failed to execute VBoxManage");
 
-  let lines = strutils::buf_to_strlines(&output.stdout, EmptyLine::Ignore);
+/// Discard the cached virtual machine list after registering or
+/// unregistering virtual machines.
+pub(crate) fn invalidate_vm_list() {
+  VM_LIST.invalidate();
+}
 
-  let mut map = HashMap::new();
 
-  // multiline
-  let re_ml1 = Regex::new(r#"^(?P<key>[^"=]+)="(?P<val>[^"=]*)"$"#).unwrap();
-  let re_ml1 = Regex::new(r#"^"(?P<key>[^"=]+)"="(?P<val>[^"=]*)"$"#).unwrap();
+/// Get the uuids of all registered virtual machines which are inaccessible.
+///
+/// These are typically left behind when a virtual machine's files are moved
+/// or deleted without unregistering it first, and can be cleaned up by
+/// unregistering them.
+pub fn get_inaccessible_vm_list() -> Result<Vec<uuid::Uuid>, Error> {
+  let lst = list_vms("vms")?;
+  Ok(
+    lst
+      .into_iter()
+      .filter(|(name, _)| name == INACCESSIBLE_NAME)
+      .map(|(_, uuid)| uuid)
+      .collect()
+  )
+}
 
-  // Capture foo="bar" -> foo=bar
-  // This appears to be most common.
-  let re1 = Regex::new(r#"^(?P<key>[^"=]+)="(?P<val>[^"=]*)"$"#).unwrap();
 
-  // Capture "foo"="bar" -> foo=bar
-  let re2 = Regex::new(r#"^"(?P<key>[^"=]+)"="(?P<val>[^"=]*)"$"#).unwrap();
+/// Get a list of the names and uuids of all running virtual machines.
+pub fn get_running_vm_list() -> Result<Vec<(String, uuid::Uuid)>, Error> {
+  list_vms("runningvms")
+}
 
-  // foo=bar -> foo=bar
-  let re3 = Regex::new(r#"^(?P<key>[^"=]+)=(?P<val>[^"=]*)$"#).unwrap();
 
-  //let re = Regex::new(r#"^"?(?P<key>[^"=]+)"?="?(?P<val>[^"=]*)"?$"#).
-  // unwrap();
+/// Register an existing virtual machine, given the path to its settings
+/// (`.vbox`) file.
+///
+/// If a disk image of the virtual machine has the same uuid as one which is
+/// already registered (typically because the virtual machine is a copy of
+/// one on this host) `Err(Error::Conflict)` is returned, unless `remap` is
+/// set.  In that case the image is given a new uuid (see
+/// [`medium::set_uuid()`]), the settings file is updated to refer to the new
+/// uuid and registration is retried.  Conflicts with the uuid of a
+/// registered virtual machine always return `Err(Error::Conflict)`; clone
+/// the virtual machine instead.
+pub fn register_vm<P>(path: P, remap: bool) -> Result<(), Error>
+where
+  P: AsRef<Path>
+{
+  let path = path.as_ref();
+  let mut remapped = HashSet::new();
 
+  loop {
+    // VBoxManage registervm <path>
 
-  // ToDo: Handle multiline entires, like descriptions
-  let mut lines = lines.iter();
-  while let Some(line) = lines.next() {
-    //println!("line: {}", line);
+    let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+    cmd.arg("registervm");
+    cmd.arg(path);
 
-    let line = line.trim_end();
-    let cap = if let Some(cap) = re1.captures(&line) {
-      Some(cap)
-    } else if let Some(cap) = re2.captures(&line) {
-      Some(cap)
-    } else if let Some(cap) = re3.captures(&line) {
-      Some(cap)
-    } else {
-      dbg!(format!("Ignored line: {}", line));
-      None
+    let (cmdstr, out) = match utils::exec(cmd) {
+      Ok(_) => break,
+      Err(Error::CommandFailed(cmdstr, out)) => (cmdstr, out),
+      Err(e) => return Err(e)
     };
 
-    if let Some(cap) = cap {
-      map.insert(cap[1].to_string(), cap[2].to_string());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let (image, old) = match register_conflict(&stderr) {
+      Some(RegisterConflict::Machine(msg)) => {
+        return Err(Error::Conflict(msg));
+      }
+      Some(RegisterConflict::Medium(image, old)) => (image, old),
+      None => return Err(Error::CommandFailed(cmdstr, out))
+    };
+
+    if !remap || !remapped.insert(image.clone()) {
+      let s = format!(
+        "Disk image '{}' has the uuid {} of an already registered medium",
+        image,
+        old
+      );
+      return Err(Error::Conflict(s));
     }
+
+    let new = medium::set_uuid(&image)?;
+    remap_uuid(path, &old, &new)?;
   }
 
-  Ok(map)
+  invalidate_vm_list();
+
+  Ok(())
 }
 
 
-#[derive(PartialEq, Eq)]
-/// VirtualBox virtual machine states.
-pub enum VmState {
-  /// This isn't actually a VirtualBox virtual machine state; it's used as a
-  /// placeholder if an unknown state is encountered.
-  Unknown,
+enum RegisterConflict {
+  /// A virtual machine with the same uuid is registered.
+  Machine(String),
 
-  /// The virtual machine is powered off.
-  PowerOff,
+  /// A disk image has the same uuid as a registered medium.
+  Medium(String, uuid::Uuid)
+}
 
-  /// The virtual machine is currently starting up.
-  Starting,
 
-  /// The virtual machine is currently up and running.
-  Running,
+fn register_conflict(stderr: &str) -> Option<RegisterConflict> {
+  // VBoxManage: error: Trying to open a VM config '...' which has the same
+  //   UUID as an existing virtual machine
+  // VBoxManage: error: Cannot register the hard disk '<path>' {<uuid>}
+  //   because a hard disk '<path>' with UUID {<uuid>} already exists
+  let re = Regex::new(
+    r#"Cannot register the hard disk '([^']+)' \{([0-9a-fA-F-]+)\}"#
+  )
+  .unwrap();
 
-  /// The virtual machine is currently paused.
-  Paused,
+  if let Some(cap) = re.captures(stderr) {
+    if let Ok(u) = uuid::Uuid::parse_str(&cap[2]) {
+      return Some(RegisterConflict::Medium(cap[1].to_string(), u));
+    }
+  }
 
-  /// The virtual machine is currently shutting down.
-  Stopping
+  stderr
+    .lines()
+    .find(|line| line.contains("same UUID as an existing virtual machine"))
+    .map(|line| {
+      let line = line.trim_start_matches("VBoxManage: error:").trim();
+      RegisterConflict::Machine(line.to_string())
+    })
 }
 
-impl From<&str> for VmState {
-  fn from(s: &str) -> Self {
-    match s {
-      "poweroff" => VmState::PowerOff,
-      "starting" => VmState::Starting,
-      "running" => VmState::Running,
-      "paused" => VmState::Paused,
-      "stopping" => VmState::Stopping,
-      _ => VmState::Unknown
-    }
-  }
+
+/// Replace references to the medium uuid `old` with `new` in a virtual
+/// machine settings file.
+fn remap_uuid(
+  path: &Path,
+  old: &uuid::Uuid,
+  new: &uuid::Uuid
+) -> Result<(), Error> {
+  let xml = std::fs::read_to_string(path)?;
+
+  // Settings files refer to media as uuid="{<uuid>}".
+  let old = old.to_hyphenated().to_string();
+  let new = new.to_hyphenated().to_string();
+  let xml = xml
+    .replace(&format!("{{{}}}", old), &format!("{{{}}}", new))
+    .replace(
+      &format!("{{{}}}", old.to_uppercase()),
+      &format!("{{{}}}", new)
+    );
+
+  std::fs::write(path, xml)?;
+
+  Ok(())
 }
 
-impl From<&String> for VmState {
-  fn from(s: &String) -> Self {
-    match s.as_ref() {
-      "poweroff" => VmState::PowerOff,
-      "starting" => VmState::Starting,
-      "running" => VmState::Running,
-      "paused" => VmState::Paused,
-      "stopping" => VmState::Stopping,
-      _ => VmState::Unknown
-    }
+
+/// Unregister a virtual machine.
+///
+/// If `delete` is `true` the virtual machine's configuration files and all
+/// its disk images (which aren't used by other virtual machines) are deleted
+/// as well.
+pub fn unregister_vm<V>(vid: V, delete: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage unregistervm $VM [--delete]
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("unregistervm");
+  cmd.arg(vid.borrow().to_string());
+  if delete {
+    cmd.arg("--delete");
   }
-}
 
+  let res = utils::exec(cmd);
+  invalidate_vm_list();
+  res?;
 
-/// A structured representation of a virtual machine's state and configuration.
-pub struct VmInfo {
-  pub shares_map: HashMap<String, PathBuf>,
-  pub shares_list: Vec<(String, PathBuf)>,
-  pub state: VmState,
-  pub snapshots: Option<snapshot::Snapshots>,
-  pub nics: Vec<nics::NICInfo>
+  Ok(())
 }
 
 
-/// Get structured information about a virtual machine.
-pub fn get_vm_info<V>(vid: V) -> Result<VmInfo, Error>
+/// Get the unparsed output of `VBoxManage showvminfo --machinereadable`.
+///
+/// This is what [`get_vm_info_map()`] and [`get_vm_info()`] parse, and is
+/// mostly useful for tracking down parsing problems.
+pub fn get_vm_info_raw<V>(vid: V) -> Result<Vec<u8>, Error>
 where
   V: Borrow<VmId>
 {
-  let map = get_vm_info_map(vid)?;
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("showvminfo");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--machinereadable");
 
-  let mut shares_list = Vec::new();
-  let mut shares_map = HashMap::new();
+  let (stdout, _) = utils::exec(cmd)?;
 
-  //
-  // Parse shares
-  //
-  let mut idx = 1;
-  loop {
-    let name_key = format!("SharedFolderNameMachineMapping{}", idx);
-    let path_key = format!("SharedFolderPathMachineMapping{}", idx);
+  Ok(stdout)
+}
 
-    let name = match map.get(&name_key) {
-      Some(nm) => nm.clone(),
-      None => break
-    };
-    let pathname = match map.get(&path_key) {
-      Some(pn) => PathBuf::from(pn),
-      None => break
-    };
 
-    shares_map.insert(name.clone(), pathname.clone());
-    shares_list.push((name, pathname));
+/// Read a virtual machine's log file.
+///
+/// `which` selects the log: `0` is the log of the current (or most recent)
+/// session, `VBox.log`, and `1` and up are the rotated logs of earlier
+/// sessions, `VBox.log.<which>`.  If `max_lines` is set only that many lines
+/// from the end of the log are returned.  Returns `Err(Error::Missing)` if
+/// the log file doesn't exist.
+pub fn read_log<V>(
+  vid: V,
+  which: u8,
+  max_lines: Option<usize>
+) -> Result<Vec<String>, Error>
+where
+  V: Borrow<VmId>
+{
+  let map = get_vm_info_map(vid.borrow())?;
+  let logdir = match map.get("LogFldr") {
+    Some(dir) => PathBuf::from(dir),
+    None => {
+      let s = format!("No log folder reported for '{}'", vid.borrow());
+      return Err(Error::MissingData(s));
+    }
+  };
 
-    idx += 1;
-  }
+  let fname = match which {
+    0 => "VBox.log".to_string(),
+    n => format!("VBox.log.{}", n)
+  };
+  let path = logdir.join(fname);
 
-  //
-  // Get VM State
-  //
-  let state = match map.get("VMState") {
-    Some(s) => VmState::from(s),
-    None => VmState::Unknown
+  let buf = match std::fs::read(&path) {
+    Ok(buf) => buf,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      let s = format!("Log file '{}' does not exist", path.display());
+      return Err(Error::Missing(s));
+    }
+    Err(e) => return Err(e.into())
   };
 
-  //
-  // Parse snapshots
-  //
-  let snaps = snapshot::get_from_map(&map)?;
+  // Logs may contain output from the guest which isn't valid UTF-8.
+  let lines: Vec<String> = String::from_utf8_lossy(&buf)
+    .lines()
+    .map(|line| line.to_string())
+    .collect();
 
-  //
-  // Parse NICs
-  //
-  let nics = nics::get_from_map(&map)?;
+  let skip = match max_lines {
+    Some(n) => lines.len().saturating_sub(n),
+    None => 0
+  };
 
-  Ok(VmInfo {
-    state,
-    shares_map,
-    shares_list,
-    snapshots: snaps,
-    nics
-  })
+  Ok(lines.into_iter().skip(skip).collect())
 }
 
 
-/// Check whether a virtual machine is currently in a certain state.
-pub fn is_vm_state<V>(vid: V, state: VmState) -> Result<bool, Error>
+/// A hardware virtualization feature; whether it's enabled in the virtual
+/// machine's settings and whether it's actually in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwFeature {
+  /// The configured setting, or `None` if `showvminfo` doesn't report it
+  /// (some settings are only reported by some VirtualBox versions).
+  pub configured: Option<bool>,
+
+  /// `true` if the virtual machine's log reports the feature as being used.
+  pub active: bool
+}
+
+
+/// The hardware virtualization features of a running virtual machine, see
+/// [`active_hw_features()`].
+#[derive(Debug, Clone)]
+pub struct HwFeatures {
+  /// VT-x or AMD-V.
+  pub hw_virt: HwFeature,
+  pub nested_paging: HwFeature,
+  pub vpid: HwFeature,
+  pub unrestricted_guest: HwFeature,
+  pub large_pages: HwFeature,
+
+  /// `true` if VirtualBox is running the virtual machine on top of the host
+  /// operating system's hypervisor (such as Hyper-V) using its native
+  /// execution manager, which is typically much slower.
+  pub nem: bool
+}
+
+
+/// Get the hardware virtualization features a running virtual machine is
+/// configured to use, along with the ones it is actually using.
+///
+/// VirtualBox silently falls back to slower modes of execution if a feature
+/// isn't available on the host, so a feature being configured doesn't mean
+/// it's in use.  What's in use is determined from the current session's log
+/// (see [`read_log()`]), which is best effort since log messages vary
+/// between VirtualBox versions.  Returns `Err(Error::InvalidArgument)` if
+/// the virtual machine isn't running.
+pub fn active_hw_features<V>(vid: V) -> Result<HwFeatures, Error>
 where
   V: Borrow<VmId>
 {
-  let vmi = get_vm_info(vid)?;
-  Ok(vmi.state == state)
-}
+  let map = get_vm_info_map(vid.borrow())?;
 
+  let state = match map.get("VMState") {
+    Some(s) => VmState::from(s),
+    None => VmState::Unknown
+  };
+  if !matches!(state, VmState::Running | VmState::Paused) {
+    let s = format!(
+      "Virtual machine '{}' is not running (state '{}')",
+      vid.borrow(),
+      state.as_str()
+    );
+    return Err(Error::InvalidArgument(s));
+  }
 
-/// Wait for a virtual machine to self-terminate.
+  let log = read_log(vid.borrow(), 0, None)?;
+
+  Ok(parse_hw_features(&map, &log))
+}
+
+
+fn parse_hw_features(
+  map: &HashMap<String, String>,
+  log: &[String]
+) -> HwFeatures {
+  let configured = |key: &str| map.get(key).map(|v| v == "on");
+  let logged = |needle: &str| log.iter().any(|line| line.contains(needle));
+
+  // For example:
+  //   HM: HMR3Init: VT-x w/ nested paging and unrestricted guest execution hw
+  //     support
+  //   HM: Enabled nested paging
+  let hw_virt = logged("HMR3Init: VT-x") || logged("HMR3Init: AMD-V");
+
+  HwFeatures {
+    hw_virt: HwFeature {
+      configured: configured("hwvirtex"),
+      active: hw_virt
+    },
+    nested_paging: HwFeature {
+      configured: configured("nestedpaging"),
+      active: hw_virt && logged("Enabled nested paging")
+    },
+    vpid: HwFeature {
+      configured: configured("vtxvpid"),
+      active: hw_virt && logged("Enabled VPID")
+    },
+    unrestricted_guest: HwFeature {
+      configured: configured("vtxux"),
+      active: hw_virt && logged("Enabled unrestricted guest execution")
+    },
+    large_pages: HwFeature {
+      configured: configured("largepages"),
+      active: hw_virt && logged("Enabled large page support")
+    },
+    nem: logged("fall back to NEM") || logged("NEMR3Init: Snail")
+  }
+}
+
+
+/// Check whether VirtualBox warns that a virtual machine's settings file
+/// needs to be upgraded to the format of the installed VirtualBox version.
+///
+/// This typically happens after upgrading VirtualBox; the settings file is
+/// upgraded the next time the virtual machine's configuration is saved.
+pub fn needs_settings_upgrade<V>(vid: V) -> Result<bool, Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage showvminfo $VM --machinereadable
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("showvminfo");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--machinereadable");
+
+  let (_, stderr) = utils::exec(cmd)?;
+
+  Ok(settings_upgrade_warning(&String::from_utf8_lossy(&stderr)))
+}
+
+
+/// Look for warnings such as "The settings file '...' needs to be upgraded"
+/// in `VBoxManage` output.
+fn settings_upgrade_warning(output: &str) -> bool {
+  output.lines().any(|line| {
+    let line = line.to_lowercase();
+    line.contains("settings file")
+      && (line.contains("upgrade") || line.contains("older format"))
+  })
+}
+
+
+/// Get information about a virtual machine as a map.
+///
+/// Returns `Err(Error::Missing)` if there's no such virtual machine.
+pub fn get_vm_info_map<V>(vid: V) -> Result<HashMap<String, String>, Error>
+where
+  V: Borrow<VmId>
+{
+  let stdout = get_vm_info_raw(vid)?;
+
+  let lines = strutils::buf_to_strlines(&stdout, EmptyLine::Ignore);
+
+  let mut map = HashMap::new();
+
+  // Capture foo="bar" -> foo=bar
+  // This appears to be most common.  Quoted values may contain '=', as in
+  // nicproperty1="MTU=9000".
+  let re1 = Regex::new(r#"^(?P<key>[^"=]+)="(?P<val>[^"]*)"$"#).unwrap();
+
+  // Capture "foo"="bar" -> foo=bar
+  let re2 = Regex::new(r#"^"(?P<key>[^"=]+)"="(?P<val>[^"]*)"$"#).unwrap();
+
+  // foo=bar -> foo=bar
+  let re3 = Regex::new(r#"^(?P<key>[^"=]+)=(?P<val>[^"=]*)$"#).unwrap();
+
+  //let re = Regex::new(r#"^"?(?P<key>[^"=]+)"?="?(?P<val>[^"=]*)"?$"#).
+  // unwrap();
+
+
+  // ToDo: Handle multiline entires, like descriptions
+  for line in lines.iter() {
+    //println!("line: {}", line);
+
+    let line = line.trim_end();
+    let cap = re1
+      .captures(line)
+      .or_else(|| re2.captures(line))
+      .or_else(|| re3.captures(line));
+
+    if let Some(cap) = cap {
+      map.insert(cap[1].to_string(), cap[2].to_string());
+    }
+  }
+
+  Ok(map)
+}
+
+
+/// Settings which differ between two virtual machines, see
+/// [`diff_vm_config()`].  Maps `showvminfo` keys to the values for the first
+/// and second virtual machine; `None` if the key is missing for one of them.
+pub type ConfigDiff = BTreeMap<String, (Option<String>, Option<String>)>;
+
+
+/// Options for [`diff_vm_config_with()`].
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+  /// Skip keys which are expected to differ between any two virtual
+  /// machines: names, uuids, MAC addresses, runtime state (such as the
+  /// machine state and snapshots) and anything whose value is an absolute
+  /// file path.
+  pub ignore_unique: bool,
+
+  /// Additional keys to skip.
+  pub ignore: Vec<String>
+}
+
+impl Default for DiffOptions {
+  fn default() -> Self {
+    DiffOptions {
+      ignore_unique: true,
+      ignore: Vec::new()
+    }
+  }
+}
+
+
+/// Compare the configurations of two virtual machines, for instance a
+/// template and one of its clones.
+///
+/// Keys which are unique to each virtual machine are skipped; see
+/// [`DiffOptions::ignore_unique`] and [`diff_vm_config_with()`].
+pub fn diff_vm_config<A, B>(a: A, b: B) -> Result<ConfigDiff, Error>
+where
+  A: Borrow<VmId>,
+  B: Borrow<VmId>
+{
+  diff_vm_config_with(a, b, &DiffOptions::default())
+}
+
+
+/// Compare the configurations of two virtual machines, skipping keys as
+/// selected by `opts`.
+pub fn diff_vm_config_with<A, B>(
+  a: A,
+  b: B,
+  opts: &DiffOptions
+) -> Result<ConfigDiff, Error>
+where
+  A: Borrow<VmId>,
+  B: Borrow<VmId>
+{
+  let a = get_vm_info_map(a)?;
+  let b = get_vm_info_map(b)?;
+
+  Ok(diff_maps(&a, &b, opts))
+}
+
+
+fn diff_maps(
+  a: &HashMap<String, String>,
+  b: &HashMap<String, String>,
+  opts: &DiffOptions
+) -> ConfigDiff {
+  let skip = |key: &str| {
+    if opts.ignore.iter().any(|k| k == key) {
+      return true;
+    }
+    opts.ignore_unique
+      && (is_unique_key(key)
+        || [a.get(key), b.get(key)]
+          .iter()
+          .flatten()
+          .any(|v| is_abs_path(v)))
+  };
+
+  let mut diff = ConfigDiff::new();
+  for key in a.keys().chain(b.keys()) {
+    if skip(key) {
+      continue;
+    }
+    let (va, vb) = (a.get(key), b.get(key));
+    if va != vb {
+      diff.insert(key.clone(), (va.cloned(), vb.cloned()));
+    }
+  }
+  diff
+}
+
+
+/// Returns `true` for `showvminfo` keys whose values are unique to each
+/// virtual machine, or reflect its runtime state rather than its
+/// configuration.
+fn is_unique_key(key: &str) -> bool {
+  const KEYS: &[&str] = &[
+    "name",
+    "UUID",
+    "hardwareuuid",
+    "VMState",
+    "VMStateChangeTime",
+    "SessionName",
+    "SessionPID",
+    "CurrentSnapshotName",
+    "CurrentSnapshotUUID",
+    "CurrentSnapshotNode"
+  ];
+  const PREFIXES: &[&str] = &["macaddress", "Snapshot", "GuestAdditions"];
+
+  KEYS.contains(&key)
+    || PREFIXES.iter().any(|p| key.starts_with(p))
+    || key.contains("ImageUUID")
+}
+
+
+fn is_abs_path(v: &str) -> bool {
+  let b = v.as_bytes();
+  v.starts_with('/')
+    || (b.len() > 2
+      && b[0].is_ascii_alphabetic()
+      && b[1] == b':'
+      && (b[2] == b'\\' || b[2] == b'/'))
+}
+
+
+#[derive(PartialEq, Eq)]
+/// VirtualBox virtual machine states.
+pub enum VmState {
+  /// This isn't actually a VirtualBox virtual machine state; it's used as a
+  /// placeholder if an unknown state is encountered.
+  Unknown,
+
+  /// The virtual machine is powered off.
+  PowerOff,
+
+  /// The virtual machine is currently starting up.
+  Starting,
+
+  /// The virtual machine is currently up and running.
+  Running,
+
+  /// The virtual machine is currently paused.
+  Paused,
+
+  /// The virtual machine is currently shutting down.
+  Stopping,
+
+  /// The virtual machine's state has been saved to disk and it is no longer
+  /// running.
+  Saved,
+
+  /// The virtual machine process terminated unexpectedly.
+  Aborted
+}
+
+impl VmState {
+  /// The state's name, as used by `showvminfo`.
+  fn as_str(&self) -> &'static str {
+    match self {
+      VmState::Unknown => "unknown",
+      VmState::PowerOff => "poweroff",
+      VmState::Starting => "starting",
+      VmState::Running => "running",
+      VmState::Paused => "paused",
+      VmState::Stopping => "stopping",
+      VmState::Saved => "saved",
+      VmState::Aborted => "aborted"
+    }
+  }
+
+  /// Returns `true` if the state is one in which the virtual machine is no
+  /// longer running (powered off, saved or aborted).
+  pub fn is_terminal(&self) -> bool {
+    matches!(self, VmState::PowerOff | VmState::Saved | VmState::Aborted)
+  }
+}
+
+impl From<&str> for VmState {
+  fn from(s: &str) -> Self {
+    match s {
+      "poweroff" => VmState::PowerOff,
+      "starting" => VmState::Starting,
+      "running" => VmState::Running,
+      "paused" => VmState::Paused,
+      "stopping" => VmState::Stopping,
+      "saved" => VmState::Saved,
+      "aborted" => VmState::Aborted,
+      _ => VmState::Unknown
+    }
+  }
+}
+
+impl From<&String> for VmState {
+  fn from(s: &String) -> Self {
+    match s.as_ref() {
+      "poweroff" => VmState::PowerOff,
+      "starting" => VmState::Starting,
+      "running" => VmState::Running,
+      "paused" => VmState::Paused,
+      "stopping" => VmState::Stopping,
+      "saved" => VmState::Saved,
+      "aborted" => VmState::Aborted,
+      _ => VmState::Unknown
+    }
+  }
+}
+
+
+/// How far the Guest Additions have come in starting up within the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GuestRunLevel {
+  /// The Guest Additions are not running.
+  None,
+
+  /// The Guest Additions' system services are running.
+  System,
+
+  /// A user has logged in to the guest.
+  Userland,
+
+  /// A user has logged in to a desktop session in the guest.
+  Desktop
+}
+
+impl GuestRunLevel {
+  fn from_level(level: u8) -> Option<Self> {
+    match level {
+      0 => Some(GuestRunLevel::None),
+      1 => Some(GuestRunLevel::System),
+      2 => Some(GuestRunLevel::Userland),
+      3 => Some(GuestRunLevel::Desktop),
+      _ => None
+    }
+  }
+}
+
+
+/// Guest Additions status as reported by a running virtual machine.
+#[derive(Debug)]
+pub struct GuestAdditionsInfo {
+  /// The Guest Additions version string, as reported by the guest (for
+  /// example `6.1.18 r142142`).
+  pub version: Option<String>,
+  pub run_level: GuestRunLevel
+}
+
+
+/// Parse Guest Additions information from a virtual machine information map.
+///
+/// Returns `Ok(None)` if the map has no Guest Additions run level, which is
+/// the case when the virtual machine isn't running.
+pub fn guest_additions_from_map(
+  map: &HashMap<String, String>
+) -> Result<Option<GuestAdditionsInfo>, Error> {
+  let run_level = match map.get("GuestAdditionsRunLevel") {
+    Some(lvl) => {
+      match lvl.parse::<u8>().ok().and_then(GuestRunLevel::from_level) {
+        Some(lvl) => lvl,
+        None => {
+          let s = format!("Unknown Guest Additions run level '{}'", lvl);
+          return Err(Error::BadFormat(s));
+        }
+      }
+    }
+    None => return Ok(None)
+  };
+
+  let version = match map.get("GuestAdditionsVersion") {
+    Some(v) if !v.is_empty() => Some(v.clone()),
+    _ => None
+  };
+
+  Ok(Some(GuestAdditionsInfo { version, run_level }))
+}
+
+
+/// A structured representation of a virtual machine's state and configuration.
+pub struct VmInfo {
+  /// The virtual machine's name.  If `VBoxManage` doesn't report one, this
+  /// is the identifier the virtual machine was looked up by.
+  pub name: String,
+
+  /// Configured memory size, in MB.
+  pub memory: Option<u32>,
+
+  /// Number of virtual CPUs.
+  pub cpus: Option<u32>,
+
+  /// Map of permanent shared folder names to host paths.
+  pub shares_map: HashMap<String, PathBuf>,
+
+  /// List of permanent shared folder names and host paths.
+  pub shares_list: Vec<(String, PathBuf)>,
+
+  /// All shared folders, including transient ones, along with their flags.
+  pub shares: Vec<shares::SharedFolder>,
+
+  pub state: VmState,
+  pub snapshots: Option<snapshot::Snapshots>,
+  pub nics: Vec<nics::NICInfo>,
+  pub groups: Vec<String>,
+  pub controllers: Vec<storage::Controller>,
+  pub media: Vec<storage::MediumRef>,
+
+  /// Boot devices in the order the BIOS tries them, for example `disk`,
+  /// `dvd`, `floppy` or `net`.
+  pub boot_order: Vec<String>,
+
+  /// Number of virtual monitors; see [`modifyvm::set_monitor_count()`].
+  pub monitor_count: Option<u8>,
+
+  pub firmware: Option<modifyvm::Firmware>,
+  pub chipset: Option<modifyvm::Chipset>,
+
+  /// Configured paravirtualization interface.
+  pub paravirt_provider: Option<modifyvm::ParavirtProvider>,
+
+  /// Paravirtualization interface actually presented to the guest; differs
+  /// from [`paravirt_provider`](VmInfo::paravirt_provider) when that is
+  /// [`Default`](modifyvm::ParavirtProvider::Default).
+  pub effective_paravirt_provider: Option<modifyvm::ParavirtProvider>,
+
+  /// Whether the I/O APIC is enabled; see [`modifyvm::set_ioapic()`].
+  pub ioapic: Option<bool>,
+
+  /// Whether the local APICs support x2APIC mode.
+  pub x2apic: Option<bool>,
+
+  /// Whether nested hardware virtualization is enabled.
+  pub nested_hw_virt: Option<bool>,
+
+  /// Whether the High Precision Event Timer is enabled.
+  pub hpet: Option<bool>,
+
+  /// Whether the real-time clock runs in UTC.
+  pub rtc_use_utc: Option<bool>,
+
+  /// Whether the virtual machine's audio device is enabled.  For
+  /// VirtualBox versions which don't report it separately it's derived from
+  /// [`audio_driver`](VmInfo::audio_driver).
+  pub audio_enabled: Option<bool>,
+
+  pub audio_controller: Option<modifyvm::AudioController>,
+
+  /// Host audio backend; [`modifyvm::AudioDriver::None`] if the virtual
+  /// machine has no audio device.
+  pub audio_driver: Option<modifyvm::AudioDriver>,
+
+  pub clipboard_mode: Option<modifyvm::ClipboardMode>,
+
+  /// `true` if files may be copied through the shared clipboard.  Always
+  /// `false` for VirtualBox versions which don't support it.
+  pub clipboard_file_transfers: bool,
+
+  pub drag_and_drop: Option<modifyvm::DragAndDropMode>,
+
+  /// Address the remote display (VRDE) server listens on; empty for all
+  /// interfaces.  `None` if not reported, which is typically the case when
+  /// the VRDE server is disabled.
+  pub vrde_address: Option<String>,
+
+  pub guest_additions: Option<GuestAdditionsInfo>,
+
+  /// `true` if the virtual machine's current state differs from its current
+  /// snapshot.
+  pub current_state_modified: bool
+}
+
+impl VmInfo {
+  /// Get the hard disk the virtual machine boots from.
+  ///
+  /// This is the first hard disk (in controller, port and device order)
+  /// attached to a bootable storage controller.  Returns `None` if there is
+  /// no such disk, or if disks aren't part of the boot order.
+  pub fn boot_disk(&self) -> Option<&storage::MediumRef> {
+    if !self.boot_order.is_empty()
+      && !self.boot_order.iter().any(|dev| dev == "disk")
+    {
+      return None;
+    }
+
+    let bootable = |name: &str| {
+      self
+        .controllers
+        .iter()
+        .any(|ctrl| ctrl.name == name && ctrl.bootable)
+    };
+
+    self.media.iter().find(|m| {
+      !m.removable && m.medium.is_some() && bootable(&m.controller)
+    })
+  }
+
+  /// Get the virtual machine's settings which can be re-applied, to it or to
+  /// another virtual machine, using [`apply_descriptor()`].
+  pub fn to_descriptor(&self) -> VmDescriptor {
+    VmDescriptor {
+      memory: self.memory,
+      cpus: self.cpus,
+      firmware: self.firmware,
+      chipset: self.chipset,
+      nested_hw_virt: self.nested_hw_virt,
+      hpet: self.hpet,
+      rtc_use_utc: self.rtc_use_utc,
+      monitor_count: self.monitor_count,
+      groups: Some(self.groups.clone()),
+      audio_controller: self.audio_controller,
+      audio_driver: self.audio_driver.clone(),
+      clipboard_mode: self.clipboard_mode,
+      nics: self
+        .nics
+        .iter()
+        .map(|nic| descriptor::NicDescriptor {
+          idx: nic.idx,
+          nictype: nic.nictype.clone(),
+          hw_type: nic.hw_type.clone()
+        })
+        .collect()
+    }
+  }
+
+  /// Remove duplicate entries from [`nics`](VmInfo::nics), keeping the
+  /// first occurrence of each.
+  pub fn dedup_nics(&mut self) {
+    let nics = std::mem::take(&mut self.nics);
+    for nic in nics {
+      if !self.nics.contains(&nic) {
+        self.nics.push(nic);
+      }
+    }
+  }
+
+  /// Get the MAC address of the first network adapter (index 1), formatted
+  /// as colon-separated hex digits, such as `08:00:27:12:34:56`.
+  ///
+  /// Returns `None` if the first adapter is disabled.
+  pub fn primary_mac(&self) -> Option<String> {
+    self
+      .nics
+      .iter()
+      .find(|nic| nic.idx == 1)
+      .map(|nic| nic.mac.to_hex_string())
+  }
+
+  /// Render the virtual machine's name, state, memory size, CPU count,
+  /// network adapters and shared folders as a JSON object.
+  ///
+  /// This is meant for quick machine-readable output without depending on a
+  /// serialization framework; the other fields are not included.
+  pub fn to_json(&self) -> String {
+    let nics: Vec<String> = self.nics.iter().map(|n| n.to_json()).collect();
+    let shares: Vec<String> =
+      self.shares.iter().map(|s| s.to_json()).collect();
+
+    format!(
+      concat!(
+        r#"{{"name":{},"state":"{}","memory":{},"cpus":{},"#,
+        r#""nics":[{}],"shares":[{}]}}"#
+      ),
+      json::string(&self.name),
+      self.state.as_str(),
+      json::opt(self.memory),
+      json::opt(self.cpus),
+      nics.join(","),
+      shares.join(",")
+    )
+  }
+}
+
+
+/// Get structured information about a virtual machine.
+pub fn get_vm_info<V>(vid: V) -> Result<VmInfo, Error>
+where
+  V: Borrow<VmId>
+{
+  let vid = vid.borrow();
+  let map = get_vm_info_map(vid)?;
+
+  let name = match map.get("name") {
+    Some(nm) => nm.clone(),
+    None => vid.to_string()
+  };
+
+  //
+  // Get memory size and CPU count
+  //
+  let parse_num = |key: &str| match map.get(key) {
+    Some(n) => match n.parse::<u32>() {
+      Ok(n) => Ok(Some(n)),
+      Err(_) => {
+        let s = format!("Unable to parse {} '{}'", key, n);
+        Err(Error::BadFormat(s))
+      }
+    },
+    None => Ok(None)
+  };
+  let memory = parse_num("memory")?;
+  let cpus = parse_num("cpus")?;
+
+  let mut shares_list = Vec::new();
+  let mut shares_map = HashMap::new();
+
+  //
+  // Parse shares
+  //
+  let shares = shares::get_from_map(&map)?;
+  for share in shares.iter().filter(|sh| !sh.transient) {
+    shares_map.insert(share.name.clone(), share.host_path.clone());
+    shares_list.push((share.name.clone(), share.host_path.clone()));
+  }
+
+  //
+  // Get VM State
+  //
+  let state = match map.get("VMState") {
+    Some(s) => VmState::from(s),
+    None => VmState::Unknown
+  };
+
+  //
+  // Parse snapshots
+  //
+  let snaps = snapshot::get_from_map(&map)?;
+
+  //
+  // Parse NICs
+  //
+  let nics = nics::get_from_map(&map)?;
+
+  //
+  // Parse groups
+  //
+  let groups = match map.get("groups") {
+    Some(g) => g
+      .split(',')
+      .filter(|g| !g.is_empty())
+      .map(|g| g.to_string())
+      .collect(),
+    None => Vec::new()
+  };
+
+  //
+  // Parse storage controllers and attached media
+  //
+  let controllers = storage::get_controllers_from_map(&map)?;
+  let media = storage::get_from_map(&map)?;
+
+  //
+  // Get boot order
+  //
+  let boot_order = (1..=4)
+    .filter_map(|n| map.get(&format!("boot{}", n)))
+    .filter(|dev| *dev != "none")
+    .cloned()
+    .collect();
+
+  //
+  // Get number of virtual monitors
+  //
+  let monitor_count = match map.get("monitorcount") {
+    Some(n) => match n.parse::<u8>() {
+      Ok(n) => Some(n),
+      Err(_) => {
+        let s = format!("Unable to parse monitor count '{}'", n);
+        return Err(Error::BadFormat(s));
+      }
+    },
+    None => None
+  };
+
+  //
+  // Get firmware and chipset
+  //
+  let firmware = map
+    .get("firmware")
+    .and_then(|f| modifyvm::Firmware::from_info(f));
+  let chipset = map
+    .get("chipset")
+    .and_then(|c| modifyvm::Chipset::from_info(c));
+
+  //
+  // Get paravirtualization interface
+  //
+  let paravirt_provider = map
+    .get("paravirtprovider")
+    .and_then(|p| modifyvm::ParavirtProvider::from_info(p));
+  let effective_paravirt_provider = map
+    .get("effparavirtprovider")
+    .and_then(|p| modifyvm::ParavirtProvider::from_info(p));
+
+  //
+  // Get interrupt controller settings
+  //
+  let ioapic = map.get("ioapic").map(|v| v == "on");
+  let x2apic = map.get("x2apic").map(|v| v == "on");
+
+  //
+  // Get nested hardware virtualization setting
+  //
+  let nested_hw_virt = map.get("nested-hw-virt").map(|v| v == "on");
+
+  //
+  // Get timekeeping settings
+  //
+  let hpet = map.get("hpet").map(|v| v == "on");
+  let rtc_use_utc = map.get("rtcuseutc").map(|v| v == "on");
+
+  //
+  // Get audio settings
+  //
+  let audio_controller = map
+    .get("audio_controller")
+    .or_else(|| map.get("audiocontroller"))
+    .and_then(|c| modifyvm::AudioController::from_info(c));
+  let audio_driver = map
+    .get("audio")
+    .or_else(|| map.get("audiodriver"))
+    .map(|d| modifyvm::AudioDriver::from(d.as_str()));
+  let audio_enabled = match map.get("audio_enabled") {
+    Some(v) => Some(v == "on"),
+    None => audio_driver
+      .as_ref()
+      .map(|d| *d != modifyvm::AudioDriver::None)
+  };
+
+  //
+  // Get clipboard and drag and drop modes
+  //
+  let clipboard_mode = map
+    .get("clipboard")
+    .or_else(|| map.get("clipboardmode"))
+    .and_then(|m| modifyvm::ClipboardMode::from_info(m));
+  let clipboard_file_transfers = map
+    .get("clipboardfiletransfers")
+    .or_else(|| map.get("clipboard_file_transfers"))
+    .is_some_and(|v| v == "enabled" || v == "on");
+  let drag_and_drop = map
+    .get("draganddrop")
+    .and_then(|m| modifyvm::DragAndDropMode::from_info(m));
+
+  //
+  // Get remote display settings
+  //
+  let vrde_address = map.get("vrdeaddress").cloned();
+
+  //
+  // Get Guest Additions status
+  //
+  let guest_additions = guest_additions_from_map(&map)?;
+
+  //
+  // Check whether the current state differs from the current snapshot
+  //
+  let current_state_modified = match map.get("CurrentStateModified") {
+    Some(v) => v == "yes" || v == "on" || v == "true",
+    None => false
+  };
+
+  Ok(VmInfo {
+    name,
+    memory,
+    cpus,
+    state,
+    shares_map,
+    shares_list,
+    shares,
+    snapshots: snaps,
+    nics,
+    groups,
+    controllers,
+    media,
+    boot_order,
+    monitor_count,
+    firmware,
+    chipset,
+    paravirt_provider,
+    effective_paravirt_provider,
+    ioapic,
+    x2apic,
+    nested_hw_virt,
+    hpet,
+    rtc_use_utc,
+    audio_enabled,
+    audio_controller,
+    audio_driver,
+    clipboard_mode,
+    clipboard_file_transfers,
+    drag_and_drop,
+    vrde_address,
+    guest_additions,
+    current_state_modified
+  })
+}
+
+
+/// Get the name of every registered virtual machine's current snapshot.
+///
+/// The virtual machines are queried concurrently, a few at a time.  A
+/// failure to query one virtual machine doesn't stop the others from being
+/// queried; the outcome for each virtual machine is returned.  The outer
+/// `Result` only fails if the list of virtual machines could not be
+/// retrieved.
+pub fn vm_snapshot_overview() -> Result<VmResults<Option<String>>, Error> {
+  let vids: Vec<VmId> = get_vm_list()?
+    .into_iter()
+    .map(|(_, uuid)| VmId::Uuid(uuid))
+    .collect();
+
+  let results = utils::parallel_map(&vids, |vid| {
+    let map = get_vm_info_map(vid)?;
+    Ok(map.get("CurrentSnapshotName").cloned())
+  });
+
+  Ok(vids.into_iter().zip(results).collect())
+}
+
+
+/// Get the snapshots of every registered virtual machine, as a flat list of
+/// snapshots paired with the virtual machine they belong to.
+///
+/// Each virtual machine's snapshots are listed in tree order (every snapshot
+/// comes before its children), with timestamps filled in.  As with
+/// [`vm_snapshot_overview()`] the virtual machines are queried concurrently,
+/// a few at a time, and a failure to query one virtual machine doesn't stop
+/// the others from being queried; such failures are returned in a separate
+/// list, along with the virtual machine they occurred for.  The outer
+/// `Result` only fails if the list of virtual machines could not be
+/// retrieved.
+pub fn all_snapshots(
+) -> Result<(Vec<(VmId, snapshot::Snapshot)>, VmErrors), Error> {
+  let vids: Vec<VmId> = get_vm_list()?
+    .into_iter()
+    .map(|(_, uuid)| VmId::Uuid(uuid))
+    .collect();
+
+  let results = utils::parallel_map(&vids, |vid| {
+    Ok(match snapshot::get_with_timestamps(vid)? {
+      Some(snaps) => snaps.tree_order().into_iter().cloned().collect(),
+      None => Vec::new()
+    })
+  });
+
+  let mut snapshots = Vec::new();
+  let mut errors = Vec::new();
+  for (vid, res) in vids.into_iter().zip(results) {
+    match res {
+      Ok(snaps) => {
+        snapshots.extend(snaps.into_iter().map(|snap| (vid.clone(), snap)))
+      }
+      Err(e) => errors.push((vid, e))
+    }
+  }
+
+  Ok((snapshots, errors))
+}
+
+
+/// Get the total number of bytes a virtual machine's attached disks occupy on
+/// the host.
+///
+/// For each attached (non-removable) disk the sizes of the disk image and of
+/// all its parent images are summed.  Each image is only counted once, even if
+/// it's shared by several attachments.  Differencing images belonging to
+/// snapshots which aren't part of the current state's chain are not included.
+pub fn disk_footprint<V>(vid: V) -> Result<u64, Error>
+where
+  V: Borrow<VmId>
+{
+  let vmi = get_vm_info(vid)?;
+
+  let mut seen = HashSet::new();
+  let mut total = 0;
+
+  for m in vmi.media.iter().filter(|m| !m.removable) {
+    let mut next = m.uuid;
+    while let Some(u) = next {
+      if !seen.insert(u) {
+        break;
+      }
+      let info = medium::info(medium::MediumKind::Disk, u.to_string())?;
+      total += info.size_on_disk.unwrap_or(0);
+      next = info.parent;
+    }
+  }
+
+  Ok(total)
+}
+
+
+/// Get the MAC address of a virtual machine's first network adapter; see
+/// [`VmInfo::primary_mac()`].
+pub fn primary_mac<V>(vid: V) -> Result<Option<String>, Error>
+where
+  V: Borrow<VmId>
+{
+  Ok(get_vm_info(vid)?.primary_mac())
+}
+
+
+/// Check whether a virtual machine is currently in a certain state.
+pub fn is_vm_state<V>(vid: V, state: VmState) -> Result<bool, Error>
+where
+  V: Borrow<VmId>
+{
+  let vmi = get_vm_info(vid)?;
+  Ok(vmi.state == state)
+}
+
+
+/// Check whether a virtual machine information map (typically aquired using
+/// [`get_vm_info_map()`]) shows the virtual machine as locked by an
+/// operation in progress.
+///
+/// This is the case while it's in a transitional state, such as while a
+/// snapshot is being taken, or if it isn't running but a session is open on
+/// it.  The check is necessarily racy; it's meant for waiting out locks held
+/// by operations which have just finished, see [`wait_for_unlock()`].
+pub fn is_locked_from_map(map: &HashMap<String, String>) -> bool {
+  const TRANSITIONAL: &[&str] = &[
+    "saving",
+    "restoring",
+    "snapshotting",
+    "livesnapshotting",
+    "onlinesnapshotting",
+    "restoringsnapshot",
+    "deletingsnapshot",
+    "deletingsnapshotlive",
+    "deletingsnapshotpaused",
+    "settingup",
+    "teleporting",
+    "teleportingpausedvm",
+    "teleportingin"
+  ];
+
+  let state = map.get("VMState").map(|s| s.as_str()).unwrap_or("");
+  if TRANSITIONAL.contains(&state) {
+    return true;
+  }
+
+  VmState::from(state).is_terminal() && map.contains_key("SessionName")
+}
+
+
+/// Check whether a virtual machine is locked by an operation in progress;
+/// see [`is_locked_from_map()`].
+pub fn is_locked<V>(vid: V) -> Result<bool, Error>
+where
+  V: Borrow<VmId>
+{
+  Ok(is_locked_from_map(&get_vm_info_map(vid)?))
+}
+
+
+/// Wait for a virtual machine to no longer be locked (see [`is_locked()`]).
+///
+/// Operations such as taking a snapshot can leave the virtual machine locked
+/// for a moment after `VBoxManage` returns, making an immediate follow-up
+/// operation fail with `Error::Busy`.  The lock is checked every `poll`;
+/// returns `Err(Error::Timeout)` if it hasn't been released within
+/// `timeout`.
+pub fn wait_for_unlock<V>(
+  vid: V,
+  timeout: Duration,
+  poll: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let start = Instant::now();
+  while is_locked(vid.borrow())? {
+    if start.elapsed() > timeout {
+      return Err(Error::Timeout);
+    }
+
+    thread::sleep(poll);
+  }
+
+  Ok(())
+}
+
+
+/// Get the process identifier of a virtual machine's session process from a
+/// virtual machine information map (typically aquired using
+/// [`get_vm_info_map()`]).
+///
+/// Returns `Ok(None)` if the map does not contain a session PID, which is the
+/// case when the virtual machine isn't running.
+pub fn session_pid_from_map(
+  map: &HashMap<String, String>
+) -> Result<Option<u32>, Error> {
+  match map.get("SessionPID") {
+    Some(pid) => match pid.parse::<u32>() {
+      Ok(pid) => Ok(Some(pid)),
+      Err(_) => {
+        let s = format!("Unable to parse session PID '{}'", pid);
+        Err(Error::BadFormat(s))
+      }
+    },
+    None => Ok(None)
+  }
+}
+
+
+/// Get the process identifier of the process hosting a running virtual
+/// machine (i.e. `VBoxHeadless` or `VirtualBoxVM`).
+pub fn session_pid<V>(vid: V) -> Result<Option<u32>, Error>
+where
+  V: Borrow<VmId>
+{
+  let map = get_vm_info_map(vid)?;
+  session_pid_from_map(&map)
+}
+
+
+/// Check whether a virtual machine has terminated, i.e. whether it is in any
+/// of the states for which [`VmState::is_terminal()`] is `true`.
+///
+/// Unlike [`wait_for_croak()`] this only checks the state once and returns
+/// immediately.
+pub fn is_croaked<V>(vid: V) -> Result<bool, Error>
+where
+  V: Borrow<VmId>
+{
+  let vmi = get_vm_info(vid)?;
+  Ok(vmi.state.is_terminal())
+}
+
+
+/// Wait for a virtual machine to self-terminate.
 ///
 /// The caller can choose to pass a timeout and what action should be taken if
 /// the operation times out.  If the timeout occurs the caller can choose
@@ -394,7 +1774,7 @@ where
 /// }
 /// ```
 ///
-/// This function polls `is_vm_state()` which calls `get_vm_info()`.  A very
+/// This function polls `is_croaked()` which calls `get_vm_info()`.  A very
 /// sad state of affairs.  :(
 pub fn wait_for_croak<V>(
   vid: V,
@@ -405,8 +1785,7 @@ where
 {
   let start = Instant::now();
   loop {
-    let poweroff = is_vm_state(vid.borrow(), VmState::PowerOff)?;
-    if poweroff {
+    if is_croaked(vid.borrow())? {
       break;
     }
     if let Some((ref max_dur, ref action)) = timeout {
@@ -434,10 +1813,1035 @@ where
 }
 
 
+/// Power off all running virtual machines.
+///
+/// If `graceful` is `true` each virtual machine is sent an ACPI power button
+/// event and is given `wait` to shut down before it is killed.  Otherwise
+/// virtual machines are killed right away, and waited on for up to `wait`.
+///
+/// A failure to stop one virtual machine does not stop the others from being
+/// processed; the outcome for each virtual machine is returned.  The outer
+/// `Result` only fails if the list of running virtual machines could not be
+/// retrieved.
+pub fn poweroff_all(
+  graceful: bool,
+  wait: Duration
+) -> Result<VmResults, Error> {
+  let running = get_running_vm_list()?;
+
+  let mut out = Vec::new();
+  for (_, uuid) in running {
+    let vid = VmId::Uuid(uuid);
+    let res = stop(&vid, graceful, wait);
+    out.push((vid, res));
+  }
+
+  Ok(out)
+}
+
+
+/// Shut down (if `graceful`) or kill a virtual machine and wait for it to
+/// terminate; see [`poweroff_all()`].
+fn stop(vid: &VmId, graceful: bool, wait: Duration) -> Result<(), Error> {
+  if graceful {
+    controlvm::acpi_power_button(vid)?;
+    wait_for_croak(vid, Some((wait, TimeoutAction::Kill)))
+  } else {
+    controlvm::kill(vid)?;
+    wait_for_croak(vid, Some((wait, TimeoutAction::Error)))
+  }
+}
+
+
+/// Make sure a virtual machine isn't running.
+///
+/// If it is running it's stopped: if `graceful` is `true` it's sent an ACPI
+/// power button event and is given `wait` to shut down before it is killed,
+/// otherwise it's killed right away and waited on for up to `wait`.
+pub fn ensure_stopped<V>(
+  vid: V,
+  graceful: bool,
+  wait: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if is_croaked(vid.borrow())? {
+    return Ok(());
+  }
+  stop(vid.borrow(), graceful, wait)
+}
+
+
+/// Make sure a virtual machine is running.
+///
+/// If it isn't it's started in the context `ctx`, and waited on for up to
+/// `wait` to reach the running state; returns `Err(Error::Timeout)` if it
+/// doesn't.  Since [`Headless::Blocking`] doesn't return until the virtual
+/// machine has terminated, it is rejected with `Err(Error::InvalidArgument)`.
+pub fn ensure_running<V>(
+  vid: V,
+  ctx: RunContext,
+  wait: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if let RunContext::Headless(Headless::Blocking) = ctx {
+    let s = "Can't wait for a blocking headless session".to_string();
+    return Err(Error::InvalidArgument(s));
+  }
+
+  if is_vm_state(vid.borrow(), VmState::Running)? {
+    return Ok(());
+  }
+
+  controlvm::start(vid.borrow(), ctx)?;
+
+  let start = Instant::now();
+  while !is_vm_state(vid.borrow(), VmState::Running)? {
+    if start.elapsed() > wait {
+      return Err(Error::Timeout);
+    }
+    thread::sleep(Duration::from_secs(1));
+  }
+
+  Ok(())
+}
+
+
+/// Stop a virtual machine (if it's running) and start it again.
+///
+/// This is [`ensure_stopped()`] followed by [`ensure_running()`]; `wait`
+/// applies to each of the two steps.
+pub fn power_cycle<V>(
+  vid: V,
+  ctx: RunContext,
+  graceful: bool,
+  wait: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  ensure_stopped(vid.borrow(), graceful, wait)?;
+  ensure_running(vid.borrow(), ctx, wait)
+}
+
+
 /*
 fn foo() {
   let _map = get_vm_info_map("hello").unwrap();
 }
 */
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  const VM1: &str = "11111111-1111-1111-1111-111111111111";
+  const VM2: &str = "22222222-2222-2222-2222-222222222222";
+  const VM3: &str = "33333333-3333-3333-3333-333333333333";
+  const SNAP1: &str = "aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa";
+  const SNAP2: &str = "bbbbbbbb-bbbb-bbbb-bbbb-bbbbbbbbbbbb";
+  const SNAP3: &str = "cccccccc-cccc-cccc-cccc-cccccccccccc";
+
+  #[test]
+  fn vm_info_clipboard_file_transfers() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nclipboardfiletransfers=\"enabled\"\n");
+    runner.ok("name=\"vm\"\nclipboardfiletransfers=\"disabled\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    assert!(get_vm_info(VmId::from("vm")).unwrap().clipboard_file_transfers);
+    assert!(!get_vm_info(VmId::from("vm")).unwrap().clipboard_file_transfers);
+    assert!(!get_vm_info(VmId::from("vm")).unwrap().clipboard_file_transfers);
+  }
+
+  fn map_of(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect()
+  }
+
+  fn feature(configured: Option<bool>, active: bool) -> HwFeature {
+    HwFeature { configured, active }
+  }
+
+  #[test]
+  fn parse_hw_features_from_running_vm() {
+    let map = map_of(&[
+      ("hwvirtex", "on"),
+      ("nestedpaging", "on"),
+      ("largepages", "off"),
+      ("vtxvpid", "on")
+    ]);
+    let log: Vec<String> = [
+      "00:00:00.512 HM: HMR3Init: VT-x w/ nested paging and unrestricted \
+       guest execution hw support",
+      "00:00:00.601 HM: Enabled nested paging",
+      "00:00:00.601 HM: Enabled unrestricted guest execution"
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+
+    let hw = parse_hw_features(&map, &log);
+    assert_eq!(hw.hw_virt, feature(Some(true), true));
+    assert_eq!(hw.nested_paging, feature(Some(true), true));
+    assert_eq!(hw.vpid, feature(Some(true), false));
+    assert_eq!(hw.unrestricted_guest, feature(None, true));
+    assert_eq!(hw.large_pages, feature(Some(false), false));
+    assert!(!hw.nem);
+  }
+
+  #[test]
+  fn parse_hw_features_software_fallback() {
+    let map = map_of(&[("hwvirtex", "on")]);
+    let log: Vec<String> = vec![
+      "00:00:00.402 NEM: NEMR3Init: Snail execution mode is active!".into(),
+      "00:00:00.403 HM: Enabled nested paging".into()
+    ];
+
+    let hw = parse_hw_features(&map, &log);
+    assert_eq!(hw.hw_virt, feature(Some(true), false));
+    assert!(!hw.nested_paging.active);
+    assert!(hw.nem);
+  }
+
+  #[test]
+  fn active_hw_features_requires_running_vm() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\n");
+
+    assert!(matches!(
+      active_hw_features(VmId::from("vm")),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn vm_info_audio_enabled() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\naudio=\"pulse\"\naudio_enabled=\"off\"\n");
+    runner.ok("name=\"vm\"\naudio=\"none\"\n");
+    runner.ok("name=\"vm\"\naudio=\"pulse\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    let enabled = || get_vm_info(VmId::from("vm")).unwrap().audio_enabled;
+    assert_eq!(enabled(), Some(false));
+    assert_eq!(enabled(), Some(false));
+    assert_eq!(enabled(), Some(true));
+    assert_eq!(enabled(), None);
+  }
+
+  #[test]
+  fn host_ready_reports_driver_problem() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "WARNING: The vboxdrv kernel module is not loaded. Either there is no ",
+      "module\n",
+      "Extension Packs: 0\n"
+    ));
+    match check_host_ready() {
+      Err(Error::HostDriver(s)) => assert!(s.starts_with("WARNING: The")),
+      res => panic!("unexpected result {:?}", res)
+    }
+  }
+
+  #[test]
+  fn host_ready_reports_unusable_extpack() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "Extension Packs: 1\n",
+      "Pack no. 0:   Oracle VM VirtualBox Extension Pack\n",
+      "Version:      6.1.38\n",
+      "Usable:       false\n",
+      "Why unusable: version mismatch\n"
+    ));
+    match check_host_ready() {
+      Err(Error::ExtPack(s)) => assert!(s.ends_with("version mismatch")),
+      res => panic!("unexpected result {:?}", res)
+    }
+
+    runner.ok(concat!(
+      "Extension Packs: 1\n",
+      "Pack no. 0:   Oracle VM VirtualBox Extension Pack\n",
+      "Usable:       true\n"
+    ));
+    check_host_ready().unwrap();
+  }
+
+  #[test]
+  fn capabilities_from_extpacks() {
+    let runner = Runner::new();
+    runner.version("7.0.10r158379");
+    runner.ok(concat!(
+      "Extension Packs: 1\n",
+      "Pack no. 0:   Oracle VM VirtualBox Extension Pack\n",
+      "Version:      7.0.10\n",
+      "Usable:       true\n",
+      "VRDE Module:  VBoxVRDP\n"
+    ));
+    runner.ok("Extension Packs: 0\n");
+
+    let caps = capabilities().unwrap();
+    assert_eq!(caps.version.to_string(), "7.0.10r158379");
+    assert_eq!(caps.extpacks.len(), 1);
+    assert!(caps.host_driver);
+    assert!(caps.usb2 && caps.usb3 && caps.vrde && caps.disk_encryption);
+
+    let caps = capabilities().unwrap();
+    assert!(caps.extpacks.is_empty());
+    assert!(!caps.usb2 && !caps.usb3 && !caps.vrde && !caps.disk_encryption);
+  }
+
+  #[test]
+  fn capabilities_ignore_unusable_extpack() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "WARNING: The vboxdrv kernel module is not loaded.\n",
+      "Extension Packs: 1\n",
+      "Pack no. 0:   Oracle VM VirtualBox Extension Pack\n",
+      "Usable:       false\n",
+      "Why unusable: version mismatch\n",
+      "VRDE Module:  VBoxVRDP\n"
+    ));
+
+    let caps = capabilities().unwrap();
+    assert!(!caps.host_driver);
+    assert!(!caps.usb3 && !caps.vrde);
+  }
+
+  #[test]
+  fn parse_vm_lines() {
+    let u = uuid::Uuid::parse_str(VM1).unwrap();
+    let line = format!("\"my \"quoted\" vm\" {{{}}}", VM1);
+    assert_eq!(parse_vm_line(&line), Some(("my \"quoted\" vm".into(), u)));
+    let line = format!("\"<inaccessible>\" {{{}}}", VM1);
+    assert_eq!(parse_vm_line(&line), Some((INACCESSIBLE_NAME.into(), u)));
+
+    assert_eq!(parse_vm_line("\"vm\" {not-a-uuid}"), None);
+    assert_eq!(parse_vm_line(&format!("vm {{{}}}", VM1)), None);
+    assert_eq!(parse_vm_line(""), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn vm_list_iter_reports_bad_lines() {
+    let runner = Runner::new();
+    runner.ok(&format!(
+      "\"one\" {{{}}}\nbogus\n\n\"two\" {{{}}}\n",
+      VM1, VM2
+    ));
+
+    let items: Vec<_> = get_vm_list_iter().unwrap().collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[0].as_ref().unwrap().0, "one");
+    assert!(matches!(items[1], Err(Error::BadFormat(_))));
+    assert_eq!(items[2].as_ref().unwrap().1.to_string(), VM2);
+    assert_eq!(runner.calls(), [["list", "vms"]]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn vm_list_iter_reports_failure_last() {
+    let runner = Runner::new();
+    runner.fail("VBoxManage: error: Failed to create the VirtualBox object!");
+
+    let mut items = get_vm_list_iter().unwrap();
+    assert!(matches!(items.next(), Some(Err(Error::CommandFailed(_, _)))));
+    assert!(items.next().is_none());
+  }
+
+  #[test]
+  fn poweroff_all_reports_each_vm() {
+    let runner = Runner::new();
+    runner.ok(&format!("\"one\" {{{}}}\n\"two\" {{{}}}\n", VM1, VM2));
+    runner.ok("");
+    runner.ok("name=\"one\"\nVMState=\"poweroff\"\n");
+    runner.fail("VBoxManage: error: Machine in invalid state");
+
+    let res = poweroff_all(false, Duration::from_secs(1)).unwrap();
+    let (vm1, vm2) = (format!("{{{}}}", VM1), format!("{{{}}}", VM2));
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0].0.to_string(), vm1);
+    assert!(res[0].1.is_ok());
+    assert_eq!(res[1].0.to_string(), vm2);
+    assert!(matches!(res[1].1, Err(Error::CommandFailed(_, _))));
+
+    let calls = runner.calls();
+    assert_eq!(calls[0], ["list", "runningvms"]);
+    assert_eq!(calls[1], ["controlvm", &vm1, "poweroff"]);
+    assert_eq!(calls[3], ["controlvm", &vm2, "poweroff"]);
+  }
+
+  #[test]
+  fn diff_vm_config_reports_changed_settings() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"template\"\n",
+      "UUID=\"11111111-1111-1111-1111-111111111111\"\n",
+      "memory=2048\n",
+      "cpus=2\n",
+      "vram=16\n",
+      "CfgFile=\"/vms/template/template.vbox\"\n"
+    ));
+    runner.ok(concat!(
+      "name=\"clone\"\n",
+      "UUID=\"22222222-2222-2222-2222-222222222222\"\n",
+      "memory=4096\n",
+      "vram=16\n",
+      "CfgFile=\"/vms/clone/clone.vbox\"\n"
+    ));
+
+    let diff = diff_vm_config(VmId::from("template"), VmId::from("clone"))
+      .unwrap();
+    let some = |s: &str| Some(s.to_string());
+    let expected: ConfigDiff = vec![
+      ("cpus".to_string(), (some("2"), None)),
+      ("memory".to_string(), (some("2048"), some("4096")))
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(diff, expected);
+  }
+
+  #[test]
+  fn diff_maps_honours_options() {
+    let a = map_of(&[("name", "a"), ("memory", "1024"), ("vram", "16")]);
+    let b = map_of(&[("name", "b"), ("memory", "1024"), ("vram", "32")]);
+
+    let opts = DiffOptions {
+      ignore_unique: false,
+      ignore: vec!["vram".to_string()]
+    };
+    let diff = diff_maps(&a, &b, &opts);
+    assert_eq!(diff.keys().collect::<Vec<_>>(), ["name"]);
+  }
+
+  #[test]
+  fn vm_info_interrupt_controllers() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nioapic=\"on\"\nx2apic=\"off\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!((info.ioapic, info.x2apic), (Some(true), Some(false)));
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!((info.ioapic, info.x2apic), (None, None));
+  }
+
+  #[test]
+  fn vm_info_nested_hw_virt() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nnested-hw-virt=\"on\"\n");
+    runner.ok("name=\"vm\"\nnested-hw-virt=\"off\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.nested_hw_virt, Some(true));
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.nested_hw_virt, Some(false));
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.nested_hw_virt, None);
+  }
+
+  #[test]
+  fn vm_info_shared_folders() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "SharedFolderNameMachineMapping1=\"data\"\n",
+      "SharedFolderPathMachineMapping1=\"/srv/data\"\n",
+      "SharedFolderWritableMachineMapping1=\"false\"\n",
+      "SharedFolderAutoMountMachineMapping1=\"true\"\n",
+      "SharedFolderAutoMountPointMachineMapping1=\"/mnt/data\"\n",
+      "SharedFolderNameTransientMapping1=\"scratch\"\n",
+      "SharedFolderPathTransientMapping1=\"/tmp/scratch\"\n"
+    ));
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.shares.len(), 2);
+
+    let data = &info.shares[0];
+    assert_eq!(data.name, "data");
+    assert_eq!(data.host_path, PathBuf::from("/srv/data"));
+    assert!(!data.writable);
+    assert!(data.automount);
+    assert_eq!(data.auto_mount_point.as_deref(), Some("/mnt/data"));
+    assert!(!data.transient);
+
+    let scratch = &info.shares[1];
+    assert_eq!(scratch.name, "scratch");
+    assert_eq!(scratch.host_path, PathBuf::from("/tmp/scratch"));
+    assert!(scratch.writable);
+    assert!(!scratch.automount);
+    assert_eq!(scratch.auto_mount_point, None);
+    assert!(scratch.transient);
+
+    let data_path = PathBuf::from("/srv/data");
+    assert_eq!(info.shares_list, [("data".to_string(), data_path.clone())]);
+    assert_eq!(info.shares_map.len(), 1);
+    assert_eq!(info.shares_map.get("data"), Some(&data_path));
+  }
+
+  #[test]
+  fn vm_info_to_json() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "VMState=\"running\"\n",
+      "memory=1024\n",
+      "cpus=2\n",
+      "nic1=\"intnet\"\n",
+      "intnet1=\"lab\"\n",
+      "macaddress1=\"080027000001\"\n",
+      "SharedFolderNameMachineMapping1=\"data\"\n",
+      "SharedFolderPathMachineMapping1=\"/srv/data\"\n"
+    ));
+
+    let mut info = get_vm_info(VmId::from("vm")).unwrap();
+    info.name = r#"say "hi" C:\vms"#.to_string();
+    assert_eq!(
+      info.to_json(),
+      concat!(
+        r#"{"name":"say \"hi\" C:\\vms","state":"running","memory":1024,"#,
+        r#""cpus":2,"nics":[{"idx":1,"type":"intnet","attachment":"lab","#,
+        r#""mac":"08:00:27:00:00:01","hw_type":null}],"#,
+        r#""shares":[{"name":"data","host_path":"/srv/data","writable":true,"#,
+        r#""automount":false,"auto_mount_point":null,"transient":false}]}"#
+      )
+    );
+  }
+
+  #[test]
+  fn vm_info_without_name_uses_vmid() {
+    let runner = Runner::new();
+    runner.ok("VMState=\"poweroff\"\n");
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.name, "vm");
+    assert_eq!(
+      info.to_json(),
+      concat!(
+        r#"{"name":"vm","state":"poweroff","memory":null,"cpus":null,"#,
+        r#""nics":[],"shares":[]}"#
+      )
+    );
+  }
+
+  #[test]
+  fn all_snapshots_flattens_each_vm() {
+    let runner = Runner::new();
+    runner.ok(&format!(
+      "\"one\" {{{}}}\n\"two\" {{{}}}\n\"three\" {{{}}}\n",
+      VM1, VM2, VM3
+    ));
+    let (vm1, vm2) = (format!("{{{}}}", VM1), format!("{{{}}}", VM2));
+    let vm3 = format!("{{{}}}", VM3);
+    runner.ok_for(
+      &vm1,
+      &format!(
+        concat!(
+          "name=\"one\"\n",
+          "SnapshotName=\"base\"\n",
+          "SnapshotUUID=\"{}\"\n",
+          "SnapshotName-1=\"child\"\n",
+          "SnapshotUUID-1=\"{}\"\n",
+          "CurrentSnapshotName=\"child\"\n",
+          "CurrentSnapshotUUID=\"{}\"\n"
+        ),
+        SNAP1, SNAP2, SNAP2
+      )
+    );
+    runner.ok_for(
+      &vm2,
+      &format!(
+        concat!(
+          "name=\"two\"\n",
+          "SnapshotName=\"clean\"\n",
+          "SnapshotUUID=\"{}\"\n",
+          "CurrentSnapshotName=\"clean\"\n",
+          "CurrentSnapshotUUID=\"{}\"\n"
+        ),
+        SNAP3, SNAP3
+      )
+    );
+    let missing = "VBoxManage: error: Could not find a registered machine";
+    runner.fail_for(&vm3, missing);
+
+    let (snaps, errors) = all_snapshots().unwrap();
+    let found: Vec<(String, &str)> = snaps
+      .iter()
+      .map(|(vid, snap)| (vid.to_string(), snap.name.as_str()))
+      .collect();
+    assert_eq!(
+      found,
+      [(vm1.clone(), "base"), (vm1, "child"), (vm2, "clean")]
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0.to_string(), vm3);
+    assert!(matches!(errors[0].1, Error::Missing(_)));
+  }
+
+  const DISK_CONFLICT: &str = concat!(
+    "VBoxManage: error: Cannot register the hard disk '/vms/vm/vm.vdi' ",
+    "{33333333-3333-3333-3333-333333333333} because a hard disk ",
+    "'/vms/old/old.vdi' with UUID {33333333-3333-3333-3333-333333333333} ",
+    "already exists\n"
+  );
+
+  #[test]
+  fn register_vm_success_and_failures() {
+    let runner = Runner::new();
+    runner.ok("");
+    runner.fail(concat!(
+      "VBoxManage: error: Trying to open a VM config '/vms/vm/vm.vbox' ",
+      "which has the same UUID as an existing virtual machine\n"
+    ));
+    runner.fail(DISK_CONFLICT);
+    runner.fail("VBoxManage: error: Could not read the settings file\n");
+
+    assert!(register_vm("/vms/vm/vm.vbox", false).is_ok());
+    match register_vm("/vms/vm/vm.vbox", true) {
+      Err(Error::Conflict(msg)) => assert!(msg.starts_with("Trying to open")),
+      res => panic!("unexpected result {:?}", res)
+    }
+    match register_vm("/vms/vm/vm.vbox", false) {
+      Err(Error::Conflict(msg)) => assert!(msg.contains("/vms/vm/vm.vdi")),
+      res => panic!("unexpected result {:?}", res)
+    }
+    assert!(matches!(
+      register_vm("/vms/vm/vm.vbox", false),
+      Err(Error::CommandFailed(_, _))
+    ));
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 4);
+    assert!(calls.iter().all(|c| *c == ["registervm", "/vms/vm/vm.vbox"]));
+  }
+
+  #[test]
+  fn register_vm_remaps_conflicting_disk() {
+    let runner = Runner::new();
+    runner.fail(DISK_CONFLICT);
+    runner.ok("UUID changed to: 44444444-4444-4444-4444-444444444444\n");
+    runner.ok("");
+
+    let path = std::env::temp_dir()
+      .join(format!("vboxhelper-register-{}.vbox", std::process::id()));
+    std::fs::write(
+      &path,
+      r#"<HardDisk uuid="{33333333-3333-3333-3333-333333333333}"/>"#
+    )
+    .unwrap();
+
+    let res = register_vm(&path, true);
+    let xml = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    res.unwrap();
+
+    assert_eq!(
+      xml,
+      r#"<HardDisk uuid="{44444444-4444-4444-4444-444444444444}"/>"#
+    );
+    let path = path.to_string_lossy();
+    assert_eq!(
+      runner.calls(),
+      [
+        vec!["registervm", &path],
+        vec!["internalcommands", "sethduuid", "/vms/vm/vm.vdi"],
+        vec!["registervm", &path]
+      ]
+    );
+  }
+
+  #[test]
+  fn session_pid_parse() {
+    let mut map = HashMap::new();
+    assert_eq!(session_pid_from_map(&map).unwrap(), None);
+
+    map.insert("SessionPID".to_string(), "4242".to_string());
+    assert_eq!(session_pid_from_map(&map).unwrap(), Some(4242));
+
+    map.insert("SessionPID".to_string(), "n/a".to_string());
+    assert!(matches!(
+      session_pid_from_map(&map),
+      Err(Error::BadFormat(_))
+    ));
+  }
+
+  #[test]
+  fn is_croaked_by_state() {
+    let runner = Runner::new();
+    let states = [
+      ("poweroff", true),
+      ("saved", true),
+      ("aborted", true),
+      ("running", false),
+      ("paused", false)
+    ];
+    for (state, _) in states.iter() {
+      runner.ok(&format!("name=\"vm\"\nVMState=\"{}\"\n", state));
+    }
+
+    for (state, croaked) in states.iter() {
+      assert_eq!(is_croaked(VmId::from("vm")).unwrap(), *croaked, "{}", state);
+    }
+  }
+
+  #[test]
+  fn inaccessible_vms_are_listed_separately() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "\"web\" {11111111-1111-1111-1111-111111111111}\n",
+      "\"<inaccessible>\" {22222222-2222-2222-2222-222222222222}\n",
+      "\"db\" {33333333-3333-3333-3333-333333333333}\n"
+    ));
+
+    let vms = get_inaccessible_vm_list().unwrap();
+    assert_eq!(vms, [uuid::Uuid::parse_str(VM2).unwrap()]);
+    assert_eq!(runner.calls(), [["list", "vms"]]);
+  }
+
+  #[test]
+  fn guest_additions_info_parse() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "GuestAdditionsRunLevel=2\n",
+      "GuestAdditionsVersion=\"7.0.10 r158379\"\n"
+    ));
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    let ga = info.guest_additions.unwrap();
+    assert_eq!(ga.run_level, GuestRunLevel::Userland);
+    assert_eq!(ga.version.as_deref(), Some("7.0.10 r158379"));
+
+    let mut map = HashMap::new();
+    assert!(guest_additions_from_map(&map).unwrap().is_none());
+    map.insert("GuestAdditionsRunLevel".to_string(), "1".to_string());
+    map.insert("GuestAdditionsVersion".to_string(), String::new());
+    let ga = guest_additions_from_map(&map).unwrap().unwrap();
+    assert_eq!((ga.run_level, ga.version), (GuestRunLevel::System, None));
+    map.insert("GuestAdditionsRunLevel".to_string(), "7".to_string());
+    assert!(matches!(
+      guest_additions_from_map(&map),
+      Err(Error::BadFormat(_))
+    ));
+  }
+
+  #[test]
+  fn disk_footprint_counts_shared_parents_once() {
+    const DISK_A: &str = "aaaaaaaa-0000-0000-0000-000000000000";
+    const DISK_B: &str = "bbbbbbbb-0000-0000-0000-000000000000";
+    const BASE: &str = "cccccccc-0000-0000-0000-000000000000";
+    const DVD: &str = "dddddddd-0000-0000-0000-000000000000";
+
+    let runner = Runner::new();
+    runner.ok(&format!(
+      concat!(
+        "name=\"vm\"\n",
+        "storagecontrollername0=\"SATA\"\n",
+        "storagecontrollerportcount0=\"3\"\n",
+        "\"SATA-0-0\"=\"/vms/a.vdi\"\n",
+        "\"SATA-ImageUUID-0-0\"=\"{}\"\n",
+        "\"SATA-1-0\"=\"/vms/b.vdi\"\n",
+        "\"SATA-ImageUUID-1-0\"=\"{}\"\n",
+        "\"SATA-2-0\"=\"/iso/tools.iso\"\n",
+        "\"SATA-ImageUUID-2-0\"=\"{}\"\n",
+        "\"SATA-IsEjected-2-0\"=\"off\"\n"
+      ),
+      DISK_A, DISK_B, DVD
+    ));
+    let medium = |uuid: &str, parent: &str, size: &str| {
+      format!(
+        concat!(
+          "UUID:           {}\n",
+          "Parent UUID:    {}\n",
+          "Location:       /vms/{}.vdi\n",
+          "Size on disk:   {}\n"
+        ),
+        uuid, parent, uuid, size
+      )
+    };
+    runner.ok(&medium(DISK_A, BASE, "10 MBytes"));
+    runner.ok(&medium(BASE, "base", "1 GBytes"));
+    runner.ok(&medium(DISK_B, BASE, "512 Bytes"));
+
+    let total = disk_footprint(VmId::from("vm")).unwrap();
+    assert_eq!(total, (10 << 20) + (1 << 30) + 512);
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls[1..],
+      [
+        format!("showmediuminfo disk {}", DISK_A),
+        format!("showmediuminfo disk {}", BASE),
+        format!("showmediuminfo disk {}", DISK_B)
+      ]
+    );
+  }
+
+  #[test]
+  fn vm_info_raw_is_unparsed() {
+    let runner = Runner::new();
+    let out = "name=\"vm\"\r\nbroken line without equals\nmemory=1024\n";
+    runner.ok(out);
+
+    let raw = get_vm_info_raw(VmId::from("vm")).unwrap();
+    assert_eq!(raw, out.as_bytes());
+    assert_eq!(runner.calls(), [["showvminfo", "vm", "--machinereadable"]]);
+  }
+
+  #[test]
+  fn wait_for_vm_polls_the_list() {
+    let runner = Runner::new();
+    let web = format!("\"web\" {{{}}}\n", VM1);
+    runner.ok("");
+    runner.ok(&web);
+    runner.ok(&format!("{}\"db\" {{{}}}\n", web, VM2));
+
+    let timeout = Duration::from_secs(5);
+    let poll = Duration::from_millis(1);
+    wait_for_vm(VmId::from("db"), timeout, poll).unwrap();
+    assert_eq!(runner.calls().len(), 3);
+    assert!(runner.calls().iter().all(|c| c == &["list", "vms"]));
+  }
+
+  #[test]
+  fn wait_for_vm_times_out() {
+    let _runner = Runner::new();
+
+    let timeout = Duration::from_millis(20);
+    let poll = Duration::from_millis(1);
+    assert!(matches!(
+      wait_for_vm(VmId::from("db"), timeout, poll),
+      Err(Error::Timeout)
+    ));
+  }
+
+  #[test]
+  fn power_cycle_stops_then_starts() {
+    let runner = Runner::new();
+    let state = |s: &str| format!("name=\"vm\"\nVMState=\"{}\"\n", s);
+    runner.ok(&state("running"));
+    runner.ok("");
+    runner.ok(&state("poweroff"));
+    runner.ok(&state("poweroff"));
+    runner.ok("");
+    runner.ok(&state("running"));
+
+    let ctx = RunContext::Headless(Headless::Detached);
+    power_cycle(VmId::from("vm"), ctx, false, Duration::from_secs(5))
+      .unwrap();
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "showvminfo vm --machinereadable",
+        "controlvm vm poweroff",
+        "showvminfo vm --machinereadable",
+        "showvminfo vm --machinereadable",
+        "startvm vm --type headless",
+        "showvminfo vm --machinereadable"
+      ]
+    );
+  }
+
+  #[test]
+  fn power_cycle_rejects_blocking_context() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\n");
+
+    let ctx = RunContext::Headless(Headless::Blocking);
+    assert!(matches!(
+      power_cycle(VmId::from("vm"), ctx, true, Duration::from_secs(5)),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn settings_upgrade_warning_is_detected() {
+    let runner = Runner::new();
+    runner.warn(
+      "name=\"vm\"\n",
+      concat!(
+        "VBoxManage: warning: The settings file '/vms/vm/vm.vbox' is of an ",
+        "older format and needs to be upgraded\n"
+      )
+    );
+    runner.ok("name=\"vm\"\n");
+    runner.warn("name=\"vm\"\n", "VBoxManage: warning: Something else\n");
+
+    assert!(needs_settings_upgrade(VmId::from("vm")).unwrap());
+    assert!(!needs_settings_upgrade(VmId::from("vm")).unwrap());
+    assert!(!needs_settings_upgrade(VmId::from("vm")).unwrap());
+    assert_eq!(runner.calls()[0], ["showvminfo", "vm", "--machinereadable"]);
+  }
+
+  #[test]
+  fn read_log_returns_tail() {
+    let runner = Runner::new();
+    let dir = std::env::temp_dir()
+      .join(format!("vboxhelper-logs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("VBox.log"), "one\ntwo\nthree\nfour\n").unwrap();
+    std::fs::write(dir.join("VBox.log.1"), "old\n").unwrap();
+    let info = format!("LogFldr=\"{}\"\n", dir.display());
+    for _ in 0..4 {
+      runner.ok(&info);
+    }
+
+    let tail = read_log(VmId::from("vm"), 0, Some(2));
+    let all = read_log(VmId::from("vm"), 0, None);
+    let old = read_log(VmId::from("vm"), 1, Some(10));
+    let missing = read_log(VmId::from("vm"), 2, None);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(tail.unwrap(), ["three", "four"]);
+    assert_eq!(all.unwrap(), ["one", "two", "three", "four"]);
+    assert_eq!(old.unwrap(), ["old"]);
+    assert!(matches!(missing, Err(Error::Missing(_))));
+  }
+
+  #[test]
+  fn vm_snapshot_overview_collects_errors() {
+    let runner = Runner::new();
+    runner.ok(&format!(
+      "\"one\" {{{}}}\n\"two\" {{{}}}\n\"three\" {{{}}}\n",
+      VM1, VM2, VM3
+    ));
+    let (vm1, vm2) = (format!("{{{}}}", VM1), format!("{{{}}}", VM2));
+    let vm3 = format!("{{{}}}", VM3);
+    runner.ok_for(
+      &vm1,
+      &format!(
+        "name=\"one\"\nCurrentSnapshotName=\"child\"\n\
+         CurrentSnapshotUUID=\"{}\"\n",
+        SNAP2
+      )
+    );
+    runner.ok_for(&vm2, "name=\"two\"\n");
+    let missing = "VBoxManage: error: Could not find a registered machine";
+    runner.fail_for(&vm3, missing);
+
+    let overview = vm_snapshot_overview().unwrap();
+    assert_eq!(overview.len(), 3);
+    assert_eq!(overview[0].0.to_string(), vm1);
+    assert_eq!(overview[0].1.as_ref().unwrap().as_deref(), Some("child"));
+    assert_eq!(overview[1].0.to_string(), vm2);
+    assert_eq!(overview[1].1.as_ref().unwrap(), &None);
+    assert_eq!(overview[2].0.to_string(), vm3);
+    assert!(matches!(overview[2].1, Err(Error::Missing(_))));
+  }
+
+  #[test]
+  fn boot_disk_skips_dvd() {
+    let runner = Runner::new();
+    let storage = concat!(
+      "storagecontrollername0=\"IDE\"\n",
+      "storagecontrollerportcount0=\"2\"\n",
+      "storagecontrollerbootable0=\"on\"\n",
+      "\"IDE-0-0\"=\"/iso/install.iso\"\n",
+      "\"IDE-IsEjected-0-0\"=\"off\"\n",
+      "storagecontrollername1=\"SATA\"\n",
+      "storagecontrollerportcount1=\"2\"\n",
+      "storagecontrollerbootable1=\"on\"\n",
+      "\"SATA-0-0\"=\"/vms/vm/vm.vdi\"\n"
+    );
+    let boot = |second: &str| {
+      format!(
+        "name=\"vm\"\nboot1=\"dvd\"\nboot2=\"{}\"\n{}",
+        second, storage
+      )
+    };
+    runner.ok(&boot("disk"));
+    runner.ok(&boot("none"));
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "storagecontrollername0=\"SATA\"\n",
+      "storagecontrollerportcount0=\"1\"\n",
+      "storagecontrollerbootable0=\"on\"\n",
+      "\"SATA-0-0\"=\"emptydrive\"\n"
+    ));
+
+    let info = get_vm_info(VmId::from("vm")).unwrap();
+    let disk = info.boot_disk().unwrap();
+    assert_eq!(disk.controller, "SATA");
+    assert_eq!((disk.port, disk.device), (0, 0));
+    assert_eq!(disk.medium.as_deref(), Some("/vms/vm/vm.vdi"));
+
+    assert!(get_vm_info(VmId::from("vm")).unwrap().boot_disk().is_none());
+    assert!(get_vm_info(VmId::from("vm")).unwrap().boot_disk().is_none());
+  }
+
+  #[test]
+  fn primary_mac_is_first_adapter() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\n");
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "nic1=\"nat\"\n",
+      "macaddress1=\"0800271A2B3C\"\n"
+    ));
+
+    let mut info = get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.primary_mac(), None);
+
+    let nic = |idx: u8, mac: &str| nics::NICInfo {
+      idx,
+      nictype: nics::NICType::Nat(nics::NatNIC { forwards: Vec::new() }),
+      mac: eui48::MacAddress::parse_str(mac).unwrap(),
+      hw_type: None,
+      properties: HashMap::new()
+    };
+    info.nics = vec![nic(2, "080027000002"), nic(1, "080027000001")];
+    assert_eq!(info.primary_mac().as_deref(), Some("08:00:27:00:00:01"));
+
+    assert_eq!(
+      primary_mac(VmId::from("vm")).unwrap().as_deref(),
+      Some("08:00:27:1a:2b:3c")
+    );
+  }
+
+  #[test]
+  fn wait_for_unlock_polls_until_released() {
+    let runner = Runner::new();
+    let state = |s: &str| format!("name=\"vm\"\nVMState=\"{}\"\n", s);
+    runner.ok(&state("livesnapshotting"));
+    runner.ok(&state("livesnapshotting"));
+    runner.ok(&state("running"));
+
+    let timeout = Duration::from_secs(5);
+    let poll = Duration::from_millis(1);
+    wait_for_unlock(VmId::from("vm"), timeout, poll).unwrap();
+    assert_eq!(runner.calls().len(), 3);
+  }
+
+  #[test]
+  fn wait_for_unlock_times_out() {
+    let runner = Runner::new();
+    let locked = "name=\"vm\"\nVMState=\"poweroff\"\nSessionName=\"GUI/Qt\"\n";
+    for _ in 0..3 {
+      runner.ok(locked);
+    }
+
+    let timeout = Duration::from_millis(1);
+    let poll = Duration::from_millis(5);
+    assert!(matches!(
+      wait_for_unlock(VmId::from("vm"), timeout, poll),
+      Err(Error::Timeout)
+    ));
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,382 @@
+//! Typed wrappers around `VBoxManage list` subcommands.
+
+use std::collections::HashMap;
+use std::process::Command;
+
+use crate::parse::parse_records;
+use crate::platform;
+use crate::utils;
+use crate::Error;
+
+
+/// A host network interface which virtual machines can bridge to.
+#[derive(Debug, Clone)]
+pub struct BridgedIf {
+  pub name: String,
+  pub guid: Option<String>,
+  pub dhcp: bool,
+  pub ip: Option<String>,
+  pub netmask: Option<String>,
+  pub mac: Option<String>,
+  pub status: Option<String>
+}
+
+/// A host-only network interface.
+#[derive(Debug, Clone)]
+pub struct HostOnlyIf {
+  pub name: String,
+  pub guid: Option<String>,
+  pub dhcp: bool,
+  pub ip: Option<String>,
+  pub netmask: Option<String>,
+  pub mac: Option<String>,
+  pub status: Option<String>,
+  pub network_name: Option<String>
+}
+
+/// A guest operating system type known to VirtualBox.
+#[derive(Debug, Clone)]
+pub struct OsType {
+  pub id: String,
+  pub description: String,
+  pub family_id: Option<String>,
+  pub family_desc: Option<String>,
+  pub is_64bit: bool
+}
+
+/// An installed extension pack.
+#[derive(Debug, Clone)]
+pub struct ExtPack {
+  pub name: String,
+  pub version: Option<String>,
+  pub revision: Option<String>,
+  pub usable: bool,
+
+  /// VirtualBox's explanation of why the pack isn't usable.
+  pub why_unusable: Option<String>,
+
+  /// Name of the remote display (VRDE) module the pack provides, if any.
+  pub vrde_module: Option<String>
+}
+
+/// A DHCP server managed by VirtualBox.
+#[derive(Debug, Clone)]
+pub struct DhcpServer {
+  pub network_name: String,
+  pub ip: Option<String>,
+  pub netmask: Option<String>,
+  pub lower_ip: Option<String>,
+  pub upper_ip: Option<String>,
+  pub enabled: bool
+}
+
+
+/// Run `VBoxManage list [opts] <what>` and parse its output into records.
+pub(crate) fn list_records(
+  what: &str,
+  opts: &[&str]
+) -> Result<Vec<HashMap<String, String>>, Error> {
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("list");
+  cmd.args(opts);
+  cmd.arg(what);
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  Ok(parse_records(&String::from_utf8_lossy(&stdout)))
+}
+
+
+fn opt(rec: &HashMap<String, String>, key: &str) -> Option<String> {
+  match rec.get(key) {
+    Some(v) if !v.is_empty() => Some(v.clone()),
+    _ => None
+  }
+}
+
+fn flag(rec: &HashMap<String, String>, key: &str) -> bool {
+  match rec.get(key) {
+    Some(v) => matches!(v.as_str(), "Enabled" | "Yes" | "yes" | "true"),
+    None => false
+  }
+}
+
+
+/// List the host interfaces virtual machines can bridge to.
+pub fn bridged_ifs() -> Result<Vec<BridgedIf>, Error> {
+  let recs = list_records("bridgedifs", &[])?;
+
+  Ok(
+    recs
+      .iter()
+      .filter_map(|rec| {
+        Some(BridgedIf {
+          name: opt(rec, "Name")?,
+          guid: opt(rec, "GUID"),
+          dhcp: flag(rec, "DHCP"),
+          ip: opt(rec, "IPAddress"),
+          netmask: opt(rec, "NetworkMask"),
+          mac: opt(rec, "HardwareAddress"),
+          status: opt(rec, "Status")
+        })
+      })
+      .collect()
+  )
+}
+
+
+/// List the host-only network interfaces.
+pub fn hostonly_ifs() -> Result<Vec<HostOnlyIf>, Error> {
+  let recs = list_records("hostonlyifs", &[])?;
+
+  Ok(
+    recs
+      .iter()
+      .filter_map(|rec| {
+        Some(HostOnlyIf {
+          name: opt(rec, "Name")?,
+          guid: opt(rec, "GUID"),
+          dhcp: flag(rec, "DHCP"),
+          ip: opt(rec, "IPAddress"),
+          netmask: opt(rec, "NetworkMask"),
+          mac: opt(rec, "HardwareAddress"),
+          status: opt(rec, "Status"),
+          network_name: opt(rec, "VBoxNetworkName")
+        })
+      })
+      .collect()
+  )
+}
+
+
+/// List the guest operating system types known to VirtualBox.
+pub fn ostypes() -> Result<Vec<OsType>, Error> {
+  let recs = list_records("ostypes", &[])?;
+
+  Ok(
+    recs
+      .iter()
+      .filter_map(|rec| {
+        Some(OsType {
+          id: opt(rec, "ID")?,
+          description: opt(rec, "Description").unwrap_or_default(),
+          family_id: opt(rec, "Family ID"),
+          family_desc: opt(rec, "Family Desc"),
+          is_64bit: flag(rec, "64 bit")
+        })
+      })
+      .collect()
+  )
+}
+
+
+/// List the installed extension packs.
+pub fn extpacks() -> Result<Vec<ExtPack>, Error> {
+  let recs = list_records("extpacks", &[])?;
+
+  Ok(extpacks_from_records(&recs))
+}
+
+
+pub(crate) fn extpacks_from_records(
+  recs: &[HashMap<String, String>]
+) -> Vec<ExtPack> {
+  // Each pack's name is stored under a "Pack no. <n>" key.  The first record
+  // additionally contains an "Extension Packs: <count>" header line.
+  recs
+    .iter()
+    .filter_map(|rec| {
+      let name = rec
+        .iter()
+        .find(|(k, _)| k.starts_with("Pack no."))
+        .map(|(_, v)| v.clone())?;
+      Some(ExtPack {
+        name,
+        version: opt(rec, "Version"),
+        revision: opt(rec, "Revision"),
+        usable: flag(rec, "Usable"),
+        why_unusable: opt(rec, "Why unusable"),
+        vrde_module: opt(rec, "VRDE Module")
+      })
+    })
+    .collect()
+}
+
+
+/// List the DHCP servers managed by VirtualBox.
+pub fn dhcp_servers() -> Result<Vec<DhcpServer>, Error> {
+  let recs = list_records("dhcpservers", &[])?;
+
+  Ok(
+    recs
+      .iter()
+      .filter_map(|rec| {
+        Some(DhcpServer {
+          network_name: opt(rec, "NetworkName")?,
+          ip: opt(rec, "Dhcpd IP").or_else(|| opt(rec, "IP")),
+          netmask: opt(rec, "NetworkMask"),
+          lower_ip: opt(rec, "LowerIPAddress"),
+          upper_ip: opt(rec, "UpperIPAddress"),
+          enabled: flag(rec, "Enabled")
+        })
+      })
+      .collect()
+  )
+}
+
+
+/// List the names of the host's optical drives, for example `/dev/sr0`.
+///
+/// See [`storage::attach_host_dvd()`](crate::storage::attach_host_dvd).
+pub fn host_dvds() -> Result<Vec<String>, Error> {
+  let recs = list_records("hostdvds", &[])?;
+
+  Ok(recs.iter().filter_map(|rec| opt(rec, "Name")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn bridged_ifs_from_output() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "Name:            eth0\n",
+      "GUID:            30687465-0000-4000-8000-080027123456\n",
+      "DHCP:            Disabled\n",
+      "IPAddress:       192.168.1.10\n",
+      "NetworkMask:     255.255.255.0\n",
+      "HardwareAddress: 08:00:27:12:34:56\n",
+      "Status:          Up\n",
+      "\n",
+      "Name:            wlan0\n",
+      "DHCP:            Enabled\n",
+      "IPAddress:       \n",
+      "Status:          Down\n",
+      "\n"
+    ));
+
+    let ifs = bridged_ifs().unwrap();
+    assert_eq!(ifs.len(), 2);
+    assert_eq!(ifs[0].name, "eth0");
+    assert!(!ifs[0].dhcp);
+    assert_eq!(ifs[0].ip.as_deref(), Some("192.168.1.10"));
+    assert_eq!(ifs[0].mac.as_deref(), Some("08:00:27:12:34:56"));
+    assert_eq!(ifs[1].name, "wlan0");
+    assert!(ifs[1].dhcp);
+    assert_eq!(ifs[1].ip, None);
+    assert_eq!(ifs[1].status.as_deref(), Some("Down"));
+    assert_eq!(runner.calls(), [["list", "bridgedifs"]]);
+  }
+
+  #[test]
+  fn ostypes_from_output() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "ID:          Other\n",
+      "Description: Other/Unknown\n",
+      "Family ID:   Other\n",
+      "Family Desc: Other\n",
+      "64 bit:      false\n",
+      "\n",
+      "ID:          Ubuntu_64\n",
+      "Description: Ubuntu (64-bit)\n",
+      "Family ID:   Linux\n",
+      "Family Desc: Linux\n",
+      "64 bit:      true\n"
+    ));
+
+    let types = ostypes().unwrap();
+    assert_eq!(types.len(), 2);
+    assert_eq!(types[0].id, "Other");
+    assert!(!types[0].is_64bit);
+    assert_eq!(types[1].id, "Ubuntu_64");
+    assert_eq!(types[1].description, "Ubuntu (64-bit)");
+    assert_eq!(types[1].family_id.as_deref(), Some("Linux"));
+    assert!(types[1].is_64bit);
+  }
+
+  #[test]
+  fn extpacks_keyed_by_pack_number() {
+    let out = concat!(
+      "Extension Packs: 2\n",
+      "Pack no. 0:   Oracle VM VirtualBox Extension Pack\n",
+      "Version:      7.0.10\n",
+      "Revision:     158379\n",
+      "Edition:\n",
+      "Description:  Oracle Cloud Infrastructure integration, USB 2.0 ...\n",
+      "VRDE Module:  VBoxVRDP\n",
+      "Usable:       true\n",
+      "Why unusable:\n",
+      "\n",
+      "Pack no. 1:   Old Pack\n",
+      "Version:      6.1.38\n",
+      "Usable:       false\n",
+      "Why unusable: The version does not match\n"
+    );
+    let packs = extpacks_from_records(&parse_records(out));
+
+    assert_eq!(packs.len(), 2);
+    assert_eq!(packs[0].name, "Oracle VM VirtualBox Extension Pack");
+    assert_eq!(packs[0].version.as_deref(), Some("7.0.10"));
+    assert_eq!(packs[0].revision.as_deref(), Some("158379"));
+    assert_eq!(packs[0].vrde_module.as_deref(), Some("VBoxVRDP"));
+    assert!(packs[0].usable);
+    assert_eq!(packs[0].why_unusable, None);
+    assert_eq!(packs[1].name, "Old Pack");
+    assert!(!packs[1].usable);
+    assert_eq!(
+      packs[1].why_unusable.as_deref(),
+      Some("The version does not match")
+    );
+
+    let none = parse_records("Extension Packs: 0\n");
+    assert!(extpacks_from_records(&none).is_empty());
+  }
+
+  #[test]
+  fn dhcp_servers_from_output() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "NetworkName:    HostInterfaceNetworking-vboxnet0\n",
+      "Dhcpd IP:       192.168.56.100\n",
+      "LowerIPAddress: 192.168.56.101\n",
+      "UpperIPAddress: 192.168.56.254\n",
+      "NetworkMask:    255.255.255.0\n",
+      "Enabled:        Yes\n",
+      "\n",
+      "NetworkName:    lab\n",
+      "IP:             10.0.0.1\n",
+      "Enabled:        No\n"
+    ));
+
+    let servers = dhcp_servers().unwrap();
+    assert_eq!(servers.len(), 2);
+    assert_eq!(servers[0].network_name, "HostInterfaceNetworking-vboxnet0");
+    assert_eq!(servers[0].ip.as_deref(), Some("192.168.56.100"));
+    assert_eq!(servers[0].lower_ip.as_deref(), Some("192.168.56.101"));
+    assert!(servers[0].enabled);
+    assert_eq!(servers[1].ip.as_deref(), Some("10.0.0.1"));
+    assert!(!servers[1].enabled);
+  }
+
+  #[test]
+  fn host_dvds_from_output() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "UUID:         31000000-0000-0000-0000-000000000000\n",
+      "Name:         /dev/sr0\n",
+      "\n",
+      "UUID:         32000000-0000-0000-0000-000000000000\n",
+      "Name:         /dev/sr1\n",
+      "\n"
+    ));
+
+    assert_eq!(host_dvds().unwrap(), ["/dev/sr0", "/dev/sr1"]);
+    assert_eq!(runner.calls(), [["list", "hostdvds"]]);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
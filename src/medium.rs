@@ -0,0 +1,621 @@
+//! Query and manage virtual media (disk images, DVD and floppy images).
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::parse::parse_records;
+use crate::platform;
+use crate::utils;
+use crate::Error;
+
+
+/// The kind of a virtual medium.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediumKind {
+  Disk,
+  Dvd,
+  Floppy
+}
+
+impl MediumKind {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      MediumKind::Disk => "disk",
+      MediumKind::Dvd => "dvd",
+      MediumKind::Floppy => "floppy"
+    }
+  }
+}
+
+
+/// How a disk image behaves when attached to virtual machines and when
+/// snapshots are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediumType {
+  /// Regular read/write image which is included in snapshots.  Can only be
+  /// attached to one virtual machine at a time.
+  Normal,
+
+  /// Read-only base image.  Writes go to a differencing image which is
+  /// discarded whenever the virtual machine is powered off (or a snapshot is
+  /// restored).
+  Immutable,
+
+  /// Read/write image which is excluded from snapshots.
+  Writethrough,
+
+  /// Read/write image which may be attached to several virtual machines at
+  /// once.  Only supported for fixed size images.
+  Shareable,
+
+  /// Read-only base image which may be attached to several virtual machines
+  /// at once; each virtual machine writes to a differencing image of its
+  /// own.
+  MultiAttach,
+
+  /// Read-only image, mostly used for DVD and floppy images.
+  Readonly
+}
+
+impl MediumType {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      MediumType::Normal => "normal",
+      MediumType::Immutable => "immutable",
+      MediumType::Writethrough => "writethrough",
+      MediumType::Shareable => "shareable",
+      MediumType::MultiAttach => "multiattach",
+      MediumType::Readonly => "readonly"
+    }
+  }
+
+  /// Parse the `Type` field reported by `showmediuminfo`, such as
+  /// `normal (base)`.
+  fn from_info(s: &str) -> Option<Self> {
+    let t = s.split_whitespace().next()?;
+    Some(match t {
+      "normal" => MediumType::Normal,
+      "immutable" => MediumType::Immutable,
+      "writethrough" => MediumType::Writethrough,
+      "shareable" => MediumType::Shareable,
+      "multiattach" => MediumType::MultiAttach,
+      "readonly" => MediumType::Readonly,
+      _ => return None
+    })
+  }
+}
+
+
+/// Information about a virtual medium.
+#[derive(Debug, Clone)]
+pub struct MediumInfo {
+  pub uuid: uuid::Uuid,
+  pub parent: Option<uuid::Uuid>,
+
+  /// Medium state, for example `created` or `inaccessible`.
+  pub state: Option<String>,
+
+  /// Medium type, for example `normal (base)` or `immutable`.
+  pub mtype: Option<String>,
+
+  pub location: String,
+
+  /// Storage format, for example `VDI` or `VMDK`.
+  pub format: Option<String>,
+
+  /// Logical size of the medium, in bytes.
+  pub capacity: Option<u64>,
+
+  /// Actual size of the medium on the host's disk, in bytes.
+  pub size_on_disk: Option<u64>,
+
+  /// `true` if the medium is encrypted.  VirtualBox versions which don't
+  /// report encryption status are treated as not encrypted.
+  pub encrypted: bool,
+
+  /// Cipher used to encrypt the medium, for example `AES-XTS256-PLAIN64`, if
+  /// reported.
+  pub cipher: Option<String>,
+
+  /// `true` if the medium is attached to at least one virtual machine
+  /// (possibly only in a snapshot).
+  pub in_use: bool
+}
+
+impl MediumInfo {
+  pub(crate) fn from_record(
+    rec: &HashMap<String, String>
+  ) -> Result<MediumInfo, Error> {
+    let parse_uuid = |key: &str| match rec.get(key) {
+      Some(u) if u == "base" || u.is_empty() => Ok(None),
+      Some(u) => match uuid::Uuid::parse_str(u) {
+        Ok(u) => Ok(Some(u)),
+        Err(_) => {
+          let s = format!("Unable to parse medium {} '{}'", key, u);
+          Err(Error::BadFormat(s))
+        }
+      },
+      None => Ok(None)
+    };
+
+    let uuid = match parse_uuid("UUID")? {
+      Some(u) => u,
+      None => {
+        return Err(Error::MissingData("Medium has no UUID".to_string()));
+      }
+    };
+
+    let location = match rec.get("Location") {
+      Some(loc) => loc.clone(),
+      None => {
+        let s = format!("Medium {} has no location", uuid);
+        return Err(Error::MissingData(s));
+      }
+    };
+
+    let opt = |key: &str| rec.get(key).filter(|v| !v.is_empty()).cloned();
+
+    Ok(MediumInfo {
+      uuid,
+      parent: parse_uuid("Parent UUID")?,
+      state: opt("State"),
+      mtype: opt("Type"),
+      location,
+      format: opt("Storage format"),
+      capacity: rec.get("Capacity").and_then(|v| parse_size(v)),
+      size_on_disk: rec.get("Size on disk").and_then(|v| parse_size(v)),
+      encrypted: rec.get("Encryption").is_some_and(|v| v == "enabled"),
+      cipher: opt("Cipher"),
+      // "In use by VMs: <vm> (UUID: <uuid>) [<snapshot> (UUID: <uuid>)]" is
+      // only present for media which are attached somewhere.
+      in_use: rec.get("In use by VMs").is_some_and(|v| !v.is_empty())
+    })
+  }
+
+  /// Get the medium's type, if it is reported and known.
+  pub fn medium_type(&self) -> Option<MediumType> {
+    self.mtype.as_deref().and_then(MediumType::from_info)
+  }
+}
+
+
+/// Parse sizes such as `20480 MBytes` into a number of bytes.
+fn parse_size(s: &str) -> Option<u64> {
+  let mut it = s.split_whitespace();
+  let num = it.next()?.parse::<f64>().ok()?;
+  let mult: u64 = match it.next().unwrap_or("Bytes") {
+    "Bytes" | "bytes" => 1,
+    "KBytes" => 1 << 10,
+    "MBytes" => 1 << 20,
+    "GBytes" => 1 << 30,
+    "TBytes" => 1 << 40,
+    _ => return None
+  };
+  Some((num * mult as f64) as u64)
+}
+
+
+/// Get information about a medium, identified by uuid or file name.
+pub fn info<T>(kind: MediumKind, target: T) -> Result<MediumInfo, Error>
+where
+  T: AsRef<str>
+{
+  // VBoxManage showmediuminfo disk|dvd|floppy <target>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("showmediuminfo");
+  cmd.arg(kind.as_arg());
+  cmd.arg(target.as_ref());
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  let recs = parse_records(&String::from_utf8_lossy(&stdout));
+  match recs.first() {
+    Some(rec) => MediumInfo::from_record(rec),
+    None => {
+      let s = format!("No information for medium '{}'", target.as_ref());
+      Err(Error::MissingData(s))
+    }
+  }
+}
+
+
+/// List all media of a kind which are registered with VirtualBox.
+///
+/// This includes media which aren't attached to any virtual machine.
+pub fn list(kind: MediumKind) -> Result<Vec<MediumInfo>, Error> {
+  // VBoxManage list --long hdds|dvds|floppies
+  //
+  // The long form is needed for the "In use by VMs" field.
+
+  let what = match kind {
+    MediumKind::Disk => "hdds",
+    MediumKind::Dvd => "dvds",
+    MediumKind::Floppy => "floppies"
+  };
+
+  crate::list::list_records(what, &["--long"])?
+    .iter()
+    .map(MediumInfo::from_record)
+    .collect()
+}
+
+
+/// List the disk images which are registered with VirtualBox, but aren't
+/// attached to any virtual machine (not even in a snapshot).
+///
+/// These are candidates for cleanup; note that a disk image may be used
+/// outside of VirtualBox's knowledge, for instance by virtual machines which
+/// are registered by another user.
+pub fn list_orphans() -> Result<Vec<MediumInfo>, Error> {
+  Ok(
+    list(MediumKind::Disk)?
+      .into_iter()
+      .filter(|m| !m.in_use)
+      .collect()
+  )
+}
+
+
+/// Change the type of a disk image, identified by uuid or file name.
+///
+/// The disk must not be attached to any virtual machine.
+pub fn set_type<T>(target: T, mtype: MediumType) -> Result<(), Error>
+where
+  T: AsRef<str>
+{
+  // VBoxManage modifymedium disk <target> --type <type>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifymedium");
+  cmd.arg("disk");
+  cmd.arg(target.as_ref());
+  cmd.arg("--type");
+  cmd.arg(mtype.as_arg());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Build a `VBoxManage mediumproperty disk <action> <target> <key>` command.
+fn mediumproperty(action: &str, target: &str, key: &str) -> Command {
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("mediumproperty");
+  cmd.arg("disk");
+  cmd.arg(action);
+  cmd.arg(target);
+  cmd.arg(key);
+  cmd
+}
+
+
+/// Get a property of a disk image, identified by uuid or file name.
+///
+/// Which properties exist depends on the image format; iSCSI targets, for
+/// instance, have `TargetAddress` and `TargetName`.  Returns `Ok(None)` if
+/// the property doesn't exist.
+pub fn get_property<T, K>(target: T, key: K) -> Result<Option<String>, Error>
+where
+  T: AsRef<str>,
+  K: AsRef<str>
+{
+  // VBoxManage mediumproperty disk get <target> <key>
+
+  let cmd = mediumproperty("get", target.as_ref(), key.as_ref());
+
+  // VBoxManage: error: Property 'foo' does not exist
+  let (stdout, _) = utils::exec_accepting(cmd, |out| {
+    String::from_utf8_lossy(&out.stderr).lines().any(|line| {
+      line.contains("Property '") && line.contains("does not exist")
+    })
+  })?;
+
+  Ok(parse_property(&String::from_utf8_lossy(&stdout), key.as_ref()))
+}
+
+
+/// Set a property of a disk image, identified by uuid or file name.
+pub fn set_property<T, K, V>(target: T, key: K, value: V) -> Result<(), Error>
+where
+  T: AsRef<str>,
+  K: AsRef<str>,
+  V: AsRef<str>
+{
+  // VBoxManage mediumproperty disk set <target> <key> <value>
+
+  let mut cmd = mediumproperty("set", target.as_ref(), key.as_ref());
+  cmd.arg(value.as_ref());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Remove a property from a disk image, identified by uuid or file name.
+pub fn delete_property<T, K>(target: T, key: K) -> Result<(), Error>
+where
+  T: AsRef<str>,
+  K: AsRef<str>
+{
+  // VBoxManage mediumproperty disk delete <target> <key>
+
+  utils::exec(mediumproperty("delete", target.as_ref(), key.as_ref()))?;
+
+  Ok(())
+}
+
+
+/// Find the `<key>=<value>` line printed by `mediumproperty get`.  Nothing
+/// is printed for properties which don't exist; the command fails instead.
+fn parse_property(output: &str, key: &str) -> Option<String> {
+  output.lines().find_map(|line| match line.trim_end().split_once('=') {
+    Some((k, v)) if k == key => Some(v.to_string()),
+    _ => None
+  })
+}
+
+
+/// Give a disk image file a new, randomly generated, uuid and return it.
+///
+/// VirtualBox refuses to register two media with the same uuid, so disk
+/// images which were copied (rather than cloned) need a fresh uuid before
+/// the copy can be attached to a virtual machine alongside the original.
+/// The image must not be registered while its uuid is changed.
+///
+/// This uses `VBoxManage internalcommands`, which is an unsupported
+/// interface intended for VirtualBox developers; its output format may
+/// change without notice.
+pub fn set_uuid<P>(path: P) -> Result<uuid::Uuid, Error>
+where
+  P: AsRef<Path>
+{
+  // VBoxManage internalcommands sethduuid <path>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("internalcommands");
+  cmd.arg("sethduuid");
+  cmd.arg(path.as_ref());
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  parse_sethduuid(&String::from_utf8_lossy(&stdout))
+}
+
+
+/// Parse the `UUID changed to: <uuid>` line printed by `sethduuid`.
+fn parse_sethduuid(output: &str) -> Result<uuid::Uuid, Error> {
+  for line in output.lines() {
+    if let Some(u) = line.trim().strip_prefix("UUID changed to:") {
+      return match uuid::Uuid::parse_str(u.trim()) {
+        Ok(u) => Ok(u),
+        Err(_) => {
+          let s = format!("Unable to parse new medium UUID '{}'", u.trim());
+          Err(Error::BadFormat(s))
+        }
+      };
+    }
+  }
+
+  Err(Error::MissingData("sethduuid did not report a new UUID".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn parse_property_finds_key() {
+    let out = "TargetAddress=10.0.0.1\nTargetName=iqn.2024-01.lab:disk\n";
+    assert_eq!(
+      parse_property(out, "TargetName").as_deref(),
+      Some("iqn.2024-01.lab:disk")
+    );
+    assert_eq!(parse_property("Empty=\n", "Empty").as_deref(), Some(""));
+    assert_eq!(parse_property(out, "Target"), None);
+    assert_eq!(parse_property("", "TargetName"), None);
+  }
+
+  #[test]
+  fn property_args() {
+    let runner = Runner::new();
+    runner.ok("AllocationBlockSize=1048576\n");
+    runner.fail(concat!(
+      "VBoxManage: error: Property 'Nope' does not exist\n",
+      "VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND ",
+      "(0x80bb0001)\n"
+    ));
+    runner.fail("VBoxManage: error: Could not find file for the medium\n");
+
+    assert_eq!(
+      get_property("disk.vdi", "AllocationBlockSize").unwrap().as_deref(),
+      Some("1048576")
+    );
+    assert_eq!(get_property("disk.vdi", "Nope").unwrap(), None);
+    assert!(matches!(
+      get_property("gone.vdi", "Nope"),
+      Err(Error::CommandFailed(_, _))
+    ));
+    set_property("disk.vdi", "Key", "some value").unwrap();
+    delete_property("disk.vdi", "Key").unwrap();
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "mediumproperty disk get disk.vdi AllocationBlockSize",
+        "mediumproperty disk get disk.vdi Nope",
+        "mediumproperty disk get gone.vdi Nope",
+        "mediumproperty disk set disk.vdi Key some value",
+        "mediumproperty disk delete disk.vdi Key"
+      ]
+    );
+  }
+
+  #[test]
+  fn set_type_args() {
+    let runner = Runner::new();
+    set_type("base.vdi", MediumType::MultiAttach).unwrap();
+    set_type("data.vdi", MediumType::Writethrough).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifymedium", "disk", "base.vdi", "--type", "multiattach"],
+        ["modifymedium", "disk", "data.vdi", "--type", "writethrough"]
+      ]
+    );
+  }
+
+  #[test]
+  fn medium_type_from_info() {
+    let runner = Runner::new();
+    let out = |mtype: &str| {
+      format!(
+        concat!(
+          "UUID:           aaaaaaaa-0000-0000-0000-000000000000\n",
+          "Parent UUID:    base\n",
+          "Location:       /vms/base.vdi\n",
+          "Type:           {}\n"
+        ),
+        mtype
+      )
+    };
+    runner.ok(&out("multiattach"));
+    runner.ok(&out("normal (base)"));
+    runner.ok(&out("something new"));
+
+    let types: Vec<_> = (0..3)
+      .map(|_| info(MediumKind::Disk, "base.vdi").unwrap().medium_type())
+      .collect();
+    assert_eq!(
+      types,
+      [Some(MediumType::MultiAttach), Some(MediumType::Normal), None]
+    );
+  }
+
+  #[test]
+  fn encrypted_medium_info() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "UUID:           aaaaaaaa-0000-0000-0000-000000000000\n",
+      "Parent UUID:    base\n",
+      "State:          created\n",
+      "Type:           normal (base)\n",
+      "Location:       /vms/secret.vdi\n",
+      "Storage format: VDI\n",
+      "Capacity:       20480 MBytes\n",
+      "Size on disk:   2 GBytes\n",
+      "Encryption:     enabled\n",
+      "Cipher:         AES-XTS256-PLAIN64\n"
+    ));
+    runner.ok(concat!(
+      "UUID:           bbbbbbbb-0000-0000-0000-000000000000\n",
+      "Location:       /vms/plain.vdi\n"
+    ));
+
+    let info1 = info(MediumKind::Disk, "secret.vdi").unwrap();
+    assert!(info1.encrypted);
+    assert_eq!(info1.cipher.as_deref(), Some("AES-XTS256-PLAIN64"));
+    assert_eq!(info1.capacity, Some(20480 << 20));
+    assert_eq!(info1.size_on_disk, Some(2 << 30));
+
+    let info2 = info(MediumKind::Disk, "plain.vdi").unwrap();
+    assert!(!info2.encrypted);
+    assert_eq!(info2.cipher, None);
+  }
+
+  #[test]
+  fn set_uuid_parses_new_uuid() {
+    let runner = Runner::new();
+    runner.ok("UUID changed to: 5c5b6a2e-7f3d-4c1e-9b0a-2d6f8e4a1c3b\n");
+    runner.ok("UUID changed to: not-a-uuid\n");
+    runner.ok("");
+
+    let u = set_uuid("/vms/copy.vdi").unwrap();
+    assert_eq!(u.to_string(), "5c5b6a2e-7f3d-4c1e-9b0a-2d6f8e4a1c3b");
+    assert!(matches!(set_uuid("/vms/copy.vdi"), Err(Error::BadFormat(_))));
+    assert!(matches!(set_uuid("/vms/copy.vdi"), Err(Error::MissingData(_))));
+    assert_eq!(
+      runner.calls()[0],
+      ["internalcommands", "sethduuid", "/vms/copy.vdi"]
+    );
+  }
+
+  const LIST_HDDS: &str = concat!(
+    "UUID:           3f2504e0-4f89-11d3-9a0c-0305e82c3301\n",
+    "Parent UUID:    base\n",
+    "State:          created\n",
+    "Type:           normal (base)\n",
+    "Location:       /vms/web/web.vdi\n",
+    "Storage format: VDI\n",
+    "Capacity:       20480 MBytes\n",
+    "Size on disk:   2 GBytes\n",
+    "Encryption:     disabled\n",
+    "In use by VMs:  web (UUID: 11111111-1111-1111-1111-111111111111)\n",
+    "\n",
+    "UUID:           5c5b6a2e-7f3d-4c1e-9b0a-2d6f8e4a1c3b\n",
+    "Parent UUID:    3f2504e0-4f89-11d3-9a0c-0305e82c3301\n",
+    "State:          inaccessible\n",
+    "Type:           normal (differencing)\n",
+    "Location:       /vms/web/Snapshots/{5c5b6a2e}.vdi\n",
+    "Storage format: VDI\n",
+    "Capacity:       20480 MBytes\n",
+    "Size on disk:   512 KBytes\n",
+    "Encryption:     disabled\n",
+    "\n"
+  );
+
+  #[test]
+  fn list_parses_captured_hdds() {
+    let runner = Runner::new();
+    runner.ok(LIST_HDDS);
+    runner.ok("");
+    runner.ok("");
+
+    let disks = list(MediumKind::Disk).unwrap();
+    assert_eq!(disks.len(), 2);
+    assert_eq!(
+      disks[0].uuid.to_string(),
+      "3f2504e0-4f89-11d3-9a0c-0305e82c3301"
+    );
+    assert_eq!(disks[0].parent, None);
+    assert_eq!(disks[0].location, "/vms/web/web.vdi");
+    assert_eq!(disks[0].format.as_deref(), Some("VDI"));
+    assert_eq!(disks[0].capacity, Some(20480 << 20));
+    assert_eq!(disks[0].size_on_disk, Some(2 << 30));
+    assert_eq!(disks[1].parent, Some(disks[0].uuid));
+    assert_eq!(disks[1].state.as_deref(), Some("inaccessible"));
+    assert_eq!(disks[1].size_on_disk, Some(512 << 10));
+
+    assert!(list(MediumKind::Dvd).unwrap().is_empty());
+    assert!(list(MediumKind::Floppy).unwrap().is_empty());
+    assert_eq!(
+      runner.calls(),
+      [
+        ["list", "--long", "hdds"],
+        ["list", "--long", "dvds"],
+        ["list", "--long", "floppies"]
+      ]
+    );
+  }
+
+  #[test]
+  fn list_orphans_skips_attached_disks() {
+    let runner = Runner::new();
+    runner.ok(LIST_HDDS);
+
+    let orphans = list_orphans().unwrap();
+    assert_eq!(orphans.len(), 1);
+    assert_eq!(orphans[0].location, "/vms/web/Snapshots/{5c5b6a2e}.vdi");
+    assert!(!orphans[0].in_use);
+    assert_eq!(runner.calls(), [["list", "--long", "hdds"]]);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
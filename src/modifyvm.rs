@@ -0,0 +1,1436 @@
+//! Change the configuration of a virtual machine.
+//!
+//! Unless otherwise noted the virtual machine must be powered off for
+//! configuration changes to be allowed.
+
+use std::borrow::Borrow;
+use std::ffi::OsStr;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+
+use crate::platform;
+use crate::strutils::onoff;
+use crate::utils;
+use crate::{version, Error, Version, VmId};
+
+
+/// Run `VBoxManage modifyvm <vid> <args>`.
+fn modifyvm<A>(vid: &VmId, args: &[A]) -> Result<(), Error>
+where
+  A: AsRef<OsStr>
+{
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.to_string());
+  cmd.args(args);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// `modifyvm` options which have been renamed between VirtualBox versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opt {
+  MonitorCount,
+  BiosBootMenu,
+  BiosLogoFadeIn,
+  BiosLogoFadeOut,
+  ClipboardMode,
+  ClipboardFileTransfers,
+  AudioController,
+  AudioDriver,
+  AudioIn,
+  AudioOut,
+  RecordingFile,
+  VrdeAddress,
+  ParavirtProvider,
+  ParavirtDebug,
+  UsbOhci,
+  UsbEhci,
+  UsbXhci,
+  RtcUseUtc,
+  NestedHwVirt
+}
+
+impl Opt {
+  /// Get the version which introduced the option's current spelling, the
+  /// current spelling and the spelling used by earlier versions.
+  fn spellings(&self) -> ((u32, u32), &'static str, &'static str) {
+    match self {
+      Opt::MonitorCount => ((7, 0), "--monitor-count", "--monitorcount"),
+      Opt::BiosBootMenu => ((7, 0), "--bios-boot-menu", "--biosbootmenu"),
+      Opt::BiosLogoFadeIn => {
+        ((7, 0), "--bios-logo-fade-in", "--bioslogofadein")
+      }
+      Opt::BiosLogoFadeOut => {
+        ((7, 0), "--bios-logo-fade-out", "--bioslogofadeout")
+      }
+      Opt::ClipboardMode => ((6, 1), "--clipboard-mode", "--clipboard"),
+      Opt::ClipboardFileTransfers => {
+        ((7, 0), "--clipboard-file-transfers", "--clipboardfiletransfers")
+      }
+      Opt::AudioController => {
+        ((7, 0), "--audio-controller", "--audiocontroller")
+      }
+      Opt::AudioDriver => ((7, 0), "--audio-driver", "--audio"),
+      Opt::AudioIn => ((7, 0), "--audio-in", "--audioin"),
+      Opt::AudioOut => ((7, 0), "--audio-out", "--audioout"),
+      Opt::RecordingFile => ((7, 0), "--recording-file", "--recordingfile"),
+      Opt::VrdeAddress => ((7, 0), "--vrde-address", "--vrdeaddress"),
+      Opt::ParavirtProvider => {
+        ((7, 0), "--paravirt-provider", "--paravirtprovider")
+      }
+      Opt::ParavirtDebug => ((7, 0), "--paravirt-debug", "--paravirtdebug"),
+      Opt::UsbOhci => ((7, 0), "--usb-ohci", "--usbohci"),
+      Opt::UsbEhci => ((7, 0), "--usb-ehci", "--usbehci"),
+      Opt::UsbXhci => ((7, 0), "--usb-xhci", "--usbxhci"),
+      Opt::RtcUseUtc => ((7, 0), "--rtc-use-utc", "--rtcuseutc"),
+      // Has had a single spelling since it was introduced in 6.0; earlier
+      // versions reject it (see set_nested_hw_virt()).
+      Opt::NestedHwVirt => ((6, 0), "--nested-hw-virt", "--nested-hw-virt")
+    }
+  }
+
+  /// Get the option's spelling for VirtualBox version `ver`.
+  fn arg(&self, ver: &Version) -> &'static str {
+    let ((major, minor), current, earlier) = self.spellings();
+    if ver.at_least(major, minor) {
+      current
+    } else {
+      earlier
+    }
+  }
+}
+
+
+/// Get the spelling of `opt` which the installed VirtualBox understands.
+fn flag(opt: Opt) -> Result<&'static str, Error> {
+  Ok(opt.arg(&version()?))
+}
+
+
+/// USB controller types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbControllerType {
+  /// USB 1.1 controller.
+  Ohci,
+
+  /// USB 2.0 controller.  Requires the VirtualBox Extension Pack.
+  Ehci,
+
+  /// USB 3.0 controller.  Requires the VirtualBox Extension Pack.
+  Xhci
+}
+
+
+/// Enable or disable a USB controller.
+pub fn set_usb_controller<V>(
+  vid: V,
+  ctrl: UsbControllerType,
+  enabled: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --usb-ohci|--usb-ehci|--usb-xhci on|off
+
+  let opt = match ctrl {
+    UsbControllerType::Ohci => Opt::UsbOhci,
+    UsbControllerType::Ehci => Opt::UsbEhci,
+    UsbControllerType::Xhci => Opt::UsbXhci
+  };
+
+  modifyvm(vid.borrow(), &[flag(opt)?, onoff(enabled)])
+}
+
+
+/// Set the groups a virtual machine belongs to.
+///
+/// Each group is a `/`-separated path starting with `/`, for example
+/// `/cluster/web`.  Passing an empty slice removes the virtual machine from
+/// all groups.  Returns `Err(Error::InvalidArgument)` for malformed group
+/// paths.
+pub fn set_groups<V>(vid: V, groups: &[&str]) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  for group in groups {
+    if !group.starts_with('/') || group.contains(',') {
+      let s = format!("Invalid group path '{}'", group);
+      return Err(Error::InvalidArgument(s));
+    }
+  }
+
+  // VBoxManage modifyvm $VM --groups /a,/a/b
+
+  modifyvm(vid.borrow(), &["--groups", &groups.join(",")])
+}
+
+
+/// Set the address the remote display (VRDE) server listens on, for
+/// example `127.0.0.1` to only allow connections from the host itself.
+///
+/// An empty address means all interfaces.  Returns
+/// `Err(Error::InvalidArgument)` if `addr` isn't an IP address.
+pub fn set_vrde_address<V>(vid: V, addr: &str) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if !addr.is_empty() && addr.parse::<IpAddr>().is_err() {
+    let s = format!("'{}' is not an IP address", addr);
+    return Err(Error::InvalidArgument(s));
+  }
+
+  // VBoxManage modifyvm $VM --vrde-address <ip>
+
+  modifyvm(vid.borrow(), &[flag(Opt::VrdeAddress)?, addr])
+}
+
+
+/// Set the amount of memory, in MiB, assigned to the virtual machine.
+pub fn set_memory<V>(vid: V, mb: u32) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --memory <mb>
+
+  modifyvm(vid.borrow(), &["--memory", &mb.to_string()])
+}
+
+
+/// Set the number of virtual CPUs.
+pub fn set_cpus<V>(vid: V, n: u32) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --cpus <n>
+
+  modifyvm(vid.borrow(), &["--cpus", &n.to_string()])
+}
+
+
+/// Enable or disable the virtual machine's I/O APIC.
+///
+/// An I/O APIC is required for virtual machines with more than one virtual
+/// CPU; VirtualBox refuses to start such a virtual machine if the I/O APIC is
+/// disabled.  Disabling it therefore returns `Err(Error::InvalidArgument)` if
+/// the virtual machine has more than one CPU.  Note that switching it after a
+/// Windows guest has been installed may leave the guest unable to boot.
+pub fn set_ioapic<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if !enabled {
+    let map = crate::get_vm_info_map(vid.borrow())?;
+    let cpus = map.get("cpus").and_then(|n| n.parse::<u32>().ok());
+    if let Some(n) = cpus.filter(|n| *n > 1) {
+      let s = format!(
+        "The I/O APIC can't be disabled for a virtual machine with {} CPUs",
+        n
+      );
+      return Err(Error::InvalidArgument(s));
+    }
+  }
+
+  // VBoxManage modifyvm $VM --ioapic on|off
+
+  modifyvm(vid.borrow(), &["--ioapic", onoff(enabled)])
+}
+
+
+/// Enable or disable x2APIC mode for the virtual machine's local APICs.
+pub fn set_x2apic<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --x2apic on|off
+
+  modifyvm(vid.borrow(), &["--x2apic", onoff(enabled)])
+}
+
+
+/// Enable or disable the virtual machine's High Precision Event Timer.
+///
+/// Some guests need an HPET for accurate timekeeping, others misbehave when
+/// one is present.
+pub fn set_hpet<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --hpet on|off
+
+  modifyvm(vid.borrow(), &["--hpet", onoff(enabled)])
+}
+
+
+/// Select whether the virtual machine's real-time clock runs in UTC (rather
+/// than in the host's local time).
+///
+/// Guests which assume a UTC hardware clock, such as most Unix-like
+/// operating systems, should have this enabled.
+pub fn set_rtc_use_utc<V>(vid: V, utc: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --rtc-use-utc on|off
+
+  modifyvm(vid.borrow(), &[flag(Opt::RtcUseUtc)?, onoff(utc)])
+}
+
+
+/// Virtual machine firmware types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+  Bios,
+
+  /// EFI, using the 64-bit or 32-bit variant depending on the guest
+  /// operating system type.
+  Efi,
+  Efi32,
+  Efi64
+}
+
+impl Firmware {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      Firmware::Bios => "bios",
+      Firmware::Efi => "efi",
+      Firmware::Efi32 => "efi32",
+      Firmware::Efi64 => "efi64"
+    }
+  }
+
+  /// Parse the `firmware` field reported by `showvminfo`, such as `BIOS` or
+  /// `EFI64`.
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "bios" => Some(Firmware::Bios),
+      "efi" => Some(Firmware::Efi),
+      "efi32" => Some(Firmware::Efi32),
+      "efi64" => Some(Firmware::Efi64),
+      _ => None
+    }
+  }
+}
+
+
+/// Select the virtual machine's firmware.  The virtual machine must be
+/// powered off.
+pub fn set_firmware<V>(vid: V, firmware: Firmware) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --firmware bios|efi|efi32|efi64
+
+  modifyvm(vid.borrow(), &["--firmware", firmware.as_arg()])
+}
+
+
+/// Emulated chipsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chipset {
+  /// Intel PIIX3; the default, and the most compatible with older guests.
+  Piix3,
+
+  /// Intel ICH9; supports more PCI slots and NICs, and PCIe.
+  Ich9
+}
+
+impl Chipset {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      Chipset::Piix3 => "piix3",
+      Chipset::Ich9 => "ich9"
+    }
+  }
+
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "piix3" => Some(Chipset::Piix3),
+      "ich9" => Some(Chipset::Ich9),
+      _ => None
+    }
+  }
+}
+
+
+/// Select the virtual machine's chipset.  The virtual machine must be
+/// powered off.
+pub fn set_chipset<V>(vid: V, chipset: Chipset) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --chipset piix3|ich9
+
+  modifyvm(vid.borrow(), &["--chipset", chipset.as_arg()])
+}
+
+
+/// Paravirtualization interfaces presented to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParavirtProvider {
+  None,
+
+  /// Let VirtualBox pick an interface based on the guest operating system
+  /// type.
+  Default,
+  Legacy,
+  Minimal,
+
+  /// Microsoft Hyper-V enlightenments; recommended for Windows guests.
+  HyperV,
+  Kvm
+}
+
+impl ParavirtProvider {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      ParavirtProvider::None => "none",
+      ParavirtProvider::Default => "default",
+      ParavirtProvider::Legacy => "legacy",
+      ParavirtProvider::Minimal => "minimal",
+      ParavirtProvider::HyperV => "hyperv",
+      ParavirtProvider::Kvm => "kvm"
+    }
+  }
+
+  /// Parse the `paravirtprovider` field reported by `showvminfo`, such as
+  /// `Default` or `HyperV`.
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "none" => Some(ParavirtProvider::None),
+      "default" => Some(ParavirtProvider::Default),
+      "legacy" => Some(ParavirtProvider::Legacy),
+      "minimal" => Some(ParavirtProvider::Minimal),
+      "hyperv" => Some(ParavirtProvider::HyperV),
+      "kvm" => Some(ParavirtProvider::Kvm),
+      _ => None
+    }
+  }
+}
+
+
+/// Select the paravirtualization interface presented to the guest.  The
+/// virtual machine must be powered off.
+pub fn set_paravirt_provider<V>(
+  vid: V,
+  provider: ParavirtProvider
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --paravirt-provider <provider>
+
+  modifyvm(vid.borrow(), &[flag(Opt::ParavirtProvider)?, provider.as_arg()])
+}
+
+
+/// Set provider specific paravirtualization debug options, for instance
+/// `[("enabled", "1"), ("vendor", "Microsoft Hv")]` to expose the Hyper-V
+/// debugging interface to a Windows guest.  An empty list clears the
+/// options.
+///
+/// Returns `Err(Error::InvalidArgument)` if a key or value contains `=` or
+/// `,`, which would make the option list ambiguous.
+pub fn set_paravirt_debug<V>(
+  vid: V,
+  opts: &[(&str, &str)]
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let mut pairs = Vec::new();
+  for (key, value) in opts {
+    if [key, value].iter().any(|s| s.contains(['=', ','])) {
+      let s = format!("Invalid paravirt debug option '{}={}'", key, value);
+      return Err(Error::InvalidArgument(s));
+    }
+    pairs.push(format!("{}={}", key, value));
+  }
+
+  // VBoxManage modifyvm $VM --paravirt-debug <key=value>[,<key=value>...]
+
+  modifyvm(vid.borrow(), &[flag(Opt::ParavirtDebug)?, &pairs.join(",")])
+}
+
+
+/// Enable or disable nested hardware virtualization, which lets the guest
+/// run virtual machines of its own using hardware virtualization.
+///
+/// Returns `Err(Error::Unsupported)` if the host CPU or the installed
+/// VirtualBox version doesn't support nested hardware virtualization.
+pub fn set_nested_hw_virt<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --nested-hw-virt on|off
+
+  let args = [flag(Opt::NestedHwVirt)?, onoff(enabled)];
+  match modifyvm(vid.borrow(), &args) {
+    Err(Error::CommandFailed(cmdstr, out)) => {
+      let stderr = String::from_utf8_lossy(&out.stderr);
+      match stderr.lines().find(|line| nested_unsupported(line)) {
+        Some(line) => Err(Error::Unsupported(line.trim().to_string())),
+        None => Err(Error::CommandFailed(cmdstr, out))
+      }
+    }
+    res => res
+  }
+}
+
+
+/// Returns `true` for error messages saying nested hardware virtualization
+/// isn't supported (or, for old versions, that `--nested-hw-virt` is an
+/// unknown option).
+fn nested_unsupported(line: &str) -> bool {
+  let line = line.to_lowercase();
+  if line.contains("unknown option") {
+    return line.contains("nested-hw-virt");
+  }
+  line.contains("nested")
+    && ["not supported", "not available", "unsupported"]
+      .iter()
+      .any(|p| line.contains(p))
+}
+
+
+/// Set a virtual machine's description.
+///
+/// Unlike most settings the description may be changed while the virtual
+/// machine is running.  The description may span multiple lines; it's passed
+/// to `VBoxManage` as a single argument, so newlines are preserved as-is.
+pub fn set_description<V>(vid: V, desc: &str) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --description <desc>
+
+  modifyvm(vid.borrow(), &["--description", desc])
+}
+
+
+/// Maximum number of virtual monitors VirtualBox supports.
+pub const MAX_MONITORS: u8 = 64;
+
+
+/// Set the number of virtual monitors.
+///
+/// Returns `Err(Error::InvalidArgument)` unless `n` is between 1 and
+/// [`MAX_MONITORS`].  More than one monitor requires the Guest Additions
+/// to be installed in the guest, and the video memory must be large enough
+/// to hold all screens.
+pub fn set_monitor_count<V>(vid: V, n: u8) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  if n == 0 || n > MAX_MONITORS {
+    let s = format!(
+      "Monitor count {} out of range; must be 1-{}",
+      n,
+      MAX_MONITORS
+    );
+    return Err(Error::InvalidArgument(s));
+  }
+
+  // VBoxManage modifyvm $VM --monitor-count <n>
+
+  modifyvm(vid.borrow(), &[flag(Opt::MonitorCount)?, &n.to_string()])
+}
+
+
+/// Whether and how the BIOS offers a boot menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMenuMode {
+  Disabled,
+
+  /// The boot menu is available (by pressing F12), but there's no message
+  /// telling the user about it.
+  MenuOnly,
+
+  /// The boot menu is available and a message about it is shown.
+  MessageAndMenu
+}
+
+impl BootMenuMode {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      BootMenuMode::Disabled => "disabled",
+      BootMenuMode::MenuOnly => "menuonly",
+      BootMenuMode::MessageAndMenu => "messageandmenu"
+    }
+  }
+}
+
+
+/// Configure the BIOS boot menu.
+pub fn set_boot_menu<V>(vid: V, mode: BootMenuMode) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --bios-boot-menu <mode>
+
+  modifyvm(vid.borrow(), &[flag(Opt::BiosBootMenu)?, mode.as_arg()])
+}
+
+
+/// Select whether the BIOS logo fades in and out.
+pub fn set_bios_logo_fade<V>(
+  vid: V,
+  fade_in: bool,
+  fade_out: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --bios-logo-fade-in on|off \
+  //  --bios-logo-fade-out on|off
+
+  modifyvm(
+    vid.borrow(),
+    &[
+      flag(Opt::BiosLogoFadeIn)?,
+      onoff(fade_in),
+      flag(Opt::BiosLogoFadeOut)?,
+      onoff(fade_out)
+    ]
+  )
+}
+
+
+/// Shared clipboard modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardMode {
+  Disabled,
+  HostToGuest,
+  GuestToHost,
+  Bidirectional
+}
+
+impl ClipboardMode {
+  pub(crate) fn as_arg(&self) -> &'static str {
+    match self {
+      ClipboardMode::Disabled => "disabled",
+      ClipboardMode::HostToGuest => "hosttoguest",
+      ClipboardMode::GuestToHost => "guesttohost",
+      ClipboardMode::Bidirectional => "bidirectional"
+    }
+  }
+
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s {
+      "disabled" => Some(ClipboardMode::Disabled),
+      "hosttoguest" => Some(ClipboardMode::HostToGuest),
+      "guesttohost" => Some(ClipboardMode::GuestToHost),
+      "bidirectional" => Some(ClipboardMode::Bidirectional),
+      _ => None
+    }
+  }
+}
+
+
+/// Allow or disallow copying files through the shared clipboard.
+///
+/// Requires VirtualBox 7.0 or later, and a bidirectional or one-way
+/// clipboard mode (see [`set_clipboard_mode()`]) to have any effect.
+pub fn set_clipboard_file_transfers<V>(
+  vid: V,
+  enabled: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --clipboard-file-transfers enabled|disabled
+
+  let val = if enabled { "enabled" } else { "disabled" };
+
+  modifyvm(vid.borrow(), &[flag(Opt::ClipboardFileTransfers)?, val])
+}
+
+
+/// Drag and drop modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DragAndDropMode {
+  Disabled,
+  HostToGuest,
+  GuestToHost,
+  Bidirectional
+}
+
+impl DragAndDropMode {
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s {
+      "disabled" => Some(DragAndDropMode::Disabled),
+      "hosttoguest" => Some(DragAndDropMode::HostToGuest),
+      "guesttohost" => Some(DragAndDropMode::GuestToHost),
+      "bidirectional" => Some(DragAndDropMode::Bidirectional),
+      _ => None
+    }
+  }
+}
+
+
+/// Set the shared clipboard mode.
+///
+/// Requires the Guest Additions to be installed in the guest to have any
+/// effect.  See also [`controlvm::set_clipboard_mode()`] for changing the
+/// mode of a running virtual machine.
+///
+/// [`controlvm::set_clipboard_mode()`]: crate::controlvm::set_clipboard_mode
+pub fn set_clipboard_mode<V>(vid: V, mode: ClipboardMode) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --clipboard-mode <mode>
+
+  modifyvm(vid.borrow(), &[flag(Opt::ClipboardMode)?, mode.as_arg()])
+}
+
+
+/// Emulated audio controllers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioController {
+  /// Intel AC'97.
+  Ac97,
+
+  /// Intel High Definition Audio.
+  Hda,
+
+  /// SoundBlaster 16.
+  Sb16
+}
+
+impl AudioController {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      AudioController::Ac97 => "ac97",
+      AudioController::Hda => "hda",
+      AudioController::Sb16 => "sb16"
+    }
+  }
+
+  pub(crate) fn from_info(s: &str) -> Option<Self> {
+    match s {
+      "ac97" => Some(AudioController::Ac97),
+      "hda" => Some(AudioController::Hda),
+      "sb16" => Some(AudioController::Sb16),
+      _ => None
+    }
+  }
+}
+
+
+/// Host audio backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioDriver {
+  /// No audio device at all.
+  None,
+
+  /// Audio device present, but all audio is discarded.
+  Null,
+
+  /// The host's default backend.
+  Default,
+  Pulse,
+  Alsa,
+  Oss,
+  CoreAudio,
+  DirectSound,
+  WasApi,
+
+  /// A backend this crate doesn't know about.
+  Other(String)
+}
+
+impl AudioDriver {
+  fn as_arg(&self) -> &str {
+    match self {
+      AudioDriver::None => "none",
+      AudioDriver::Null => "null",
+      AudioDriver::Default => "default",
+      AudioDriver::Pulse => "pulse",
+      AudioDriver::Alsa => "alsa",
+      AudioDriver::Oss => "oss",
+      AudioDriver::CoreAudio => "coreaudio",
+      AudioDriver::DirectSound => "dsound",
+      AudioDriver::WasApi => "was",
+      AudioDriver::Other(s) => s
+    }
+  }
+}
+
+impl From<&str> for AudioDriver {
+  fn from(s: &str) -> Self {
+    match s {
+      "none" => AudioDriver::None,
+      "null" => AudioDriver::Null,
+      "default" => AudioDriver::Default,
+      "pulse" => AudioDriver::Pulse,
+      "alsa" => AudioDriver::Alsa,
+      "oss" => AudioDriver::Oss,
+      "coreaudio" => AudioDriver::CoreAudio,
+      "dsound" => AudioDriver::DirectSound,
+      "was" => AudioDriver::WasApi,
+      s => AudioDriver::Other(s.to_string())
+    }
+  }
+}
+
+
+/// Select the emulated audio controller.
+pub fn set_audio_controller<V>(
+  vid: V,
+  ctrl: AudioController
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --audio-controller ac97|hda|sb16
+
+  modifyvm(vid.borrow(), &[flag(Opt::AudioController)?, ctrl.as_arg()])
+}
+
+
+/// Enable or disable the virtual machine's audio device.
+///
+/// VirtualBox 7.0 and later keep the audio device's settings while it's
+/// disabled.  Earlier versions have no separate switch; disabling audio
+/// selects the [`AudioDriver::None`] backend, and there's no way to know
+/// which backend to restore.  On those versions enabling audio returns
+/// `Err(Error::Unsupported)`; select a backend using [`set_audio_driver()`]
+/// instead.
+pub fn set_audio_enabled<V>(vid: V, enabled: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --audio-enabled on|off
+  // VBoxManage modifyvm $VM --audio none  (before 7.0)
+
+  modifyvm(vid.borrow(), &audio_enabled_args(&version()?, enabled)?)
+}
+
+
+fn audio_enabled_args(
+  ver: &Version,
+  enabled: bool
+) -> Result<[&'static str; 2], Error> {
+  if ver.at_least(7, 0) {
+    Ok(["--audio-enabled", onoff(enabled)])
+  } else if !enabled {
+    Ok([Opt::AudioDriver.arg(ver), "none"])
+  } else {
+    let s = format!(
+      "VirtualBox {} can't enable audio without selecting a backend",
+      ver
+    );
+    Err(Error::Unsupported(s))
+  }
+}
+
+
+/// Select the host audio backend.  [`AudioDriver::None`] removes the
+/// virtual machine's audio device.
+pub fn set_audio_driver<V>(vid: V, driver: &AudioDriver) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --audio-driver <driver>
+
+  modifyvm(vid.borrow(), &[flag(Opt::AudioDriver)?, driver.as_arg()])
+}
+
+
+/// Enable or disable audio input and output.
+///
+/// This doesn't enable the audio device itself; it only controls whether
+/// the guest may capture and play audio through it.
+pub fn set_audio_io<V>(vid: V, input: bool, output: bool) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --audio-in on|off --audio-out on|off
+
+  modifyvm(
+    vid.borrow(),
+    &[
+      flag(Opt::AudioIn)?,
+      onoff(input),
+      flag(Opt::AudioOut)?,
+      onoff(output)
+    ]
+  )
+}
+
+
+/// Enable or disable recording of the virtual machine's screens.
+///
+/// If `file` is given the recording is written to it, otherwise VirtualBox
+/// picks a file in the virtual machine's folder.
+pub fn set_recording<V>(
+  vid: V,
+  enabled: bool,
+  file: Option<&Path>
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --recording on|off [--recording-file <file>]
+
+  let mut args: Vec<&OsStr> =
+    vec![OsStr::new("--recording"), OsStr::new(onoff(enabled))];
+  if let Some(file) = file {
+    args.push(OsStr::new(flag(Opt::RecordingFile)?));
+    args.push(file.as_os_str());
+  }
+
+  modifyvm(vid.borrow(), &args)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  fn ver(s: &str) -> Version {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn opt_spelling_by_version() {
+    let table = [
+      (Opt::MonitorCount, "6.1.38", "--monitorcount", "--monitor-count"),
+      (Opt::BiosBootMenu, "6.1.38", "--biosbootmenu", "--bios-boot-menu"),
+      (
+        Opt::BiosLogoFadeIn,
+        "6.1.38",
+        "--bioslogofadein",
+        "--bios-logo-fade-in"
+      ),
+      (
+        Opt::BiosLogoFadeOut,
+        "6.1.38",
+        "--bioslogofadeout",
+        "--bios-logo-fade-out"
+      ),
+      (Opt::ClipboardMode, "6.0.24", "--clipboard", "--clipboard-mode"),
+      (
+        Opt::ClipboardFileTransfers,
+        "6.1.38",
+        "--clipboardfiletransfers",
+        "--clipboard-file-transfers"
+      ),
+      (
+        Opt::AudioController,
+        "6.1.38",
+        "--audiocontroller",
+        "--audio-controller"
+      ),
+      (Opt::AudioDriver, "6.1.38", "--audio", "--audio-driver"),
+      (Opt::AudioIn, "6.1.38", "--audioin", "--audio-in"),
+      (Opt::AudioOut, "6.1.38", "--audioout", "--audio-out"),
+      (Opt::RecordingFile, "6.1.38", "--recordingfile", "--recording-file"),
+      (Opt::VrdeAddress, "6.1.38", "--vrdeaddress", "--vrde-address"),
+      (
+        Opt::ParavirtProvider,
+        "6.1.38",
+        "--paravirtprovider",
+        "--paravirt-provider"
+      ),
+      (Opt::ParavirtDebug, "6.1.38", "--paravirtdebug", "--paravirt-debug"),
+      (Opt::UsbOhci, "6.1.38", "--usbohci", "--usb-ohci"),
+      (Opt::UsbEhci, "6.1.38", "--usbehci", "--usb-ehci"),
+      (Opt::UsbXhci, "6.1.38", "--usbxhci", "--usb-xhci"),
+      (Opt::RtcUseUtc, "6.1.38", "--rtcuseutc", "--rtc-use-utc"),
+      (Opt::NestedHwVirt, "5.2.44", "--nested-hw-virt", "--nested-hw-virt")
+    ];
+
+    for (opt, before, old, new) in table.iter() {
+      let ((major, minor), _, _) = opt.spellings();
+      let cutoff = ver(&format!("{}.{}.0", major, minor));
+      assert_eq!(opt.arg(&ver(before)), *old, "{:?} in {}", opt, before);
+      assert_eq!(opt.arg(&cutoff), *new, "{:?} at the cutoff", opt);
+      assert_eq!(opt.arg(&ver("7.1.4")), *new, "{:?} in 7.1.4", opt);
+    }
+  }
+
+  #[test]
+  fn audio_enabled_args_by_version() {
+    let v7 = ver("7.0.10r158379");
+    let v61 = ver("6.1.38r153438");
+    assert_eq!(
+      audio_enabled_args(&v7, true).unwrap(),
+      ["--audio-enabled", "on"]
+    );
+    assert_eq!(
+      audio_enabled_args(&v7, false).unwrap(),
+      ["--audio-enabled", "off"]
+    );
+    assert_eq!(audio_enabled_args(&v61, false).unwrap(), ["--audio", "none"]);
+    assert!(matches!(
+      audio_enabled_args(&v61, true),
+      Err(Error::Unsupported(_))
+    ));
+  }
+
+  #[test]
+  fn clipboard_file_transfers_args() {
+    let runner = Runner::new();
+    set_clipboard_file_transfers(VmId::from("vm"), true).unwrap();
+    runner.version("6.1.38r153438");
+    set_clipboard_file_transfers(VmId::from("vm"), false).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--clipboard-file-transfers", "enabled"],
+        ["modifyvm", "vm", "--clipboardfiletransfers", "disabled"]
+      ]
+    );
+  }
+
+  #[test]
+  fn usb_controller_args() {
+    let runner = Runner::new();
+    let ctrls = [
+      UsbControllerType::Ohci,
+      UsbControllerType::Ehci,
+      UsbControllerType::Xhci
+    ];
+    for ctrl in ctrls.iter() {
+      set_usb_controller(VmId::from("vm"), *ctrl, true).unwrap();
+      set_usb_controller(VmId::from("vm"), *ctrl, false).unwrap();
+    }
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--usb-ohci", "on"],
+        ["modifyvm", "vm", "--usb-ohci", "off"],
+        ["modifyvm", "vm", "--usb-ehci", "on"],
+        ["modifyvm", "vm", "--usb-ehci", "off"],
+        ["modifyvm", "vm", "--usb-xhci", "on"],
+        ["modifyvm", "vm", "--usb-xhci", "off"]
+      ]
+    );
+  }
+
+  #[test]
+  fn nested_hw_virt_args() {
+    let runner = Runner::new();
+    set_nested_hw_virt(VmId::from("vm"), true).unwrap();
+    set_nested_hw_virt(VmId::from("vm"), false).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--nested-hw-virt", "on"],
+        ["modifyvm", "vm", "--nested-hw-virt", "off"]
+      ]
+    );
+  }
+
+  #[test]
+  fn nested_hw_virt_unsupported() {
+    let runner = Runner::new();
+    runner.fail(concat!(
+      "VBoxManage: error: Nested hardware virtualization is not supported ",
+      "on this host\n"
+    ));
+    runner.fail("VBoxManage: error: Unknown option: --nested-hw-virt\n");
+    runner.fail("VBoxManage: error: Unknown option: --bogus\n");
+    runner.fail("VBoxManage: error: Code E_FAIL (0x80004005)\n");
+
+    assert!(matches!(
+      set_nested_hw_virt(VmId::from("vm"), true),
+      Err(Error::Unsupported(_))
+    ));
+    assert!(matches!(
+      set_nested_hw_virt(VmId::from("vm"), true),
+      Err(Error::Unsupported(_))
+    ));
+    assert!(matches!(
+      set_nested_hw_virt(VmId::from("vm"), true),
+      Err(Error::CommandFailed(..))
+    ));
+    assert!(matches!(
+      set_nested_hw_virt(VmId::from("vm"), true),
+      Err(Error::CommandFailed(..))
+    ));
+  }
+
+  #[test]
+  fn ioapic_and_x2apic_args() {
+    let runner = Runner::new();
+    set_ioapic(VmId::from("vm"), true).unwrap();
+    runner.ok("name=\"vm\"\ncpus=1\n");
+    set_ioapic(VmId::from("vm"), false).unwrap();
+    set_x2apic(VmId::from("vm"), true).unwrap();
+    set_x2apic(VmId::from("vm"), false).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        vec!["modifyvm", "vm", "--ioapic", "on"],
+        vec!["showvminfo", "vm", "--machinereadable"],
+        vec!["modifyvm", "vm", "--ioapic", "off"],
+        vec!["modifyvm", "vm", "--x2apic", "on"],
+        vec!["modifyvm", "vm", "--x2apic", "off"]
+      ]
+    );
+  }
+
+  #[test]
+  fn ioapic_required_for_smp() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\ncpus=4\n");
+
+    assert!(matches!(
+      set_ioapic(VmId::from("vm"), false),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(runner.calls(), [["showvminfo", "vm", "--machinereadable"]]);
+  }
+
+  #[test]
+  fn description_keeps_newlines() {
+    let runner = Runner::new();
+    let desc = "Build agent\nOwner: ci\n\n  indented line";
+    set_description(VmId::from("vm"), desc).unwrap();
+
+    assert_eq!(runner.calls(), [["modifyvm", "vm", "--description", desc]]);
+  }
+
+  #[test]
+  fn paravirt_provider_parse() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "paravirtprovider=\"default\"\n",
+      "effparavirtprovider=\"hyperv\"\n"
+    ));
+    runner.ok("name=\"vm\"\nparavirtprovider=\"KVM\"\n");
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.paravirt_provider, Some(ParavirtProvider::Default));
+    assert_eq!(
+      info.effective_paravirt_provider,
+      Some(ParavirtProvider::HyperV)
+    );
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.paravirt_provider, Some(ParavirtProvider::Kvm));
+    assert_eq!(info.effective_paravirt_provider, None);
+  }
+
+  #[test]
+  fn paravirt_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_paravirt_provider(&vid, ParavirtProvider::HyperV).unwrap();
+    set_paravirt_debug(&vid, &[("enabled", "1"), ("vendor", "Microsoft Hv")])
+      .unwrap();
+    set_paravirt_debug(&vid, &[]).unwrap();
+    runner.version("6.1.38r153438");
+    set_paravirt_provider(&vid, ParavirtProvider::Minimal).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--paravirt-provider", "hyperv"],
+        [
+          "modifyvm",
+          "vm",
+          "--paravirt-debug",
+          "enabled=1,vendor=Microsoft Hv"
+        ],
+        ["modifyvm", "vm", "--paravirt-debug", ""],
+        ["modifyvm", "vm", "--paravirtprovider", "minimal"]
+      ]
+    );
+  }
+
+  #[test]
+  fn paravirt_debug_rejects_separators() {
+    let runner = Runner::new();
+    for opts in [[("a=b", "1")], [("vendor", "x,y")]].iter() {
+      assert!(matches!(
+        set_paravirt_debug(VmId::from("vm"), opts),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn groups_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_groups(&vid, &["/cluster/web", "/cluster/web/eu", "/lab"]).unwrap();
+    set_groups(&vid, &[]).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--groups", "/cluster/web,/cluster/web/eu,/lab"],
+        ["modifyvm", "vm", "--groups", ""]
+      ]
+    );
+  }
+
+  #[test]
+  fn groups_must_be_absolute_paths() {
+    let runner = Runner::new();
+    for group in ["cluster", "/a,/b"].iter() {
+      assert!(matches!(
+        set_groups(VmId::from("vm"), &["/ok", group]),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn timekeeping_parse() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nhpet=\"on\"\nrtcuseutc=\"off\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!((info.hpet, info.rtc_use_utc), (Some(true), Some(false)));
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!((info.hpet, info.rtc_use_utc), (None, None));
+  }
+
+  #[test]
+  fn timekeeping_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_hpet(&vid, true).unwrap();
+    set_hpet(&vid, false).unwrap();
+    set_rtc_use_utc(&vid, true).unwrap();
+    runner.version("6.1.38r153438");
+    set_rtc_use_utc(&vid, false).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--hpet", "on"],
+        ["modifyvm", "vm", "--hpet", "off"],
+        ["modifyvm", "vm", "--rtc-use-utc", "on"],
+        ["modifyvm", "vm", "--rtcuseutc", "off"]
+      ]
+    );
+  }
+
+  #[test]
+  fn audio_parse() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "audio_controller=\"hda\"\n",
+      "audio=\"pulse\"\n",
+      "audio_enabled=\"off\"\n"
+    ));
+    runner.ok("name=\"vm\"\naudiocontroller=\"ac97\"\naudio=\"none\"\n");
+    runner.ok("name=\"vm\"\naudiocontroller=\"sb16\"\naudio=\"jack\"\n");
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.audio_controller, Some(AudioController::Hda));
+    assert_eq!(info.audio_driver, Some(AudioDriver::Pulse));
+    assert_eq!(info.audio_enabled, Some(false));
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.audio_controller, Some(AudioController::Ac97));
+    assert_eq!(info.audio_driver, Some(AudioDriver::None));
+    assert_eq!(info.audio_enabled, Some(false));
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.audio_controller, Some(AudioController::Sb16));
+    assert_eq!(info.audio_driver, Some(AudioDriver::Other("jack".into())));
+    assert_eq!(info.audio_enabled, Some(true));
+  }
+
+  #[test]
+  fn audio_controller_and_driver_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_audio_controller(&vid, AudioController::Hda).unwrap();
+    set_audio_driver(&vid, &AudioDriver::Pulse).unwrap();
+    runner.version("6.1.38r153438");
+    set_audio_controller(&vid, AudioController::Sb16).unwrap();
+    set_audio_driver(&vid, &AudioDriver::DirectSound).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--audio-controller", "hda"],
+        ["modifyvm", "vm", "--audio-driver", "pulse"],
+        ["modifyvm", "vm", "--audiocontroller", "sb16"],
+        ["modifyvm", "vm", "--audio", "dsound"]
+      ]
+    );
+  }
+
+  #[test]
+  fn boot_menu_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    let modes = [
+      BootMenuMode::Disabled,
+      BootMenuMode::MenuOnly,
+      BootMenuMode::MessageAndMenu
+    ];
+    for mode in modes.iter() {
+      set_boot_menu(&vid, *mode).unwrap();
+    }
+    set_bios_logo_fade(&vid, true, false).unwrap();
+    runner.version("6.1.38r153438");
+    set_boot_menu(&vid, BootMenuMode::MenuOnly).unwrap();
+    set_bios_logo_fade(&vid, false, true).unwrap();
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "modifyvm vm --bios-boot-menu disabled",
+        "modifyvm vm --bios-boot-menu menuonly",
+        "modifyvm vm --bios-boot-menu messageandmenu",
+        "modifyvm vm --bios-logo-fade-in on --bios-logo-fade-out off",
+        "modifyvm vm --biosbootmenu menuonly",
+        "modifyvm vm --bioslogofadein off --bioslogofadeout on"
+      ]
+    );
+  }
+
+  #[test]
+  fn clipboard_and_dnd_parse() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "clipboard=\"bidirectional\"\n",
+      "draganddrop=\"hosttoguest\"\n"
+    ));
+    runner.ok("name=\"vm\"\nclipboardmode=\"guesttohost\"\n");
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "clipboard=\"sideways\"\n",
+      "draganddrop=\"disabled\"\n"
+    ));
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.clipboard_mode, Some(ClipboardMode::Bidirectional));
+    assert_eq!(info.drag_and_drop, Some(DragAndDropMode::HostToGuest));
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.clipboard_mode, Some(ClipboardMode::GuestToHost));
+    assert_eq!(info.drag_and_drop, None);
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.clipboard_mode, None);
+    assert_eq!(info.drag_and_drop, Some(DragAndDropMode::Disabled));
+  }
+
+  #[test]
+  fn monitor_count_parse() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nmonitorcount=3\n");
+    runner.ok("name=\"vm\"\n");
+    runner.ok("name=\"vm\"\nmonitorcount=many\n");
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.monitor_count, Some(3));
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    assert_eq!(info.monitor_count, None);
+    assert!(matches!(
+      crate::get_vm_info(VmId::from("vm")),
+      Err(Error::BadFormat(_))
+    ));
+  }
+
+  #[test]
+  fn monitor_count_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_monitor_count(&vid, 2).unwrap();
+    set_monitor_count(&vid, MAX_MONITORS).unwrap();
+    runner.version("6.1.38r153438");
+    set_monitor_count(&vid, 1).unwrap();
+    for n in [0, MAX_MONITORS + 1].iter() {
+      assert!(matches!(
+        set_monitor_count(&vid, *n),
+        Err(Error::InvalidArgument(_))
+      ));
+    }
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--monitor-count", "2"],
+        ["modifyvm", "vm", "--monitor-count", "64"],
+        ["modifyvm", "vm", "--monitorcount", "1"]
+      ]
+    );
+  }
+
+  #[test]
+  fn firmware_and_chipset_args() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    let firmwares =
+      [Firmware::Bios, Firmware::Efi, Firmware::Efi32, Firmware::Efi64];
+    for fw in &firmwares {
+      set_firmware(&vid, *fw).unwrap();
+    }
+    set_chipset(&vid, Chipset::Piix3).unwrap();
+    set_chipset(&vid, Chipset::Ich9).unwrap();
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "modifyvm vm --firmware bios",
+        "modifyvm vm --firmware efi",
+        "modifyvm vm --firmware efi32",
+        "modifyvm vm --firmware efi64",
+        "modifyvm vm --chipset piix3",
+        "modifyvm vm --chipset ich9"
+      ]
+    );
+  }
+
+  #[test]
+  fn vrde_address_args_and_parse() {
+    let runner = Runner::new();
+    let vid = VmId::from("vm");
+    set_vrde_address(&vid, "127.0.0.1").unwrap();
+    set_vrde_address(&vid, "").unwrap();
+    assert!(matches!(
+      set_vrde_address(&vid, "localhost"),
+      Err(Error::InvalidArgument(_))
+    ));
+    runner.version("6.1.38r153438");
+    set_vrde_address(&vid, "::1").unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--vrde-address", "127.0.0.1"],
+        ["modifyvm", "vm", "--vrde-address", ""],
+        ["modifyvm", "vm", "--vrdeaddress", "::1"]
+      ]
+    );
+
+    runner.ok("name=\"vm\"\nvrde=\"on\"\nvrdeaddress=\"127.0.0.1\"\n");
+    runner.ok("name=\"vm\"\nvrde=\"off\"\n");
+    let info = crate::get_vm_info(&vid).unwrap();
+    assert_eq!(info.vrde_address.as_deref(), Some("127.0.0.1"));
+    let info = crate::get_vm_info(&vid).unwrap();
+    assert_eq!(info.vrde_address, None);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
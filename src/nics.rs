@@ -1,29 +1,250 @@
+use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::process::Command;
+use std::str::FromStr;
 
 use crate::err::Error;
+use crate::json;
+use crate::platform;
+use crate::utils;
+use crate::VmId;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BridgedNIC {
   pub adapter: String
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct IntNetNIC {
   pub name: String
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NatNIC {
+  pub forwards: Vec<PortForwardRule>
+}
+
+/// An adapter attached to a generic network driver, such as `UDPTunnel` or
+/// `VDE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenericNIC {
+  pub driver: String,
+
+  /// Driver-specific properties, for example `dest` and `dport` for
+  /// `UDPTunnel`.
+  pub properties: HashMap<String, String>
+}
+
+impl Hash for GenericNIC {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.driver.hash(state);
+    hash_properties(&self.properties, state);
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum NICType {
   Bridged(BridgedNIC),
-  IntNet(IntNetNIC)
+  IntNet(IntNetNIC),
+  Nat(NatNIC),
+  Generic(GenericNIC)
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Protocol {
+  Tcp,
+  Udp
+}
+
+
+/// A NAT port forwarding rule.
+///
+/// The string representation is the one used by `VBoxManage`, i.e.
+/// `name,proto,hostip,hostport,guestip,guestport`, where the IP fields may be
+/// left empty.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortForwardRule {
+  pub name: String,
+  pub proto: Protocol,
+  pub host_ip: Option<String>,
+  pub host_port: u16,
+  pub guest_ip: Option<String>,
+  pub guest_port: u16
+}
+
+impl fmt::Display for PortForwardRule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let proto = match self.proto {
+      Protocol::Tcp => "tcp",
+      Protocol::Udp => "udp"
+    };
+    write!(
+      f,
+      "{},{},{},{},{},{}",
+      self.name,
+      proto,
+      self.host_ip.as_deref().unwrap_or(""),
+      self.host_port,
+      self.guest_ip.as_deref().unwrap_or(""),
+      self.guest_port
+    )
+  }
+}
+
+impl FromStr for PortForwardRule {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let fields: Vec<&str> = s.split(',').collect();
+    if fields.len() != 6 {
+      let s = format!("Port forwarding rule '{}' does not have six fields", s);
+      return Err(Error::BadFormat(s));
+    }
+
+    let proto = match fields[1] {
+      "tcp" => Protocol::Tcp,
+      "udp" => Protocol::Udp,
+      p => {
+        let s = format!("Unknown port forwarding protocol '{}'", p);
+        return Err(Error::BadFormat(s));
+      }
+    };
+
+    let opt_ip = |ip: &str| {
+      if ip.is_empty() {
+        None
+      } else {
+        Some(ip.to_string())
+      }
+    };
+
+    let port = |p: &str| match p.parse::<u16>() {
+      Ok(p) => Ok(p),
+      Err(_) => {
+        let s = format!("Invalid port '{}' in forwarding rule", p);
+        Err(Error::BadFormat(s))
+      }
+    };
+
+    Ok(PortForwardRule {
+      name: fields[0].to_string(),
+      proto,
+      host_ip: opt_ip(fields[2]),
+      host_port: port(fields[3])?,
+      guest_ip: opt_ip(fields[4]),
+      guest_port: port(fields[5])?
+    })
+  }
 }
 
-#[derive(Debug)]
+/// Emulated network adapter hardware model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HwType {
+  /// AMD PCnet-PCI II.
+  Am79C970A,
+
+  /// AMD PCnet-FAST III.
+  Am79C973,
+
+  /// AMD PCnet-ISA/LANCE.
+  Am79C960,
+
+  /// Intel PRO/1000 MT Desktop.
+  I82540EM,
+
+  /// Intel PRO/1000 T Server.
+  I82543GC,
+
+  /// Intel PRO/1000 MT Server.
+  I82545EM,
+
+  /// Paravirtualized network adapter (virtio-net).
+  Virtio,
+
+  /// A model not known to this crate.
+  Other(String)
+}
+
+impl HwType {
+  pub(crate) fn as_arg(&self) -> &str {
+    match self {
+      HwType::Am79C970A => "Am79C970A",
+      HwType::Am79C973 => "Am79C973",
+      HwType::Am79C960 => "Am79C960",
+      HwType::I82540EM => "82540EM",
+      HwType::I82543GC => "82543GC",
+      HwType::I82545EM => "82545EM",
+      HwType::Virtio => "virtio",
+      HwType::Other(s) => s
+    }
+  }
+}
+
+impl From<&str> for HwType {
+  fn from(s: &str) -> Self {
+    match s {
+      "Am79C970A" => HwType::Am79C970A,
+      "Am79C973" => HwType::Am79C973,
+      "Am79C960" => HwType::Am79C960,
+      "82540EM" => HwType::I82540EM,
+      "82543GC" => HwType::I82543GC,
+      "82545EM" => HwType::I82545EM,
+      "virtio" => HwType::Virtio,
+      s => HwType::Other(s.to_string())
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct NICInfo {
   pub idx: u8,
   pub nictype: NICType,
-  pub mac: eui48::MacAddress
+  pub mac: eui48::MacAddress,
+  pub hw_type: Option<HwType>,
+
+  /// Driver properties set on the adapter (see [`set_property()`]).
+  pub properties: HashMap<String, String>
+}
+
+impl Hash for NICInfo {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.idx.hash(state);
+    self.nictype.hash(state);
+    self.mac.hash(state);
+    self.hw_type.hash(state);
+    hash_properties(&self.properties, state);
+  }
+}
+
+
+/// `HashMap` doesn't implement `Hash`; hash the properties in sorted order so
+/// that equal maps hash equally.
+fn hash_properties<H: Hasher>(props: &HashMap<String, String>, state: &mut H) {
+  let mut props: Vec<_> = props.iter().collect();
+  props.sort();
+  props.hash(state);
+}
+
+impl NICInfo {
+  pub(crate) fn to_json(&self) -> String {
+    let (nictype, attachment) = match self.nictype {
+      NICType::Bridged(ref b) => ("bridged", Some(&b.adapter)),
+      NICType::IntNet(ref i) => ("intnet", Some(&i.name)),
+      NICType::Nat(_) => ("nat", None),
+      NICType::Generic(ref g) => ("generic", Some(&g.driver))
+    };
+    format!(
+      r#"{{"idx":{},"type":"{}","attachment":{},"mac":"{}","hw_type":{}}}"#,
+      self.idx,
+      nictype,
+      json::opt(attachment.map(|a| json::string(a))),
+      self.mac.to_hex_string(),
+      json::opt(self.hw_type.as_ref().map(|h| json::string(h.as_arg())))
+    )
+  }
 }
 
 
@@ -66,10 +287,24 @@ pub fn get_from_map(
             name: name.to_string()
           })
         }
-        _ => {
-          println!("unrecognized nic type: {}", v);
-          continue;
+        "nat" => NICType::Nat(NatNIC {
+          forwards: get_port_forwards(map)?
+        }),
+        "generic" => {
+          let key = format!("nicgenericdrv{}", idx);
+          let driver = match map.get(&key) {
+            Some(driver) => driver,
+            None => {
+              // missing critical information..
+              continue;
+            }
+          };
+          NICType::Generic(GenericNIC {
+            driver: driver.to_string(),
+            properties: get_generic_properties(map, idx)
+          })
         }
+        _ => continue
       };
 
       let key = format!("macaddress{}", idx);
@@ -78,11 +313,582 @@ pub fn get_from_map(
         None => continue
       };
 
-      nics.push(NICInfo { idx, nictype, mac });
+      let key = format!("nictype{}", idx);
+      let hw_type = map.get(&key).map(|t| HwType::from(t.as_str()));
+
+      nics.push(NICInfo {
+        idx,
+        nictype,
+        mac,
+        hw_type,
+        properties: get_generic_properties(map, idx)
+      });
     }
   }
 
   Ok(nics)
 }
 
+
+/// Collect the `name=value` properties of an adapter.
+///
+/// Properties are reported as `nicproperty<idx>`, with a suffix to tell them
+/// apart if there are several, e.g. `nicproperty1_2`.
+fn get_generic_properties(
+  map: &HashMap<String, String>,
+  idx: u8
+) -> HashMap<String, String> {
+  let prefix = format!("nicproperty{}", idx);
+
+  map
+    .iter()
+    .filter(|(k, _)| match k.strip_prefix(&prefix) {
+      Some(rest) => !rest.starts_with(|c: char| c.is_ascii_digit()),
+      None => false
+    })
+    .filter_map(|(_, v)| {
+      let mut it = v.splitn(2, '=');
+      match (it.next(), it.next()) {
+        (Some(name), Some(val)) => Some((name.to_string(), val.to_string())),
+        _ => None
+      }
+    })
+    .collect()
+}
+
+
+/// Add the `--nicgenericdrv<idx>` and `--nicproperty<idx>` arguments for a
+/// generic driver adapter.
+fn generic_args(
+  cmd: &mut Command,
+  idx: u8,
+  driver: &str,
+  props: &HashMap<String, String>
+) {
+  cmd.arg(format!("--nicgenericdrv{}", idx));
+  cmd.arg(driver);
+
+  // Sorted to get a predictable command line.
+  let mut props: Vec<_> = props.iter().collect();
+  props.sort();
+  for (name, val) in props {
+    cmd.arg(format!("--nicproperty{}", idx));
+    cmd.arg(format!("{}={}", name, val));
+  }
+}
+
+
+/// Format a MAC address the way `VBoxManage` expects it (12 hex digits
+/// without separators).
+fn mac_arg(mac: &eui48::MacAddress) -> String {
+  mac.as_bytes().iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+
+/// Change the attachment type of network adapter `idx` while keeping its MAC
+/// address.
+///
+/// The adapter's current MAC address is read first and re-applied together
+/// with the new attachment type, so DHCP reservations and similar keyed on the
+/// MAC address keep working.  For NAT attachments the port forwarding rules
+/// in `new_type` are added as well.  The virtual machine must be powered off.
+pub fn reconfigure<V>(vid: V, idx: u8, new_type: &NICType) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let map = crate::get_vm_info_map(vid.borrow())?;
+  let nics = get_from_map(&map)?;
+  let mac = nics.iter().find(|n| n.idx == idx).map(|n| n.mac);
+
+  // VBoxManage modifyvm $VM --nic1 bridged --bridgeadapter1 eth0 \
+  //  --macaddress1 080027000001
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--nic{}", idx));
+  match new_type {
+    NICType::Bridged(b) => {
+      cmd.arg("bridged");
+      cmd.arg(format!("--bridgeadapter{}", idx));
+      cmd.arg(&b.adapter);
+    }
+    NICType::IntNet(i) => {
+      cmd.arg("intnet");
+      cmd.arg(format!("--intnet{}", idx));
+      cmd.arg(&i.name);
+    }
+    NICType::Nat(n) => {
+      cmd.arg("nat");
+      for rule in &n.forwards {
+        cmd.arg(format!("--natpf{}", idx));
+        cmd.arg(rule.to_string());
+      }
+    }
+    NICType::Generic(g) => {
+      cmd.arg("generic");
+      generic_args(&mut cmd, idx, &g.driver, &g.properties);
+    }
+  }
+  if let Some(mac) = mac {
+    cmd.arg(format!("--macaddress{}", idx));
+    cmd.arg(mac_arg(&mac));
+  }
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Attach network adapter `idx` to the generic network driver `driver`, with
+/// the driver-specific properties `props`.
+pub fn set_generic<V, D>(
+  vid: V,
+  idx: u8,
+  driver: D,
+  props: &HashMap<String, String>
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  D: AsRef<str>
+{
+  // VBoxManage modifyvm $VM --nic1 generic --nicgenericdrv1 UDPTunnel \
+  //  --nicproperty1 dest=10.0.0.1 --nicproperty1 dport=10001
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--nic{}", idx));
+  cmd.arg("generic");
+  generic_args(&mut cmd, idx, driver.as_ref(), props);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Set a driver property on network adapter `idx`.
+///
+/// Which properties are available depends on the adapter's attachment; they
+/// are typically used for tuning (such as the MTU or segmentation offloads)
+/// and for configuring generic drivers (see [`set_generic()`]).
+pub fn set_property<V, K>(
+  vid: V,
+  idx: u8,
+  key: K,
+  value: &str
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  K: AsRef<str>
+{
+  // VBoxManage modifyvm $VM --nicproperty1 <key>=<value>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--nicproperty{}", idx));
+  cmd.arg(format!("{}={}", key.as_ref(), value));
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Set the emulated hardware model of network adapter `idx`.
+pub fn set_hw_type<V>(vid: V, idx: u8, hw: &HwType) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --nictype1 virtio
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--nictype{}", idx));
+  cmd.arg(hw.as_arg());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Parse the NAT port forwarding rules (`Forwarding(<n>)` keys) from a
+/// virtual machine information map.
+///
+/// The `--machinereadable` output does not say which NIC a forwarding rule
+/// belongs to, so if a virtual machine has multiple NAT interfaces the
+/// returned rules can not be attributed to a specific one.
+pub fn get_port_forwards(
+  map: &HashMap<String, String>
+) -> Result<Vec<PortForwardRule>, Error> {
+  let mut rules = Vec::new();
+
+  let mut idx = 0;
+  while let Some(v) = map.get(&format!("Forwarding({})", idx)) {
+    rules.push(v.parse::<PortForwardRule>()?);
+    idx += 1;
+  }
+
+  Ok(rules)
+}
+
+
+/// Add a port forwarding rule to the NAT interface `idx`.
+pub fn add_port_forward<V>(
+  vid: V,
+  idx: u8,
+  rule: &PortForwardRule
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifyvm $VM --natpf1 "ssh,tcp,,2222,,22"
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--natpf{}", idx));
+  cmd.arg(rule.to_string());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Remove the port forwarding rule named `name` from the NAT interface
+/// `idx`.
+pub fn remove_port_forward<V, N>(vid: V, idx: u8, name: N) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  N: AsRef<str>
+{
+  // VBoxManage modifyvm $VM --natpf1 delete "ssh"
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifyvm");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg(format!("--natpf{}", idx));
+  cmd.arg("delete");
+  cmd.arg(name.as_ref());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Make the port forwarding rules of the NAT interface `idx` match
+/// `desired`.
+///
+/// Rules are matched by name.  Current rules which aren't in `desired`, or
+/// whose settings differ from the desired rule of the same name, are removed;
+/// desired rules which aren't present are then added.  Calling this function
+/// again with the same rules is a no-op.
+pub fn reconcile_port_forwards<V>(
+  vid: V,
+  idx: u8,
+  desired: &[PortForwardRule]
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  let map = crate::get_vm_info_map(vid.borrow())?;
+  let current = get_port_forwards(&map)?;
+
+  for rule in &current {
+    if !desired.contains(rule) {
+      remove_port_forward(vid.borrow(), idx, &rule.name)?;
+    }
+  }
+
+  for rule in desired {
+    if !current.contains(rule) {
+      add_port_forward(vid.borrow(), idx, rule)?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  fn rule(name: &str, host_port: u16, guest_port: u16) -> PortForwardRule {
+    PortForwardRule {
+      name: name.to_string(),
+      proto: Protocol::Tcp,
+      host_ip: None,
+      host_port,
+      guest_ip: None,
+      guest_port
+    }
+  }
+
+  #[test]
+  fn port_forward_rule_roundtrip() {
+    let r = "ssh,tcp,127.0.0.1,2222,,22".parse::<PortForwardRule>().unwrap();
+    assert_eq!(r.name, "ssh");
+    assert_eq!(r.proto, Protocol::Tcp);
+    assert_eq!(r.host_ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(r.host_port, 2222);
+    assert_eq!(r.guest_ip, None);
+    assert_eq!(r.guest_port, 22);
+    assert_eq!(r.to_string(), "ssh,tcp,127.0.0.1,2222,,22");
+  }
+
+  #[test]
+  fn port_forward_rule_rejects_bad_input() {
+    assert!("ssh,tcp,,2222,22".parse::<PortForwardRule>().is_err());
+    assert!("ssh,icmp,,2222,,22".parse::<PortForwardRule>().is_err());
+    assert!("ssh,tcp,,65536,,22".parse::<PortForwardRule>().is_err());
+  }
+
+  #[test]
+  fn port_forwards_from_map() {
+    let mut map = HashMap::new();
+    map.insert("Forwarding(0)".to_string(), "ssh,tcp,,2222,,22".to_string());
+    map.insert("Forwarding(1)".to_string(), "web,tcp,,8080,,80".to_string());
+    let rules = get_port_forwards(&map).unwrap();
+    assert_eq!(rules, vec![rule("ssh", 2222, 22), rule("web", 8080, 80)]);
+  }
+
+  #[test]
+  fn reconcile_removes_stale_and_adds_missing_rules() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "Forwarding(0)=\"ssh,tcp,,2222,,22\"\n",
+      "Forwarding(1)=\"web,tcp,,8080,,80\"\n"
+    ));
+
+    let desired = [rule("ssh", 2222, 22), rule("web", 8081, 80)];
+    reconcile_port_forwards(VmId::from("vm"), 1, &desired).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[0][0], "showvminfo");
+    assert_eq!(calls[1], ["modifyvm", "vm", "--natpf1", "delete", "web"]);
+    assert_eq!(calls[2], ["modifyvm", "vm", "--natpf1", "web,tcp,,8081,,80"]);
+  }
+
+  #[test]
+  fn reconfigure_keeps_mac_address() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "nic1=\"nat\"\n",
+      "macaddress1=\"080027AABBCC\"\n"
+    ));
+
+    let bridged = NICType::Bridged(BridgedNIC {
+      adapter: "eth0".to_string()
+    });
+    reconfigure(VmId::from("vm"), 1, &bridged).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0], ["showvminfo", "vm", "--machinereadable"]);
+    assert_eq!(
+      calls[1].join(" "),
+      "modifyvm vm --nic1 bridged --bridgeadapter1 eth0 \
+       --macaddress1 080027AABBCC"
+    );
+  }
+
+  #[test]
+  fn reconcile_is_a_noop_when_rules_match() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nForwarding(0)=\"ssh,tcp,,2222,,22\"\n");
+
+    reconcile_port_forwards(VmId::from("vm"), 1, &[rule("ssh", 2222, 22)])
+      .unwrap();
+
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn hw_type_from_map() {
+    let mut map = HashMap::new();
+    for (k, v) in [
+      ("nic1", "nat"),
+      ("macaddress1", "080027000001"),
+      ("nictype1", "virtio"),
+      ("nic2", "intnet"),
+      ("intnet2", "lab"),
+      ("macaddress2", "080027000002"),
+      ("nictype2", "82540EM"),
+      ("nic3", "intnet"),
+      ("intnet3", "lab"),
+      ("macaddress3", "080027000003"),
+      ("nictype3", "NE2000"),
+      ("nic4", "intnet"),
+      ("intnet4", "lab"),
+      ("macaddress4", "080027000004")
+    ]
+    .iter()
+    {
+      map.insert(k.to_string(), v.to_string());
+    }
+
+    let hw: Vec<_> = get_from_map(&map)
+      .unwrap()
+      .into_iter()
+      .map(|n| (n.idx, n.hw_type))
+      .collect();
+    assert_eq!(
+      hw,
+      [
+        (1, Some(HwType::Virtio)),
+        (2, Some(HwType::I82540EM)),
+        (3, Some(HwType::Other("NE2000".to_string()))),
+        (4, None)
+      ]
+    );
+  }
+
+  #[test]
+  fn hw_type_args() {
+    let runner = Runner::new();
+    set_hw_type(VmId::from("vm"), 2, &HwType::Virtio).unwrap();
+    set_hw_type(VmId::from("vm"), 1, &HwType::I82545EM).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--nictype2", "virtio"],
+        ["modifyvm", "vm", "--nictype1", "82545EM"]
+      ]
+    );
+  }
+
+  #[test]
+  fn generic_nic_from_map() {
+    let mut map = HashMap::new();
+    for (k, v) in [
+      ("nic1", "generic"),
+      ("nicgenericdrv1", "UDPTunnel"),
+      ("nicproperty1", "dest=10.0.0.1"),
+      ("nicproperty1_2", "dport=10001"),
+      ("macaddress1", "080027000001"),
+      ("nicproperty10", "other=ignored")
+    ]
+    .iter()
+    {
+      map.insert(k.to_string(), v.to_string());
+    }
+
+    let nics = get_from_map(&map).unwrap();
+    assert_eq!(nics.len(), 1);
+    let mut props = HashMap::new();
+    props.insert("dest".to_string(), "10.0.0.1".to_string());
+    props.insert("dport".to_string(), "10001".to_string());
+    assert_eq!(
+      nics[0].nictype,
+      NICType::Generic(GenericNIC {
+        driver: "UDPTunnel".to_string(),
+        properties: props
+      })
+    );
+  }
+
+  #[test]
+  fn set_generic_args() {
+    let runner = Runner::new();
+    let mut props = HashMap::new();
+    props.insert("dport".to_string(), "10001".to_string());
+    props.insert("dest".to_string(), "10.0.0.1".to_string());
+    set_generic(VmId::from("vm"), 2, "UDPTunnel", &props).unwrap();
+
+    assert_eq!(
+      runner.calls()[0].join(" "),
+      "modifyvm vm --nic2 generic --nicgenericdrv2 UDPTunnel \
+       --nicproperty2 dest=10.0.0.1 --nicproperty2 dport=10001"
+    );
+  }
+
+  #[test]
+  fn nic_properties_from_vm_info() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "nic1=\"intnet\"\n",
+      "intnet1=\"lab\"\n",
+      "macaddress1=\"080027000001\"\n",
+      "nicproperty1=\"MTU=9000\"\n",
+      "nicproperty1_2=\"TSO=off\"\n",
+      "nicproperty1_3=\"malformed\"\n"
+    ));
+
+    let info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    let props = &info.nics[0].properties;
+    assert_eq!(props.len(), 2);
+    assert_eq!(props["MTU"], "9000");
+    assert_eq!(props["TSO"], "off");
+  }
+
+  #[test]
+  fn set_property_args() {
+    let runner = Runner::new();
+    set_property(VmId::from("vm"), 1, "MTU", "9000").unwrap();
+    set_property(VmId::from("vm"), 3, "name", "a value=x").unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [
+        ["modifyvm", "vm", "--nicproperty1", "MTU=9000"],
+        ["modifyvm", "vm", "--nicproperty3", "name=a value=x"]
+      ]
+    );
+  }
+
+  #[test]
+  fn equal_nics_hash_equally() {
+    let nic = |props: &[(&str, &str)]| NICInfo {
+      idx: 1,
+      nictype: NICType::IntNet(IntNetNIC {
+        name: "lab".to_string()
+      }),
+      mac: eui48::MacAddress::parse_str("080027000001").unwrap(),
+      hw_type: Some(HwType::Virtio),
+      properties: props
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    };
+    let a = nic(&[("MTU", "9000"), ("Promisc", "deny")]);
+    let b = nic(&[("Promisc", "deny"), ("MTU", "9000")]);
+    assert_eq!(a, b);
+
+    let mut set = std::collections::HashSet::new();
+    set.insert(a);
+    assert!(set.contains(&b));
+    assert!(!set.contains(&nic(&[("MTU", "1500")])));
+
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "name=\"vm\"\n",
+      "nic1=\"intnet\"\n",
+      "intnet1=\"lab\"\n",
+      "macaddress1=\"080027000001\"\n"
+    ));
+    let mut info = crate::get_vm_info(VmId::from("vm")).unwrap();
+    info.nics.push(b);
+    info.nics.push(nic(&[]));
+    info.nics.push(nic(&[("MTU", "9000"), ("Promisc", "deny")]));
+    info.dedup_nics();
+    assert_eq!(info.nics.len(), 3);
+    assert_eq!(info.nics[0].hw_type, None);
+    assert_eq!(info.nics[1].properties.len(), 2);
+    assert!(info.nics[2].properties.is_empty());
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,181 @@
+//! Manipulate the UEFI variable store (NVRAM) of virtual machines using EFI
+//! firmware, for instance to set up Secure Boot.
+//!
+//! This wraps `VBoxManage modifynvram`, which was introduced in VirtualBox
+//! 7.0.  All functions return `Err(Error::Unsupported)` on earlier versions.
+//! The virtual machine must be powered off.
+
+use std::borrow::Borrow;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+use crate::platform;
+use crate::strutils::{buf_to_strlines, EmptyLine};
+use crate::utils;
+use crate::{version, Error, VmId};
+
+
+/// Run `VBoxManage modifynvram <vid> <args>` and return its output.
+fn modifynvram<A>(vid: &VmId, args: &[A]) -> Result<Vec<u8>, Error>
+where
+  A: AsRef<OsStr>
+{
+  let ver = version()?;
+  if !ver.at_least(7, 0) {
+    let s = format!("modifynvram requires VirtualBox 7.0 (found {})", ver);
+    return Err(Error::Unsupported(s));
+  }
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("modifynvram");
+  cmd.arg(vid.to_string());
+  cmd.args(args);
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  Ok(stdout)
+}
+
+
+/// Initialize the virtual machine's UEFI variable store.
+///
+/// This needs to be done before keys can be enrolled.
+pub fn init_uefi_vars<V>(vid: V) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifynvram $VM inituefivarstore
+
+  modifynvram(vid.borrow(), &["inituefivarstore"])?;
+
+  Ok(())
+}
+
+
+/// Enroll Microsoft's Secure Boot keys (KEK and signature database) in the
+/// virtual machine's UEFI variable store.
+pub fn enroll_ms_keys<V>(vid: V) -> Result<(), Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifynvram $VM enrollmssignatures
+
+  modifynvram(vid.borrow(), &["enrollmssignatures"])?;
+
+  Ok(())
+}
+
+
+/// Enroll a Machine Owner Key, read from the DER encoded certificate file
+/// `mok`, on behalf of the owner `owner`.
+///
+/// This lets the guest boot kernels and modules signed with the key while
+/// Secure Boot is enabled.
+pub fn enroll_mok<V, P>(
+  vid: V,
+  mok: P,
+  owner: &uuid::Uuid
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>
+{
+  // VBoxManage modifynvram $VM enrollmok --mok=<file> --owner-uuid=<uuid>
+
+  let mut mok_arg = OsStr::new("--mok=").to_os_string();
+  mok_arg.push(mok.as_ref());
+
+  modifynvram(
+    vid.borrow(),
+    &[
+      OsStr::new("enrollmok"),
+      &mok_arg,
+      OsStr::new(&format!("--owner-uuid={}", owner.to_hyphenated()))
+    ]
+  )?;
+
+  Ok(())
+}
+
+
+/// List the names of the variables in the virtual machine's UEFI variable
+/// store.
+pub fn list_uefi_vars<V>(vid: V) -> Result<Vec<String>, Error>
+where
+  V: Borrow<VmId>
+{
+  // VBoxManage modifynvram $VM listvars
+
+  let stdout = modifynvram(vid.borrow(), &["listvars"])?;
+
+  Ok(parse_listvars(&buf_to_strlines(&stdout, EmptyLine::Ignore)))
+}
+
+
+/// Parse `listvars` output, which has one `<name> <owner uuid>` line per
+/// variable.
+fn parse_listvars(lines: &[String]) -> Vec<String> {
+  lines
+    .iter()
+    .filter_map(|line| line.split_whitespace().next())
+    .map(|name| name.to_string())
+    .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn nvram_args() {
+    let runner = Runner::new();
+    runner.ok("");
+    runner.ok("");
+    runner.ok("");
+    runner.ok(concat!(
+      "PK 8be4df61-93ca-11d2-aa0d-00e098032b8c\n",
+      "\n",
+      "db d719b2cb-3d3a-4596-a3bc-dad00e67656f\n"
+    ));
+
+    let vid = VmId::from("vm");
+    let owner =
+      uuid::Uuid::parse_str("605dab50-e046-4300-abb6-3dd810dd8b23").unwrap();
+    init_uefi_vars(&vid).unwrap();
+    enroll_ms_keys(&vid).unwrap();
+    enroll_mok(&vid, "/keys/mok.der", &owner).unwrap();
+    assert_eq!(list_uefi_vars(&vid).unwrap(), ["PK", "db"]);
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "modifynvram vm inituefivarstore",
+        "modifynvram vm enrollmssignatures",
+        concat!(
+          "modifynvram vm enrollmok --mok=/keys/mok.der ",
+          "--owner-uuid=605dab50-e046-4300-abb6-3dd810dd8b23"
+        ),
+        "modifynvram vm listvars"
+      ]
+    );
+  }
+
+  #[test]
+  fn nvram_requires_7_0() {
+    let runner = Runner::new();
+    runner.version("6.1.38r153438");
+
+    assert!(matches!(
+      init_uefi_vars(VmId::from("vm")),
+      Err(Error::Unsupported(_))
+    ));
+    assert!(runner.calls().is_empty());
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
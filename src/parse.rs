@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+
+/// Parse the output of commands which print records of `key: value` lines
+/// separated by blank lines (most `VBoxManage list` subcommands).
+///
+/// Keys and values are trimmed.  Lines without a colon are ignored.  If a key
+/// occurs more than once within a record the last value wins.
+pub(crate) fn parse_records(output: &str) -> Vec<HashMap<String, String>> {
+  let mut records = Vec::new();
+  let mut rec = HashMap::new();
+
+  for line in output.lines() {
+    let line = line.trim();
+    if line.is_empty() {
+      if !rec.is_empty() {
+        records.push(rec);
+        rec = HashMap::new();
+      }
+      continue;
+    }
+
+    if let Some(idx) = line.find(':') {
+      let key = line[..idx].trim();
+      let val = line[(idx + 1)..].trim();
+      if !key.is_empty() {
+        rec.insert(key.to_string(), val.to_string());
+      }
+    }
+  }
+
+  if !rec.is_empty() {
+    records.push(rec);
+  }
+
+  records
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_are_split_on_blank_lines() {
+    let out = concat!(
+      "\n",
+      "Name:            eth0   \n",
+      "HardwareAddress: 08:00:27:12:34:56\n",
+      "garbage without a colon\n",
+      "\n",
+      "\n",
+      "   \n",
+      "Name:            eth1\n",
+      "Name:            wlan0\n",
+      ": no key\n"
+    );
+    let recs = parse_records(out);
+
+    assert_eq!(recs.len(), 2);
+    assert_eq!(recs[0].len(), 2);
+    assert_eq!(recs[0]["Name"], "eth0");
+    assert_eq!(recs[0]["HardwareAddress"], "08:00:27:12:34:56");
+    assert_eq!(recs[1].len(), 1);
+    assert_eq!(recs[1]["Name"], "wlan0");
+  }
+
+  #[test]
+  fn empty_output_has_no_records() {
+    assert!(parse_records("").is_empty());
+    assert!(parse_records("\n\n  \n").is_empty());
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
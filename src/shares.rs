@@ -0,0 +1,113 @@
+//! Shared folders between the host and a virtual machine.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::err::Error;
+use crate::json;
+
+
+/// A folder shared between the host and a virtual machine.
+#[derive(Debug, Clone)]
+pub struct SharedFolder {
+  pub name: String,
+  pub host_path: PathBuf,
+  pub writable: bool,
+  pub automount: bool,
+  pub auto_mount_point: Option<String>,
+
+  /// Transient shares only exist while the virtual machine is running and are
+  /// not stored in its configuration.
+  pub transient: bool
+}
+
+impl SharedFolder {
+  pub(crate) fn to_json(&self) -> String {
+    format!(
+      concat!(
+        r#"{{"name":{},"host_path":{},"writable":{},"automount":{},"#,
+        r#""auto_mount_point":{},"transient":{}}}"#
+      ),
+      json::string(&self.name),
+      json::string(&self.host_path.to_string_lossy()),
+      self.writable,
+      self.automount,
+      json::opt(self.auto_mount_point.as_deref().map(json::string)),
+      self.transient
+    )
+  }
+}
+
+
+fn parse_flag(
+  map: &HashMap<String, String>,
+  key: &str,
+  default: bool
+) -> bool {
+  match map.get(key).map(String::as_str) {
+    Some("true") | Some("yes") | Some("on") | Some("1") => true,
+    Some("false") | Some("no") | Some("off") | Some("0") => false,
+    _ => default
+  }
+}
+
+
+fn get_mappings(
+  map: &HashMap<String, String>,
+  kind: &str,
+  out: &mut Vec<SharedFolder>
+) {
+  let mut idx = 1;
+  loop {
+    let name_key = format!("SharedFolderName{}Mapping{}", kind, idx);
+    let path_key = format!("SharedFolderPath{}Mapping{}", kind, idx);
+
+    let name = match map.get(&name_key) {
+      Some(nm) => nm.clone(),
+      None => break
+    };
+    let host_path = match map.get(&path_key) {
+      Some(pn) => PathBuf::from(pn),
+      None => break
+    };
+
+    // Older versions of VirtualBox do not report the flags; fall back to
+    // VirtualBox's defaults for those.
+    let writable_key = format!("SharedFolderWritable{}Mapping{}", kind, idx);
+    let automount_key = format!("SharedFolderAutoMount{}Mapping{}", kind, idx);
+    let mountpoint_key =
+      format!("SharedFolderAutoMountPoint{}Mapping{}", kind, idx);
+
+    let auto_mount_point = match map.get(&mountpoint_key) {
+      Some(mp) if !mp.is_empty() => Some(mp.clone()),
+      _ => None
+    };
+
+    out.push(SharedFolder {
+      name,
+      host_path,
+      writable: parse_flag(map, &writable_key, true),
+      automount: parse_flag(map, &automount_key, false),
+      auto_mount_point,
+      transient: kind == "Transient"
+    });
+
+    idx += 1;
+  }
+}
+
+
+/// Parse all shared folders, both permanent and transient, from a virtual
+/// machine information map.
+pub fn get_from_map(
+  map: &HashMap<String, String>
+) -> Result<Vec<SharedFolder>, Error> {
+  let mut shares = Vec::new();
+
+  get_mappings(map, "Machine", &mut shares);
+  get_mappings(map, "Transient", &mut shares);
+
+  Ok(shares)
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -2,16 +2,19 @@
 
 use std::borrow::Borrow;
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::process::Command;
 use std::str::FromStr;
+use std::time::Duration;
 
 use regex::Regex;
 
 use crate::platform;
 use crate::strutils::{buf_to_strlines, EmptyLine};
 use crate::utils;
-use crate::VmId;
+use crate::{appliance, clonevm, controlvm, TimeoutAction, VmId, VmState};
 
 use crate::Error;
 
@@ -20,14 +23,16 @@ pub enum SnapshotId {
   Uuid(uuid::Uuid)
 }
 
-impl ToString for SnapshotId {
-  fn to_string(&self) -> String {
-    match &*self {
-      SnapshotId::Name(s) => s.clone(),
-      SnapshotId::Uuid(u) => u
-        .to_hyphenated()
-        .encode_lower(&mut uuid::Uuid::encode_buffer())
-        .to_string()
+impl fmt::Display for SnapshotId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SnapshotId::Name(s) => write!(f, "{}", s),
+      SnapshotId::Uuid(u) => write!(
+        f,
+        "{}",
+        u.to_hyphenated()
+          .encode_lower(&mut uuid::Uuid::encode_buffer())
+      )
     }
   }
 }
@@ -49,12 +54,17 @@ impl FromStr for SnapshotId {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Snapshot {
   pub name: String,
   pub uuid: uuid::Uuid,
   pub desc: Vec<String>,
-  pub children: Vec<uuid::Uuid>
+  pub children: Vec<uuid::Uuid>,
+
+  /// When the snapshot was taken, as an ISO 8601 UTC timestamp (for example
+  /// `2023-05-01T10:20:30Z`), if known.  Only set by
+  /// [`Snapshots::load_timestamps()`].
+  pub timestamp: Option<String>
 }
 
 impl Hash for Snapshot {
@@ -72,6 +82,22 @@ impl PartialEq for Snapshot {
 impl Eq for Snapshot {}
 
 
+/// What to do when a snapshot name matches more than one snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmbiguityPolicy {
+  /// Fail with `Error::Ambiguous`.
+  Error,
+
+  /// Pick the match which comes first in the snapshot tree (parents before
+  /// children, earlier children before later ones).
+  First,
+
+  /// Pick the match which was taken most recently.  If the timestamps
+  /// aren't known the match which comes last in the snapshot tree is picked.
+  Latest
+}
+
+
 /// Get a HashMap of all snapshots.
 pub fn map<V>(vid: V) -> Result<HashMap<String, String>, Error>
 where
@@ -150,7 +176,7 @@ impl Snapshots {
     N: AsRef<str>
   {
     let mut out = Vec::new();
-    for (_, snap) in &self.map {
+    for snap in self.map.values() {
       if snap.name.as_str() == name.as_ref() {
         out.push(snap);
       }
@@ -158,17 +184,180 @@ impl Snapshots {
     out
   }
 
+  /// Get the most recently taken snapshot.
+  ///
+  /// This isn't necessarily the current snapshot ([`get_current()`]), which
+  /// is the one the virtual machine's current state is based on.  If all
+  /// snapshots have timestamps (see [`load_timestamps()`]) the newest one is
+  /// returned.  Otherwise the deepest snapshot in the tree is returned,
+  /// preferring later children on ties.
+  ///
+  /// [`get_current()`]: Snapshots::get_current
+  /// [`load_timestamps()`]: Snapshots::load_timestamps
+  pub fn latest(&self) -> Option<&Snapshot> {
+    if self.map.values().all(|snap| snap.timestamp.is_some()) {
+      // ISO 8601 UTC timestamps sort chronologically as strings.
+      return self.map.values().max_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    }
+
+    let mut deepest: Option<(usize, &Snapshot)> = None;
+    let mut stack = vec![(self.root, 0)];
+    while let Some((u, depth)) = stack.pop() {
+      let snap = match self.map.get(&u) {
+        Some(snap) => snap,
+        None => continue
+      };
+      let deeper = match deepest {
+        Some((d, _)) => depth >= d,
+        None => true
+      };
+      if deeper {
+        deepest = Some((depth, snap));
+      }
+      // Pushed in reverse so that children are visited in index order.
+      for child in snap.children.iter().rev() {
+        stack.push((*child, depth + 1));
+      }
+    }
+
+    deepest.map(|(_, snap)| snap)
+  }
+
+  /// Fill in the snapshots' timestamps from the virtual machine's settings
+  /// (`.vbox`) file, which is the only place `VBoxManage` exposes them.
+  ///
+  /// The path is the `CfgFile` entry of [`get_vm_info_map()`].  Snapshots
+  /// which aren't found in the file are left without a timestamp.
+  ///
+  /// [`get_vm_info_map()`]: crate::get_vm_info_map
+  pub fn load_timestamps<P>(&mut self, cfg: P) -> Result<(), Error>
+  where
+    P: AsRef<Path>
+  {
+    let xml = std::fs::read_to_string(cfg)?;
+    self.timestamps_from_xml(&xml);
+    Ok(())
+  }
+
+  /// Set timestamps from the `<Snapshot uuid="{...}" name="..."
+  /// timeStamp="2023-05-01T10:20:30Z">` elements of a settings file.
+  fn timestamps_from_xml(&mut self, xml: &str) {
+    let re_elem = Regex::new(r#"<Snapshot\s[^>]*>"#).unwrap();
+    let re_uuid = Regex::new(r#"\suuid="\{?([0-9a-fA-F-]+)\}?""#).unwrap();
+    let re_ts = Regex::new(r#"\stimeStamp="([^"]+)""#).unwrap();
+
+    for elem in re_elem.find_iter(xml) {
+      let elem = elem.as_str();
+      let u = match re_uuid
+        .captures(elem)
+        .and_then(|cap| uuid::Uuid::parse_str(&cap[1]).ok())
+      {
+        Some(u) => u,
+        None => continue
+      };
+      if let Some(snap) = self.map.get_mut(&u) {
+        snap.timestamp = re_ts.captures(elem).map(|cap| cap[1].to_string());
+      }
+    }
+  }
+
+  /// Get the uuids of all snapshots ordered so that every snapshot comes
+  /// after all of its descendants.
+  pub fn leaves_first(&self) -> Vec<uuid::Uuid> {
+    let mut out = Vec::new();
+
+    // Depth-first; each snapshot is emitted once all its children have been.
+    let mut stack = vec![(self.root, false)];
+    while let Some((u, expanded)) = stack.pop() {
+      if expanded {
+        out.push(u);
+        continue;
+      }
+      stack.push((u, true));
+      if let Some(snap) = self.map.get(&u) {
+        for child in snap.children.iter().rev() {
+          stack.push((*child, false));
+        }
+      }
+    }
+
+    out
+  }
+
+  /// Get all snapshots in tree order; each snapshot comes before its
+  /// children, and earlier children (and their descendants) before later
+  /// ones.
+  pub(crate) fn tree_order(&self) -> Vec<&Snapshot> {
+    let mut out = Vec::new();
+    let mut stack = vec![self.root];
+    while let Some(u) = stack.pop() {
+      if let Some(snap) = self.map.get(&u) {
+        out.push(snap);
+        for child in snap.children.iter().rev() {
+          stack.push(*child);
+        }
+      }
+    }
+    out
+  }
+
+  /// Find the snapshot identified by `sid`, using `policy` to choose between
+  /// snapshots which share the same name.
+  ///
+  /// Returns `Err(Error::Missing)` if no snapshot matches.
+  pub fn resolve<S>(
+    &self,
+    sid: S,
+    policy: AmbiguityPolicy
+  ) -> Result<&Snapshot, Error>
+  where
+    S: Borrow<SnapshotId>
+  {
+    let sid = sid.borrow();
+    let found = self.get(sid);
+    match found.len() {
+      0 => {
+        let s = format!("No snapshot '{}'", sid);
+        return Err(Error::Missing(s));
+      }
+      1 => return Ok(found[0]),
+      _ => {}
+    }
+
+    let mut matches: Vec<&Snapshot> = self
+      .tree_order()
+      .into_iter()
+      .filter(|snap| found.contains(snap))
+      .collect();
+
+    match policy {
+      AmbiguityPolicy::Error => {
+        let s = format!("Multiple snapshots named '{}'", sid);
+        Err(Error::Ambiguous(s))
+      }
+      AmbiguityPolicy::First => Ok(matches.remove(0)),
+      AmbiguityPolicy::Latest => {
+        if matches.iter().all(|snap| snap.timestamp.is_some()) {
+          // ISO 8601 UTC timestamps sort chronologically as strings.  The
+          // sort is stable, so ties stay in tree order.
+          matches.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        }
+        Ok(matches.pop().unwrap())
+      }
+    }
+  }
+
   pub fn get_unique_by_name(&self, name: &str) -> Result<&Snapshot, Error> {
     let snaplist = self.get_by_name(name);
     match snaplist.len() {
       0 => {
         let s = format!("The VM has no snapshot named '{}'", name);
-        return Err(Error::MissingData(s));
+        Err(Error::MissingData(s))
       }
       1 => Ok(snaplist[0]),
       _ => {
         let s = format!("The VM has multiple snapshots named '{}'", name);
-        return Err(Error::Ambiguous(s));
+        Err(Error::Ambiguous(s))
       }
     }
   }
@@ -188,6 +377,74 @@ where
 }
 
 
+/// Find the snapshot of a virtual machine identified by `sid`, using `policy`
+/// to choose between snapshots which share the same name.
+///
+/// Unlike [`get()`] this reads the snapshot timestamps (see
+/// [`Snapshots::load_timestamps()`]) when `policy` is
+/// [`AmbiguityPolicy::Latest`].  Returns `Err(Error::Missing)` if the
+/// virtual machine has no matching snapshot.
+pub fn resolve<V, S>(
+  vid: V,
+  sid: S,
+  policy: AmbiguityPolicy
+) -> Result<Snapshot, Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>
+{
+  let snaps = if policy == AmbiguityPolicy::Latest {
+    get_with_timestamps(vid.borrow())?
+  } else {
+    get(vid.borrow())?
+  };
+  let snaps = match snaps {
+    Some(snaps) => snaps,
+    None => {
+      let s = format!("Virtual machine '{}' has no snapshots", vid.borrow());
+      return Err(Error::Missing(s));
+    }
+  };
+
+  match snaps.resolve(sid.borrow(), policy) {
+    Ok(snap) => Ok(snap.clone()),
+    Err(Error::Missing(_)) => {
+      let s = format!(
+        "Virtual machine '{}' has no snapshot '{}'",
+        vid.borrow(),
+        sid.borrow()
+      );
+      Err(Error::Missing(s))
+    }
+    Err(Error::Ambiguous(_)) => {
+      let s = format!(
+        "The VM '{}' has multiple snapshots named '{}'",
+        vid.borrow(),
+        sid.borrow()
+      );
+      Err(Error::Ambiguous(s))
+    }
+    Err(e) => Err(e)
+  }
+}
+
+
+/// Get a virtual machine's snapshots along with their timestamps.
+pub(crate) fn get_with_timestamps(
+  vid: &VmId
+) -> Result<Option<Snapshots>, Error> {
+  let map = crate::get_vm_info_map(vid)?;
+  let mut snaps = match get_from_map(&map)? {
+    Some(snaps) => snaps,
+    None => return Ok(None)
+  };
+  if let Some(cfgfile) = map.get("CfgFile") {
+    snaps.load_timestamps(cfgfile)?;
+  }
+  Ok(Some(snaps))
+}
+
+
 /// Convert a HashMap of snapshots (typically aquired using [`map()`]) to a
 /// structured representation of the snapshots tree.
 pub fn get_from_map(
@@ -197,34 +454,51 @@ pub fn get_from_map(
   // The HashSet isn't used here because it does not allow nodes to be edited.
   let mut snapmap = HashMap::new();
 
-  let mut root_uuid: Option<uuid::Uuid> = None;
-  let mut current_uuid: Option<uuid::Uuid> = None;
-
   let mut q = VecDeque::new();
 
   // Get root snapshot
-  match (map.get("SnapshotName"), map.get("SnapshotUUID")) {
+  let root_uuid = match (map.get("SnapshotName"), map.get("SnapshotUUID")) {
     (Some(_), Some(uid)) => {
       // Push "branch" (empty string, since it's the root node) on to stack
       q.push_back("".to_string());
 
-      root_uuid = match uuid::Uuid::parse_str(uid) {
-        Ok(u) => Some(u),
+      match uuid::Uuid::parse_str(uid) {
+        Ok(u) => u,
         Err(_) => {
           let s = format!("Unable to parse root UUID '{}'", uid);
           return Err(Error::BadFormat(s));
         }
-      };
+      }
     }
     _ => {
       // No results
       return Ok(None);
     }
-  }
+  };
 
-  while !q.is_empty() {
-    let curbranch = q.pop_back().unwrap();
+  // Child snapshots have keys of the form "SnapshotUUID-X-Y-Z", where
+  // "-X-Y" is the parent's branch and "Z" is the child's index.  The indices
+  // are normally contiguous, but don't rely on it; collect the children of
+  // each branch from whatever keys are actually present.
+  let mut children: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+  for key in map.keys() {
+    let branch = match key.strip_prefix("SnapshotUUID") {
+      Some(branch) if branch.starts_with('-') => branch,
+      _ => continue
+    };
+    let idx = branch.rfind('-').unwrap();
+    if let Ok(n) = branch[(idx + 1)..].parse::<usize>() {
+      children
+        .entry(branch[..idx].to_string())
+        .or_default()
+        .push((n, branch.to_string()));
+    }
+  }
+  for kids in children.values_mut() {
+    kids.sort();
+  }
 
+  while let Some(curbranch) = q.pop_back() {
     let uuid_key = format!("SnapshotUUID{}", curbranch);
     let name_key = format!("SnapshotName{}", curbranch);
 
@@ -245,27 +519,22 @@ pub fn get_from_map(
         name: nm.clone(),
         uuid: u,
         desc: Vec::new(),
-        children: Vec::new()
+        children: Vec::new(),
+        timestamp: None
       }
     );
 
-    // Generate a -X-Y-Z branch name, stick it to a "SnapshotUUID" string and
-    // see if the combined name exists in the map.  If it does, then
-    for i in 1..usize::MAX {
-      let branch = format!("{}-{}", curbranch, i);
+    // Add all children of this branch, in index order, to the queue.
+    if let Some(kids) = children.get(&curbranch) {
+      for (_, branch) in kids {
+        let key = format!("SnapshotUUID{}", branch);
+        let v = &map[&key];
 
-      let key = format!("SnapshotUUID{}", branch);
-
-      // If this key exists in the map, then add it to the queue
-      if let Some(v) = map.get(&key) {
         // Get UUID of current child node
         let cuid = match uuid::Uuid::parse_str(v) {
           Ok(u) => u,
           Err(_) => {
-            let s = format!(
-              "Unable to parse UUID '{}' for 'SnapshotUUID{}'",
-              uid, curbranch
-            );
+            let s = format!("Unable to parse UUID '{}' for '{}'", v, key);
             return Err(Error::BadFormat(s));
           }
         };
@@ -276,63 +545,91 @@ pub fn get_from_map(
         }
 
         // Push this child on to the processing queue for further processing
-        q.push_back(branch);
-      } else {
-        // No more children -- break out of index iteration
-        break;
+        q.push_back(branch.clone());
       }
     }
   }
 
-  if let Some(us) = map.get("CurrentSnapshotUUID") {
-    current_uuid = Some(match uuid::Uuid::parse_str(us) {
+  let current_uuid = if let Some(us) = map.get("CurrentSnapshotUUID") {
+    match uuid::Uuid::parse_str(us) {
       Ok(u) => u,
       Err(_) => {
         let s = format!("Unable to parse current UUID '{}'", us);
         return Err(Error::BadFormat(s));
       }
-    });
+    }
   } else {
     return Err(Error::MissingData(
       "Can't find expected field 'CurrentSnapshotUUID".to_string()
     ));
-  }
+  };
 
   let snaps = Snapshots {
     map: snapmap,
-    root: match root_uuid {
-      Some(u) => u,
-      None => {
-        // Ok to panic since we already know we have it
-        panic!("No root UUID");
-      }
-    },
-    current: match current_uuid {
-      Some(u) => u,
-      None => {
-        // Ok to panic since we know we have it
-        panic!("No current UUID");
-      }
-    }
+    root: root_uuid,
+    current: current_uuid
   };
 
   Ok(Some(snaps))
 }
 
 
+fn take_cmd(vid: &VmId, nm: &str, desc: Option<&str>, live: bool) -> Command {
+  // VBoxManage snapshot <vid> take <nm> [--description <desc>] [--live]
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("snapshot");
+  cmd.arg(vid.to_string());
+  cmd.arg("take");
+  cmd.arg(nm);
+  if let Some(desc) = desc {
+    cmd.arg("--description");
+    cmd.arg(desc);
+  }
+  if live {
+    cmd.arg("--live");
+  }
+  cmd
+}
+
+
 /// Take a new snapshot at current vm state.
+///
+/// The virtual machine may remain locked for a moment after this returns;
+/// use [`wait_for_unlock()`](crate::wait_for_unlock) before operations which
+/// need to modify it.
 pub fn take<V, N>(vid: V, nm: N) -> Result<(), Error>
 where
   V: Borrow<VmId>,
   N: AsRef<str>
 {
-  // VBoxManage snapshot <vid> take <nm>
+  let cmd = take_cmd(vid.borrow(), nm.as_ref(), None, false);
 
-  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
-  cmd.arg("snapshot");
-  cmd.arg(vid.borrow().to_string());
-  cmd.arg("take");
-  cmd.arg(nm.as_ref());
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Take a snapshot of a running virtual machine without pausing it.
+///
+/// Live snapshots only apply to running virtual machines, so this returns
+/// `Err(Error::InvalidArgument)` without attempting to take the snapshot if
+/// the virtual machine isn't running.
+pub fn take_live<V, N>(vid: V, nm: N, desc: Option<&str>) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  N: AsRef<str>
+{
+  if !crate::is_vm_state(vid.borrow(), VmState::Running)? {
+    let s = format!(
+      "Virtual machine '{}' is not running; can't take a live snapshot",
+      vid.borrow()
+    );
+    return Err(Error::InvalidArgument(s));
+  }
+
+  let cmd = take_cmd(vid.borrow(), nm.as_ref(), desc, true);
 
   utils::exec(cmd)?;
 
@@ -340,6 +637,86 @@ where
 }
 
 
+/// Take a snapshot named `name` of each of a set of virtual machines, with
+/// all of them paused while the snapshots are taken.
+///
+/// All running virtual machines in `vids` are paused first, so that the
+/// snapshots capture (nearly) the same point in time, then a snapshot of
+/// each virtual machine is taken, and finally the paused virtual machines
+/// are resumed.  Virtual machines which weren't running are snapshotted as
+/// they are.  Returns the uuid of each new snapshot.
+///
+/// If pausing or taking a snapshot fails the remaining virtual machines are
+/// left alone, every virtual machine which was paused is resumed (even if
+/// some of the resumes fail), and the first error is returned.  Snapshots
+/// which were already taken are kept.
+pub fn take_fleet_quiesced(
+  vids: &[VmId],
+  name: &str
+) -> Result<Vec<(VmId, SnapshotId)>, Error> {
+  let mut paused = Vec::new();
+  let res = pause_and_take(vids, name, &mut paused);
+
+  let mut resumed = Ok(());
+  for vid in paused {
+    if let Err(e) = controlvm::resume(vid) {
+      if resumed.is_ok() {
+        resumed = Err(e);
+      }
+    }
+  }
+
+  let out = res?;
+  resumed?;
+  Ok(out)
+}
+
+
+/// The pausing and snapshotting part of [`take_fleet_quiesced()`].  Paused
+/// virtual machines are recorded in `paused` so they can be resumed no
+/// matter how this fails.
+fn pause_and_take<'a>(
+  vids: &'a [VmId],
+  name: &str,
+  paused: &mut Vec<&'a VmId>
+) -> Result<Vec<(VmId, SnapshotId)>, Error> {
+  for vid in vids {
+    if crate::is_vm_state(vid, VmState::Running)? {
+      controlvm::pause(vid)?;
+      paused.push(vid);
+    }
+  }
+
+  let mut out = Vec::new();
+  for vid in vids {
+    take(vid, name)?;
+    let snap = match get(vid)? {
+      Some(snaps) => snaps.current,
+      None => {
+        let s = format!("Snapshot of '{}' vanished", vid);
+        return Err(Error::Missing(s));
+      }
+    };
+    out.push((vid.clone(), SnapshotId::Uuid(snap)));
+  }
+
+  Ok(out)
+}
+
+
+/// Returns `Ok(true)` if the virtual machine's current state has been
+/// modified since its current snapshot was taken.
+///
+/// This can be used to decide whether taking a new snapshot is warranted.
+pub fn has_unsaved_changes<V>(vid: V) -> Result<bool, Error>
+where
+  V: Borrow<VmId>
+{
+  let vmi = crate::get_vm_info(vid)?;
+  Ok(vmi.current_state_modified)
+}
+
+
 /// Returns `Ok(true)` if the virtual machine `vid` has one or more snapshots
 /// named `name`.
 pub fn have_name<V, N>(vid: V, name: N) -> Result<bool, Error>
@@ -375,7 +752,7 @@ where
     } else if snaplist.len() > 1 {
       let s = format!(
         "Virtual machine '{}' has multiple snapshots named '{}'",
-        vid.borrow().to_string(),
+        vid.borrow(),
         name.as_ref()
       );
       return Err(Error::Ambiguous(s));
@@ -384,7 +761,7 @@ where
 
   let s = format!(
     "Virtual machine '{}' has no snapshot named '{}'",
-    vid.borrow().to_string(),
+    vid.borrow(),
     name.as_ref()
   );
   Err(Error::Missing(s))
@@ -413,6 +790,50 @@ where
 }
 
 
+/// Rename a snapshot, unless another snapshot of the virtual machine already
+/// uses the new name.
+///
+/// Returns `Err(Error::Ambiguous)` if a different snapshot is named
+/// `newname`, since the rename would make name-based lookups of either
+/// snapshot ambiguous.  If `force` is `true` the rename is done regardless.
+/// Returns `Err(Error::Missing)` if `sid` doesn't identify a snapshot.
+pub fn rename_checked<V, S, N>(
+  vid: V,
+  sid: S,
+  newname: N,
+  force: bool
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>,
+  N: AsRef<str>
+{
+  let snaps = match get(vid.borrow())? {
+    Some(snaps) => snaps,
+    None => {
+      let s = format!("Virtual machine '{}' has no snapshots", vid.borrow());
+      return Err(Error::Missing(s));
+    }
+  };
+  let target = snaps.resolve(sid.borrow(), AmbiguityPolicy::Error)?.uuid;
+
+  let taken = snaps
+    .get_by_name(newname.as_ref())
+    .iter()
+    .any(|snap| snap.uuid != target);
+  if taken && !force {
+    let s = format!(
+      "Virtual machine '{}' already has a snapshot named '{}'",
+      vid.borrow(),
+      newname.as_ref()
+    );
+    return Err(Error::Ambiguous(s));
+  }
+
+  rename(vid, SnapshotId::Uuid(target), newname)
+}
+
+
 /// Restore a virtual machine to a snapshot.
 ///
 /// If `snap_id` is `None` the "current" snapshot is restored.  Otherwise
@@ -421,16 +842,48 @@ pub fn restore<V, S>(vid: V, snap_id: Option<S>) -> Result<(), Error>
 where
   V: Borrow<VmId>,
   S: Borrow<SnapshotId>
+{
+  utils::exec(restore_cmd(vid.borrow(), snap_id)?)?;
+
+  Ok(())
+}
+
+
+/// Restore a virtual machine to a snapshot, calling `on_progress` with the
+/// completion percentage as the restore progresses.
+///
+/// Works like [`restore()`].  The percentages are taken from the progress
+/// indicator `VBoxManage` prints, so they're coarse (typically in steps of
+/// 10) and may not be reported at all for quick restores.
+pub fn restore_with_progress<V, S, F>(
+  vid: V,
+  snap_id: Option<S>,
+  on_progress: F
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>,
+  F: FnMut(u8)
+{
+  utils::exec_progress(restore_cmd(vid.borrow(), snap_id)?, on_progress)?;
+
+  Ok(())
+}
+
+
+fn restore_cmd<S>(vid: &VmId, snap_id: Option<S>) -> Result<Command, Error>
+where
+  S: Borrow<SnapshotId>
 {
   if let Some(ref snap_id) = snap_id {
     if let SnapshotId::Name(nm) = snap_id.borrow() {
-      let snaps = get(vid.borrow())?;
+      let snaps = get(vid)?;
       if let Some(snaps) = snaps {
         let snaplist = snaps.get_by_name(nm);
         if snaplist.len() > 1 {
           let s = format!(
             "The VM '{}' has multiple snapshots named '{}'",
-            vid.borrow().to_string(),
+            vid,
             nm
           );
           return Err(Error::Ambiguous(s));
@@ -444,21 +897,340 @@ where
 
   let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
 
-  cmd.arg("snapshot".to_string());
-  cmd.arg(vid.borrow().to_string());
+  cmd.arg("snapshot");
+  cmd.arg(vid.to_string());
   if let Some(snap_id) = snap_id {
-    cmd.arg("restore".to_string());
+    cmd.arg("restore");
     cmd.arg(snap_id.borrow().to_string());
   } else {
-    cmd.arg("restorecurrent".to_string());
+    cmd.arg("restorecurrent");
   }
 
-  utils::exec(cmd)?;
+  Ok(cmd)
+}
+
+
+/// Restore a virtual machine to a snapshot, and return the snapshot which was
+/// restored.
+///
+/// Works like [`restore()`], but resolves the snapshot first so that the
+/// caller learns its name, uuid and description without having to query
+/// again.  If `snap_id` is `None` the current snapshot is restored and
+/// returned.
+pub fn restore_info<V, S>(
+  vid: V,
+  snap_id: Option<S>
+) -> Result<Snapshot, Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>
+{
+  let snaps = match get(vid.borrow())? {
+    Some(snaps) => snaps,
+    None => {
+      let s = format!("Virtual machine '{}' has no snapshots", vid.borrow());
+      return Err(Error::Missing(s));
+    }
+  };
+
+  let snap = match snap_id {
+    Some(ref sid) => {
+      let found = snaps.get(sid.borrow());
+      match found.len() {
+        0 => {
+          let s = format!(
+            "Virtual machine '{}' has no snapshot '{}'",
+            vid.borrow(),
+            sid.borrow()
+          );
+          return Err(Error::Missing(s));
+        }
+        1 => found[0].clone(),
+        _ => {
+          let s = format!(
+            "The VM '{}' has multiple snapshots named '{}'",
+            vid.borrow(),
+            sid.borrow()
+          );
+          return Err(Error::Ambiguous(s));
+        }
+      }
+    }
+    None => match snaps.get_current() {
+      Some(snap) => snap.clone(),
+      None => {
+        let s = format!(
+          "Current snapshot of '{}' is not in the snapshot tree",
+          vid.borrow()
+        );
+        return Err(Error::MissingData(s));
+      }
+    }
+  };
+
+  restore(vid, Some(SnapshotId::Uuid(snap.uuid)))?;
+
+  Ok(snap)
+}
+
 
+/// Export the state of a virtual machine at a snapshot to an appliance file
+/// (typically `.ova`).
+///
+/// This clones the virtual machine from the snapshot to a temporary virtual
+/// machine, exports the clone and then deletes it.  The temporary virtual
+/// machine is deleted even if the export fails.
+pub fn export<V, S, P>(vid: V, snap: S, output: P) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>,
+  P: AsRef<Path>
+{
+  let snapid = match snap.borrow() {
+    SnapshotId::Name(nm) => SnapshotId::Name(nm.clone()),
+    SnapshotId::Uuid(u) => SnapshotId::Uuid(*u)
+  };
+
+  let mut opts = clonevm::CloneOptions::new(export_name(vid.borrow()));
+  opts.snapshot = Some(snapid);
+  opts.mode = Some(clonevm::CloneMode::Machine);
+
+  let tmp = VmId::Uuid(clonevm::clone(vid.borrow(), &opts)?);
+
+  let res = appliance::export(&tmp, output);
+  let cleanup = crate::unregister_vm(&tmp, true);
+
+  // Report the export's error rather than the cleanup's if both fail.
+  res.and(cleanup)
+}
+
+
+/// Name of the temporary virtual machine [`export()`] clones `vid` to.
+///
+/// Uuids are used without braces and anything which isn't safe to use in a
+/// file name (the clone's directory is named after it) is replaced.
+fn export_name(vid: &VmId) -> String {
+  let base = match vid {
+    VmId::Name(nm) => nm.clone(),
+    VmId::Uuid(u) => u.to_simple().to_string()
+  };
+  let base: String = base
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect();
+  format!("{}-export-{}", base, std::process::id())
+}
+
+
+/// Reset a virtual machine to a baseline snapshot.
+///
+/// Makes sure the virtual machine is powered off (killing it if needed and
+/// waiting up to `wait` for it to terminate), and then restores the
+/// `baseline` snapshot.  The virtual machine is left powered off.
+///
+/// Returns `Err(Error::Missing)` if there's no such snapshot and
+/// `Err(Error::Ambiguous)` if `baseline` is a name shared by multiple
+/// snapshots.  The virtual machine is not touched in these cases.
+pub fn reset_to_baseline<V, S>(
+  vid: V,
+  baseline: S,
+  wait: Duration
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  S: Borrow<SnapshotId>
+{
+  let vmi = crate::get_vm_info(vid.borrow())?;
+
+  let found = match vmi.snapshots {
+    Some(ref snaps) => snaps.get(baseline.borrow()).len(),
+    None => 0
+  };
+  match found {
+    0 => {
+      let s = format!(
+        "Virtual machine '{}' has no baseline snapshot '{}'",
+        vid.borrow(),
+        baseline.borrow()
+      );
+      return Err(Error::Missing(s));
+    }
+    1 => {}
+    _ => {
+      let s = format!(
+        "Virtual machine '{}' has multiple snapshots named '{}'",
+        vid.borrow(),
+        baseline.borrow()
+      );
+      return Err(Error::Ambiguous(s));
+    }
+  }
+
+  power_off(vid.borrow(), &vmi.state, wait)?;
+
+  restore(vid, Some(baseline))
+}
+
+
+/// Kill the virtual machine if `state` is a running state, and wait up to
+/// `wait` for it to terminate.
+fn power_off(
+  vid: &VmId,
+  state: &VmState,
+  wait: Duration
+) -> Result<(), Error> {
+  match state {
+    VmState::Starting
+    | VmState::Running
+    | VmState::Paused
+    | VmState::Stopping => {
+      controlvm::kill(vid)?;
+      crate::wait_for_croak(vid, Some((wait, TimeoutAction::Error)))?;
+    }
+    _ => {}
+  }
   Ok(())
 }
 
 
+/// How long a rollback waits for a killed virtual machine to terminate.
+const ROLLBACK_WAIT: Duration = Duration::from_secs(30);
+
+
+/// Restores a virtual machine to, and deletes, a checkpoint snapshot when
+/// dropped, unless disarmed.
+struct CheckpointGuard {
+  vid: VmId,
+  snap: uuid::Uuid,
+  armed: bool
+}
+
+impl CheckpointGuard {
+  /// Power off the virtual machine if it's running, restore it to the
+  /// checkpoint and delete the checkpoint.
+  fn rollback(&mut self) -> Result<(), Error> {
+    self.armed = false;
+    let vmi = crate::get_vm_info(&self.vid)?;
+    power_off(&self.vid, &vmi.state, ROLLBACK_WAIT)?;
+    let sid = SnapshotId::Uuid(self.snap);
+    restore(&self.vid, Some(&sid))?;
+    delete(&self.vid, &sid)
+  }
+
+  /// Keep the virtual machine's current state and delete the checkpoint.
+  fn commit(&mut self) -> Result<(), Error> {
+    self.armed = false;
+    delete(&self.vid, SnapshotId::Uuid(self.snap))
+  }
+}
+
+impl Drop for CheckpointGuard {
+  fn drop(&mut self) {
+    if self.armed {
+      // Best effort; there's no one to report errors to while unwinding.
+      let _ = self.rollback();
+    }
+  }
+}
+
+
+/// Take a snapshot named `name`, run `f`, and then restore the virtual
+/// machine to the snapshot and delete it.
+///
+/// The restore and delete happen regardless of whether `f` succeeds, fails
+/// or panics, which makes this useful for running destructive tests against
+/// a virtual machine.  Restoring a snapshot requires the virtual machine to
+/// not be running, so if `f` leaves it running it is killed first.
+///
+/// If `f` fails its error is returned (even if the rollback fails as well).
+///
+/// Note that a virtual machine which was running when the snapshot was taken
+/// is left in the saved state, not running, after the rollback; use
+/// [`controlvm::start()`] if it needs to be running again.
+pub fn with_checkpoint<V, N, F, T, E>(
+  vid: V,
+  name: N,
+  f: F
+) -> Result<T, Error>
+where
+  V: Borrow<VmId>,
+  N: AsRef<str>,
+  F: FnOnce() -> Result<T, E>,
+  E: Into<Error>
+{
+  let mut guard = take_checkpoint(vid.borrow(), name.as_ref())?;
+
+  match f() {
+    Ok(v) => {
+      guard.rollback()?;
+      Ok(v)
+    }
+    Err(e) => {
+      let _ = guard.rollback();
+      Err(e.into())
+    }
+  }
+}
+
+
+/// Take a snapshot and return a guard which rolls back to it.
+fn take_checkpoint(vid: &VmId, name: &str) -> Result<CheckpointGuard, Error> {
+  take(vid, name)?;
+
+  // The newly taken snapshot becomes the current snapshot; use its uuid
+  // rather than the name to avoid ambiguities.
+  let snap = match get(vid)? {
+    Some(snaps) => snaps.current,
+    None => {
+      let s = format!("Checkpoint snapshot for '{}' vanished", vid);
+      return Err(Error::Missing(s));
+    }
+  };
+
+  Ok(CheckpointGuard {
+    vid: vid.clone(),
+    snap,
+    armed: true
+  })
+}
+
+
+/// Take a snapshot, run `f`, and roll the virtual machine back to the
+/// snapshot if `f` fails.
+///
+/// On success the changes made by `f` are kept and the snapshot is deleted.
+/// If `f` fails (or panics) the virtual machine is killed if it's running,
+/// restored to the snapshot, and the snapshot is deleted; the error from `f`
+/// is returned even if the rollback fails as well.
+///
+/// Most configuration changes require the virtual machine to be powered off,
+/// so it's generally best to call this on a powered off virtual machine.  If
+/// it was running when the snapshot was taken, a rollback leaves it in the
+/// saved state.
+pub fn with_rollback<V, F>(vid: V, f: F) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  F: FnOnce() -> Result<(), Error>
+{
+  let name = format!("vboxhelper-rollback-{}", std::process::id());
+  let mut guard = take_checkpoint(vid.borrow(), &name)?;
+
+  match f() {
+    Ok(()) => guard.commit(),
+    Err(e) => {
+      let _ = guard.rollback();
+      Err(e)
+    }
+  }
+}
+
+
 /// Delete a snapshot.
 ///
 /// Croaks if the snapshot does not exist.
@@ -480,6 +1252,31 @@ where
 }
 
 
+/// Delete all of a virtual machine's snapshots, and return the number of
+/// snapshots deleted.
+///
+/// Snapshots are deleted children before parents.  The virtual machine's
+/// current state is kept.  VirtualBox can delete snapshots of a running
+/// virtual machine, but it's considerably slower and the virtual machine is
+/// paused while each snapshot is merged, so it's best to power it off first.
+pub fn delete_all<V>(vid: V) -> Result<usize, Error>
+where
+  V: Borrow<VmId>
+{
+  let snaps = match get(vid.borrow())? {
+    Some(snaps) => snaps,
+    None => return Ok(0)
+  };
+
+  let order = snaps.leaves_first();
+  for u in &order {
+    delete(vid.borrow(), SnapshotId::Uuid(*u))?;
+  }
+
+  Ok(order.len())
+}
+
+
 /// Just like `delete()` but checks if the snapshot exists first.
 pub fn delete_if_exists<V, S>(vid: V, sid: S) -> Result<(), Error>
 where
@@ -502,4 +1299,646 @@ where
   Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  const BASE: &str = "11111111-1111-1111-1111-111111111111";
+  const CHILD: &str = "22222222-2222-2222-2222-222222222222";
+
+  /// showvminfo output for a virtual machine in `state` with two snapshots;
+  /// `child` is the name of the second one.
+  fn vminfo(state: &str, child: &str) -> String {
+    format!(
+      concat!(
+        "name=\"vm\"\n",
+        "VMState=\"{}\"\n",
+        "SnapshotName=\"base\"\n",
+        "SnapshotUUID=\"{}\"\n",
+        "SnapshotName-1=\"{}\"\n",
+        "SnapshotUUID-1=\"{}\"\n",
+        "CurrentSnapshotName=\"{}\"\n",
+        "CurrentSnapshotUUID=\"{}\"\n"
+      ),
+      state, BASE, child, CHILD, child, CHILD
+    )
+  }
+
+  #[test]
+  fn busy_machine_is_transient() {
+    let runner = Runner::new();
+    runner.fail(concat!(
+      "VBoxManage: error: The machine is not mutable (state is Saving)\n",
+      "VBoxManage: error: Details: code VBOX_E_INVALID_VM_STATE ",
+      "(0x80bb0002)\n"
+    ));
+
+    match take(VmId::from("vm"), "snap") {
+      Err(e @ Error::Busy(_)) => assert!(e.is_transient()),
+      res => panic!("unexpected result {:?}", res)
+    }
+  }
+
+  #[test]
+  fn fleet_snapshot_resumes_all_after_failure() {
+    let runner = Runner::new();
+    runner.ok(&vminfo("running", "child"));
+    runner.ok("");
+    runner.ok(&vminfo("running", "child"));
+    runner.ok("");
+    runner.ok(&vminfo("poweroff", "child"));
+    runner.ok("");
+    runner.ok(&vminfo("running", "child"));
+    runner.fail("VBoxManage: error: Snapshot operation failed");
+
+    let vids = [VmId::from("vm1"), VmId::from("vm2"), VmId::from("vm3")];
+    let res = take_fleet_quiesced(&vids, "fleet");
+    assert!(matches!(res, Err(Error::CommandFailed(_, _))));
+
+    let calls = runner.calls();
+    let tail: Vec<_> = calls[5..].iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      tail,
+      [
+        "snapshot vm1 take fleet",
+        "snapshot vm1 list --machinereadable",
+        "snapshot vm2 take fleet",
+        "controlvm vm1 resume",
+        "controlvm vm2 resume"
+      ]
+    );
+    assert_eq!(calls[1], ["controlvm", "vm1", "pause"]);
+    assert_eq!(calls[3], ["controlvm", "vm2", "pause"]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn restore_reports_progress() {
+    let runner = Runner::new();
+    runner.ok(concat!(
+      "0%...10%...20%...30%...40%...50%...",
+      "60%...70%...80%...90%...100%\n"
+    ));
+    runner.ok("");
+
+    let sid = SnapshotId::Uuid(uuid::Uuid::parse_str(BASE).unwrap());
+    let mut seen = Vec::new();
+    restore_with_progress(VmId::from("vm"), Some(&sid), |p| seen.push(p))
+      .unwrap();
+    assert_eq!(seen, (0..=100).step_by(10).collect::<Vec<u8>>());
+
+    // No progress indicator; no calls.
+    seen.clear();
+    restore_with_progress(VmId::from("vm"), Some(&sid), |p| seen.push(p))
+      .unwrap();
+    assert!(seen.is_empty());
+    assert_eq!(runner.calls()[0], ["snapshot", "vm", "restore", BASE]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn restore_with_progress_reports_failure() {
+    let runner = Runner::new();
+    runner.fail("0%...\nVBoxManage: error: The machine is not mutable\n");
+
+    let sid = SnapshotId::Uuid(uuid::Uuid::parse_str(BASE).unwrap());
+    let mut seen = Vec::new();
+    let res =
+      restore_with_progress(VmId::from("vm"), Some(&sid), |p| seen.push(p));
+    assert!(matches!(res, Err(Error::Busy(_))));
+    assert_eq!(seen, [0]);
+  }
+
+  #[test]
+  fn reset_to_missing_baseline_leaves_vm_alone() {
+    let runner = Runner::new();
+    runner.ok(&vminfo("running", "child"));
+
+    let sid = SnapshotId::from("nope");
+    let wait = Duration::from_secs(1);
+    let res = reset_to_baseline(VmId::from("vm"), &sid, wait);
+    assert!(matches!(res, Err(Error::Missing(_))));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn reset_to_ambiguous_baseline_leaves_vm_alone() {
+    let runner = Runner::new();
+    runner.ok(&vminfo("running", "base"));
+
+    let sid = SnapshotId::from("base");
+    let wait = Duration::from_secs(1);
+    let res = reset_to_baseline(VmId::from("vm"), &sid, wait);
+    assert!(matches!(res, Err(Error::Ambiguous(_))));
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn reset_to_baseline_kills_running_vm_before_restoring() {
+    let runner = Runner::new();
+    runner.ok(&vminfo("running", "child"));
+    runner.ok("");
+    runner.ok(&vminfo("poweroff", "child"));
+    runner.ok(&vminfo("poweroff", "child"));
+
+    let sid = SnapshotId::from("base");
+    reset_to_baseline(VmId::from("vm"), &sid, Duration::from_secs(1)).unwrap();
+
+    // The restore looks the name up again before running.
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 5);
+    assert_eq!(calls[1], ["controlvm", "vm", "poweroff"]);
+    assert_eq!(calls[2][0], "showvminfo");
+    assert_eq!(calls[4], ["snapshot", "vm", "restore", "base"]);
+  }
+
+  #[test]
+  fn reset_to_baseline_restores_powered_off_vm() {
+    let runner = Runner::new();
+    runner.ok(&vminfo("poweroff", "child"));
+    runner.ok(&vminfo("poweroff", "child"));
+
+    let sid = SnapshotId::from("base");
+    reset_to_baseline(VmId::from("vm"), &sid, Duration::from_secs(1)).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[2], ["snapshot", "vm", "restore", "base"]);
+  }
+
+  #[test]
+  fn export_name_is_sanitised() {
+    let pid = std::process::id();
+    let u = uuid::Uuid::parse_str(BASE).unwrap();
+    assert_eq!(
+      export_name(&VmId::Uuid(u)),
+      format!("11111111111111111111111111111111-export-{}", pid)
+    );
+    assert_eq!(
+      export_name(&VmId::from("my vm/1.0")),
+      format!("my_vm_1_0-export-{}", pid)
+    );
+  }
+
+  /// Script the replies for cloning `vm` to the temporary virtual machine
+  /// [`export()`] uses; the clone gets the uuid `CHILD`.
+  fn script_clone(runner: &Runner) -> String {
+    let tmp = export_name(&VmId::from("vm"));
+    runner.ok(&format!("\"vm\" {{{}}}\n", BASE));
+    runner.ok("");
+    runner.ok(&format!("\"vm\" {{{}}}\n\"{}\" {{{}}}\n", BASE, tmp, CHILD));
+    tmp
+  }
+
+  #[test]
+  fn export_clones_exports_and_cleans_up() {
+    let runner = Runner::new();
+    let tmp = script_clone(&runner);
+
+    let sid = SnapshotId::from("base");
+    export(VmId::from("vm"), &sid, "out.ova").unwrap();
+
+    let calls = runner.calls();
+    let clone = format!("{{{}}}", CHILD);
+    assert_eq!(calls.len(), 5);
+    assert_eq!(calls[0], ["list", "vms"]);
+    assert_eq!(
+      calls[1],
+      [
+        "clonevm",
+        "vm",
+        "--name",
+        &tmp,
+        "--register",
+        "--snapshot",
+        "base",
+        "--mode",
+        "machine"
+      ]
+    );
+    assert_eq!(calls[2], ["list", "vms"]);
+    assert_eq!(calls[3], ["export", &clone, "--output", "out.ova"]);
+    assert_eq!(calls[4], ["unregistervm", &clone, "--delete"]);
+  }
+
+  #[test]
+  fn export_cleans_up_after_failure() {
+    let runner = Runner::new();
+    script_clone(&runner);
+    runner.fail("VBoxManage: error: Appliance write failed");
+
+    let sid = SnapshotId::from("base");
+    let res = export(VmId::from("vm"), &sid, "out.ova");
+    assert!(matches!(res, Err(Error::CommandFailed(_, _))));
+
+    let calls = runner.calls();
+    let clone = format!("{{{}}}", CHILD);
+    assert_eq!(calls[3][0], "export");
+    assert_eq!(calls[4], ["unregistervm", &clone, "--delete"]);
+  }
+
+  /// Script the replies for taking a checkpoint and rolling back to it; the
+  /// checkpoint is the current snapshot in `vminfo()`.
+  fn script_checkpoint(runner: &Runner) {
+    runner.ok("");
+    runner.ok(&vminfo("poweroff", "child"));
+  }
+
+  fn script_rollback(runner: &Runner) {
+    runner.ok(&vminfo("poweroff", "child"));
+    runner.ok("");
+    runner.ok("");
+  }
+
+  fn assert_rolled_back(calls: &[Vec<String>]) {
+    assert_eq!(calls.len(), 5);
+    assert_eq!(calls[0], ["snapshot", "vm", "take", "chk"]);
+    assert_eq!(calls[1], ["snapshot", "vm", "list", "--machinereadable"]);
+    assert_eq!(calls[2][0], "showvminfo");
+    assert_eq!(calls[3], ["snapshot", "vm", "restore", CHILD]);
+    assert_eq!(calls[4], ["snapshot", "vm", "delete", CHILD]);
+  }
+
+  #[test]
+  fn checkpoint_rolls_back_on_success() {
+    let runner = Runner::new();
+    script_checkpoint(&runner);
+    script_rollback(&runner);
+
+    let res = with_checkpoint(VmId::from("vm"), "chk", || Ok::<_, Error>(42));
+    assert_eq!(res.unwrap(), 42);
+    assert_rolled_back(&runner.calls());
+  }
+
+  #[test]
+  fn checkpoint_rolls_back_and_returns_error() {
+    let runner = Runner::new();
+    script_checkpoint(&runner);
+    script_rollback(&runner);
+
+    let res = with_checkpoint(VmId::from("vm"), "chk", || {
+      Err::<(), _>(Error::Busy("in use".to_string()))
+    });
+    assert!(matches!(res, Err(Error::Busy(_))));
+    assert_rolled_back(&runner.calls());
+  }
+
+  #[test]
+  fn checkpoint_rolls_back_on_panic() {
+    let runner = Runner::new();
+    script_checkpoint(&runner);
+    script_rollback(&runner);
+
+    let res = std::panic::catch_unwind(|| {
+      with_checkpoint(VmId::from("vm"), "chk", || -> Result<(), Error> {
+        panic!("test failed")
+      })
+    });
+    assert!(res.is_err());
+    assert_rolled_back(&runner.calls());
+  }
+
+  #[test]
+  fn rollback_keeps_changes_on_success() {
+    let runner = Runner::new();
+    script_checkpoint(&runner);
+
+    with_rollback(VmId::from("vm"), || Ok(())).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[0][..3], ["snapshot", "vm", "take"]);
+    assert_eq!(calls[2], ["snapshot", "vm", "delete", CHILD]);
+  }
+
+  #[test]
+  fn rollback_restores_on_failure() {
+    let runner = Runner::new();
+    script_checkpoint(&runner);
+    script_rollback(&runner);
+
+    let res = with_rollback(VmId::from("vm"), || {
+      Err(Error::InvalidArgument("bad".to_string()))
+    });
+    assert!(matches!(res, Err(Error::InvalidArgument(_))));
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 5);
+    assert_eq!(calls[3], ["snapshot", "vm", "restore", CHILD]);
+    assert_eq!(calls[4], ["snapshot", "vm", "delete", CHILD]);
+  }
+
+  const A: &str = "aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa";
+  const B: &str = "bbbbbbbb-bbbb-bbbb-bbbb-bbbbbbbbbbbb";
+  const C: &str = "cccccccc-cccc-cccc-cccc-cccccccccccc";
+
+  /// `snapshot list` output for the tree
+  ///
+  /// ```text
+  /// base -+- a --- c (current)
+  ///       +- b
+  /// ```
+  fn tree() -> String {
+    named_tree("a", "b", "c")
+  }
+
+  /// The same tree as [`tree()`], with the snapshots other than the root
+  /// named `a`, `b` and `c`.
+  fn named_tree(a: &str, b: &str, c: &str) -> String {
+    format!(
+      concat!(
+        "SnapshotName=\"base\"\n",
+        "SnapshotUUID=\"{}\"\n",
+        "SnapshotName-1=\"{}\"\n",
+        "SnapshotUUID-1=\"{}\"\n",
+        "SnapshotName-1-1=\"{}\"\n",
+        "SnapshotUUID-1-1=\"{}\"\n",
+        "SnapshotName-2=\"{}\"\n",
+        "SnapshotUUID-2=\"{}\"\n",
+        "CurrentSnapshotName=\"{}\"\n",
+        "CurrentSnapshotUUID=\"{}\"\n"
+      ),
+      BASE, a, A, c, C, b, B, c, C
+    )
+  }
+
+  /// Timestamps for [`tree()`], in the order `base`, `a`, `c`, `b`.
+  fn tree_xml(ts: [&str; 4]) -> String {
+    let mut xml = String::new();
+    for (u, ts) in [BASE, A, C, B].iter().zip(ts.iter()) {
+      xml.push_str(&format!(
+        "<Snapshot uuid=\"{{{}}}\" name=\"x\" timeStamp=\"{}\">\n",
+        u, ts
+      ));
+    }
+    xml
+  }
+
+  #[test]
+  fn latest_without_timestamps_is_deepest() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+
+    let snaps = get(VmId::from("vm")).unwrap().unwrap();
+    assert_eq!(snaps.latest().unwrap().name, "c");
+  }
+
+  #[test]
+  fn latest_uses_timestamps() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+
+    let mut snaps = get(VmId::from("vm")).unwrap().unwrap();
+    snaps.timestamps_from_xml(&tree_xml([
+      "2023-05-01T10:00:00Z",
+      "2023-05-02T10:00:00Z",
+      "2023-05-03T10:00:00Z",
+      "2023-05-04T10:00:00Z"
+    ]));
+
+    let c = snaps.get_by_uuid(&uuid::Uuid::parse_str(C).unwrap()).unwrap();
+    assert_eq!(c.timestamp.as_deref(), Some("2023-05-03T10:00:00Z"));
+    assert_eq!(snaps.get_current().unwrap().name, "c");
+    assert_eq!(snaps.latest().unwrap().name, "b");
+  }
+
+  #[test]
+  fn load_timestamps_reports_unreadable_file() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+
+    let mut snaps = get(VmId::from("vm")).unwrap().unwrap();
+    let res = snaps.load_timestamps("/nonexistent/vm.vbox");
+    assert!(matches!(res, Err(Error::IO(_))));
+  }
+
+  #[test]
+  fn resolve_duplicate_names_by_policy() {
+    let runner = Runner::new();
+    runner.ok(&named_tree("dup", "dup", "dup"));
+
+    let mut snaps = get(VmId::from("vm")).unwrap().unwrap();
+    let dup = SnapshotId::from("dup");
+    let uuid = |snap: &Snapshot| snap.uuid.to_string();
+
+    assert!(matches!(
+      snaps.resolve(&dup, AmbiguityPolicy::Error),
+      Err(Error::Ambiguous(_))
+    ));
+    assert!(matches!(
+      snaps.resolve(SnapshotId::from("nope"), AmbiguityPolicy::First),
+      Err(Error::Missing(_))
+    ));
+    let base = snaps.resolve(SnapshotId::from("base"), AmbiguityPolicy::Error);
+    assert_eq!(uuid(base.unwrap()), BASE);
+
+    // Tree order is base, a, c, b.
+    let first = snaps.resolve(&dup, AmbiguityPolicy::First).unwrap();
+    assert_eq!(uuid(first), A);
+    let latest = snaps.resolve(&dup, AmbiguityPolicy::Latest).unwrap();
+    assert_eq!(uuid(latest), B);
+
+    // With timestamps the newest wins, regardless of tree order.
+    snaps.timestamps_from_xml(&tree_xml([
+      "2023-05-01T10:00:00Z",
+      "2023-05-02T10:00:00Z",
+      "2023-05-04T10:00:00Z",
+      "2023-05-03T10:00:00Z"
+    ]));
+    let latest = snaps.resolve(&dup, AmbiguityPolicy::Latest).unwrap();
+    assert_eq!(uuid(latest), C);
+  }
+
+  #[test]
+  fn resolve_reports_vm_in_errors() {
+    let runner = Runner::new();
+    runner.ok(&named_tree("dup", "dup", "dup"));
+
+    let dup = SnapshotId::from("dup");
+    match resolve(VmId::from("vm"), &dup, AmbiguityPolicy::Error) {
+      Err(Error::Ambiguous(s)) => assert!(s.contains("'vm'")),
+      res => panic!("unexpected result {:?}", res)
+    }
+    assert_eq!(
+      runner.calls(),
+      [["snapshot", "vm", "list", "--machinereadable"]]
+    );
+  }
+
+  #[test]
+  fn delete_all_deletes_children_first() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+
+    assert_eq!(delete_all(VmId::from("vm")).unwrap(), 4);
+
+    let deleted: Vec<_> =
+      runner.calls()[1..].iter().map(|c| c[3].clone()).collect();
+    assert_eq!(deleted, [C, A, B, BASE]);
+  }
+
+  #[test]
+  fn delete_all_without_snapshots() {
+    let runner = Runner::new();
+    runner.ok("This machine does not have any snapshots\n");
+
+    assert_eq!(delete_all(VmId::from("vm")).unwrap(), 0);
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn get_from_map_with_index_gaps() {
+    let map: HashMap<String, String> = [
+      ("SnapshotName", "base"),
+      ("SnapshotUUID", BASE),
+      ("SnapshotName-1", "one"),
+      ("SnapshotUUID-1", A),
+      ("SnapshotName-3", "three"),
+      ("SnapshotUUID-3", B),
+      ("SnapshotName-3-2", "three-two"),
+      ("SnapshotUUID-3-2", C),
+      ("CurrentSnapshotName", "three-two"),
+      ("CurrentSnapshotUUID", C)
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    let snaps = get_from_map(&map).unwrap().unwrap();
+    let uuid = |s: &str| uuid::Uuid::parse_str(s).unwrap();
+    let root = snaps.get_root().unwrap();
+    assert_eq!(root.name, "base");
+    assert_eq!(root.children, [uuid(A), uuid(B)]);
+    assert_eq!(snaps.get_by_uuid(&uuid(A)).unwrap().name, "one");
+    let three = snaps.get_by_uuid(&uuid(B)).unwrap();
+    assert_eq!(three.name, "three");
+    assert_eq!(three.children, [uuid(C)]);
+    assert_eq!(snaps.get_by_uuid(&uuid(C)).unwrap().name, "three-two");
+    assert_eq!(snaps.map.len(), 4);
+    assert_eq!(snaps.leaves_first().len(), 4);
+  }
+
+  #[test]
+  fn take_live_args() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"running\"\n");
+
+    take_live(VmId::from("vm"), "snap", Some("before upgrade")).unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(
+      calls[1],
+      [
+        "snapshot",
+        "vm",
+        "take",
+        "snap",
+        "--description",
+        "before upgrade",
+        "--live"
+      ]
+    );
+  }
+
+  #[test]
+  fn take_live_requires_running_vm() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nVMState=\"poweroff\"\n");
+
+    assert!(matches!(
+      take_live(VmId::from("vm"), "snap", None),
+      Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(runner.calls(), [["showvminfo", "vm", "--machinereadable"]]);
+  }
+
+  #[test]
+  fn has_unsaved_changes_parses_yes_no() {
+    let runner = Runner::new();
+    runner.ok("name=\"vm\"\nCurrentStateModified=\"yes\"\n");
+    runner.ok("name=\"vm\"\nCurrentStateModified=\"no\"\n");
+    runner.ok("name=\"vm\"\n");
+
+    assert!(has_unsaved_changes(VmId::from("vm")).unwrap());
+    assert!(!has_unsaved_changes(VmId::from("vm")).unwrap());
+    assert!(!has_unsaved_changes(VmId::from("vm")).unwrap());
+  }
+
+  #[test]
+  fn rename_checked_refuses_collisions() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+
+    let a = SnapshotId::from("a");
+    match rename_checked(VmId::from("vm"), &a, "b", false) {
+      Err(Error::Ambiguous(s)) => assert!(s.contains("'b'")),
+      res => panic!("unexpected result {:?}", res)
+    }
+    assert_eq!(runner.calls().len(), 1);
+  }
+
+  #[test]
+  fn rename_checked_renames_by_uuid() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+    runner.ok("");
+    runner.ok(&tree());
+    runner.ok("");
+    runner.ok(&tree());
+    runner.ok("");
+
+    let a = SnapshotId::from("a");
+    rename_checked(VmId::from("vm"), &a, "d", false).unwrap();
+    rename_checked(VmId::from("vm"), &a, "a", false).unwrap();
+    rename_checked(VmId::from("vm"), &a, "b", true).unwrap();
+
+    let edits: Vec<_> = runner
+      .calls()
+      .iter()
+      .filter(|c| c[2] == "edit")
+      .map(|c| c.join(" "))
+      .collect();
+    assert_eq!(
+      edits,
+      [
+        format!("snapshot vm edit {} --name=d", A),
+        format!("snapshot vm edit {} --name=a", A),
+        format!("snapshot vm edit {} --name=b", A)
+      ]
+    );
+  }
+
+  #[test]
+  fn restore_info_returns_restored_snapshot() {
+    let runner = Runner::new();
+    runner.ok(&tree());
+    runner.ok("");
+    runner.ok(&tree());
+
+    let vid = VmId::from("vm");
+    let snap = restore_info(&vid, Some(SnapshotId::from("b"))).unwrap();
+    assert_eq!(snap.name, "b");
+    assert_eq!(snap.uuid.to_string(), B);
+
+    let snap = restore_info(&vid, None::<SnapshotId>).unwrap();
+    assert_eq!(snap.name, "c");
+    assert_eq!(snap.uuid.to_string(), C);
+
+    let calls: Vec<String> =
+      runner.calls().iter().map(|c| c.join(" ")).collect();
+    assert_eq!(
+      calls,
+      [
+        "snapshot vm list --machinereadable".to_string(),
+        format!("snapshot vm restore {}", B),
+        "snapshot vm list --machinereadable".to_string(),
+        format!("snapshot vm restore {}", C)
+      ]
+    );
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
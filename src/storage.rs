@@ -1,16 +1,228 @@
 use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::path::Path;
 use std::process::Command;
 
 use crate::platform;
+use crate::strutils::onoff;
 use crate::utils;
 use crate::Error;
 use crate::VmId;
 
 pub enum Type {
-  DvdDrive
+  DvdDrive,
+
+  /// A hard disk.
+  HardDisk,
+
+  /// A floppy disk drive.  Floppies are a legacy device which is mostly
+  /// useful for feeding drivers to old guest operating systems during
+  /// installation.
+  Floppy
+}
+
+impl Type {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      Type::DvdDrive => "dvddrive",
+      Type::HardDisk => "hdd",
+      Type::Floppy => "fdd"
+    }
+  }
+}
+
+
+/// Storage controller bus types.
+pub enum Bus {
+  IDE,
+  SATA,
+  SCSI,
+  SAS,
+  USB,
+  PCIe,
+  VirtIO,
+
+  /// Floppy controller.  A virtual machine can only have a single floppy
+  /// controller, and it is only of interest for legacy guests.
+  Floppy
+}
+
+impl Bus {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      Bus::IDE => "ide",
+      Bus::SATA => "sata",
+      Bus::SCSI => "scsi",
+      Bus::SAS => "sas",
+      Bus::USB => "usb",
+      Bus::PCIe => "pcie",
+      Bus::VirtIO => "virtio",
+      Bus::Floppy => "floppy"
+    }
+  }
+
+  /// Maximum number of ports VirtualBox supports for a controller on this
+  /// bus.
+  pub fn max_ports(&self) -> u8 {
+    match self {
+      Bus::IDE => 2,
+      Bus::SATA => 30,
+      Bus::SCSI => 16,
+      Bus::SAS => 255,
+      Bus::USB => 8,
+      Bus::PCIe => 255,
+      Bus::VirtIO => 255,
+      Bus::Floppy => 1
+    }
+  }
+}
+
+
+/// Optional storage controller settings.  Settings which are `None` are left
+/// to VirtualBox's defaults.
+#[derive(Default)]
+pub struct ControllerOptions {
+  /// Number of ports on the controller.  Must be between 1 and the bus'
+  /// [`Bus::max_ports()`].
+  pub port_count: Option<u8>,
+
+  /// Whether the host's I/O cache should be used for the controller.
+  pub host_io_cache: Option<bool>,
+
+  /// Whether the BIOS may boot from the controller.
+  pub bootable: Option<bool>
+}
+
+/// A storage controller as reported by `showvminfo`.
+#[derive(Debug, Clone)]
+pub struct Controller {
+  pub name: String,
+
+  /// Controller chipset type, for example `IntelAhci` or `PIIX4`.
+  pub ctype: String,
+  pub port_count: u8,
+  pub bootable: bool
+}
+
+
+/// A medium attached to one of a virtual machine's storage controllers.
+#[derive(Debug, Clone)]
+pub struct MediumRef {
+  /// Name of the controller the medium is attached to.
+  pub controller: String,
+  pub port: u8,
+  pub device: u8,
+
+  /// Location of the medium, or `None` for an empty removable drive.
+  pub medium: Option<String>,
+  pub uuid: Option<uuid::Uuid>,
+
+  /// `true` for removable (DVD and floppy) drives.
+  pub removable: bool
+}
+
+
+/// Parse a virtual machine's storage controllers from a virtual machine
+/// information map.
+pub fn get_controllers_from_map(
+  map: &HashMap<String, String>
+) -> Result<Vec<Controller>, Error> {
+  let mut ctrls = Vec::new();
+
+  let mut idx = 0;
+  while let Some(name) = map.get(&format!("storagecontrollername{}", idx)) {
+    let ctype = map
+      .get(&format!("storagecontrollertype{}", idx))
+      .cloned()
+      .unwrap_or_default();
+
+    let port_count =
+      match map.get(&format!("storagecontrollerportcount{}", idx)) {
+        Some(n) => match n.parse::<u8>() {
+          Ok(n) => n,
+          Err(_) => {
+            let s = format!("Unable to parse port count '{}'", n);
+            return Err(Error::BadFormat(s));
+          }
+        },
+        None => 0
+      };
+
+    let bootable = map
+      .get(&format!("storagecontrollerbootable{}", idx))
+      .map(|b| b == "on")
+      .unwrap_or(false);
+
+    ctrls.push(Controller {
+      name: name.clone(),
+      ctype,
+      port_count,
+      bootable
+    });
+
+    idx += 1;
+  }
+
+  Ok(ctrls)
 }
 
+
+/// Parse the media attached to a virtual machine's storage controllers from a
+/// virtual machine information map.
+pub fn get_from_map(
+  map: &HashMap<String, String>
+) -> Result<Vec<MediumRef>, Error> {
+  let ctrls = get_controllers_from_map(map)?;
+
+  let mut media = Vec::new();
+
+  // Attachments are reported as "<controller>-<port>-<device>"="<medium>",
+  // with the medium's uuid in "<controller>-ImageUUID-<port>-<device>".
+  // Removable drives additionally have a "<controller>-IsEjected-..." key.
+  for ctrl in &ctrls {
+    for port in 0..ctrl.port_count {
+      for device in 0..2 {
+        let key = format!("{}-{}-{}", ctrl.name, port, device);
+        let medium = match map.get(&key) {
+          Some(m) if m == "none" => continue,
+          Some(m) if m == "emptydrive" => None,
+          Some(m) => Some(m.clone()),
+          None => continue
+        };
+
+        let uuid_key =
+          format!("{}-ImageUUID-{}-{}", ctrl.name, port, device);
+        let uuid = match map.get(&uuid_key) {
+          Some(u) => match uuid::Uuid::parse_str(u) {
+            Ok(u) => Some(u),
+            Err(_) => {
+              let s =
+                format!("Unable to parse UUID '{}' for '{}'", u, uuid_key);
+              return Err(Error::BadFormat(s));
+            }
+          },
+          None => None
+        };
+
+        let ejected_key =
+          format!("{}-IsEjected-{}-{}", ctrl.name, port, device);
+
+        media.push(MediumRef {
+          controller: ctrl.name.clone(),
+          port,
+          device,
+          removable: medium.is_none() || map.contains_key(&ejected_key),
+          medium,
+          uuid
+        });
+      }
+    }
+  }
+
+  Ok(media)
+}
+
+
 pub struct IdeInfo {
   pub port: u8,
   pub device: u8,
@@ -47,11 +259,7 @@ where
       cmd.arg("--device");
       cmd.arg(info.device.to_string());
       cmd.arg("--type");
-      match info.stype {
-        Type::DvdDrive => {
-          cmd.arg("dvddrive");
-        }
-      }
+      cmd.arg(info.stype.as_arg());
       cmd.arg("--medium");
       cmd.arg(medium.as_ref());
     }
@@ -62,4 +270,369 @@ where
   Ok(())
 }
 
+
+/// Add a storage controller named `name` to a virtual machine.
+///
+/// Returns `Err(Error::InvalidArgument)` if the requested port count isn't
+/// supported by the bus type.  The virtual machine must be powered off.
+pub fn create_controller<V, N>(
+  vid: V,
+  name: N,
+  bus: Bus,
+  opts: &ControllerOptions
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  N: AsRef<str>
+{
+  // VBoxManage storagectl $VM --name "SATA" --add sata [--portcount <n>] \
+  //  [--hostiocache on|off] [--bootable on|off]
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("storagectl");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--name");
+  cmd.arg(name.as_ref());
+  cmd.arg("--add");
+  cmd.arg(bus.as_arg());
+
+  if let Some(n) = opts.port_count {
+    if n == 0 || n > bus.max_ports() {
+      let s = format!(
+        "Port count {} out of range; {} controllers support 1-{} ports",
+        n,
+        bus.as_arg(),
+        bus.max_ports()
+      );
+      return Err(Error::InvalidArgument(s));
+    }
+    cmd.arg("--portcount");
+    cmd.arg(n.to_string());
+  }
+  if let Some(cache) = opts.host_io_cache {
+    cmd.arg("--hostiocache");
+    cmd.arg(onoff(cache));
+  }
+  if let Some(bootable) = opts.bootable {
+    cmd.arg("--bootable");
+    cmd.arg(onoff(bootable));
+  }
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Attach a floppy disk image to a floppy controller.
+///
+/// Floppy drives are legacy hardware; they are supported for the benefit of
+/// old guest operating systems which need drivers fed to them during
+/// installation.  The controller named `controller` must be a floppy
+/// controller (see [`create_controller()`] and [`Bus::Floppy`]).  Floppy
+/// controllers have a single port with two devices.
+pub fn attach_floppy<V, C, P>(
+  vid: V,
+  controller: C,
+  port: u8,
+  device: u8,
+  image: P
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  C: AsRef<str>,
+  P: AsRef<Path>
+{
+  // VBoxManage storageattach $VM --storagectl "Floppy" --port 0 --device 0 \
+  //  --type fdd --medium drivers.img
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("storageattach");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--storagectl");
+  cmd.arg(controller.as_ref());
+  cmd.arg("--port");
+  cmd.arg(port.to_string());
+  cmd.arg("--device");
+  cmd.arg(device.to_string());
+  cmd.arg("--type");
+  cmd.arg("fdd");
+  cmd.arg("--medium");
+  cmd.arg(image.as_ref());
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// Attach one of the host's optical drives (see [`list::host_dvds()`]) to a
+/// DVD drive of a virtual machine.
+///
+/// [`list::host_dvds()`]: crate::list::host_dvds
+pub fn attach_host_dvd<V, C, H>(
+  vid: V,
+  controller: C,
+  port: u8,
+  device: u8,
+  host_drive: H
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  C: AsRef<str>,
+  H: AsRef<str>
+{
+  // VBoxManage storageattach $VM --storagectl "SATA" --port 1 --device 0 \
+  //  --type dvddrive --medium host:/dev/sr0
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("storageattach");
+  cmd.arg(vid.borrow().to_string());
+  cmd.arg("--storagectl");
+  cmd.arg(controller.as_ref());
+  cmd.arg("--port");
+  cmd.arg(port.to_string());
+  cmd.arg("--device");
+  cmd.arg(device.to_string());
+  cmd.arg("--type");
+  cmd.arg("dvddrive");
+  cmd.arg("--medium");
+  cmd.arg(format!("host:{}", host_drive.as_ref()));
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+/// A single medium attachment, see [`StorageAttach`].
+pub struct Attachment {
+  /// Name of the storage controller to attach the medium to.
+  pub controller: String,
+  pub port: u8,
+  pub device: u8,
+  pub stype: Type,
+
+  /// The medium; a path to an image file, a medium uuid or one of the
+  /// special values `emptydrive`, `none` or `host:<drive>`.
+  pub medium: String
+}
+
+
+/// A set of medium attachments to apply to a virtual machine in one go.
+///
+/// `VBoxManage storageattach` only handles a single attachment per
+/// invocation, so the attachments are applied one after another, in the
+/// order they were added.  A failed attachment does not stop the remaining
+/// ones from being applied.
+///
+/// ```no_run
+/// use vboxhelper::storage::{StorageAttach, Type};
+/// use vboxhelper::VmId;
+///
+/// let mut sa = StorageAttach::new(VmId::from("myvm"));
+/// sa.add("SATA", 0, 0, Type::HardDisk, "/vms/myvm/disk.vdi");
+/// sa.add("SATA", 1, 0, Type::DvdDrive, "/isos/install.iso");
+/// for (att, res) in sa.attachments.iter().zip(sa.apply_all()) {
+///   if let Err(e) = res {
+///     eprintln!("{}-{}-{}: {:?}", att.controller, att.port, att.device, e);
+///   }
+/// }
+/// ```
+pub struct StorageAttach {
+  pub vid: VmId,
+  pub attachments: Vec<Attachment>
+}
+
+impl StorageAttach {
+  pub fn new(vid: VmId) -> Self {
+    StorageAttach {
+      vid,
+      attachments: Vec::new()
+    }
+  }
+
+  /// Queue an attachment.
+  pub fn add<C, M>(
+    &mut self,
+    controller: C,
+    port: u8,
+    device: u8,
+    stype: Type,
+    medium: M
+  ) -> &mut Self
+  where
+    C: AsRef<str>,
+    M: AsRef<str>
+  {
+    self.attachments.push(Attachment {
+      controller: controller.as_ref().to_string(),
+      port,
+      device,
+      stype,
+      medium: medium.as_ref().to_string()
+    });
+    self
+  }
+
+  /// Apply all queued attachments, in order.
+  ///
+  /// Returns one result per attachment, in the same order as
+  /// [`attachments`](StorageAttach::attachments).
+  pub fn apply_all(&self) -> Vec<Result<(), Error>> {
+    self
+      .attachments
+      .iter()
+      .map(|att| attach_one(&self.vid, att))
+      .collect()
+  }
+}
+
+
+fn attach_one(vid: &VmId, att: &Attachment) -> Result<(), Error> {
+  // VBoxManage storageattach $VM --storagectl <ctrl> --port <n> \
+  //  --device <n> --type <type> --medium <medium>
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("storageattach");
+  cmd.arg(vid.to_string());
+  cmd.arg("--storagectl");
+  cmd.arg(&att.controller);
+  cmd.arg("--port");
+  cmd.arg(att.port.to_string());
+  cmd.arg("--device");
+  cmd.arg(att.device.to_string());
+  cmd.arg("--type");
+  cmd.arg(att.stype.as_arg());
+  cmd.arg("--medium");
+  cmd.arg(&att.medium);
+
+  utils::exec(cmd)?;
+
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  #[test]
+  fn sata_controller_with_30_ports() {
+    let runner = Runner::new();
+    let opts = ControllerOptions {
+      port_count: Some(30),
+      host_io_cache: Some(false),
+      bootable: Some(true)
+    };
+    create_controller(VmId::from("vm"), "SATA", Bus::SATA, &opts).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [[
+        "storagectl",
+        "vm",
+        "--name",
+        "SATA",
+        "--add",
+        "sata",
+        "--portcount",
+        "30",
+        "--hostiocache",
+        "off",
+        "--bootable",
+        "on"
+      ]]
+    );
+  }
+
+  #[test]
+  fn port_count_beyond_bus_limit_is_rejected() {
+    let runner = Runner::new();
+    let opts = ControllerOptions {
+      port_count: Some(31),
+      ..Default::default()
+    };
+    let res = create_controller(VmId::from("vm"), "SATA", Bus::SATA, &opts);
+    assert!(matches!(res, Err(Error::InvalidArgument(_))));
+
+    let opts = ControllerOptions {
+      port_count: Some(0),
+      ..Default::default()
+    };
+    let res = create_controller(VmId::from("vm"), "SATA", Bus::SATA, &opts);
+    assert!(matches!(res, Err(Error::InvalidArgument(_))));
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn floppy_controller_and_image() {
+    let runner = Runner::new();
+    let opts = ControllerOptions::default();
+    create_controller(VmId::from("vm"), "Floppy", Bus::Floppy, &opts).unwrap();
+    attach_floppy(VmId::from("vm"), "Floppy", 0, 1, "drivers.img").unwrap();
+
+    let calls = runner.calls();
+    assert_eq!(calls[0].join(" "), "storagectl vm --name Floppy --add floppy");
+    assert_eq!(
+      calls[1].join(" "),
+      "storageattach vm --storagectl Floppy --port 0 --device 1 --type fdd \
+       --medium drivers.img"
+    );
+  }
+
+  #[test]
+  fn apply_all_reports_each_attachment() {
+    let runner = Runner::new();
+    runner.ok("");
+    runner.fail("VBoxManage: error: Could not find file for the medium");
+    runner.ok("");
+
+    let mut sa = StorageAttach::new(VmId::from("vm"));
+    sa.add("SATA", 0, 0, Type::HardDisk, "disk.vdi")
+      .add("SATA", 1, 0, Type::DvdDrive, "missing.iso")
+      .add("SATA", 2, 0, Type::DvdDrive, "tools.iso");
+    let res = sa.apply_all();
+
+    assert_eq!(res.len(), 3);
+    assert!(res[0].is_ok());
+    assert!(matches!(res[1], Err(Error::CommandFailed(_, _))));
+    assert!(res[2].is_ok());
+
+    let calls = runner.calls();
+    assert_eq!(
+      calls[1],
+      [
+        "storageattach",
+        "vm",
+        "--storagectl",
+        "SATA",
+        "--port",
+        "1",
+        "--device",
+        "0",
+        "--type",
+        "dvddrive",
+        "--medium",
+        "missing.iso"
+      ]
+    );
+    let media: Vec<&str> = calls.iter().map(|c| c[11].as_str()).collect();
+    assert_eq!(media, ["disk.vdi", "missing.iso", "tools.iso"]);
+  }
+
+  #[test]
+  fn host_dvd_args() {
+    let runner = Runner::new();
+    attach_host_dvd(VmId::from("vm"), "SATA", 1, 0, "/dev/sr0").unwrap();
+
+    assert_eq!(
+      runner.calls()[0].join(" "),
+      "storageattach vm --storagectl SATA --port 1 --device 0 \
+       --type dvddrive --medium host:/dev/sr0"
+    );
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
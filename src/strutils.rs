@@ -1,15 +1,16 @@
 #[derive(PartialEq, Eq)]
 pub(crate) enum EmptyLine {
+  #[allow(dead_code)]
   Keep,
   Ignore
 }
 
-pub(crate) fn buf_to_strlines(buf: &Vec<u8>, el: EmptyLine) -> Vec<String> {
-  let sbuf = std::str::from_utf8(&buf).expect("Buffer not UTF-8");
+pub(crate) fn buf_to_strlines(buf: &[u8], el: EmptyLine) -> Vec<String> {
+  let sbuf = std::str::from_utf8(buf).expect("Buffer not UTF-8");
 
   let mut out = Vec::new();
-  for line in sbuf.split("\n") {
-    if line.len() == 0 && el == EmptyLine::Ignore {
+  for line in sbuf.split('\n') {
+    if line.is_empty() && el == EmptyLine::Ignore {
       continue;
     }
     out.push(line.to_string());
@@ -18,4 +19,14 @@ pub(crate) fn buf_to_strlines(buf: &Vec<u8>, el: EmptyLine) -> Vec<String> {
   out
 }
 
+
+/// Get the `on`/`off` argument `VBoxManage` uses for boolean options.
+pub(crate) fn onoff(b: bool) -> &'static str {
+  if b {
+    "on"
+  } else {
+    "off"
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
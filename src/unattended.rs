@@ -0,0 +1,194 @@
+//! Unattended guest operating system installation.
+//!
+//! VirtualBox can prepare an installation medium which installs a guest
+//! operating system without any user interaction.  The virtual machine must
+//! already be created and configured with an appropriate OS type.
+
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::platform;
+use crate::utils;
+use crate::{Error, VmId};
+
+
+/// Options for an unattended installation.
+///
+/// Fields which are `None` are left to VirtualBox's defaults.
+#[derive(Clone)]
+pub struct UnattendedOptions {
+  /// Login name of the user account to create.
+  pub user: String,
+
+  /// Password of the user account (and the administrator/root account).
+  pub password: String,
+
+  pub full_user_name: Option<String>,
+
+  /// Fully qualified host name of the guest.
+  pub hostname: Option<String>,
+
+  /// Locale in `ll_CC` form, for example `en_US`.
+  pub locale: Option<String>,
+
+  pub country: Option<String>,
+  pub time_zone: Option<String>,
+
+  /// Product key, for guest operating systems which require one.
+  pub product_key: Option<String>,
+
+  /// Install the Guest Additions as part of the installation.
+  pub install_additions: bool,
+
+  /// Guest Additions ISO to use, if not the one bundled with VirtualBox.
+  pub additions_iso: Option<PathBuf>
+}
+
+impl UnattendedOptions {
+  pub fn new<U, P>(user: U, password: P) -> Self
+  where
+    U: AsRef<str>,
+    P: AsRef<str>
+  {
+    UnattendedOptions {
+      user: user.as_ref().to_string(),
+      password: password.as_ref().to_string(),
+      full_user_name: None,
+      hostname: None,
+      locale: None,
+      country: None,
+      time_zone: None,
+      product_key: None,
+      install_additions: false,
+      additions_iso: None
+    }
+  }
+}
+
+
+/// Start an unattended installation of the operating system on the `iso`
+/// installation medium.
+///
+/// The password is not included in the command line stored in any errors
+/// returned.
+pub fn install<V, P>(
+  vid: V,
+  iso: P,
+  opts: &UnattendedOptions
+) -> Result<(), Error>
+where
+  V: Borrow<VmId>,
+  P: AsRef<Path>
+{
+  // VBoxManage unattended install $VM --iso=<iso> --user=<user> \
+  //  --password=<pass> [--hostname=<fqdn>] [--install-additions] ...
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("unattended");
+  cmd.arg("install");
+  cmd.arg(vid.borrow().to_string());
+
+  cmd.arg("--iso");
+  cmd.arg(iso.as_ref());
+  cmd.arg("--user");
+  cmd.arg(&opts.user);
+  cmd.arg("--password");
+  cmd.arg(&opts.password);
+
+  if let Some(ref full_name) = opts.full_user_name {
+    cmd.arg("--full-user-name");
+    cmd.arg(full_name);
+  }
+  if let Some(ref hostname) = opts.hostname {
+    cmd.arg("--hostname");
+    cmd.arg(hostname);
+  }
+  if let Some(ref locale) = opts.locale {
+    cmd.arg("--locale");
+    cmd.arg(locale);
+  }
+  if let Some(ref country) = opts.country {
+    cmd.arg("--country");
+    cmd.arg(country);
+  }
+  if let Some(ref tz) = opts.time_zone {
+    cmd.arg("--time-zone");
+    cmd.arg(tz);
+  }
+  if let Some(ref key) = opts.product_key {
+    cmd.arg("--key");
+    cmd.arg(key);
+  }
+  if opts.install_additions {
+    cmd.arg("--install-additions");
+  }
+  if let Some(ref iso) = opts.additions_iso {
+    cmd.arg("--additions-iso");
+    cmd.arg(iso);
+  }
+
+  utils::exec_redacted(cmd, &[&opts.password])?;
+
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  fn options() -> UnattendedOptions {
+    let mut opts = UnattendedOptions::new("alice", "hunter2");
+    opts.hostname = Some("guest.example.org".to_string());
+    opts.locale = Some("en_US".to_string());
+    opts.time_zone = Some("UTC".to_string());
+    opts.install_additions = true;
+    opts
+  }
+
+  #[test]
+  fn install_args() {
+    let runner = Runner::new();
+    install(VmId::from("vm"), "/iso/os.iso", &options()).unwrap();
+
+    assert_eq!(
+      runner.calls(),
+      [vec![
+        "unattended",
+        "install",
+        "vm",
+        "--iso",
+        "/iso/os.iso",
+        "--user",
+        "alice",
+        "--password",
+        "hunter2",
+        "--hostname",
+        "guest.example.org",
+        "--locale",
+        "en_US",
+        "--time-zone",
+        "UTC",
+        "--install-additions"
+      ]]
+    );
+  }
+
+  #[test]
+  fn install_error_hides_password() {
+    let runner = Runner::new();
+    runner.fail("VBoxManage: error: Unattended installation failed\n");
+
+    match install(VmId::from("vm"), "/iso/os.iso", &options()) {
+      Err(Error::CommandFailed(cmdstr, _)) => {
+        assert!(cmdstr.contains("--password"));
+        assert!(!cmdstr.contains("hunter2"));
+      }
+      res => panic!("unexpected result {:?}", res)
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
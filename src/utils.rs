@@ -1,20 +1,1111 @@
-use std::process::Command;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
 
 use crate::err::Error;
 
 
-pub(crate) fn exec(mut cmd: Command) -> Result<(Vec<u8>, Vec<u8>), Error> {
-  let out = match cmd.output() {
+pub(crate) fn exec(cmd: Command) -> Result<(Vec<u8>, Vec<u8>), Error> {
+  exec_redacted(cmd, &[])
+}
+
+
+/// Same as [`exec()`], but replaces all occurrences of the strings in
+/// `secrets` with `***` in the command line stored in returned errors.
+pub(crate) fn exec_redacted(
+  cmd: Command,
+  secrets: &[&str]
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+  exec_inner(cmd, secrets, |_| false)
+}
+
+
+/// Same as [`exec()`], but a command which exits with a non-zero status is
+/// considered successful if `accept` returns `true` for its output.
+///
+/// This is for subcommands which report benign conditions through their exit
+/// status.
+pub(crate) fn exec_accepting<F>(
+  cmd: Command,
+  accept: F
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  F: Fn(&Output) -> bool
+{
+  exec_inner(cmd, &[], accept)
+}
+
+
+/// How long to wait before retrying a command which failed with a transient
+/// error (see [`config::Config::retries`]).
+const RETRY_DELAY: Duration = if cfg!(test) {
+  Duration::ZERO
+} else {
+  Duration::from_millis(500)
+};
+
+
+fn exec_inner<F>(
+  cmd: Command,
+  secrets: &[&str],
+  accept: F
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  F: Fn(&Output) -> bool
+{
+  let cfg = config::get();
+  let mut cmd = configure(cmd, &cfg);
+
+  with_retries(cfg.retries, || {
+    exec_once(&mut cmd, secrets, &accept, cfg.timeout)
+  })
+}
+
+
+/// Call `f` until it succeeds or fails with an error which isn't transient,
+/// at most `retries` additional times.
+fn with_retries<T, F>(retries: u32, mut f: F) -> Result<T, Error>
+where
+  F: FnMut() -> Result<T, Error>
+{
+  let mut attempt = 0;
+  loop {
+    match f() {
+      Err(e) if e.is_transient() && attempt < retries => {
+        attempt += 1;
+        thread::sleep(RETRY_DELAY);
+      }
+      res => return res
+    }
+  }
+}
+
+
+fn exec_once<F>(
+  cmd: &mut Command,
+  secrets: &[&str],
+  accept: &F,
+  timeout: Option<Duration>
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  F: Fn(&Output) -> bool
+{
+  let notify = observe(cmd, secrets);
+
+  let out = match run(cmd, timeout) {
     Ok(out) => out,
+    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+      notify(false);
+      return Err(Error::Timeout);
+    }
     Err(_) => {
+      notify(false);
+      let cmdstr = redact(&format!("{:?}", cmd), secrets);
+      return Err(Error::FailedToExecute(cmdstr));
+    }
+  };
+
+  if out.status.success() || accept(&out) {
+    notify(true);
+    Ok((out.stdout, out.stderr))
+  } else {
+    notify(false);
+    let cmdstr = redact(&format!("{:?}", cmd), secrets);
+    Err(classify_failure(cmdstr, out))
+  }
+}
+
+
+/// Run a command to completion and collect its output.  Unit tests replace
+/// `VBoxManage` with a scripted [`mock::Runner`].
+fn run(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Output> {
+  if let Some(timeout) = timeout {
+    return run_with_timeout(cmd, timeout);
+  }
+  #[cfg(test)]
+  if let Some(res) = mock::run(cmd) {
+    return res;
+  }
+  cmd.output()
+}
+
+
+/// Same as [`run()`], but the command is killed and an error of the kind
+/// `TimedOut` is returned if it hasn't exited after `timeout`.
+fn run_with_timeout(
+  cmd: &mut Command,
+  timeout: Duration
+) -> io::Result<Output> {
+  let mut child = spawn(cmd)?;
+
+  // Drain both pipes while waiting, so the command can't block on a full
+  // pipe.
+  let pipes: Vec<Box<dyn Read + Send>> = vec![
+    Box::new(child.stdout.take().unwrap()),
+    Box::new(child.stderr.take().unwrap())
+  ];
+  let readers: Vec<_> = pipes
+    .into_iter()
+    .map(|mut pipe| {
+      thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+      })
+    })
+    .collect();
+
+  let deadline = Instant::now() + timeout;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+    }
+    thread::sleep(Duration::from_millis(10));
+  };
+
+  let mut outputs = readers
+    .into_iter()
+    .map(|reader| reader.join().unwrap_or_default());
+  Ok(Output {
+    status,
+    stdout: outputs.next().unwrap_or_default(),
+    stderr: outputs.next().unwrap_or_default()
+  })
+}
+
+
+/// Start a command with its output piped.  Like [`run()`], unit tests get
+/// the scripted reply instead.
+fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+  #[cfg(test)]
+  if let Some(mut sh) = mock::spawnable(cmd) {
+    return sh.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+  }
+  cmd.stdout(Stdio::piped());
+  cmd.stderr(Stdio::piped());
+  cmd.spawn()
+}
+
+
+/// Apply the configured `VBoxManage` executable, global flags and
+/// environment variables (see [`config::Config`]) to a command.
+///
+/// Only the environment variables apply to other VirtualBox tools.  Variables
+/// set on the command itself take precedence over the configured ones.
+fn configure(cmd: Command, cfg: &config::Config) -> Command {
+  let is_vboxmanage = Path::new(cmd.get_program())
+    .file_stem()
+    .is_some_and(|stem| stem == "VBoxManage");
+  let vboxmanage = cfg.vboxmanage.as_ref().filter(|_| is_vboxmanage);
+  let flags = if is_vboxmanage { &cfg.global_flags[..] } else { &[] };
+  if vboxmanage.is_none() && flags.is_empty() && cfg.env.is_empty() {
+    return cmd;
+  }
+
+  let mut out = match vboxmanage {
+    Some(path) => Command::new(path),
+    None => Command::new(cmd.get_program())
+  };
+  out.args(flags);
+  out.args(cmd.get_args());
+  out.envs(cfg.env.iter().map(|(key, val)| (key, val)));
+  for (key, val) in cmd.get_envs() {
+    match val {
+      Some(val) => out.env(key, val),
+      None => out.env_remove(key)
+    };
+  }
+  if let Some(dir) = cmd.get_current_dir() {
+    out.current_dir(dir);
+  }
+  out
+}
+
+
+/// Replace all occurrences of the strings in `secrets` with `***`.
+fn redact(s: &str, secrets: &[&str]) -> String {
+  let mut s = s.to_string();
+  for secret in secrets {
+    if !secret.is_empty() {
+      s = s.replace(secret, "***");
+    }
+  }
+  s
+}
+
+
+/// Start timing a command; the returned function reports the outcome to the
+/// configured observer, if any (see [`config::Config::observer`]).
+fn observe(cmd: &Command, secrets: &[&str]) -> Box<dyn Fn(bool) + Send> {
+  let observer = match config::get().observer {
+    Some(observer) => observer,
+    None => return Box::new(|_| {})
+  };
+  let argv: Vec<String> = std::iter::once(cmd.get_program())
+    .chain(cmd.get_args())
+    .map(|arg| redact(&arg.to_string_lossy(), secrets))
+    .collect();
+  let start = Instant::now();
+
+  Box::new(move |success| observer(&argv, start.elapsed(), success))
+}
+
+
+/// Run a command and read its output line by line, as it's produced.
+///
+/// The command's exit status is checked once all of its output has been
+/// read; a failure is reported as the iterator's last item.  If the iterator
+/// is dropped early the command is killed.  The configured timeout and
+/// retries (see [`config::Config`]) don't apply.
+pub(crate) fn exec_lines(cmd: Command) -> Result<ExecLines, Error> {
+  let mut cmd = configure(cmd, &config::get());
+  let notify = observe(&cmd, &[]);
+
+  let mut child = match spawn(&mut cmd) {
+    Ok(child) => child,
+    Err(_) => {
+      notify(false);
       return Err(Error::FailedToExecute(format!("{:?}", cmd)));
     }
   };
+  let reader = BufReader::new(child.stdout.take().unwrap());
+
+  Ok(ExecLines {
+    cmdstr: format!("{:?}", cmd),
+    child: Some(child),
+    reader,
+    finish: notify
+  })
+}
+
+
+/// Same as [`exec()`], but calls `on_progress` with the percentages of the
+/// `0%...10%...20%...` progress indicator `VBoxManage` prints while waiting
+/// for long-running operations.
+///
+/// Commands which don't print a progress indicator simply don't cause any
+/// calls.  If the command is retried (see [`config::Config::retries`]) the
+/// progress starts over.
+pub(crate) fn exec_progress<F>(
+  cmd: Command,
+  mut on_progress: F
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  F: FnMut(u8)
+{
+  let cfg = config::get();
+  let mut cmd = configure(cmd, &cfg);
+
+  with_retries(cfg.retries, || {
+    exec_progress_once(&mut cmd, &mut on_progress, cfg.timeout)
+  })
+}
+
+
+fn exec_progress_once<F>(
+  cmd: &mut Command,
+  on_progress: &mut F,
+  timeout: Option<Duration>
+) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+  F: FnMut(u8)
+{
+  let notify = observe(cmd, &[]);
 
+  let mut child = match spawn(cmd) {
+    Ok(child) => child,
+    Err(_) => {
+      notify(false);
+      return Err(Error::FailedToExecute(format!("{:?}", cmd)));
+    }
+  };
+
+  // Depending on the version the indicator is printed to stdout or stderr,
+  // so read both as output arrives.  The callback is called on this thread.
+  let (tx, rx) = mpsc::channel::<(usize, Vec<u8>)>();
+  let pipes: Vec<Box<dyn Read + Send>> = vec![
+    Box::new(child.stdout.take().unwrap()),
+    Box::new(child.stderr.take().unwrap())
+  ];
+  let readers: Vec<_> = pipes
+    .into_iter()
+    .enumerate()
+    .map(|(idx, mut pipe)| {
+      let tx = tx.clone();
+      thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = pipe.read(&mut buf) {
+          if n == 0 || tx.send((idx, buf[..n].to_vec())).is_err() {
+            break;
+          }
+        }
+      })
+    })
+    .collect();
+  drop(tx);
+
+  let deadline = timeout.map(|timeout| Instant::now() + timeout);
+  let mut outputs = [Vec::new(), Vec::new()];
+  let mut parsers = [ProgressParser::default(), ProgressParser::default()];
+  loop {
+    let received = match deadline {
+      Some(deadline) => {
+        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+      }
+      None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+    };
+    let (idx, chunk) = match received {
+      Ok(msg) => msg,
+      Err(RecvTimeoutError::Disconnected) => break,
+      Err(RecvTimeoutError::Timeout) => {
+        let _ = child.kill();
+        let _ = child.wait();
+        notify(false);
+        return Err(Error::Timeout);
+      }
+    };
+    for b in &chunk {
+      if let Some(pct) = parsers[idx].feed(*b) {
+        on_progress(pct);
+      }
+    }
+    outputs[idx].extend_from_slice(&chunk);
+  }
+  for reader in readers {
+    let _ = reader.join();
+  }
+
+  let status = match child.wait() {
+    Ok(status) => status,
+    Err(e) => {
+      notify(false);
+      return Err(e.into());
+    }
+  };
+  let [stdout, stderr] = outputs;
+  let out = Output {
+    status,
+    stdout,
+    stderr
+  };
+
+  notify(out.status.success());
   if out.status.success() {
     Ok((out.stdout, out.stderr))
   } else {
-    Err(Error::CommandFailed(format!("{:?}", cmd), out))
+    Err(classify_failure(format!("{:?}", cmd), out))
+  }
+}
+
+
+/// Picks the percentages out of a `0%...10%...20%...` progress indicator, one
+/// byte at a time.
+#[derive(Default)]
+struct ProgressParser {
+  digits: Option<u32>,
+  /// Set when a run of digits grew past 100; the rest of the run is skipped.
+  discard: bool
+}
+
+impl ProgressParser {
+  fn feed(&mut self, b: u8) -> Option<u8> {
+    match b {
+      b'0'..=b'9' if self.discard => None,
+      b'0'..=b'9' => {
+        let n = self.digits.unwrap_or(0) * 10 + u32::from(b - b'0');
+        // Anything larger than 100 isn't a percentage.
+        if n <= 100 {
+          self.digits = Some(n);
+        } else {
+          self.digits = None;
+          self.discard = true;
+        }
+        None
+      }
+      _ => {
+        self.discard = false;
+        match (b, self.digits.take()) {
+          (b'%', Some(n)) => Some(n as u8),
+          _ => None
+        }
+      }
+    }
+  }
+}
+
+
+/// Iterator over the output lines of a command; see [`exec_lines()`].
+pub(crate) struct ExecLines {
+  cmdstr: String,
+  child: Option<Child>,
+  reader: BufReader<ChildStdout>,
+  finish: Box<dyn Fn(bool) + Send>
+}
+
+impl ExecLines {
+  /// Wait for the command to exit and check its exit status.
+  fn wait(&mut self, mut child: Child) -> Result<(), Error> {
+    let mut stderr = Vec::new();
+    if let Some(mut pipe) = child.stderr.take() {
+      let _ = pipe.read_to_end(&mut stderr);
+    }
+    let status = match child.wait() {
+      Ok(status) => status,
+      Err(e) => {
+        (self.finish)(false);
+        return Err(e.into());
+      }
+    };
+    (self.finish)(status.success());
+    if status.success() {
+      Ok(())
+    } else {
+      let out = Output {
+        status,
+        stdout: Vec::new(),
+        stderr
+      };
+      Err(classify_failure(self.cmdstr.clone(), out))
+    }
+  }
+}
+
+impl Iterator for ExecLines {
+  type Item = Result<String, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let child = self.child.take()?;
+
+    let mut buf = Vec::new();
+    match self.reader.read_until(b'\n', &mut buf) {
+      Ok(0) => match self.wait(child) {
+        Ok(()) => None,
+        Err(e) => Some(Err(e))
+      },
+      Ok(_) => {
+        self.child = Some(child);
+        // Same treatment as buf_to_strlines(); drop the line ending and
+        // replace invalid UTF-8.
+        let line = String::from_utf8_lossy(&buf);
+        Some(Ok(line.trim_end_matches(['\r', '\n']).to_string()))
+      }
+      Err(e) => {
+        let _ = self.wait(child);
+        Some(Err(e.into()))
+      }
+    }
+  }
+}
+
+impl Drop for ExecLines {
+  fn drop(&mut self) {
+    if let Some(mut child) = self.child.take() {
+      let _ = child.kill();
+      let _ = child.wait();
+      (self.finish)(false);
+    }
+  }
+}
+
+
+/// Look for messages about the VirtualBox kernel driver not being available
+/// in `VBoxManage` output, and return the offending line.
+///
+/// These are printed as errors when trying to start a virtual machine, and
+/// as warnings by most other commands.
+pub(crate) fn driver_problem(text: &str) -> Option<String> {
+  const PATTERNS: &[&str] = &[
+    // Linux, 6.x:
+    // WARNING: The vboxdrv kernel module is not loaded. ...
+    "vboxdrv kernel module is not loaded",
+    // Linux, 7.x:
+    // WARNING: The VirtualBox kernel modules are not loaded. ...
+    "VirtualBox kernel modules are not loaded",
+    "kernel driver is either not loaded or not set up correctly",
+    // Kernel driver not installed (rc=-1908)
+    "Kernel driver not installed",
+    "VERR_VM_DRIVER_NOT_INSTALLED",
+    "VERR_VM_DRIVER_NOT_ACCESSIBLE"
+  ];
+
+  text
+    .lines()
+    .find(|line| PATTERNS.iter().any(|p| line.contains(p)))
+    .map(|line| line.trim().to_string())
+}
+
+
+/// Turn the output of a failed command into an `Error`.
+///
+/// Failures with a well-known cause are mapped to a more specific error than
+/// `Error::CommandFailed`.
+pub(crate) fn classify_failure(cmdstr: String, out: Output) -> Error {
+  let stderr = String::from_utf8_lossy(&out.stderr);
+
+  // VBoxManage: error: Could not find a registered machine named 'foo'
+  // VBoxManage: error: Could not find a registered machine with UUID {...}
+  for line in stderr.lines() {
+    if let Some(idx) = line.find("Could not find a registered machine") {
+      return Error::Missing(line[idx..].trim_end().to_string());
+    }
+  }
+
+  // VBoxManage: error: The machine is not mutable (state is Saving)
+  // VBoxManage: error: The machine 'foo' is already locked for a session
+  //  (or being unlocked)
+  const BUSY: &[&str] = &[
+    "is not mutable",
+    "is already locked for a session",
+    "is already locked by a session",
+    "another operation is in progress"
+  ];
+  for line in stderr.lines() {
+    if BUSY.iter().any(|p| line.contains(p)) {
+      return Error::Busy(line.trim().to_string());
+    }
+  }
+
+  if let Some(line) = driver_problem(&stderr) {
+    return Error::HostDriver(line);
+  }
+
+  // VBoxManage: error: Implementation of the USB 2.0 controller not found!
+  // VBoxManage: error: ... either install the 'Oracle VM VirtualBox
+  // Extension Pack' or disable USB 2.0 support in the VM settings ...
+  for line in stderr.lines() {
+    if line.to_lowercase().contains("extension pack") {
+      return Error::ExtPack(line.trim().to_string());
+    }
+  }
+
+  Error::CommandFailed(cmdstr, out)
+}
+
+
+/// Maximum number of `VBoxManage` processes [`parallel_map()`] runs at once.
+const MAX_CONCURRENCY: usize = 4;
+
+
+/// Apply `f` to each item of `items` using a small pool of threads, and
+/// return the results in the order of `items`.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+  T: Sync,
+  R: Send,
+  F: Fn(&T) -> R + Sync
+{
+  let next = AtomicUsize::new(0);
+  let results: Mutex<Vec<Option<R>>> =
+    Mutex::new(items.iter().map(|_| None).collect());
+
+  thread::scope(|scope| {
+    for _ in 0..MAX_CONCURRENCY.min(items.len()) {
+      scope.spawn(|| loop {
+        let idx = next.fetch_add(1, Ordering::SeqCst);
+        let item = match items.get(idx) {
+          Some(item) => item,
+          None => break
+        };
+        let res = f(item);
+        results.lock().unwrap_or_else(|e| e.into_inner())[idx] = Some(res);
+      });
+    }
+  });
+
+  // A worker panicking would have propagated out of the scope, so every
+  // item has a result.
+  results
+    .into_inner()
+    .unwrap_or_else(|e| e.into_inner())
+    .into_iter()
+    .map(|res| res.unwrap())
+    .collect()
+}
+
+
+#[cfg(test)]
+pub(crate) mod mock {
+  //! A scripted stand-in for `VBoxManage`, for unit tests.
+  //!
+  //! While a [`Runner`] exists every command run through `exec()` and its
+  //! variants is recorded instead of being run, and answered with the next
+  //! queued reply (or an empty, successful one).  `VBoxManage --version` is
+  //! answered separately and isn't recorded; creating a `Runner` or changing
+  //! its version discards the cached version.  Commands whose output is
+  //! streamed are replaced by a shell command which prints the reply (on
+  //! unix only).  Commands which are run
+  //! concurrently, in no particular order, can instead be answered by
+  //! argument using [`Runner::ok_for()`] and [`Runner::fail_for()`].
+
+  use std::collections::VecDeque;
+  use std::io;
+  use std::process::{Command, ExitStatus, Output};
+  use std::sync::{Mutex, MutexGuard};
+  use std::thread;
+  use std::time::Duration;
+
+  struct Script {
+    replies: VecDeque<(i32, String, String)>,
+    by_arg: Vec<(String, (i32, String, String))>,
+    calls: Vec<Vec<String>>,
+    version: String,
+    delay: Duration
+  }
+
+  static SCRIPT: Mutex<Option<Script>> = Mutex::new(None);
+
+  /// Serializes the tests which use a [`Runner`] (or change the global
+  /// configuration), since the script is shared by all threads.
+  static SERIAL: Mutex<()> = Mutex::new(());
+
+  fn script() -> MutexGuard<'static, Option<Script>> {
+    SCRIPT.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  pub(crate) struct Runner {
+    _serial: MutexGuard<'static, ()>
+  }
+
+  impl Runner {
+    pub(crate) fn new() -> Self {
+      let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+      *script() = Some(Script {
+        replies: VecDeque::new(),
+        by_arg: Vec::new(),
+        calls: Vec::new(),
+        version: "7.0.10r158379".to_string(),
+        delay: Duration::ZERO
+      });
+      crate::version::clear_cache();
+      Runner { _serial: serial }
+    }
+
+    /// Set the version reported by `VBoxManage --version`.
+    pub(crate) fn version(&self, ver: &str) -> &Self {
+      if let Some(s) = script().as_mut() {
+        s.version = ver.to_string();
+      }
+      crate::version::clear_cache();
+      self
+    }
+
+    /// Make every command take (at least) `delay` to run.
+    pub(crate) fn delay(&self, delay: Duration) -> &Self {
+      if let Some(s) = script().as_mut() {
+        s.delay = delay;
+      }
+      self
+    }
+
+    /// Queue a successful reply with the output `stdout`.
+    pub(crate) fn ok(&self, stdout: &str) -> &Self {
+      self.reply(0, stdout, "")
+    }
+
+    /// Queue a successful reply with the output `stdout` which also prints
+    /// `stderr`, as commands do when they warn about something.
+    pub(crate) fn warn(&self, stdout: &str, stderr: &str) -> &Self {
+      self.reply(0, stdout, stderr)
+    }
+
+    /// Queue a failed reply with the error output `stderr`.
+    pub(crate) fn fail(&self, stderr: &str) -> &Self {
+      self.reply(1, "", stderr)
+    }
+
+    /// Answer every command which has the argument `arg` with the output
+    /// `stdout`, instead of replying from the queue.
+    pub(crate) fn ok_for(&self, arg: &str, stdout: &str) -> &Self {
+      self.reply_for(arg, 0, stdout, "")
+    }
+
+    /// Fail every command which has the argument `arg` with the error
+    /// output `stderr`, instead of replying from the queue.
+    pub(crate) fn fail_for(&self, arg: &str, stderr: &str) -> &Self {
+      self.reply_for(arg, 1, "", stderr)
+    }
+
+    fn reply_for(
+      &self,
+      arg: &str,
+      code: i32,
+      stdout: &str,
+      stderr: &str
+    ) -> &Self {
+      if let Some(s) = script().as_mut() {
+        let reply = (code, stdout.to_string(), stderr.to_string());
+        s.by_arg.push((arg.to_string(), reply));
+      }
+      self
+    }
+
+    fn reply(&self, code: i32, stdout: &str, stderr: &str) -> &Self {
+      if let Some(s) = script().as_mut() {
+        let reply = (code, stdout.to_string(), stderr.to_string());
+        s.replies.push_back(reply);
+      }
+      self
+    }
+
+    /// Get the arguments of each command run so far, in order.
+    pub(crate) fn calls(&self) -> Vec<Vec<String>> {
+      script().as_ref().map(|s| s.calls.clone()).unwrap_or_default()
+    }
+  }
+
+  impl Drop for Runner {
+    fn drop(&mut self) {
+      *script() = None;
+    }
+  }
+
+  pub(super) fn run(cmd: &Command) -> Option<io::Result<Output>> {
+    let ((code, stdout, stderr), delay) = next_reply(cmd)?;
+    thread::sleep(delay);
+    Some(Ok(output(code, &stdout, &stderr)))
+  }
+
+  /// Get a command which produces the scripted reply to `cmd` through real
+  /// pipes, for the functions which stream a command's output.
+  #[cfg(unix)]
+  pub(super) fn spawnable(cmd: &Command) -> Option<Command> {
+    let ((code, stdout, stderr), delay) = next_reply(cmd)?;
+    let delay = format!("{}.{:03}", delay.as_secs(), delay.subsec_millis());
+    let mut sh = Command::new("sh");
+    sh.args([
+      "-c",
+      r#"[ "$4" = 0.000 ] || sleep "$4"
+printf %s "$1"; printf %s "$2" >&2; exit "$3""#
+    ]);
+    sh.args(["sh", &stdout, &stderr, &code.to_string(), &delay]);
+    Some(sh)
+  }
+
+  #[cfg(not(unix))]
+  pub(super) fn spawnable(_cmd: &Command) -> Option<Command> {
+    None
+  }
+
+  /// Record `cmd` and get the reply to it and how long to take to give it,
+  /// or `None` if no [`Runner`] exists.
+  fn next_reply(
+    cmd: &Command
+  ) -> Option<((i32, String, String), Duration)> {
+    let mut guard = script();
+    let script = guard.as_mut()?;
+
+    let args: Vec<String> = cmd
+      .get_args()
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect();
+    if args == ["--version"] {
+      let reply = (0, script.version.clone(), String::new());
+      return Some((reply, Duration::ZERO));
+    }
+    let by_arg = script
+      .by_arg
+      .iter()
+      .find(|(arg, _)| args.contains(arg))
+      .map(|(_, reply)| reply.clone());
+    script.calls.push(args);
+
+    let reply = match by_arg {
+      Some(reply) => reply,
+      None => script
+        .replies
+        .pop_front()
+        .unwrap_or((0, String::new(), String::new()))
+    };
+    Some((reply, script.delay))
+  }
+
+  fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+    Output {
+      status: exit_status(code),
+      stdout: stdout.as_bytes().to_vec(),
+      stderr: stderr.as_bytes().to_vec()
+    }
+  }
+
+  #[cfg(unix)]
+  fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(code << 8)
+  }
+
+  #[cfg(windows)]
+  fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::mock::Runner;
+  use super::*;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  fn vboxmanage(args: &[&str]) -> Command {
+    let mut cmd = Command::new("VBoxManage");
+    cmd.args(args);
+    cmd
+  }
+
+  #[test]
+  fn runner_records_calls_and_replays_output() {
+    let runner = Runner::new();
+    runner.ok("first").fail("VBoxManage: error: oops");
+
+    let (stdout, _) = exec(vboxmanage(&["list", "vms"])).unwrap();
+    assert_eq!(stdout, b"first");
+    match exec(vboxmanage(&["startvm", "vm"])) {
+      Err(Error::CommandFailed(_, out)) => {
+        assert_eq!(out.stderr, b"VBoxManage: error: oops")
+      }
+      res => panic!("unexpected result {:?}", res)
+    }
+    // Nothing left to replay; succeeds with no output.
+    assert!(exec(vboxmanage(&["list", "hdds"])).unwrap().0.is_empty());
+
+    assert_eq!(
+      runner.calls(),
+      [vec!["list", "vms"], vec!["startvm", "vm"], vec!["list", "hdds"]]
+    );
+  }
+
+  #[test]
+  fn runner_reports_scripted_version() {
+    let runner = Runner::new();
+    runner.version("6.1.38_Ubuntur153438");
+
+    let ver = crate::version().unwrap();
+    assert_eq!((ver.major, ver.minor, ver.build), (6, 1, 38));
+    assert!(runner.calls().is_empty());
+  }
+
+  #[test]
+  fn unknown_vm_is_missing() {
+    let runner = Runner::new();
+    runner.fail(concat!(
+      "VBoxManage: error: Could not find a registered machine named 'nope'\n",
+      "VBoxManage: error: Details: code VBOX_E_OBJECT_NOT_FOUND (0x80bb0001)\n"
+    ));
+
+    match exec(vboxmanage(&["showvminfo", "nope"])) {
+      Err(Error::Missing(s)) => {
+        assert_eq!(s, "Could not find a registered machine named 'nope'")
+      }
+      res => panic!("unexpected result {:?}", res)
+    }
+  }
+
+  #[test]
+  fn host_problems_are_classified() {
+    let runner = Runner::new();
+    runner.fail(concat!(
+      "VBoxManage: error: The virtual machine 'vm' has terminated ",
+      "unexpectedly during startup with exit code 1 (0x1)\n",
+      "VBoxManage: error: Details: code NS_ERROR_FAILURE (0x80004005)\n",
+      "Kernel driver not installed (rc=-1908)\n"
+    ));
+    runner.fail(concat!(
+      "VBoxManage: error: Implementation of the USB 2.0 controller not ",
+      "found!\n",
+      "VBoxManage: error: Because the USB 2.0 controller state is part of ",
+      "the saved VM state, the VM cannot be started. To fix this problem, ",
+      "either install the 'Oracle VM VirtualBox Extension Pack' or disable ",
+      "USB 2.0 support in the VM settings.\n"
+    ));
+
+    let res = exec(vboxmanage(&["startvm", "vm"]));
+    assert!(matches!(res, Err(Error::HostDriver(_))));
+    let res = exec(vboxmanage(&["startvm", "vm"]));
+    assert!(matches!(res, Err(Error::ExtPack(_))));
+  }
+
+  #[test]
+  fn observer_sees_each_command() {
+    let runner = Runner::new();
+    runner.delay(Duration::from_millis(50));
+    runner.ok("").fail("VBoxManage: error: oops");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    config::set(config::Config {
+      observer: Some(Arc::new(move |argv: &[String], dur, success| {
+        log.lock().unwrap().push((argv.to_vec(), dur, success));
+      })),
+      ..Default::default()
+    });
+
+    let _ = exec(vboxmanage(&["list", "vms"]));
+    let _ = exec_redacted(vboxmanage(&["startvm", "hunter2"]), &["hunter2"]);
+    config::set(config::Config::default());
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0].0, ["VBoxManage", "list", "vms"]);
+    assert_eq!(seen[1].0, ["VBoxManage", "startvm", "***"]);
+    assert!(seen.iter().all(|(_, dur, _)| *dur >= Duration::from_millis(50)));
+    assert!(seen[0].2);
+    assert!(!seen[1].2);
+  }
+
+  #[test]
+  fn global_flags_precede_subcommand() {
+    let runner = Runner::new();
+    config::set(config::Config {
+      global_flags: vec!["-q".to_string(), "--nologo".to_string()],
+      ..Default::default()
+    });
+
+    let _ = exec(vboxmanage(&["showvminfo", "vm", "--machinereadable"]));
+    let mut other = Command::new("VBoxHeadless");
+    other.args(["--startvm", "vm"]);
+    let _ = exec(other);
+    config::set(config::Config::default());
+
+    assert_eq!(
+      runner.calls(),
+      [
+        vec!["-q", "--nologo", "showvminfo", "vm", "--machinereadable"],
+        vec!["--startvm", "vm"]
+      ]
+    );
+  }
+
+  #[test]
+  fn configure_applies_path_and_environment() {
+    let cfg = config::Config {
+      global_flags: vec!["-q".to_string()],
+      vboxmanage: Some("/opt/vbox/VBoxManage".into()),
+      env: vec![
+        ("VBOX_USER_HOME".to_string(), "/srv/vbox".to_string()),
+        ("LANG".to_string(), "C".to_string())
+      ],
+      ..Default::default()
+    };
+    let mut cmd = vboxmanage(&["list", "vms"]);
+    cmd.env("LANG", "en_US.UTF-8");
+    let cmd = configure(cmd, &cfg);
+    assert_eq!(cmd.get_program(), "/opt/vbox/VBoxManage");
+    assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-q", "list", "vms"]);
+    let envs: Vec<(String, String)> = cmd
+      .get_envs()
+      .map(|(key, val)| {
+        let val = val.unwrap_or_default().to_string_lossy();
+        (key.to_string_lossy().into_owned(), val.into_owned())
+      })
+      .collect();
+    assert!(envs.contains(&("VBOX_USER_HOME".into(), "/srv/vbox".into())));
+    assert!(envs.contains(&("LANG".into(), "en_US.UTF-8".into())));
+
+    let mut other = Command::new("VBoxHeadless");
+    other.args(["--startvm", "vm"]);
+    let other = configure(other, &cfg);
+    assert_eq!(other.get_program(), "VBoxHeadless");
+    assert_eq!(other.get_args().collect::<Vec<_>>(), ["--startvm", "vm"]);
+    assert_eq!(other.get_envs().count(), 2);
+  }
+
+  #[test]
+  fn transient_errors_are_retried() {
+    let runner = Runner::new();
+    config::set(config::Config {
+      retries: 2,
+      ..Default::default()
+    });
+    let busy = "VBoxManage: error: The machine is not mutable (state Saving)";
+    runner.fail(busy).fail(busy).ok("done");
+    runner.fail(busy).fail(busy).fail(busy);
+    runner.fail("VBoxManage: error: oops");
+
+    let first = exec(vboxmanage(&["modifyvm", "vm", "--cpus", "2"]));
+    let second = exec(vboxmanage(&["modifyvm", "vm", "--cpus", "2"]));
+    let third = exec(vboxmanage(&["modifyvm", "vm", "--cpus", "2"]));
+    config::set(config::Config::default());
+
+    assert_eq!(first.unwrap().0, b"done");
+    assert!(matches!(second, Err(Error::Busy(_))));
+    assert!(matches!(third, Err(Error::CommandFailed(_, _))));
+    assert_eq!(runner.calls().len(), 7);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn slow_commands_time_out() {
+    let runner = Runner::new();
+    config::set(config::Config {
+      timeout: Some(Duration::from_millis(200)),
+      ..Default::default()
+    });
+    runner.ok("fast");
+    let fast = exec(vboxmanage(&["list", "vms"]));
+    runner.delay(Duration::from_secs(5));
+    let start = Instant::now();
+    let slow = exec(vboxmanage(&["list", "vms"]));
+    config::set(config::Config::default());
+
+    assert_eq!(fast.unwrap().0, b"fast");
+    assert!(matches!(slow, Err(Error::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(2));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn progress_commands_retry_and_time_out() {
+    let runner = Runner::new();
+    config::set(config::Config {
+      retries: 1,
+      timeout: Some(Duration::from_millis(200)),
+      ..Default::default()
+    });
+    let restore = || vboxmanage(&["snapshot", "vm", "restore", "snap"]);
+    let busy = "VBoxManage: error: The machine is not mutable (state Saving)";
+    runner.fail(busy);
+    runner.ok("0%...50%...100%\n");
+    let mut pcts = Vec::new();
+    let res = exec_progress(restore(), |p| pcts.push(p));
+    assert!(res.is_ok());
+    assert_eq!(runner.calls().len(), 2);
+
+    runner.delay(Duration::from_secs(5));
+    let start = Instant::now();
+    let res = exec_progress(restore(), |_| {});
+    config::set(config::Config::default());
+
+    assert_eq!(pcts, [0, 50, 100]);
+    assert!(matches!(res, Err(Error::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(2));
+  }
+
+  #[test]
+  fn progress_parser_picks_percentages() {
+    let mut parser = ProgressParser::default();
+    let text = "Restoring snapshot 1234: \
+                0%...10%...20%...1000%...1005%...2005%...10005%...100%\n";
+    let pcts: Vec<u8> = text.bytes().filter_map(|b| parser.feed(b)).collect();
+    assert_eq!(pcts, [0, 10, 20, 100]);
+  }
+
+  #[test]
+  fn redact_masks_secrets() {
+    assert_eq!(redact("--password hunter2", &["hunter2"]), "--password ***");
+    assert_eq!(redact("unchanged", &[""]), "unchanged");
   }
 }
 
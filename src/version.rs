@@ -0,0 +1,205 @@
+//! Detect which version of VirtualBox is installed.
+//!
+//! The syntax of some `VBoxManage` subcommands differs between VirtualBox
+//! releases; command builders which are affected consult [`version()`] to
+//! select the right form.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::config;
+use crate::platform;
+use crate::strutils::{buf_to_strlines, EmptyLine};
+use crate::utils;
+use crate::Error;
+
+
+/// A VirtualBox version, as reported by `VBoxManage --version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+  pub major: u32,
+  pub minor: u32,
+  pub build: u32,
+
+  /// Source revision, for example `158379` in `7.0.10r158379`.
+  pub revision: Option<u32>
+}
+
+impl Version {
+  /// Returns `true` if this version is `major.minor` or later.
+  pub fn at_least(&self, major: u32, minor: u32) -> bool {
+    (self.major, self.minor) >= (major, minor)
+  }
+}
+
+impl fmt::Display for Version {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}.{}.{}", self.major, self.minor, self.build)?;
+    if let Some(rev) = self.revision {
+      write!(f, "r{}", rev)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Version {
+  type Err = Error;
+
+  /// Parse version strings such as `7.0.10r158379` or (as shipped by some
+  /// distributions) `6.1.38_Ubuntur153438`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    let bad = || Error::BadFormat(format!("Unable to parse version '{}'", s));
+
+    let end = s
+      .find(|c: char| !c.is_ascii_digit() && c != '.')
+      .unwrap_or(s.len());
+    let mut nums = s[..end].split('.').map(|n| n.parse::<u32>());
+    let mut next = || match nums.next() {
+      Some(Ok(n)) => Ok(n),
+      _ => Err(bad())
+    };
+    let major = next()?;
+    let minor = next()?;
+    let build = next().unwrap_or(0);
+
+    let revision = match s[end..].rfind('r') {
+      Some(idx) => s[end + idx + 1..].parse::<u32>().ok(),
+      None => None
+    };
+
+    Ok(Version {
+      major,
+      minor,
+      build,
+      revision
+    })
+  }
+}
+
+
+/// The cached version, along with the configured `VBoxManage` executable
+/// (see [`config::Config::vboxmanage`]) it was reported by.
+static VERSION: Mutex<Option<(Option<PathBuf>, Version)>> = Mutex::new(None);
+
+
+/// Get the version of the installed VirtualBox.
+///
+/// `VBoxManage` is only queried the first time this is called; the result is
+/// cached for the lifetime of the process, or until a different `VBoxManage`
+/// executable is configured.
+pub fn version() -> Result<Version, Error> {
+  let path = config::get().vboxmanage;
+  let mut cached = VERSION.lock().unwrap_or_else(|e| e.into_inner());
+  if let Some((ref cached_path, ver)) = *cached {
+    if *cached_path == path {
+      return Ok(ver);
+    }
+  }
+
+  // VBoxManage --version
+
+  let mut cmd = Command::new(platform::get_cmd("VBoxManage"));
+  cmd.arg("--version");
+
+  let (stdout, _) = utils::exec(cmd)?;
+
+  // Warnings (for instance about the kernel module not being loaded) may
+  // precede the version, so use the last line.
+  let lines = buf_to_strlines(&stdout, EmptyLine::Ignore);
+  let ver = match lines.last() {
+    Some(line) => line.parse::<Version>()?,
+    None => {
+      let s = "VBoxManage did not report a version".to_string();
+      return Err(Error::MissingData(s));
+    }
+  };
+
+  *cached = Some((path, ver));
+
+  Ok(ver)
+}
+
+
+/// Forget the cached version, so the next call to [`version()`] queries
+/// `VBoxManage` again.  Tests script the version, so it mustn't stick
+/// between them.
+#[cfg(test)]
+pub(crate) fn clear_cache() {
+  *VERSION.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::mock::Runner;
+
+  fn ver(major: u32, minor: u32, build: u32, rev: Option<u32>) -> Version {
+    Version {
+      major,
+      minor,
+      build,
+      revision: rev
+    }
+  }
+
+  #[test]
+  fn parse_versions() {
+    let v = "7.0.10r158379".parse::<Version>().unwrap();
+    assert_eq!(v, ver(7, 0, 10, Some(158379)));
+    assert_eq!(v.to_string(), "7.0.10r158379");
+
+    let v = "6.1.38_Ubuntur153438\n".parse::<Version>().unwrap();
+    assert_eq!(v, ver(6, 1, 38, Some(153438)));
+
+    assert_eq!("7.1".parse::<Version>().unwrap(), ver(7, 1, 0, None));
+  }
+
+  #[test]
+  fn parse_rejects_garbage() {
+    assert!("".parse::<Version>().is_err());
+    assert!("7".parse::<Version>().is_err());
+    assert!("WARNING: blah".parse::<Version>().is_err());
+  }
+
+  #[test]
+  fn at_least_compares_major_and_minor() {
+    let v = ver(6, 1, 38, None);
+    assert!(v.at_least(6, 1));
+    assert!(v.at_least(5, 2));
+    assert!(!v.at_least(7, 0));
+  }
+
+  #[test]
+  fn version_uses_last_line() {
+    let runner = Runner::new();
+    runner.version(concat!(
+      "WARNING: The vboxdrv kernel module is not loaded.\n",
+      "7.0.6r155176"
+    ));
+    assert_eq!(version().unwrap(), ver(7, 0, 6, Some(155176)));
+  }
+
+  #[test]
+  fn version_cache_follows_configured_executable() {
+    let _runner = Runner::new();
+    let old = ver(6, 1, 38, None);
+    *VERSION.lock().unwrap() = Some((None, old));
+    assert_eq!(version().unwrap(), old);
+
+    config::set(config::Config {
+      vboxmanage: Some(PathBuf::from("/opt/vbox7/VBoxManage")),
+      ..Default::default()
+    });
+    let new = version();
+    config::set(config::Config::default());
+
+    assert_eq!(new.unwrap(), ver(7, 0, 10, Some(158379)));
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
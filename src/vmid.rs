@@ -45,14 +45,13 @@ pub enum VmId {
 
 impl fmt::Display for VmId {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match &*self {
+    match self {
       VmId::Name(n) => write!(f, "{}", n),
       VmId::Uuid(u) => write!(
         f,
         "{{{}}}",
         u.to_hyphenated()
           .encode_lower(&mut uuid::Uuid::encode_buffer())
-          .to_string()
       )
     }
   }